@@ -8,42 +8,184 @@ extern crate tokio_service;
 extern crate rand;
 extern crate time;
 extern crate clap;
+extern crate libc;
+extern crate log;
 
 use rcache::client;
 use rcache::service;
 use rcache::cache;
+use rcache::membership::Membership;
 use std::error::Error;
-use std::net::SocketAddr;
 use rcache::message::{Message, Op, Code};
-use futures::Future;
+use futures::{future, Future};
+use futures::sync::oneshot;
+use std::io;
+use std::mem;
+use std::path::PathBuf;
+use std::ptr;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::thread;
+use std::time::Duration;
 use tokio_core::reactor::Core;
 use rcache::stats::Stats;
 use clap::{Arg, App, SubCommand, ArgMatches};
 
 
 static DEFAULT_CACHE_SIZE: usize = 2000000;
+static DEFAULT_SHARDS: usize = 1;
+
+/// The write end of the pipe `SIGINT` and `SIGTERM` are reported on, to stop the server
+/// gracefully, see `stop_signal`.
+static STOP_PIPE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Writes the library's log records, such as failed snapshots or replication errors, to stderr.
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{}: {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
 
 fn main() {
     let set = SubCommand::with_name("SET")
         .arg(Arg::with_name("KEY").required(true).index(1))
-        .arg(Arg::with_name("VALUE").required(true).index(2));
+        .arg(Arg::with_name("VALUE").required(true).index(2))
+        .arg(Arg::with_name("TTL").long("ttl").takes_value(true).help(
+            "Expire the key after this many seconds",
+        ));
+
+    let incr = SubCommand::with_name("INCR")
+        .arg(Arg::with_name("KEY").required(true).index(1))
+        .arg(Arg::with_name("DELTA").index(2));
+
+    let decr = SubCommand::with_name("DECR")
+        .arg(Arg::with_name("KEY").required(true).index(1))
+        .arg(Arg::with_name("DELTA").index(2));
+
+    let ttl = SubCommand::with_name("TTL").arg(Arg::with_name("KEY").required(true).index(1));
 
     let get = SubCommand::with_name("GET").arg(Arg::with_name("KEY").required(true).index(1));
 
+    let del = SubCommand::with_name("DEL").arg(Arg::with_name("KEY").required(true).index(1));
+
     let stats = SubCommand::with_name("STATS").about("Retrieves stats from given server");
 
+    let snapshot = SubCommand::with_name("SNAPSHOT").about(
+        "Saves the server's cache to its snapshot file",
+    );
+
     let client = SubCommand::with_name("client")
         .about("Run a client command on server at given address")
+        .arg(Arg::with_name("timeout").long("timeout").takes_value(true).help(
+            "Give up on the command if the server hasn't got to it within this many milliseconds",
+        ))
         .subcommand(get)
         .subcommand(set)
-        .subcommand(stats);
+        .subcommand(del)
+        .subcommand(incr)
+        .subcommand(decr)
+        .subcommand(ttl)
+        .subcommand(stats)
+        .subcommand(snapshot);
 
     let server = SubCommand::with_name("server")
         .about("Start a server at given address")
-        .arg(Arg::with_name("Cache Size").long("cache_size").help(
+        .arg(Arg::with_name("cache_size").long("cache_size").takes_value(true).help(
             "Maximum number of entries in cache, default: 2,000,000",
-        ));
+        ))
+        .arg(Arg::with_name("memory_limit").long("memory_limit").takes_value(true).help(
+            "Bound the cache by memory instead of entries, in bytes or with a K, M or G suffix",
+        ))
+        .arg(Arg::with_name("shards").long("shards").takes_value(true).help(
+            "Number of shards to split the cache into, each with its own worker thread, default: 1",
+        ))
+        .arg(Arg::with_name("memcached_addr").long("memcached_addr").takes_value(true).help(
+            "Also serve the memcached text protocol at this address",
+        ))
+        .arg(Arg::with_name("memcached_binary_addr").long("memcached_binary_addr").takes_value(true).help(
+            "Also serve the memcached binary protocol at this address",
+        ))
+        .arg(Arg::with_name("resp_addr").long("resp_addr").takes_value(true).help(
+            "Also serve Redis clients at this address",
+        ))
+        .arg(Arg::with_name("http_addr").long("http_addr").takes_value(true).help(
+            "Also serve the HTTP API at this address",
+        ))
+        .arg(Arg::with_name("max_key_size").long("max_key_size").takes_value(true).help(
+            "Reject keys larger than this, in bytes or with a K, M or G suffix, default: 64K",
+        ))
+        .arg(Arg::with_name("max_value_size").long("max_value_size").takes_value(true).help(
+            "Reject values larger than this, default: 32M",
+        ))
+        .arg(Arg::with_name("max_frame_size").long("max_frame_size").takes_value(true).help(
+            "Reject frames larger than this, default: 64M",
+        ))
+        .arg(Arg::with_name("hard_frame_size").long("hard_frame_size").takes_value(true).help(
+            "Close connections sending frames larger than this, >= max_frame_size, default: 1G",
+        ))
+        .arg(Arg::with_name("socket_mode").long("socket_mode").takes_value(true).help(
+            "Permissions of Unix domain sockets the server listens on, in octal, e.g. 660",
+        ))
+        .arg(Arg::with_name("snapshot_path").long("snapshot_path").takes_value(true).help(
+            "Load the cache from this file on startup, and save it there on shutdown",
+        ))
+        .arg(
+            Arg::with_name("snapshot_interval")
+                .long("snapshot_interval")
+                .takes_value(true)
+                .requires("snapshot_path")
+                .help("Also save the cache every this many seconds"),
+        )
+        .arg(
+            Arg::with_name("log_path")
+                .long("log_path")
+                .takes_value(true)
+                .requires("snapshot_path")
+                .help("Append every write to this file, and replay it on startup"),
+        )
+        .arg(
+            Arg::with_name("fsync")
+                .long("fsync")
+                .takes_value(true)
+                .possible_values(&["always", "everysec", "no"])
+                .requires("log_path")
+                .help("When to sync the log to disk, default: everysec"),
+        )
+        .arg(Arg::with_name("replica_of").long("replica_of").takes_value(true).help(
+            "Serve a read only replica of the rcache server at this address",
+        ))
+        .arg(Arg::with_name("cluster_config").long("cluster_config").takes_value(true).help(
+            "Serve a node of the cluster whose nodes are listed in this file, an address and \
+             optional weight to a line",
+        ))
+        .arg(
+            Arg::with_name("cluster_node")
+                .long("cluster_node")
+                .takes_value(true)
+                .requires("cluster_config")
+                .help(
+                    "The address of this node in the cluster config, if it isn't the one it \
+                     binds to, as when binding to 0.0.0.0",
+                ),
+        )
+        .arg(
+            Arg::with_name("no_forwarding")
+                .long("no_forwarding")
+                .requires("cluster_config")
+                .help("Answer requests for other nodes' keys with the owner's address"),
+        );
 
     let matches = App::new("rcache")
         .version("0.1")
@@ -53,7 +195,8 @@ fn main() {
             Arg::with_name("Socket Address")
                 .help(
                     "Address to bind to if running server subcommand, or theaddress \
-                of the rcache server if running a client command",
+                of the rcache server if running a client command. A Unix domain socket \
+                is given as unix:/path/to/sock",
                 )
                 .required(true)
                 .index(1),
@@ -62,6 +205,10 @@ fn main() {
         .subcommand(server)
         .get_matches();
 
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(log::LevelFilter::Info);
+    }
+
     match run(&matches) {
         Ok(result) => println!("{}", result),
         Err(err) => println!("err: {}", err),
@@ -70,18 +217,99 @@ fn main() {
 
 fn run(matches: &ArgMatches) -> Result<String, String> {
     // Unwraps in here are safe because clap has already validated that required params are present
-    let addr: SocketAddr = matches
-        .value_of("Socket Address")
-        .unwrap()
-        .parse()
-        .map_err(|_| "Failed to parse socket address.")?;
+    let addr: service::Addr = matches.value_of("Socket Address").unwrap().parse()?;
 
     if let Some(matches) = matches.subcommand_matches("server") {
+        let membership = match matches.value_of("cluster_config") {
+            Some(path) => {
+                let this: service::Addr = match matches.value_of("cluster_node") {
+                    Some(node) => node.parse()?,
+                    None => addr.clone(),
+                };
+                let forward = !matches.is_present("no_forwarding");
+                Some(Membership::load(path.as_ref(), &this, forward).map_err(|e| e.to_string())?)
+            }
+            None => None,
+        };
         let cache_size: usize = matches
             .value_of("cache_size")
             .map(|s| s.parse().unwrap_or_else(|_| DEFAULT_CACHE_SIZE))
             .unwrap_or_else(|| DEFAULT_CACHE_SIZE);
-        run_server(addr, cache_size).map(|_| "success".to_owned())
+        let capacity = match matches.value_of("memory_limit") {
+            Some(limit) => cache::Capacity::Bytes(parse_size(limit)?),
+            None => cache::Capacity::Entries(cache_size),
+        };
+        let shards: usize = matches
+            .value_of("shards")
+            .map(|s| s.parse().unwrap_or_else(|_| DEFAULT_SHARDS))
+            .unwrap_or_else(|| DEFAULT_SHARDS);
+        let defaults = service::Limits::default();
+        let limits = service::Limits {
+            max_key_len: size_arg(matches, "max_key_size", defaults.max_key_len)?,
+            max_value_len: size_arg(matches, "max_value_size", defaults.max_value_len)?,
+            max_frame_len: size_arg(matches, "max_frame_size", defaults.max_frame_len)?,
+            hard_frame_len: size_arg(matches, "hard_frame_size", defaults.hard_frame_len)?,
+        };
+        limits.validate().map_err(|e| e.to_string())?;
+        let mode = match matches.value_of("socket_mode") {
+            Some(mode) => {
+                Some(u32::from_str_radix(mode, 8).map_err(
+                    |_| format!("Failed to parse socket mode: {}", mode),
+                )?)
+            }
+            None => None,
+        };
+
+        let mut listeners = vec![
+            service::Listener {
+                protocol: service::Protocol::Rcache,
+                addr: addr,
+            },
+        ];
+        let protocols = [
+            ("memcached_addr", service::Protocol::MemcachedText),
+            ("memcached_binary_addr", service::Protocol::MemcachedBinary),
+            ("resp_addr", service::Protocol::Resp),
+            ("http_addr", service::Protocol::Http),
+        ];
+        for &(arg, protocol) in &protocols {
+            if let Some(addr) = matches.value_of(arg) {
+                listeners.push(service::Listener {
+                    protocol: protocol,
+                    addr: addr.parse()?,
+                });
+            }
+        }
+        for listener in &mut listeners {
+            if let service::Addr::Unix(_, ref mut socket_mode) = listener.addr {
+                *socket_mode = mode;
+            }
+        }
+        let interval = match matches.value_of("snapshot_interval") {
+            Some(secs) => {
+                Some(Duration::from_secs(secs.parse().map_err(
+                    |_| format!("Failed to parse snapshot interval: {}", secs),
+                )?))
+            }
+            None => None,
+        };
+        let persistence = cache::Persistence {
+            snapshot_path: matches.value_of("snapshot_path").map(PathBuf::from),
+            snapshot_interval: interval,
+            log_path: matches.value_of("log_path").map(PathBuf::from),
+            fsync: match matches.value_of("fsync") {
+                Some(fsync) => fsync.parse()?,
+                None => cache::Fsync::default(),
+            },
+        };
+        let cache = match matches.value_of("replica_of") {
+            Some(primary) => {
+                let primary = primary.parse()?;
+                cache::Cache::replica(capacity, shards, persistence, primary)
+            }
+            None => cache::Cache::with_persistence(capacity, shards, persistence),
+        }.map_err(|e| e.description().to_owned())?;
+        run_server(listeners, limits, cache, membership).map(|_| "success".to_owned())
     } else if let Some(matches) = matches.subcommand_matches("client") {
         run_client(addr, matches)
     } else {
@@ -89,9 +317,43 @@ fn run(matches: &ArgMatches) -> Result<String, String> {
     }
 }
 
-fn run_client(addr: SocketAddr, matches: &ArgMatches) -> Result<String, String> {
+/// Parse a byte size such as `1048576`, `512K`, `64M` or `2G`.
+fn parse_size(s: &str) -> Result<usize, String> {
+    let (digits, multiplier) = match s.chars().last() {
+        Some('K') | Some('k') => (&s[..s.len() - 1], 1 << 10),
+        Some('M') | Some('m') => (&s[..s.len() - 1], 1 << 20),
+        Some('G') | Some('g') => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1),
+    };
+    let n = digits.parse::<usize>().map_err(
+        |_| format!("Failed to parse size: {}", s),
+    )?;
+    n.checked_mul(multiplier).ok_or_else(
+        || format!("Size is too large: {}", s),
+    )
+}
+
+/// The size given for `arg`, or `default`.
+fn size_arg(matches: &ArgMatches, arg: &str, default: usize) -> Result<usize, String> {
+    matches.value_of(arg).map(parse_size).unwrap_or(Ok(default))
+}
+
+fn run_client(addr: service::Addr, matches: &ArgMatches) -> Result<String, String> {
     let mut core = Core::new().map_err(|e| e.description().to_owned())?;
-    let client = client::Client::connect(&addr, &core.handle());
+    let timeout = match matches.value_of("timeout") {
+        Some(ms) => {
+            Some(Duration::from_millis(ms.parse().map_err(
+                |_| format!("Failed to parse timeout: {}", ms),
+            )?))
+        }
+        None => None,
+    };
+    let client = client::Client::connect_addr(&addr, &core.handle()).map(move |client| {
+        match timeout {
+            Some(timeout) => client.with_timeout(timeout),
+            None => client,
+        }
+    });
 
     // Unwraps in here are safe because clap has already validated that required params are present
     let client_cmd = |client: client::Client| match matches.subcommand() {
@@ -104,30 +366,119 @@ fn run_client(addr: SocketAddr, matches: &ArgMatches) -> Result<String, String>
             // handle SET
             let key = matches.value_of("KEY").unwrap();
             let value = matches.value_of("VALUE").unwrap();
-            client.set(key.to_owned().into_bytes(), value.to_owned().into_bytes())
+            match matches.value_of("TTL").and_then(|ttl| ttl.parse().ok()) {
+                Some(ttl) => {
+                    client.set_with_ttl(key.to_owned().into_bytes(), value.to_owned().into_bytes(), ttl)
+                }
+                None => client.set(key.to_owned().into_bytes(), value.to_owned().into_bytes()),
+            }
+        }
+        ("INCR", Some(matches)) => {
+            // handle INCR, creating the counter if it doesn't exist
+            let key = matches.value_of("KEY").unwrap();
+            let delta = matches.value_of("DELTA").and_then(|d| d.parse().ok()).unwrap_or(1);
+            client.incr_or_init(key.to_owned().into_bytes(), delta, delta, 0)
+        }
+        ("DECR", Some(matches)) => {
+            // handle DECR
+            let key = matches.value_of("KEY").unwrap();
+            let delta = matches.value_of("DELTA").and_then(|d| d.parse().ok()).unwrap_or(1);
+            client.decr(key.to_owned().into_bytes(), delta)
+        }
+        ("TTL", Some(matches)) => {
+            // handle TTL
+            let key = matches.value_of("KEY").unwrap();
+            client.ttl(key.to_owned().into_bytes())
+        }
+        ("DEL", Some(matches)) => {
+            // handle DEL
+            let key = matches.value_of("KEY").unwrap();
+            client.del(key.to_owned().into_bytes())
         }
         ("STATS", _) => client.stats(),
+        ("SNAPSHOT", _) => client.snapshot(),
         _ => unimplemented!(),
     };
 
 
-    let exec = client.and_then(client_cmd).map(|msg| handle_response(&msg));
+    let exec = client
+        .map_err(client::Error::from)
+        .and_then(client_cmd)
+        .map(|msg| handle_response(&msg));
 
-    // TODO: Don't want to unwrap here, should propagate error to top level
-    core.run(exec).expect("core failure")
+    core.run(exec).unwrap_or_else(|e| Err(e.to_string()))
 }
 
-fn run_server(addr: SocketAddr, cache_size: usize) -> Result<(), String> {
-    let cache = cache::Cache::new(cache_size).unwrap();
-    cache.start(cache_size);
-
+/// Serve `cache` until the process is sent `SIGINT` or `SIGTERM`, as a node of a cluster if
+/// there's a `membership`. The cache is dropped before returning, which saves its snapshot if it
+/// has one.
+fn run_server(
+    listeners: Vec<service::Listener>,
+    limits: service::Limits,
+    cache: cache::Cache,
+    membership: Option<Membership>,
+) -> Result<(), String> {
     // TODO: Figure out the idiomatic way to build up these middleware
-    let service = service::StatService {
-        stats: Arc::new(Stats::default()),
-        inner: service::CacheService { cache: Arc::new(cache) },
-    };
+    let stats = Arc::new(Stats::default());
+    let cache = service::CacheService { cache: Arc::new(cache) };
+    match membership {
+        Some(membership) => {
+            let service = service::StatService {
+                stats: stats,
+                inner: service::ClusterService {
+                    inner: cache,
+                    membership: Arc::new(membership),
+                },
+            };
+            service::serve_until(listeners, limits, service, stop_signal())
+        }
+        None => {
+            let service = service::StatService {
+                stats: stats,
+                inner: cache,
+            };
+            service::serve_until(listeners, limits, service, stop_signal())
+        }
+    }.map_err(|e| e.description().to_owned())
+}
+
+extern "C" fn request_stop(_: libc::c_int) {
+    let fd = STOP_PIPE.load(Ordering::SeqCst) as libc::c_int;
+    unsafe {
+        libc::write(fd, b"x".as_ptr() as *const libc::c_void, 1);
+    }
+}
 
-    service::serve(addr, service).map_err(|e| e.description().to_owned())
+/// A future that resolves once the process is sent `SIGINT` or `SIGTERM`. The signal handler
+/// only writes a byte to a pipe, as little else is safe to do in a handler, and a thread blocked
+/// reading the other end resolves the future.
+fn stop_signal() -> Box<Future<Item = (), Error = io::Error>> {
+    let mut fds = [0 as libc::c_int; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Box::new(future::err(io::Error::last_os_error()));
+    }
+    let (read, write) = (fds[0], fds[1]);
+    STOP_PIPE.store(write as usize, Ordering::SeqCst);
+    unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = request_stop as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigaction(libc::SIGINT, &action, ptr::null_mut());
+        libc::sigaction(libc::SIGTERM, &action, ptr::null_mut());
+    }
+
+    let (snd, rcv) = oneshot::channel();
+    thread::spawn(move || {
+        let mut byte = 0u8;
+        loop {
+            let read = unsafe { libc::read(read, &mut byte as *mut u8 as *mut libc::c_void, 1) };
+            if read >= 0 || io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+                break;
+            }
+        }
+        let _ = snd.send(());
+    });
+    Box::new(rcv.map_err(|e| io::Error::new(io::ErrorKind::Other, e)))
 }
 
 // Decode utf-8 strings if the message type_id is 1, otherwise just defer to builtin formatter
@@ -144,6 +495,26 @@ fn handle_response(msg: &Message) -> Result<String, String> {
                 Ok(format!("{}", msg))
             }
         }
+        (Op::Ttl, Code::Hit, Some(payload)) => {
+            match payload.as_u64() {
+                Some(0) => Ok("no expiry".to_owned()),
+                Some(ttl) => Ok(format!("{}s", ttl)),
+                None => Ok(format!("{}", msg)),
+            }
+        }
+        (Op::Incr, Code::Ok, Some(payload)) |
+        (Op::Decr, Code::Ok, Some(payload)) => {
+            match payload.as_u64() {
+                Some(value) => Ok(format!("{}", value)),
+                None => Ok(format!("{}", msg)),
+            }
+        }
+        (Op::Snapshot, Code::Ok, Some(payload)) => {
+            match payload.as_u64() {
+                Some(count) => Ok(format!("saved {} entries", count)),
+                None => Ok(format!("{}", msg)),
+            }
+        }
         (Op::Stats, _, Some(payload)) => {
             String::from_utf8(payload.data().to_owned()).map_err(|_| {
                 "expected a utf8-encoded string".to_owned()
@@ -160,6 +531,7 @@ mod tests {
     use std::thread;
     use rand::Rng;
     use rcache::message::{self, Op};
+    use std::net::SocketAddr;
     use tokio_service::Service;
 
     fn build_sets(count: usize) -> Vec<Message> {
@@ -191,13 +563,31 @@ mod tests {
             .collect()
     }
 
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1024"), Ok(1024));
+        assert_eq!(parse_size("512K"), Ok(512 * 1024));
+        assert_eq!(parse_size("64m"), Ok(64 * 1024 * 1024));
+        assert_eq!(parse_size("2G"), Ok(2 * 1024 * 1024 * 1024));
+        assert!(parse_size("lots").is_err());
+        assert!(parse_size("18446744073709551615G").is_err());
+    }
+
     /// TODO: Better benchmarking.
     #[bench]
     fn bench_gets_full_cache(b: &mut Bencher) {
         let mut core = Core::new().unwrap();
         let addr: SocketAddr = "127.0.0.1:12345".parse().unwrap();
 
-        thread::spawn(move || run_server(addr.clone(), 200000));
+        let listeners = vec![
+            service::Listener {
+                protocol: service::Protocol::Rcache,
+                addr: service::Addr::Tcp(addr),
+            },
+        ];
+        let limits = service::Limits::default();
+        let cache = cache::Cache::with_shards(cache::Capacity::Entries(200000), 1).unwrap();
+        thread::spawn(move || run_server(listeners, limits, cache, None));
         let duration = std::time::Duration::new(0, 1000);
         thread::sleep(duration);
 
@@ -220,8 +610,9 @@ mod tests {
             });
             core.run(requests).unwrap();
 
-            let stats =
-                client::Client::connect(&addr, &core.handle()).and_then(|client| client.stats());
+            let stats = client::Client::connect(&addr, &core.handle())
+                .map_err(client::Error::from)
+                .and_then(|client| client.stats());
             println!("{}", handle_response(&core.run(stats).unwrap()).unwrap())
         })
 
@@ -0,0 +1,632 @@
+use tokio_io::codec::{Encoder, Decoder};
+use std::io;
+use bytes::{Buf, BufMut, BigEndian, BytesMut};
+use codec::Limits;
+use message::{self, Message, Op, Code};
+use text_codec;
+use error;
+use time;
+
+const HEADER_LEN: usize = 24;
+
+const MAGIC_REQUEST: u8 = 0x80;
+const MAGIC_RESPONSE: u8 = 0x81;
+
+const OP_GET: u8 = 0x00;
+const OP_SET: u8 = 0x01;
+const OP_ADD: u8 = 0x02;
+const OP_REPLACE: u8 = 0x03;
+const OP_DELETE: u8 = 0x04;
+const OP_INCREMENT: u8 = 0x05;
+const OP_DECREMENT: u8 = 0x06;
+const OP_GETQ: u8 = 0x09;
+const OP_NOOP: u8 = 0x0a;
+const OP_VERSION: u8 = 0x0b;
+const OP_GETK: u8 = 0x0c;
+const OP_GETKQ: u8 = 0x0d;
+const OP_APPEND: u8 = 0x0e;
+const OP_PREPEND: u8 = 0x0f;
+const OP_STAT: u8 = 0x10;
+const OP_SETQ: u8 = 0x11;
+const OP_ADDQ: u8 = 0x12;
+const OP_REPLACEQ: u8 = 0x13;
+const OP_DELETEQ: u8 = 0x14;
+const OP_INCREMENTQ: u8 = 0x15;
+const OP_DECREMENTQ: u8 = 0x16;
+const OP_APPENDQ: u8 = 0x19;
+const OP_PREPENDQ: u8 = 0x1a;
+
+const STATUS_OK: u16 = 0x0000;
+const STATUS_KEY_NOT_FOUND: u16 = 0x0001;
+const STATUS_KEY_EXISTS: u16 = 0x0002;
+const STATUS_VALUE_TOO_LARGE: u16 = 0x0003;
+const STATUS_INVALID_ARGUMENTS: u16 = 0x0004;
+const STATUS_NOT_STORED: u16 = 0x0005;
+const STATUS_NON_NUMERIC: u16 = 0x0006;
+const STATUS_NOT_MY_VBUCKET: u16 = 0x0007;
+const STATUS_UNKNOWN_COMMAND: u16 = 0x0081;
+const STATUS_INTERNAL_ERROR: u16 = 0x0084;
+
+/// Incr and decr requests with this expiration fail on a missing key instead of creating it.
+const NO_INITIAL_VALUE: u32 = 0xffff_ffff;
+
+/// A codec for the memcached binary protocol.
+///
+/// +- magic -+- opcode -+- key len -+- extras len -+- data type -+- status -+- body len -+
+/// | u8      | u8       | u16       | u8           | u8          | u16      | u32        |
+/// +---------+----------+-----------+--------------+-------------+----------+------------+
+///
+/// +- opaque -+- cas -+- extras -+- key -+- value -+
+/// | u32      | u64   | [u8]     | [u8]  | [u8]    |
+/// +----------+-------+----------+-------+---------+
+///
+/// Requests are decoded into a `Message` and a `BinaryRequest`, which carries the opcode and the
+/// opaque the response has to echo. The opaque plays the part of `CacheCodec`'s request id.
+///
+/// The quiet opcodes (`GetQ`, `SetQ`, ...) only write a response when there is something to
+/// report: a hit for the quiet gets, an error for the rest. Responses are written in request order,
+/// so a `Noop` response tells the client every earlier quiet request has completed.
+///
+/// Values and client flags are stored as in `TextCodec`. A `Set` or `Replace` with a cas value is
+/// a `Op::Cas`. `Quit` and `Flush` aren't supported, and get `Unknown command`.
+///
+/// A key may be up to `max_key_len` long, and a value up to `max_value_len`, see `Limits`.
+pub struct BinaryCodec {
+    limits: Limits,
+}
+
+impl Default for BinaryCodec {
+    fn default() -> BinaryCodec {
+        BinaryCodec::with_limits(Limits::default())
+    }
+}
+
+impl BinaryCodec {
+    pub fn with_limits(limits: Limits) -> BinaryCodec {
+        BinaryCodec { limits: limits }
+    }
+}
+
+/// The part of a binary request needed to write its response.
+#[derive(Debug, PartialEq, Clone)]
+pub struct BinaryRequest {
+    opcode: u8,
+    opaque: u32,
+    /// The key, for `GetK` and `GetKQ`, whose responses include it.
+    key: Option<Vec<u8>>,
+    /// The status of a request the codec rejected, which the response carries instead of a status
+    /// derived from its code.
+    status: Option<u16>,
+}
+
+impl Decoder for BinaryCodec {
+    type Item = (BinaryRequest, Message);
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<(BinaryRequest, Message)>, io::Error> {
+        if buf.len() < HEADER_LEN {
+            return Ok(None);
+        }
+
+        let (opcode, key_len, extras_len, body_len, opaque, cas) = {
+            let mut cursor = io::Cursor::new(&buf.as_ref()[..HEADER_LEN]);
+            if cursor.get_u8() != MAGIC_REQUEST {
+                return Err(
+                    error::Error::new(error::ErrorKind::InvalidData, "bad magic byte").into(),
+                );
+            }
+            let opcode = cursor.get_u8();
+            let key_len = cursor.get_u16::<BigEndian>() as usize;
+            let extras_len = cursor.get_u8() as usize;
+            // Data type and vbucket id are unused.
+            cursor.advance(3);
+            let body_len = cursor.get_u32::<BigEndian>() as usize;
+            let opaque = cursor.get_u32::<BigEndian>();
+            let cas = cursor.get_u64::<BigEndian>();
+            (opcode, key_len, extras_len, body_len, opaque, cas)
+        };
+
+        if key_len + extras_len > body_len {
+            return Err(
+                error::Error::new(error::ErrorKind::BadMessage, "body shorter than key and extras")
+                    .into(),
+            );
+        }
+        if body_len - key_len - extras_len > self.limits.max_value_len {
+            return Err(error::Error::new(error::ErrorKind::TooLarge, "value too large").into());
+        }
+        if buf.len() < HEADER_LEN + body_len {
+            return Ok(None);
+        }
+
+        let frame = buf.split_to(HEADER_LEN + body_len);
+        let body = &frame[HEADER_LEN..];
+        let req = BinaryRequest {
+            opcode: opcode,
+            opaque: opaque,
+            key: None,
+            status: None,
+        };
+        Ok(Some(request(
+            req,
+            &self.limits,
+            cas,
+            &body[..extras_len],
+            &body[extras_len..extras_len + key_len],
+            &body[extras_len + key_len..],
+        )))
+    }
+}
+
+/// Translate a request into a `Message`, or into an error response if it's malformed.
+fn request(
+    mut req: BinaryRequest,
+    limits: &Limits,
+    cas: u64,
+    extras: &[u8],
+    key: &[u8],
+    value: &[u8],
+) -> (BinaryRequest, Message) {
+    let op = match req.opcode {
+        OP_NOOP | OP_VERSION => return (req, message::response(Op::Get, Code::Ok, None)),
+        OP_STAT => return (req, message::request(Op::Stats, vec![], None)),
+        opcode => {
+            match base_op(opcode) {
+                Some(op) => op,
+                // An unknown command has no op, and is answered as a get.
+                None => return rejected(req, Op::Get, STATUS_UNKNOWN_COMMAND, "Unknown command"),
+            }
+        }
+    };
+
+    if key.is_empty() || key.len() > limits.max_key_len {
+        return rejected(req, op, STATUS_INVALID_ARGUMENTS, "Invalid key");
+    }
+    let key = key.to_vec();
+
+    let msg = match op {
+        Op::Get | Op::Del => {
+            if !extras.is_empty() || !value.is_empty() {
+                return rejected(req, op, STATUS_INVALID_ARGUMENTS, "Invalid arguments");
+            }
+            if req.opcode == OP_GETK || req.opcode == OP_GETKQ {
+                req.key = Some(key.clone());
+            }
+            message::request(op, key, None)
+        }
+        Op::Set | Op::Add | Op::Replace => {
+            if extras.len() != 8 {
+                return rejected(req, op, STATUS_INVALID_ARGUMENTS, "Invalid arguments");
+            }
+            let mut extras = io::Cursor::new(extras);
+            let flags = extras.get_u32::<BigEndian>();
+            let exptime = extras.get_u32::<BigEndian>();
+
+            match text_codec::ttl(i64::from(exptime), time::get_time().sec) {
+                Some(ttl) => {
+                    let op = if cas != 0 && op != Op::Add { Op::Cas } else { op };
+                    let payload = message::payload(0, value.to_vec())
+                        .with_flags(flags)
+                        .with_ttl(ttl)
+                        .with_version(cas);
+                    message::request(op, key, Some(payload))
+                }
+                // The item has already expired, which is the same as removing it.
+                None => message::request(Op::Del, key, None),
+            }
+        }
+        Op::Append | Op::Prepend => {
+            if !extras.is_empty() {
+                return rejected(req, op, STATUS_INVALID_ARGUMENTS, "Invalid arguments");
+            }
+            message::request(op, key, Some(message::payload(0, value.to_vec())))
+        }
+        Op::Incr | Op::Decr => {
+            if extras.len() != 20 || !value.is_empty() {
+                return rejected(req, op, STATUS_INVALID_ARGUMENTS, "Invalid arguments");
+            }
+            let mut extras = io::Cursor::new(extras);
+            let delta = extras.get_u64::<BigEndian>();
+            let initial = extras.get_u64::<BigEndian>();
+            let exptime = extras.get_u32::<BigEndian>();
+
+            let payload = if exptime == NO_INITIAL_VALUE {
+                message::counter_payload(delta, None)
+            } else {
+                let ttl = text_codec::ttl(i64::from(exptime), time::get_time().sec).unwrap_or(0);
+                message::counter_payload(delta, Some(initial)).with_ttl(ttl)
+            };
+            message::request(op, key, Some(payload))
+        }
+        _ => return rejected(req, op, STATUS_UNKNOWN_COMMAND, "Unknown command"),
+    };
+    (req, msg)
+}
+
+/// An error response to a request for `op`, which the encoder answers with `status`.
+fn rejected(
+    mut req: BinaryRequest,
+    op: Op,
+    status: u16,
+    description: &str,
+) -> (BinaryRequest, Message) {
+    req.status = Some(status);
+    let kind = if status == STATUS_UNKNOWN_COMMAND {
+        error::ErrorKind::UnknownOp
+    } else {
+        error::ErrorKind::BadMessage
+    };
+    (req, message::error_response(op, &error::Error::new(kind, description)))
+}
+
+/// The op an opcode maps to, for the opcodes that are served by the cache.
+fn base_op(opcode: u8) -> Option<Op> {
+    match opcode {
+        OP_GET | OP_GETQ | OP_GETK | OP_GETKQ => Some(Op::Get),
+        OP_SET | OP_SETQ => Some(Op::Set),
+        OP_ADD | OP_ADDQ => Some(Op::Add),
+        OP_REPLACE | OP_REPLACEQ => Some(Op::Replace),
+        OP_DELETE | OP_DELETEQ => Some(Op::Del),
+        OP_INCREMENT | OP_INCREMENTQ => Some(Op::Incr),
+        OP_DECREMENT | OP_DECREMENTQ => Some(Op::Decr),
+        OP_APPEND | OP_APPENDQ => Some(Op::Append),
+        OP_PREPEND | OP_PREPENDQ => Some(Op::Prepend),
+        _ => None,
+    }
+}
+
+fn is_quiet(opcode: u8) -> bool {
+    match opcode {
+        OP_GETQ | OP_GETKQ | OP_SETQ | OP_ADDQ | OP_REPLACEQ | OP_DELETEQ | OP_INCREMENTQ |
+        OP_DECREMENTQ | OP_APPENDQ | OP_PREPENDQ => true,
+        _ => false,
+    }
+}
+
+/// The status of the response `msg` to a request for `op`.
+fn status(op: Op, msg: &Message) -> u16 {
+    match (op, msg.op(), msg.code()) {
+        // A store with an expiration in the past.
+        (Op::Set, Op::Del, _) |
+        (Op::Add, Op::Del, _) |
+        (Op::Replace, Op::Del, _) |
+        (_, _, Code::Ok) |
+        (_, _, Code::Hit) => STATUS_OK,
+        (Op::Add, _, Code::NotStored) |
+        (_, _, Code::Exists) => STATUS_KEY_EXISTS,
+        (Op::Replace, _, Code::NotStored) |
+        (_, _, Code::Miss) => STATUS_KEY_NOT_FOUND,
+        // The protocol has no status for a read only server, and a refused write wasn't stored.
+        (_, _, Code::NotStored) |
+        (_, _, Code::ReadOnly) => STATUS_NOT_STORED,
+        (_, _, Code::TooLarge) => STATUS_VALUE_TOO_LARGE,
+        // The key is on another server, which is what a vbucket that isn't the server's means.
+        (_, _, Code::Moved) => STATUS_NOT_MY_VBUCKET,
+        (_, _, Code::Error) => error_status(op, msg),
+        _ => STATUS_INTERNAL_ERROR,
+    }
+}
+
+/// The status for an error response, from the kind of error it carries.
+fn error_status(op: Op, msg: &Message) -> u16 {
+    match msg.error().map(|e| *e.kind()) {
+        Some(error::ErrorKind::NotNumeric) => STATUS_NON_NUMERIC,
+        Some(error::ErrorKind::TooLarge) => STATUS_VALUE_TOO_LARGE,
+        Some(error::ErrorKind::UnknownOp) => STATUS_UNKNOWN_COMMAND,
+        Some(error::ErrorKind::BadMessage) |
+        Some(error::ErrorKind::InvalidData) => STATUS_INVALID_ARGUMENTS,
+        // An untyped error from a counter op is most likely a value that isn't a number.
+        _ if op == Op::Incr || op == Op::Decr => STATUS_NON_NUMERIC,
+        _ => STATUS_INTERNAL_ERROR,
+    }
+}
+
+impl Encoder for BinaryCodec {
+    type Item = (BinaryRequest, Message);
+    type Error = io::Error;
+
+    fn encode(&mut self, item: (BinaryRequest, Message), buf: &mut BytesMut) -> io::Result<()> {
+        let (req, msg) = item;
+
+        if let Some(status) = req.status {
+            let description = msg.payload().map(|p| p.data().to_vec()).unwrap_or_default();
+            put_packet(buf, &req, status, 0, &[], &[], &description);
+            return Ok(());
+        }
+
+        match req.opcode {
+            OP_NOOP => put_packet(buf, &req, STATUS_OK, 0, &[], &[], &[]),
+            OP_VERSION => {
+                let version = env!("CARGO_PKG_VERSION").as_bytes();
+                put_packet(buf, &req, STATUS_OK, 0, &[], &[], version)
+            }
+            // Each stat is a packet with its name as the key, and an empty packet ends the list.
+            OP_STAT => {
+                let stats = msg.payload()
+                    .map(|p| String::from_utf8_lossy(p.data()).into_owned())
+                    .unwrap_or_default();
+                for (name, value) in text_codec::parse_stats(&stats) {
+                    put_packet(buf, &req, STATUS_OK, 0, &[], name.as_bytes(), value.as_bytes());
+                }
+                put_packet(buf, &req, STATUS_OK, 0, &[], &[], &[]);
+            }
+            opcode => {
+                let op = base_op(opcode).unwrap_or(Op::Get);
+                let status = status(op, &msg);
+
+                let quiet = is_quiet(opcode);
+                if quiet && op == Op::Get && status == STATUS_KEY_NOT_FOUND {
+                    return Ok(());
+                }
+                if quiet && op != Op::Get && status == STATUS_OK {
+                    return Ok(());
+                }
+
+                match (status, msg.payload()) {
+                    (STATUS_OK, Some(payload)) if op == Op::Get => {
+                        // Counters are written back as decimal strings, as memcached stores them.
+                        let value = match payload.as_u64() {
+                            Some(n) => n.to_string().into_bytes(),
+                            None => payload.data().to_vec(),
+                        };
+                        let mut extras = Vec::with_capacity(4);
+                        extras.put_u32::<BigEndian>(payload.flags());
+                        let key = req.key.as_ref().map(|k| k.as_slice()).unwrap_or(&[]);
+                        put_packet(buf, &req, STATUS_OK, payload.version(), &extras, key, &value);
+                    }
+                    (STATUS_OK, Some(payload)) => {
+                        put_packet(buf, &req, STATUS_OK, 0, &[], &[], payload.data())
+                    }
+                    (STATUS_OK, None) => put_packet(buf, &req, STATUS_OK, 0, &[], &[], &[]),
+                    (status, Some(payload)) if msg.code() == Code::Error => {
+                        put_packet(buf, &req, status, 0, &[], &[], payload.data())
+                    }
+                    (status, _) => {
+                        put_packet(buf, &req, status, 0, &[], &[], status_message(status))
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn status_message(status: u16) -> &'static [u8] {
+    match status {
+        STATUS_KEY_NOT_FOUND => b"Not found",
+        STATUS_KEY_EXISTS => b"Data exists for key.",
+        STATUS_VALUE_TOO_LARGE => b"Too large.",
+        STATUS_NOT_STORED => b"Not stored.",
+        _ => b"",
+    }
+}
+
+fn put_packet(
+    buf: &mut BytesMut,
+    req: &BinaryRequest,
+    status: u16,
+    cas: u64,
+    extras: &[u8],
+    key: &[u8],
+    value: &[u8],
+) {
+    let body_len = extras.len() + key.len() + value.len();
+    buf.reserve(HEADER_LEN + body_len);
+    buf.put_u8(MAGIC_RESPONSE);
+    buf.put_u8(req.opcode);
+    buf.put_u16::<BigEndian>(key.len() as u16);
+    buf.put_u8(extras.len() as u8);
+    buf.put_u8(0);
+    buf.put_u16::<BigEndian>(status);
+    buf.put_u32::<BigEndian>(body_len as u32);
+    buf.put_u32::<BigEndian>(req.opaque);
+    buf.put_u64::<BigEndian>(cas);
+    buf.put_slice(extras);
+    buf.put_slice(key);
+    buf.put_slice(value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::time::Duration;
+    use service::Protocol;
+    use testing;
+
+    /// A request packet.
+    fn packet(opcode: u8, opaque: u32, cas: u64, extras: &[u8], key: &[u8], value: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.put_u8(MAGIC_REQUEST);
+        buf.put_u8(opcode);
+        buf.put_u16::<BigEndian>(key.len() as u16);
+        buf.put_u8(extras.len() as u8);
+        buf.put_u8(0);
+        buf.put_u16::<BigEndian>(0);
+        buf.put_u32::<BigEndian>((extras.len() + key.len() + value.len()) as u32);
+        buf.put_u32::<BigEndian>(opaque);
+        buf.put_u64::<BigEndian>(cas);
+        buf.put_slice(extras);
+        buf.put_slice(key);
+        buf.put_slice(value);
+        buf
+    }
+
+    fn set_extras(flags: u32, exptime: u32) -> Vec<u8> {
+        let mut extras = Vec::new();
+        extras.put_u32::<BigEndian>(flags);
+        extras.put_u32::<BigEndian>(exptime);
+        extras
+    }
+
+    /// (opcode, status, opaque, cas, extras, key, value) of a response packet.
+    type Response = (u8, u16, u32, u64, Vec<u8>, Vec<u8>, Vec<u8>);
+
+    fn read_response(stream: &mut TcpStream) -> Response {
+        let mut header = [0; HEADER_LEN];
+        stream.read_exact(&mut header).unwrap();
+        let mut cursor = io::Cursor::new(&header[..]);
+        assert_eq!(cursor.get_u8(), MAGIC_RESPONSE);
+        let opcode = cursor.get_u8();
+        let key_len = cursor.get_u16::<BigEndian>() as usize;
+        let extras_len = cursor.get_u8() as usize;
+        cursor.advance(1);
+        let status = cursor.get_u16::<BigEndian>();
+        let body_len = cursor.get_u32::<BigEndian>() as usize;
+        let opaque = cursor.get_u32::<BigEndian>();
+        let cas = cursor.get_u64::<BigEndian>();
+
+        let mut body = vec![0; body_len];
+        stream.read_exact(&mut body).unwrap();
+        let value = body.split_off(extras_len + key_len);
+        let key = body.split_off(extras_len);
+        (opcode, status, opaque, cas, body, key, value)
+    }
+
+    #[test]
+    fn test_decode() {
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&packet(OP_SETQ, 7, 0, &set_extras(3, 60), b"foo", b"bar"));
+        buf.extend_from_slice(&packet(OP_SET, 8, 42, &set_extras(0, 0), b"foo", b"baz"));
+        buf.extend_from_slice(&packet(OP_GETK, 9, 0, &[], b"foo", &[]));
+        buf.extend_from_slice(&packet(0x08, 10, 0, &[], &[], &[]));
+        buf.extend_from_slice(&packet(OP_GET, 11, 0, &[], &[], &[]));
+
+        let mut codec = BinaryCodec::default();
+        let (req, msg) = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(req.opaque, 7);
+        assert_eq!(
+            msg,
+            message::request(
+                Op::Set,
+                "foo".into(),
+                Some(message::payload(0, "bar".into()).with_flags(3).with_ttl(60)),
+            )
+        );
+
+        let (_, msg) = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(msg.op(), Op::Cas);
+        assert_eq!(msg.payload().unwrap().version(), 42);
+
+        let (req, msg) = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(req.key, Some("foo".into()));
+        assert_eq!(msg, message::request(Op::Get, "foo".into(), None));
+
+        let (req, _) = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(req.status, Some(STATUS_UNKNOWN_COMMAND));
+        let (req, _) = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(req.status, Some(STATUS_INVALID_ARGUMENTS));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_decode_partial() {
+        let packet = packet(OP_SET, 1, 0, &set_extras(0, 0), b"foo", b"bar");
+        let mut buf = BytesMut::from(&packet[..30]);
+        let mut codec = BinaryCodec::default();
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+
+        buf.extend_from_slice(&packet[30..]);
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+
+        let mut buf = BytesMut::from(vec![0x81; HEADER_LEN]);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut codec = BinaryCodec::with_limits(Limits {
+            max_key_len: 4,
+            max_value_len: 8,
+            ..Limits::default()
+        });
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&packet(OP_GET, 1, 0, &[], b"fooba", b""));
+        buf.extend_from_slice(&packet(OP_SET, 2, 0, &set_extras(0, 0), b"foob", b"12345678"));
+        let (req, _) = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(req.status, Some(STATUS_INVALID_ARGUMENTS));
+        let (_, msg) = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(msg.payload().unwrap().data(), b"12345678");
+
+        let mut buf = BytesMut::from(packet(OP_SET, 3, 0, &set_extras(0, 0), b"foo", b"123456789"));
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_status() {
+        let resp = |op, code| message::response(op, code, None);
+        assert_eq!(status(Op::Add, &resp(Op::Add, Code::NotStored)), STATUS_KEY_EXISTS);
+        assert_eq!(status(Op::Replace, &resp(Op::Replace, Code::NotStored)), STATUS_KEY_NOT_FOUND);
+        assert_eq!(status(Op::Append, &resp(Op::Append, Code::NotStored)), STATUS_NOT_STORED);
+        assert_eq!(status(Op::Set, &resp(Op::Del, Code::Miss)), STATUS_OK);
+        assert_eq!(status(Op::Set, &resp(Op::Set, Code::ReadOnly)), STATUS_NOT_STORED);
+        assert_eq!(status(Op::Get, &resp(Op::Get, Code::Moved)), STATUS_NOT_MY_VBUCKET);
+        assert_eq!(status(Op::Incr, &resp(Op::Get, Code::Error)), STATUS_NON_NUMERIC);
+
+        let err = |kind| message::error_response(Op::Set, &error::Error::new(kind, ""));
+        assert_eq!(status(Op::Set, &err(error::ErrorKind::TooLarge)), STATUS_VALUE_TOO_LARGE);
+        assert_eq!(status(Op::Set, &err(error::ErrorKind::BadMessage)), STATUS_INVALID_ARGUMENTS);
+        assert_eq!(status(Op::Set, &err(error::ErrorKind::Other)), STATUS_INTERNAL_ERROR);
+    }
+
+    #[test]
+    fn test_quiet_ops() {
+        let addr = testing::spawn_cache(Protocol::MemcachedBinary);
+
+        let mut stream = TcpStream::connect(&addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        // Only the hit, the failed add and the noop are answered.
+        let mut requests = Vec::new();
+        requests.extend(packet(OP_GETQ, 1, 0, &[], b"foo", &[]));
+        requests.extend(packet(OP_SETQ, 2, 0, &set_extras(5, 0), b"foo", b"bar"));
+        requests.extend(packet(OP_GETKQ, 3, 0, &[], b"foo", &[]));
+        requests.extend(packet(OP_ADDQ, 4, 0, &set_extras(0, 0), b"foo", b"baz"));
+        requests.extend(packet(OP_GETQ, 5, 0, &[], b"missing", &[]));
+        requests.extend(packet(OP_NOOP, 6, 0, &[], &[], &[]));
+        stream.write_all(&requests).unwrap();
+
+        let (opcode, status, opaque, cas, extras, key, value) = read_response(&mut stream);
+        assert_eq!((opcode, status, opaque), (OP_GETKQ, STATUS_OK, 3));
+        assert!(cas > 0);
+        assert_eq!(extras, vec![0, 0, 0, 5]);
+        assert_eq!((key.as_slice(), value.as_slice()), (&b"foo"[..], &b"bar"[..]));
+
+        let (opcode, status, opaque, ..) = read_response(&mut stream);
+        assert_eq!((opcode, status, opaque), (OP_ADDQ, STATUS_KEY_EXISTS, 4));
+
+        let (opcode, status, opaque, ..) = read_response(&mut stream);
+        assert_eq!((opcode, status, opaque), (OP_NOOP, STATUS_OK, 6));
+
+        // Counters, with and without an initial value.
+        let mut extras = Vec::new();
+        extras.put_u64::<BigEndian>(2);
+        extras.put_u64::<BigEndian>(10);
+        extras.put_u32::<BigEndian>(NO_INITIAL_VALUE);
+        stream.write_all(&packet(OP_INCREMENT, 7, 0, &extras, b"n", &[])).unwrap();
+        let (_, status, ..) = read_response(&mut stream);
+        assert_eq!(status, STATUS_KEY_NOT_FOUND);
+
+        extras.truncate(16);
+        extras.put_u32::<BigEndian>(0);
+        stream.write_all(&packet(OP_INCREMENT, 8, 0, &extras, b"n", &[])).unwrap();
+        stream.write_all(&packet(OP_INCREMENT, 9, 0, &extras, b"n", &[])).unwrap();
+        let (_, _, _, _, _, _, value) = read_response(&mut stream);
+        assert_eq!(value, vec![0, 0, 0, 0, 0, 0, 0, 10]);
+        let (_, _, _, _, _, _, value) = read_response(&mut stream);
+        assert_eq!(value, vec![0, 0, 0, 0, 0, 0, 0, 12]);
+
+        stream.write_all(&packet(OP_INCREMENT, 10, 0, &extras, b"foo", &[])).unwrap();
+        let (_, status, ..) = read_response(&mut stream);
+        assert_eq!(status, STATUS_NON_NUMERIC);
+
+        stream.write_all(&packet(OP_DELETE, 11, 0, &[], b"foo", &[])).unwrap();
+        stream.write_all(&packet(OP_GET, 12, 0, &[], b"foo", &[])).unwrap();
+        let (_, status, ..) = read_response(&mut stream);
+        assert_eq!(status, STATUS_OK);
+        let (_, status, opaque, _, _, _, value) = read_response(&mut stream);
+        assert_eq!((status, opaque), (STATUS_KEY_NOT_FOUND, 12));
+        assert_eq!(value, b"Not found");
+    }
+}
@@ -1,83 +1,993 @@
 use message::{self, Message, Op, Code, Payload};
-use tokio_core::reactor::Core;
-use std::error::Error;
-use futures::sync::oneshot::Sender;
-use futures_cpupool::CpuPool;
-use futures::future;
+use futures::sync::oneshot::{self, Sender};
 use std::io;
+use std::cmp;
+use std::mem;
+use std::str;
+use std::thread;
+use std::hash::{Hash, Hasher};
+use std::collections::hash_map::DefaultHasher;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
 use error;
-use lru_cache::LruCache;
+use store::Store;
+use snapshot::{self, Record, Snapshotter};
+use oplog::{self, OpLog, Entry};
+use replication::{Backlog, Dump, Replica};
+use service::Addr;
+use stats::StoreStats;
 use deque::{self, Worker, Stealer, Stolen};
+use bytes::{Buf, BigEndian};
 
+/// How often the worker sweeps the store for expired entries.
+const SWEEP_INTERVAL_MS: u64 = 100;
+/// The most entries a single sweep will expire, so that a mass expiry can't stall the worker.
+const SWEEP_LIMIT: usize = 1000;
+/// The most entries a worker copies for a snapshot or a full sync between two units of work, so
+/// that copying a large store doesn't stall the requests queued behind it.
+const COPY_CHUNK: usize = 1000;
+/// How often a cache being dropped checks that its workers are still running, while it waits for
+/// its final snapshot.
+const STOPPED_POLL_MS: u64 = 10;
 
-type Store = LruCache<Vec<u8>, Payload>;
+/// A unit of work for a shard's worker.
+enum Work {
+    /// A request, and the channel to send its response on.
+    Request(Sender<Message>, Message),
+    /// The entries of a batch whose keys live on this shard, and their positions in the batch.
+    Part(Arc<Gather>, Vec<usize>, Vec<(Vec<u8>, Option<Payload>)>),
+    /// A copy of the store for a replica's full sync.
+    Dump(Arc<Dump>),
+}
+
+/// A copy of a shard's store being taken a chunk at a time, between units of work.
+struct Copying {
+    keys: Vec<(Vec<u8>, Instant)>,
+    copied: usize,
+    records: Vec<(Instant, Record)>,
+    target: Target,
+}
+
+/// What a `Copying` is for.
+enum Target {
+    /// The snapshot of this generation.
+    Snapshot(Arc<Snapshotter>, usize),
+    /// A replica's full sync.
+    Sync(Arc<Dump>),
+}
+
+impl Copying {
+    fn new(store: &Store, target: Target) -> Self {
+        let keys = store.keys(Instant::now());
+        Copying {
+            records: Vec::with_capacity(keys.len()),
+            keys: keys,
+            copied: 0,
+            target: target,
+        }
+    }
+
+    /// Copy the entries of the next `COPY_CHUNK` keys that are still there, returning true once
+    /// every key has been copied.
+    fn advance(&mut self, store: &mut Store) -> bool {
+        let now = Instant::now();
+        let wall = SystemTime::now();
+        let end = cmp::min(self.copied + COPY_CHUNK, self.keys.len());
+        for &(ref key, used) in &self.keys[self.copied..end] {
+            if let Some(record) = store.record(key, now, wall) {
+                self.records.push((used, record));
+            }
+        }
+        self.copied = end;
+        self.copied == self.keys.len()
+    }
+
+    /// Hand the finished copy of `shard`'s store over to what it was for.
+    fn contribute(self, shard: usize) {
+        match self.target {
+            Target::Snapshot(snapshots, generation) => {
+                Snapshotter::contribute(&snapshots, generation, shard, self.records);
+            }
+            Target::Sync(dump) => {
+                let records = self.records.into_iter().map(|(_, record)| record).collect();
+                dump.contribute(shard, records);
+            }
+        }
+    }
+}
+
+/// Collects the results of a batch that was split across shards, and sends the
+/// `Message::BatchResponse` once the last shard has finished its part.
+struct Gather {
+    op: Op,
+    deadline: Option<Instant>,
+    state: Mutex<GatherState>,
+}
+
+struct GatherState {
+    results: Vec<(Code, Option<Payload>)>,
+    remaining: usize,
+    snd: Option<Sender<Message>>,
+}
+
+impl Gather {
+    fn new(
+        op: Op,
+        deadline: Option<Instant>,
+        entries: usize,
+        parts: usize,
+        snd: Sender<Message>,
+    ) -> Self {
+        Gather {
+            op: op,
+            deadline: deadline,
+            state: Mutex::new(GatherState {
+                results: vec![(Code::Error, None); entries],
+                remaining: parts,
+                snd: Some(snd),
+            }),
+        }
+    }
+
+    /// Record the results of one part, responding if it was the last.
+    fn complete(&self, indices: Vec<usize>, results: Vec<(Code, Option<Payload>)>) {
+        let mut state = self.state.lock().unwrap();
+        for (i, result) in indices.into_iter().zip(results) {
+            state.results[i] = result;
+        }
+
+        state.remaining -= 1;
+        if state.remaining == 0 {
+            let results = mem::replace(&mut state.results, Vec::new());
+            if let Some(snd) = state.snd.take() {
+                if snd.send(message::batch_response(self.op, results)).is_err() {
+                    warn!("Failed to send batch response.");
+                }
+            }
+        }
+    }
+}
+
+/// How the size of the cache is bounded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Capacity {
+    /// At most this many entries.
+    Entries(usize),
+    /// At most this many bytes, counting keys, payload data and an estimate of per-entry overhead.
+    Bytes(usize),
+}
+
+impl Capacity {
+    /// The capacity of each of `shards` shards that together have this capacity.
+    fn split(self, shards: usize) -> Capacity {
+        match self {
+            Capacity::Entries(n) => Capacity::Entries((n + shards - 1) / shards),
+            Capacity::Bytes(n) => Capacity::Bytes((n + shards - 1) / shards),
+        }
+    }
+}
+
+pub use oplog::Fsync;
 
-type Work = (Sender<Message>, Message);
+/// Where, and how, a cache is persisted. The default is not at all.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Persistence {
+    /// Save snapshots to, and restore from, this path.
+    pub snapshot_path: Option<PathBuf>,
+    /// Save a snapshot this often, as well as when asked to and on shutdown.
+    pub snapshot_interval: Option<Duration>,
+    /// Append every write to, and replay on startup, the operation log at this path. The log is
+    /// compacted by snapshots, so without a `snapshot_path` it grows with every write.
+    pub log_path: Option<PathBuf>,
+    /// When the operation log is flushed to disk.
+    pub fsync: Fsync,
+}
 
-/// A thread safe wrapper around `LruCache` that synchronizes reads/writes via a single
-/// threaded worker that reads requests from a dequeue and pushes responses into a channel
+/// A thread safe wrapper around the LRU `Store` that synchronizes reads/writes via single
+/// threaded workers that read requests from a dequeue and push responses into a channel
 /// provided by the request (`Work`) payload.
+///
+/// The store can be split into shards, each an independent `Store` owned by its own worker.
+/// Keys are hashed to pick a shard, so a key always lives on the same worker, and operations on
+/// different shards proceed in parallel.
+///
+/// The cache can be persisted to a snapshot file and loaded from it on startup, see
+/// `with_snapshots`, and to an operation log of every write, see `with_persistence`.
+///
+/// Any cache can be replicated to read only replicas, see `replica`.
 pub struct Cache {
-    pool: CpuPool,
-    core: Core,
-    stealer: Stealer<Work>,
+    shards: Vec<Shard>,
+    snapshots: Option<Arc<Snapshotter>>,
+    backlog: Option<Arc<Backlog>>,
+    replica: Option<Arc<Replica>>,
+}
+
+/// A single worker and its queue.
+struct Shard {
     worker: Worker<Work>,
+    thread: thread::Thread,
+    stats: Arc<StoreStats>,
+    shutdown: Arc<AtomicBool>,
+    /// Set once the worker's thread has exited, whether it was shut down or panicked.
+    stopped: Arc<AtomicBool>,
 }
 
 impl Cache {
-    /// Initialize a new `Cache` with `capacity` and start the worker thread.
+    /// Initialize a new `Cache` holding up to `capacity` entries and start the worker thread.
     pub fn new(capacity: usize) -> Result<Self, io::Error> {
-        let (worker, stealer) = deque::new();
-        let cache = Cache {
-            pool: CpuPool::new_num_cpus(),
-            core: Core::new()?,
-            worker: worker,
-            stealer: stealer,
+        Cache::with_shards(Capacity::Entries(capacity), 1)
+    }
+
+    /// Initialize a new `Cache` split into `shards` shards and start a worker thread for each.
+    /// `capacity` is divided evenly between the shards.
+    pub fn with_shards(capacity: Capacity, shards: usize) -> Result<Self, io::Error> {
+        Cache::with_persistence(capacity, shards, Persistence::default())
+    }
+
+    /// Like `with_shards`, with the entries, their ttls and versions, and the order they were
+    /// used in saved to a snapshot at `path`: when an `Op::Snapshot` request asks for it, every
+    /// `interval` if one is given, and when the cache is dropped. If there is a snapshot at
+    /// `path` already, the cache starts out with its entries, less any that have expired since.
+    ///
+    /// A snapshot that can't be read is reported and ignored, so that the cache starts empty
+    /// rather than not at all.
+    pub fn with_snapshots(
+        capacity: Capacity,
+        shards: usize,
+        path: PathBuf,
+        interval: Option<Duration>,
+    ) -> Result<Self, io::Error> {
+        let persistence = Persistence {
+            snapshot_path: Some(path),
+            snapshot_interval: interval,
+            ..Persistence::default()
+        };
+        Cache::with_persistence(capacity, shards, persistence)
+    }
+
+    /// Like `with_shards`, persisted as `persistence` says: to snapshots, see `with_snapshots`,
+    /// to an operation log, or both.
+    ///
+    /// With an operation log, the outcome of every write is appended to the log before the write
+    /// is answered, and the log is replayed on startup, over the snapshot if there is one. How
+    /// much of the log is on disk when the write is answered is up to `persistence.fsync`. Each
+    /// snapshot compacts the log to the writes made since. A log that can't be opened fails the
+    /// cache, rather than risk losing the writes in it.
+    pub fn with_persistence(
+        capacity: Capacity,
+        shards: usize,
+        persistence: Persistence,
+    ) -> Result<Self, io::Error> {
+        Cache::build(capacity, shards, persistence, None)
+    }
+
+    /// Like `with_persistence`, as a read only replica of the server at `primary`.
+    ///
+    /// The replica copies the primary's store with an `Op::Sync` request, then applies the
+    /// writes the primary makes from then on, in the order it made them. It serves reads, and
+    /// answers writes with `Code::ReadOnly`. If it loses the primary, it reconnects, and syncs
+    /// from scratch if the primary no longer has the writes it missed. How many writes it is
+    /// behind the primary is in its stats, as `replication_lag`, with `replication_status` saying
+    /// whether it is connected, syncing from scratch, or disconnected. The lag is `unknown`
+    /// unless it is connected.
+    ///
+    /// The primary's evictions aren't replicated: each side evicts to fit its own capacity.
+    pub fn replica(
+        capacity: Capacity,
+        shards: usize,
+        persistence: Persistence,
+        primary: Addr,
+    ) -> Result<Self, io::Error> {
+        Cache::build(capacity, shards, persistence, Some(primary))
+    }
+
+    fn build(
+        capacity: Capacity,
+        shards: usize,
+        persistence: Persistence,
+        primary: Option<Addr>,
+    ) -> Result<Self, io::Error> {
+        let shards = cmp::max(shards, 1);
+        let shard_capacity = capacity.split(shards);
+
+        let mut stores: Vec<Store> = (0..shards).map(|_| Store::new(shard_capacity)).collect();
+        let mut generation = 0;
+        if let Some(ref path) = persistence.snapshot_path {
+            match restore(&mut stores, path) {
+                Ok((saved, restored)) => {
+                    generation = saved;
+                    if restored > 0 {
+                        info!("Restored {} entries from {}.", restored, path.display());
+                    }
+                }
+                Err(e) => warn!("Ignoring snapshot {}: {}.", path.display(), e),
+            }
+        }
+
+        let log = match persistence.log_path {
+            Some(ref path) => {
+                let recovered = oplog::recover(path, generation, shards, persistence.fsync)?;
+                let replayed = replay(&mut stores, recovered.entries);
+                if replayed > 0 {
+                    info!("Replayed {} writes from {}.", replayed, path.display());
+                }
+                generation = recovered.generation;
+                Some(recovered.log)
+            }
+            None => None,
+        };
+
+        let interval = persistence.snapshot_interval;
+        let snapshots = persistence.snapshot_path.map(|path| {
+            Arc::new(Snapshotter::new(path, interval, shards, generation, log.clone()))
+        });
+        // A replica only takes writes from its primary, so there are none to pass on.
+        let features = Features {
+            snapshots: snapshots,
+            log: log,
+            backlog: match primary {
+                Some(_) => None,
+                None => Some(Arc::new(Backlog::new(shards))),
+            },
+            replica: primary.map(|primary| Arc::new(Replica::new(primary, shards))),
+        };
+        Cache::start(stores, features)
+    }
+
+    /// Start a worker for each of `stores`, then follow the primary if the cache is a replica.
+    fn start(stores: Vec<Store>, features: Features) -> Result<Self, io::Error> {
+        // Snapshots are only enabled once every worker is running, so that a cache that fails
+        // to start doesn't overwrite the snapshot as it's dropped.
+        let mut cache = Cache {
+            shards: Vec::with_capacity(stores.len()),
+            snapshots: None,
+            backlog: features.backlog.clone(),
+            replica: None,
         };
+        for (i, store) in stores.into_iter().enumerate() {
+            let (worker, stealer) = deque::new();
+            let stats = Arc::new(StoreStats::default());
+            let shutdown = Arc::new(AtomicBool::new(false));
+            let stopped = Arc::new(AtomicBool::new(false));
+            stats.update(&store);
+            let features = features.clone();
+            let thread = start(
+                i,
+                stealer,
+                store,
+                stats.clone(),
+                shutdown.clone(),
+                stopped.clone(),
+                features,
+            )?;
 
-        cache.start(capacity);
+            cache.shards.push(Shard {
+                worker: worker,
+                thread: thread,
+                stats: stats,
+                shutdown: shutdown,
+                stopped: stopped,
+            });
+        }
+        cache.snapshots = features.snapshots;
+        if let Some(ref replica) = features.replica {
+            let threads = cache.shards.iter().map(|shard| shard.thread.clone()).collect();
+            Replica::start(replica, threads)?;
+        }
+        cache.replica = features.replica;
         Ok(cache)
     }
 
-    /// Start the stealer thread, which has unsynchronized access to the underlying store.
-    /// `Work` is pushed to the worker via the deque. `Work` is a (Sender<Message>, Message) pair
-    /// where `Message` is a request to do work on the store and `Sender` is a channel to send the result.
+    /// Push work onto the queue of the shard that owns the message's key and wake its worker.
+    /// `snd` is a `futures::sync::oneshot::Sender<Message>`. When the worker has completed the
+    /// request, it will send its `Message::Response` via the sender.
+    ///
+    /// `Stats` requests are answered here, from the stats each worker publishes.
+    ///
+    /// A `Message::BatchRequest` is split by shard, and each shard runs its part of the batch as a
+    /// single unit of work. The response is sent once every part is done.
+    ///
+    /// Work whose deadline has passed by the time a worker gets to it is answered with
+    /// `Code::Timeout` without touching the store.
     ///
-    /// TODO: using `loop_fn` doesn't do what I thought, and this thread currently pegs the CPU just waiting for work.
-    /// I think I need to make the work queue a pollable stream so that we can wait for new work without pegging the CPU.
-    pub fn start(&self, capacity: usize) {
-        let stealer = self.stealer.clone();
-        // Loop infinitely, attempting to steal work from the deque.
-        // When work is obtained, it's dispatched to the `handle` method, which returns a Result containing
-        // the `Message::Response` variant. The response will be returned via the `Sender`
-        let work = future::loop_fn(
-            (stealer, LruCache::new(capacity)),
-            |(stealer, mut store): (Stealer<Work>, Store)| {
+    /// `Snapshot` requests start a snapshot, and are answered once it is on disk.
+    ///
+    /// `Sync` requests from replicas are answered from the backlog of writes, or with a copy of
+    /// every shard's store, see `replication::Backlog`. A replica refuses writes.
+    pub fn process(&self, message: Message, snd: Sender<Message>) {
+        if self.replica.is_some() && message.op().is_write() {
+            if snd.send(refused(&message, Code::ReadOnly)).is_err() {
+                warn!("Failed to send read only response.");
+            }
+            return;
+        }
+
+        if message.op() == Op::Sync && !message.is_batch() {
+            return self.sync(&message, snd);
+        }
+
+        if message.op() == Op::Stats && !message.is_batch() {
+            if snd.send(self.stats()).is_err() {
+                warn!("Failed to send stats.");
+            }
+            return;
+        }
+
+        if message.op() == Op::Snapshot && !message.is_batch() {
+            match self.snapshots {
+                Some(ref snapshots) => {
+                    snapshots.request(Some(snd));
+                    self.wake_all();
+                }
+                None => {
+                    let e = error::Error::new(error::ErrorKind::Other, "snapshots are not enabled");
+                    if snd.send(message::error_response(Op::Snapshot, &e)).is_err() {
+                        warn!("Failed to send snapshot response.");
+                    }
+                }
+            }
+            return;
+        }
+
+        let message = match message {
+            Message::BatchRequest(op, entries, deadline, forwarded) => {
+                if self.shards.len() > 1 {
+                    return self.split_batch(op, entries, deadline, snd);
+                }
+                Message::BatchRequest(op, entries, deadline, forwarded)
+            }
+            message => message,
+        };
+
+        let shard = &self.shards[self.shard_for(message.key().unwrap_or(&[]))];
+        shard.worker.push(Work::Request(snd, message));
+        shard.thread.unpark();
+    }
+
+    /// Answer a replica's `Sync` request, having every shard copy its store for a full sync.
+    fn sync(&self, message: &Message, snd: Sender<Message>) {
+        let backlog = match self.backlog {
+            Some(ref backlog) => backlog,
+            None => {
+                let e = error::Error::new(error::ErrorKind::Other, "replicas can't be synced from");
+                if snd.send(message::error_response(Op::Sync, &e)).is_err() {
+                    warn!("Failed to send sync response.");
+                }
+                return;
+            }
+        };
+        if let Some(dump) = Backlog::sync(backlog, message, snd) {
+            for shard in &self.shards {
+                shard.worker.push(Work::Dump(dump.clone()));
+                shard.thread.unpark();
+            }
+        }
+    }
+
+    /// Push the entries of a batch to the shards that own their keys.
+    fn split_batch(
+        &self,
+        op: Op,
+        entries: Vec<(Vec<u8>, Option<Payload>)>,
+        deadline: Option<Instant>,
+        snd: Sender<Message>,
+    ) {
+        if entries.is_empty() {
+            if snd.send(message::batch_response(op, vec![])).is_err() {
+                warn!("Failed to send batch response.");
+            }
+            return;
+        }
+
+        let count = entries.len();
+        let mut parts: Vec<(Vec<usize>, Vec<(Vec<u8>, Option<Payload>)>)> =
+            self.shards.iter().map(|_| (Vec::new(), Vec::new())).collect();
+        for (i, entry) in entries.into_iter().enumerate() {
+            let shard = self.shard_for(&entry.0);
+            parts[shard].0.push(i);
+            parts[shard].1.push(entry);
+        }
+
+        let busy = parts.iter().filter(|part| !part.0.is_empty()).count();
+        let gather = Arc::new(Gather::new(op, deadline, count, busy, snd));
+        for (shard, (indices, entries)) in self.shards.iter().zip(parts) {
+            if indices.is_empty() {
+                continue;
+            }
+            shard.worker.push(Work::Part(gather.clone(), indices, entries));
+            shard.thread.unpark();
+        }
+    }
+
+    /// The `Stats` response, with totals across all shards and the key count of each shard.
+    fn stats(&self) -> Message {
+        let keys: Vec<usize> = self.shards.iter().map(|shard| shard.stats.keys()).collect();
+        let bytes: usize = self.shards.iter().map(|shard| shard.stats.bytes()).sum();
+        let expired: usize = self.shards.iter().map(|shard| shard.stats.expired()).sum();
+        let evicted: usize = self.shards.iter().map(|shard| shard.stats.evicted()).sum();
+        let mut stats = format!(
+            "keys: {}, bytes: {}, expired: {}, evicted: {}, shard_keys: {:?}",
+            keys.iter().sum::<usize>(),
+            bytes,
+            expired,
+            evicted,
+            keys
+        );
+        match (self.backlog.as_ref(), self.replica.as_ref()) {
+            (Some(backlog), _) if backlog.is_active() => {
+                stats += &format!(", replication_offset: {}", backlog.head());
+            }
+            (_, Some(replica)) => {
+                let lag = match replica.lag() {
+                    Some(lag) => lag.to_string(),
+                    None => "unknown".to_owned(),
+                };
+                stats += &format!(
+                    ", replication_status: {}, replication_offset: {}, replication_lag: {}",
+                    replica.status(),
+                    replica.offset(),
+                    lag
+                );
+            }
+            _ => (),
+        }
+        message::response(
+            Op::Stats,
+            Code::Ok,
+            Some(message::payload(message::TYPE_ID_UTF8, stats.into_bytes())),
+        )
+    }
+
+    fn shard_for(&self, key: &[u8]) -> usize {
+        shard_for(key, self.shards.len())
+    }
+
+    /// Unpark every worker, so that they notice a new snapshot without waiting for their next
+    /// sweep.
+    fn wake_all(&self) {
+        for shard in &self.shards {
+            shard.thread.unpark();
+        }
+    }
+
+    /// Wait for the response to a snapshot request, unless a worker stops first: its part of the
+    /// snapshot would never be handed over, and the snapshot never answered.
+    fn wait_for_snapshot(&self, mut rcv: oneshot::Receiver<Message>) -> Result<Message, String> {
+        loop {
+            match rcv.try_recv() {
+                Ok(Some(msg)) => return Ok(msg),
+                Ok(None) => (),
+                Err(e) => return Err(e.to_string()),
+            }
+            if self.shards.iter().any(|shard| shard.stopped.load(Ordering::SeqCst)) {
+                return Err("a worker has stopped".to_owned());
+            }
+            thread::sleep(Duration::from_millis(STOPPED_POLL_MS));
+        }
+    }
+}
+
+impl Drop for Cache {
+    /// Stop following the primary, if the cache is a replica, and save a final snapshot, if
+    /// snapshots are enabled, then stop the worker threads once they have drained their queues.
+    fn drop(&mut self) {
+        if let Some(ref replica) = self.replica {
+            replica.stop();
+        }
+
+        if let Some(ref snapshots) = self.snapshots {
+            let (snd, rcv) = oneshot::channel();
+            snapshots.request(Some(snd));
+            self.wake_all();
+            match self.wait_for_snapshot(rcv) {
+                Ok(Message::Response(_, Code::Ok, _)) => (),
+                Ok(msg) => error!("Failed to save snapshot on shutdown: {}", msg),
+                Err(e) => error!("Failed to save snapshot on shutdown: {}", e),
+            }
+        }
+
+        for shard in &self.shards {
+            shard.shutdown.store(true, Ordering::SeqCst);
+            shard.thread.unpark();
+        }
+    }
+}
+
+/// The shard of `shards` that owns `key`.
+pub(crate) fn shard_for(key: &[u8], shards: usize) -> usize {
+    if shards == 1 {
+        return 0;
+    }
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    (hasher.finish() % shards as u64) as usize
+}
+
+/// Load the snapshot at `path` into `stores`, giving each entry to the shard that owns its key,
+/// and return the generation of the snapshot and the number of entries loaded. A missing
+/// snapshot loads nothing, and is generation 0.
+///
+/// The stores may be split differently from the cache that saved the snapshot, or be smaller. As
+/// the entries of the whole cache are saved from least to most recently used, the entries that
+/// don't fit are the ones that were least recently used. Each entry is loaded as used a
+/// nanosecond after the one before, so that the stores keep that order between them for the next
+/// snapshot.
+fn restore(stores: &mut [Store], path: &Path) -> io::Result<(usize, usize)> {
+    let (generation, records) = match snapshot::read(path) {
+        Ok(snapshot) => snapshot,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok((0, 0)),
+        Err(e) => return Err(e),
+    };
+
+    let mut now = Instant::now();
+    let wall = SystemTime::now();
+    let shards = stores.len();
+    let mut restored = 0;
+    for record in records {
+        now += Duration::new(0, 1);
+        if let Ok(true) = stores[shard_for(&record.key, shards)].restore(record, now, wall) {
+            restored += 1;
+        }
+    }
+    Ok((generation, restored))
+}
+
+/// Apply the writes read from an operation log to `stores`, in the order they were made, and
+/// return how many there were. As in `restore`, each write is a nanosecond after the one before.
+fn replay(stores: &mut [Store], entries: Vec<Entry>) -> usize {
+    let mut now = Instant::now();
+    let wall = SystemTime::now();
+    let shards = stores.len();
+    let replayed = entries.len();
+    for entry in entries {
+        now += Duration::new(0, 1);
+        let shard = shard_for(entry.key(), shards);
+        apply(&mut stores[shard], entry, now, wall);
+    }
+    replayed
+}
+
+/// Apply a write from an operation log or a primary to `store`.
+fn apply(store: &mut Store, entry: Entry, now: Instant, wall: SystemTime) {
+    match entry {
+        Entry::Put(record) => {
+            let _ = store.restore(record, now, wall);
+        }
+        Entry::Del(key) => {
+            store.remove(&key, now);
+        }
+    }
+}
+
+/// What a worker does besides serving requests: saving snapshots, logging writes, passing them
+/// on to replicas, or taking them from a primary.
+#[derive(Clone)]
+struct Features {
+    snapshots: Option<Arc<Snapshotter>>,
+    log: Option<Arc<OpLog>>,
+    backlog: Option<Arc<Backlog>>,
+    replica: Option<Arc<Replica>>,
+}
+
+impl Features {
+    /// Whether the outcome of writes has to be recorded, for the log or for replicas.
+    fn records_writes(&self) -> bool {
+        self.log.is_some() || self.backlog.as_ref().map_or(false, |backlog| backlog.is_active())
+    }
+}
+
+/// Start a worker thread, which has unsynchronized access to the underlying store.
+/// `Work` is pushed to the worker via the deque. `Work` is either a request to do work on the store
+/// paired with a `Sender` to send the result on, or this shard's part of a batch.
+///
+/// When the deque is empty the worker parks until `Cache::process` unparks it, so an idle cache
+/// costs no CPU. Because an unpark that arrives before the worker parks is not lost, work pushed
+/// between a failed steal and the call to `park` is picked up immediately. The worker also wakes
+/// every `SWEEP_INTERVAL_MS` to sweep expired entries out of the store.
+///
+/// If snapshots are enabled, the worker copies the store for each new snapshot it sees, a chunk
+/// at a time between units of work, and checks whether a timed snapshot is due when it sweeps.
+/// Copies for replicas' full syncs are taken the same way. If there is an operation log, the
+/// worker appends the outcome of each write to it before responding, and likewise to the backlog
+/// once a replica has synced. A replica's worker applies the writes from the primary between
+/// units of work. The worker doesn't park while a copy is in progress.
+fn start(
+    id: usize,
+    stealer: Stealer<Work>,
+    mut store: Store,
+    stats: Arc<StoreStats>,
+    shutdown: Arc<AtomicBool>,
+    stopped: Arc<AtomicBool>,
+    features: Features,
+) -> io::Result<thread::Thread> {
+    let sweep_interval = Duration::from_millis(SWEEP_INTERVAL_MS);
+
+    let handle = thread::Builder::new()
+        .name(format!("rcache-worker-{}", id))
+        .spawn(move || {
+            let _stopped = Stopped(stopped);
+            let mut last_sweep = Instant::now();
+            let mut snapshotted = features.snapshots.as_ref().map_or(0, |s| s.generation());
+            let mut copies: Vec<Copying> = Vec::new();
+
+            loop {
+                // Work is dispatched to the `handle` method, which returns a Result containing
+                // the `Message::Response` variant. The response will be returned via the `Sender`
                 match stealer.steal() {
-                    Stolen::Empty => (), // Continue
-                    Stolen::Abort => (), // TODO: Handle aborts, the obvious manner of doing this doesn't seem to be working
-                    Stolen::Data(work) => {
-                        let (snd, msg) = work;
-                        let success = match handle(&mut store, msg) {
-                            Ok(msg) => snd.send(msg),
-                            Err(e) => snd.send(handle_error(&e)),
+                    Stolen::Data(Work::Request(snd, msg)) => {
+                        let written = if features.records_writes() {
+                            written_keys(&msg)
+                        } else {
+                            Vec::new()
+                        };
+                        let before = logged_records(&features, &mut store, &written);
+                        let mut response = match msg {
+                            ref msg if expired(msg.deadline()) => refused(msg, Code::Timeout),
+                            Message::BatchRequest(op, entries, ..) => {
+                                message::batch_response(op, handle_batch(&mut store, op, entries))
+                            }
+                            msg => {
+                                let op = msg.op();
+                                match handle(&mut store, msg) {
+                                    Ok(msg) => msg,
+                                    Err(e) => message::error_response(op, &e),
+                                }
+                            }
                         };
-                        match success {
-                            Ok(_) => (),
-                            Err(e) => println!("Failed to send: {}.", e),
+                        if !written.is_empty() {
+                            let codes = response_codes(&response);
+                            let recorded =
+                                record_writes(&features, id, &mut store, &written, codes);
+                            if let Err(e) = recorded {
+                                rewind(&mut store, written, before);
+                                response = unrecorded(response, &e);
+                            }
+                        }
+                        // Publish stats before responding, so a client sees its own writes reflected.
+                        stats.update(&store);
+                        if let Err(e) = snd.send(response) {
+                            warn!("Failed to send: {}.", e);
                         }
                     }
-                };
-                future::ok(future::Loop::Continue((stealer, store)))
-            },
-        );
-        self.core.handle().spawn(self.pool.spawn(work));
+                    Stolen::Data(Work::Part(gather, indices, entries)) => {
+                        let written = if features.records_writes() && gather.op.is_write() {
+                            entries.iter().map(|&(ref key, _)| key.clone()).collect()
+                        } else {
+                            Vec::new()
+                        };
+                        let before = logged_records(&features, &mut store, &written);
+                        let mut results = if expired(gather.deadline) {
+                            vec![(Code::Timeout, None); entries.len()]
+                        } else {
+                            handle_batch(&mut store, gather.op, entries)
+                        };
+                        if !written.is_empty() {
+                            let codes = results.iter().map(|&(code, _)| code).collect();
+                            let recorded =
+                                record_writes(&features, id, &mut store, &written, codes);
+                            if let Err(e) = recorded {
+                                rewind(&mut store, written, before);
+                                results = unrecorded_results(gather.op, results, &e);
+                            }
+                        }
+                        stats.update(&store);
+                        gather.complete(indices, results);
+                    }
+                    Stolen::Data(Work::Dump(dump)) => {
+                        copies.push(Copying::new(&store, Target::Sync(dump)));
+                    }
+                    // We lost a race for the item at the top of the deque, it's still there to be retried.
+                    Stolen::Abort => continue,
+                    // A copy in progress is carried on with rather than parking.
+                    Stolen::Empty if copies.is_empty() => {
+                        if shutdown.load(Ordering::SeqCst) {
+                            break;
+                        }
+                        thread::park_timeout(sweep_interval);
+                    }
+                    Stolen::Empty => {}
+                }
+
+                let now = Instant::now();
+                if now - last_sweep >= sweep_interval {
+                    store.sweep(now, SWEEP_LIMIT);
+                    stats.update(&store);
+                    last_sweep = now;
+                    if let Some(ref snapshots) = features.snapshots {
+                        snapshots.tick(now);
+                    }
+                    if let Some(ref backlog) = features.backlog {
+                        backlog.tick(now);
+                    }
+                }
+
+                if let Some(ref replica) = features.replica {
+                    if let Some(pending) = replica.take(id) {
+                        if pending.reset {
+                            store.clear();
+                        }
+                        let wall = SystemTime::now();
+                        for entry in pending.entries {
+                            apply(&mut store, entry, now, wall);
+                        }
+                        stats.update(&store);
+                    }
+                }
+
+                if let Some(ref snapshots) = features.snapshots {
+                    let generation = snapshots.generation();
+                    if generation != snapshotted {
+                        if snapshots.begin(generation, id) {
+                            let target = Target::Snapshot(snapshots.clone(), generation);
+                            copies.push(Copying::new(&store, target));
+                        }
+                        snapshotted = generation;
+                    }
+                }
+
+                let mut i = 0;
+                while i < copies.len() {
+                    if copies[i].advance(&mut store) {
+                        copies.swap_remove(i).contribute(id);
+                    } else {
+                        i += 1;
+                    }
+                }
+            }
+        })?;
+
+    Ok(handle.thread().clone())
+}
+
+/// Sets its flag when dropped, as a worker's thread exits, even by panicking.
+struct Stopped(Arc<AtomicBool>);
+
+impl Drop for Stopped {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// The keys `msg` writes to, one for each entry of a batch, or none if it doesn't write.
+fn written_keys(msg: &Message) -> Vec<Vec<u8>> {
+    if !msg.op().is_write() {
+        return Vec::new();
+    }
+    match *msg {
+        Message::BatchRequest(_, ref entries, ..) => {
+            entries.iter().map(|&(ref key, _)| key.clone()).collect()
+        }
+        _ => msg.key().map(|key| vec![key.to_vec()]).unwrap_or_default(),
     }
+}
 
-    /// Push work onto the queue. `snd` is a `futures::sync::oneshot::Sender<Message>`. When the
-    /// worker has completed the request, it will send its `Message::Response` via the sender.
-    pub fn process(&self, message: Message, snd: Sender<Message>) {
-        self.worker.push((snd, message));
+/// The codes of `response`, one for each entry of a batch.
+fn response_codes(response: &Message) -> Vec<Code> {
+    match *response {
+        Message::BatchResponse(_, ref results) => results.iter().map(|&(code, _)| code).collect(),
+        Message::Response(_, code, _) => vec![code],
+        _ => Vec::new(),
+    }
+}
+
+/// Whether a write answered with `code` changed the store, and so has to be recorded.
+fn recorded(code: Code) -> bool {
+    code == Code::Ok || code == Code::Hit
+}
+
+/// Append what's now in `store` under each of `keys` whose write succeeded, as told by `codes`,
+/// to the operation log and the backlog for replicas: the entry if there is one, or its removal.
+/// A write that couldn't be appended to the log is an error, as it would be lost on restart. It
+/// isn't passed on to replicas, and the caller undoes it, see `rewind`.
+fn record_writes(
+    features: &Features,
+    shard: usize,
+    store: &mut Store,
+    keys: &[Vec<u8>],
+    codes: Vec<Code>,
+) -> Result<(), error::Error> {
+    let now = Instant::now();
+    let wall = SystemTime::now();
+    let entries: Vec<Entry> = keys.iter()
+        .zip(codes)
+        .filter(|&(_, code)| recorded(code))
+        .map(|(key, _)| match store.record(key, now, wall) {
+            Some(record) => Entry::Put(record),
+            None => Entry::Del(key.clone()),
+        })
+        .collect();
+    if entries.is_empty() {
+        return Ok(());
+    }
+    if let Some(ref log) = features.log {
+        if let Err(e) = log.append(shard, &entries) {
+            let description = format!("failed to append to {}: {}", log.path().display(), e);
+            error!("{}.", description);
+            return Err(error::Error::new(error::ErrorKind::Other, &description));
+        }
+    }
+    if let Some(ref backlog) = features.backlog {
+        if backlog.is_active() {
+            backlog.append(entries);
+        }
+    }
+    Ok(())
+}
+
+/// What's in `store` under each of `keys` before they're written, if the writes are logged, so
+/// that they can be undone if the log fails to record them.
+fn logged_records(features: &Features, store: &mut Store, keys: &[Vec<u8>]) -> Vec<Option<Record>> {
+    if features.log.is_none() {
+        return Vec::new();
+    }
+    let now = Instant::now();
+    let wall = SystemTime::now();
+    keys.iter().map(|key| store.record(key, now, wall)).collect()
+}
+
+/// Undo the writes to `keys`, putting back what `before` says was there, as the log failed to
+/// record them. Entries that the writes evicted stay evicted.
+fn rewind(store: &mut Store, keys: Vec<Vec<u8>>, before: Vec<Option<Record>>) {
+    let now = Instant::now();
+    let wall = SystemTime::now();
+    for (key, record) in keys.into_iter().zip(before) {
+        match record {
+            Some(record) => {
+                if let Err(e) = store.restore(record, now, wall) {
+                    warn!("Failed to undo a write: {}.", e);
+                }
+            }
+            None => {
+                store.remove(&key, now);
+            }
+        }
+    }
+}
+
+/// `response`, with each write it reports as made answered with `e` instead, as it couldn't be
+/// recorded.
+fn unrecorded(response: Message, e: &error::Error) -> Message {
+    match response {
+        Message::BatchResponse(op, results) => {
+            message::batch_response(op, unrecorded_results(op, results, e))
+        }
+        Message::Response(op, code, _) if recorded(code) => message::error_response(op, e),
+        response => response,
+    }
+}
+
+/// The `results` of a batch of `op`, with each write reported as made answered with `e` instead.
+fn unrecorded_results(
+    op: Op,
+    results: Vec<(Code, Option<Payload>)>,
+    e: &error::Error,
+) -> Vec<(Code, Option<Payload>)> {
+    let error = message::error_response(op, e);
+    results
+        .into_iter()
+        .map(|(code, payload)| if recorded(code) {
+            (Code::Error, error.payload().cloned())
+        } else {
+            (code, payload)
+        })
+        .collect()
+}
+
+/// Whether the caller has given up on work with `deadline`.
+pub(crate) fn expired(deadline: Option<Instant>) -> bool {
+    deadline.map_or(false, |deadline| deadline <= Instant::now())
+}
+
+/// The response to a request that was refused with `code`, such as `Code::Timeout` for one whose
+/// deadline has passed, with the code for each entry of a batch.
+pub(crate) fn refused(msg: &Message, code: Code) -> Message {
+    match *msg {
+        Message::BatchRequest(op, ref entries, ..) => {
+            message::batch_response(op, vec![(code, None); entries.len()])
+        }
+        _ => message::response(msg.op(), code, None),
     }
 }
 
@@ -86,32 +996,163 @@ impl Cache {
 fn handle(store: &mut Store, message: Message) -> Result<Message, error::Error> {
     let op = message.op();
     let (key, payload) = message.consume_request()?;
+    let now = Instant::now();
 
     let response = match op {
         Op::Set => {
             let key = key;
             let payload = payload.ok_or_else(|| "no payload given to set op")?;
-            store.insert(key, payload);
-            message::response(Op::Set, Code::Ok, None)
+            insert(store, Op::Set, key, payload, now)?
         }
 
-        Op::Get => {
-            if let Some(ref mut payload) = store.get_mut(key.as_slice()) {
-                message::response(Op::Get, Code::Hit, Some(payload.clone()))
+        // Only write if there is no entry at the key.
+        Op::Add => {
+            let payload = payload.ok_or_else(|| "no payload given to add op")?;
+            if store.contains(key.as_slice(), now) {
+                message::response(Op::Add, Code::NotStored, None)
+            } else {
+                insert(store, Op::Add, key, payload, now)?
+            }
+        }
+
+        // Only write if there is an entry at the key.
+        Op::Replace => {
+            let payload = payload.ok_or_else(|| "no payload given to replace op")?;
+            if store.contains(key.as_slice(), now) {
+                insert(store, Op::Replace, key, payload, now)?
             } else {
-                message::response(Op::Get, Code::Miss, None)
+                message::response(Op::Replace, Code::NotStored, None)
+            }
+        }
+
+        // Add the payload's data after or before an existing entry's data. The entry keeps its
+        // type_id, flags and expiry.
+        Op::Append | Op::Prepend => {
+            let payload = payload.ok_or_else(|| "no payload given to append op")?;
+            match store.get(key.as_slice(), now) {
+                Some(current) => {
+                    let mut data = Vec::with_capacity(current.data().len() + payload.data().len());
+                    if op == Op::Append {
+                        data.extend_from_slice(current.data());
+                        data.extend_from_slice(payload.data());
+                    } else {
+                        data.extend_from_slice(payload.data());
+                        data.extend_from_slice(current.data());
+                    }
+                    let updated = message::payload(current.type_id(), data)
+                        .with_flags(current.flags());
+                    match store.replace(key.as_slice(), updated, now) {
+                        Ok(_) => message::response(op, Code::Ok, None),
+                        Err(ref e) if *e.kind() == error::ErrorKind::TooLarge => {
+                            message::response(op, Code::TooLarge, None)
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+                None => message::response(op, Code::NotStored, None),
+            }
+        }
+
+        // Only write if the entry's version still matches the version of the caller's payload.
+        Op::Cas => {
+            let payload = payload.ok_or_else(|| "no payload given to cas op")?;
+            match store.version(key.as_slice(), now) {
+                None => message::response(Op::Cas, Code::Miss, None),
+                Some(version) if version != payload.version() => {
+                    message::response(Op::Cas, Code::Exists, None)
+                }
+                Some(_) => insert(store, Op::Cas, key, payload, now)?,
+            }
+        }
+
+        Op::Get => {
+            match store.get(key.as_slice(), now) {
+                Some(payload) => message::response(Op::Get, Code::Hit, Some(payload)),
+                None => message::response(Op::Get, Code::Miss, None),
+            }
+        }
+
+        // Adds or subtracts the delta from the counter at `key` and responds with its new value.
+        // Incrementing wraps around at 2^64, decrementing stops at 0. A missing key is initialized
+        // with the request's initial value, if it has one, and the payload's ttl.
+        Op::Incr | Op::Decr => {
+            let payload = payload.ok_or_else(|| "no payload given to counter op")?;
+            let (delta, initial) = parse_counter_request(&payload)?;
+
+            match store.get(key.as_slice(), now) {
+                Some(current) => {
+                    let value = counter_value(&current)?;
+                    let value = if op == Op::Incr {
+                        value.wrapping_add(delta)
+                    } else {
+                        value.saturating_sub(delta)
+                    };
+                    let counter = message::u64_payload(value).with_flags(current.flags());
+                    store.replace(key.as_slice(), counter, now)?;
+                    message::response(op, Code::Ok, Some(message::u64_payload(value)))
+                }
+                None => {
+                    match initial {
+                        Some(initial) => {
+                            let counter = message::u64_payload(initial)
+                                .with_ttl_millis(payload.ttl_millis());
+                            store.insert(key, counter, now)?;
+                            message::response(op, Code::Ok, Some(message::u64_payload(initial)))
+                        }
+                        None => message::response(op, Code::Miss, None),
+                    }
+                }
             }
         }
 
-        // TODO
         Op::Del => {
-            message::response(Op::Del, Code::Ok, None)
+            if store.remove(key.as_slice(), now) {
+                message::response(Op::Del, Code::Hit, None)
+            } else {
+                message::response(Op::Del, Code::Miss, None)
+            }
+        }
+
+        // Responds with the remaining ttl in seconds, 0 if the key never expires.
+        Op::Ttl => {
+            match store.ttl(key.as_slice(), now) {
+                Some(ttl) => {
+                    message::response(Op::Ttl, Code::Hit, Some(message::u64_payload(u64::from(ttl))))
+                }
+                None => message::response(Op::Ttl, Code::Miss, None),
+            }
         }
+
+        Op::Hello => {
+            return Err(error::Error::new(
+                error::ErrorKind::BadMessage,
+                "hello is only valid as the first frame of a connection",
+            ))
+        }
+
+        Op::Snapshot => {
+            return Err(error::Error::new(
+                error::ErrorKind::BadMessage,
+                "snapshot can't be batched",
+            ))
+        }
+
+        Op::Sync => {
+            return Err(error::Error::new(error::ErrorKind::BadMessage, "sync can't be batched"))
+        }
+
         Op::Stats => {
+            let stats = format!(
+                "keys: {}, bytes: {}, expired: {}, evicted: {}",
+                store.len(),
+                store.bytes(),
+                store.expired(),
+                store.evicted()
+            );
             message::response(
                 Op::Stats,
                 Code::Ok,
-                Some(message::payload(store.len() as u32, vec![])),
+                Some(message::payload(message::TYPE_ID_UTF8, stats.into_bytes())),
             )
         }
     };
@@ -119,19 +1160,516 @@ fn handle(store: &mut Store, message: Message) -> Result<Message, error::Error>
     Ok(response)
 }
 
-/// Creates a `Message::Response`, setting the error code and
-/// and passing the error description as the payload. Responses with an error code should
-/// enforce the invariant that the payload contain a UTF8-encoded string, so that clients
-/// can safely decode the payload for human consumption.
-///
-/// TODO: match over the error kind and translate it into an appropriate error for the front end.
-fn handle_error(err: &error::Error) -> Message {
-    message::response(
-        Op::Get,
-        Code::Error,
-        Some(message::payload(
-            0,
-            err.description().to_owned().into_bytes(),
-        )),
-    )
+/// Handle each entry of a batch as a request for `op`, in order, returning a (code, payload)
+/// result per entry. An entry that fails doesn't stop the rest of the batch.
+fn handle_batch(
+    store: &mut Store,
+    op: Op,
+    entries: Vec<(Vec<u8>, Option<Payload>)>,
+) -> Vec<(Code, Option<Payload>)> {
+    entries
+        .into_iter()
+        .map(|(key, payload)| {
+            let response = match handle(store, message::request(op, key, payload)) {
+                Ok(msg) => msg,
+                Err(e) => message::error_response(op, &e),
+            };
+            response
+                .consume_response()
+                .map(|(_, code, payload)| (code, payload))
+                .unwrap_or((Code::Error, None))
+        })
+        .collect()
+}
+
+/// The delta and optional initial value of an `Incr` or `Decr` request, see `message::counter_payload`.
+fn parse_counter_request(payload: &Payload) -> Result<(u64, Option<u64>), error::Error> {
+    let data = payload.data();
+    if payload.type_id() != message::TYPE_ID_U64 || (data.len() != 8 && data.len() != 16) {
+        return Err(error::Error::new(
+            error::ErrorKind::BadMessage,
+            "expected a u64 delta and optional initial value",
+        ));
+    }
+
+    let mut cursor = io::Cursor::new(data);
+    let delta = cursor.get_u64::<BigEndian>();
+    let initial = if data.len() == 16 {
+        Some(cursor.get_u64::<BigEndian>())
+    } else {
+        None
+    };
+    Ok((delta, initial))
+}
+
+/// Read a stored counter. Counters are `TYPE_ID_U64` payloads, but a decimal string is accepted
+/// too, so that a value set by a text client can be incremented.
+fn counter_value(payload: &Payload) -> Result<u64, error::Error> {
+    if let Some(value) = payload.as_u64() {
+        return Ok(value);
+    }
+
+    str::from_utf8(payload.data())
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| {
+            error::Error::new(error::ErrorKind::NotNumeric, "value is not a 64-bit integer")
+        })
+}
+
+/// Insert `payload` into the store, responding with `Code::TooLarge` if it can never fit.
+fn insert(
+    store: &mut Store,
+    op: Op,
+    key: Vec<u8>,
+    payload: Payload,
+    now: Instant,
+) -> Result<Message, error::Error> {
+    match store.insert(key, payload, now) {
+        Ok(()) => Ok(message::response(op, Code::Ok, None)),
+        Err(ref e) if *e.kind() == error::ErrorKind::TooLarge => {
+            Ok(message::response(op, Code::TooLarge, None))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{future, Future};
+    use futures::sync::oneshot;
+    use test::Bencher;
+    use testing::{self, TempDir};
+
+    fn call(cache: &Cache, msg: Message) -> oneshot::Receiver<Message> {
+        let (snd, rcv) = oneshot::channel();
+        cache.process(msg, snd);
+        rcv
+    }
+
+    #[test]
+    fn test_wakes_after_idle() {
+        let cache = Cache::new(10).unwrap();
+        let set = message::request(Op::Set, "foo".into(), Some(message::payload(1, "bar".into())));
+        assert_eq!(call(&cache, set).wait().unwrap().code(), Code::Ok);
+
+        // Let the worker park, then make sure new work still wakes it.
+        thread::sleep(Duration::from_millis(SWEEP_INTERVAL_MS * 3));
+
+        let get = message::request(Op::Get, "foo".into(), None);
+        let resp = call(&cache, get).wait().unwrap();
+        assert_eq!(resp.code(), Code::Hit);
+    }
+
+    #[test]
+    fn test_shards() {
+        let cache = Cache::with_shards(Capacity::Entries(1000), 4).unwrap();
+        let sets: Vec<_> = (0..100)
+            .map(|i| {
+                let key = format!("key{}", i).into_bytes();
+                call(&cache, message::request(Op::Set, key, Some(message::payload(1, vec![i]))))
+            })
+            .collect();
+        future::join_all(sets).wait().unwrap();
+
+        for i in 0..100 {
+            let key = format!("key{}", i).into_bytes();
+            let resp = call(&cache, message::request(Op::Get, key, None)).wait().unwrap();
+            assert_eq!(resp.code(), Code::Hit);
+            assert_eq!(resp.payload().unwrap().data(), &[i]);
+        }
+
+        let keys: Vec<usize> = cache.shards.iter().map(|shard| shard.stats.keys()).collect();
+        assert_eq!(keys.iter().sum::<usize>(), 100);
+        assert!(keys.iter().all(|&n| n > 0));
+
+        let resp = call(&cache, message::request(Op::Stats, vec![], None)).wait().unwrap();
+        let stats = String::from_utf8(resp.payload().unwrap().data().to_vec()).unwrap();
+        assert!(stats.starts_with("keys: 100, "));
+        assert!(stats.contains("shard_keys: ["));
+    }
+
+    #[test]
+    fn test_batch() {
+        let cache = Cache::with_shards(Capacity::Entries(1000), 4).unwrap();
+        let entries = (0..50)
+            .map(|i| {
+                let key = format!("key{}", i).into_bytes();
+                (key, Some(message::payload(1, vec![i])))
+            })
+            .collect();
+        let resp = call(&cache, message::batch_request(Op::Set, entries)).wait().unwrap();
+        match resp {
+            Message::BatchResponse(Op::Set, ref results) => {
+                assert_eq!(results.len(), 50);
+                assert!(results.iter().all(|r| r.0 == Code::Ok));
+            }
+            ref msg => panic!("unexpected response {}", msg),
+        }
+
+        // Every other key is missing, and the results come back in request order.
+        let keys = (0..100).map(|i| (format!("key{}", i * 2).into_bytes(), None)).collect();
+        let resp = call(&cache, message::batch_request(Op::Get, keys)).wait().unwrap();
+        match resp {
+            Message::BatchResponse(Op::Get, ref results) => {
+                assert_eq!(results.len(), 100);
+                for (i, result) in results.iter().enumerate() {
+                    if i * 2 < 50 {
+                        assert_eq!(result.0, Code::Hit);
+                        assert_eq!(result.1.as_ref().unwrap().data(), &[(i * 2) as u8]);
+                    } else {
+                        assert_eq!(result.0, Code::Miss);
+                    }
+                }
+            }
+            ref msg => panic!("unexpected response {}", msg),
+        }
+
+        let resp = call(&cache, message::batch_request(Op::Get, vec![])).wait().unwrap();
+        assert_eq!(resp, message::batch_response(Op::Get, vec![]));
+
+        // A failing entry doesn't fail the batch.
+        let single = Cache::new(10).unwrap();
+        let entries = vec![("a".into(), None), ("b".into(), Some(message::payload(1, "x".into())))];
+        let resp = call(&single, message::batch_request(Op::Set, entries)).wait().unwrap();
+        let codes: Vec<Code> = match resp {
+            Message::BatchResponse(_, results) => results.into_iter().map(|r| r.0).collect(),
+            msg => panic!("unexpected response {}", msg),
+        };
+        assert_eq!(codes, vec![Code::Error, Code::Ok]);
+    }
+
+    #[test]
+    fn test_deadline() {
+        let cache = Cache::with_shards(Capacity::Entries(1000), 4).unwrap();
+        let past = Instant::now();
+        let future = Instant::now() + Duration::from_secs(60);
+
+        let set = message::request(Op::Set, "foo".into(), Some(message::payload(1, "bar".into())));
+        let resp = call(&cache, set.clone().with_deadline(past)).wait().unwrap();
+        assert_eq!(resp, message::response(Op::Set, Code::Timeout, None));
+
+        // The timed out set never reached the store.
+        let get = message::request(Op::Get, "foo".into(), None);
+        assert_eq!(call(&cache, get.clone()).wait().unwrap().code(), Code::Miss);
+
+        assert_eq!(call(&cache, set.with_deadline(future)).wait().unwrap().code(), Code::Ok);
+        assert_eq!(call(&cache, get.with_deadline(future)).wait().unwrap().code(), Code::Hit);
+
+        // A batch split across shards times out entry by entry.
+        let keys = (0..20).map(|i| (vec![i], None)).collect();
+        let batch = message::batch_request(Op::Get, keys).with_deadline(past);
+        match call(&cache, batch).wait().unwrap() {
+            Message::BatchResponse(Op::Get, ref results) => {
+                assert_eq!(results.len(), 20);
+                assert!(results.iter().all(|result| result.0 == Code::Timeout));
+            }
+            other => panic!("unexpected response {}", other),
+        }
+    }
+
+    #[test]
+    fn test_too_large() {
+        let cache = Cache::with_shards(Capacity::Bytes(1024), 1).unwrap();
+        let set = message::request(Op::Set, "foo".into(), Some(message::payload(1, vec![0; 100])));
+        assert_eq!(call(&cache, set).wait().unwrap().code(), Code::Ok);
+
+        let set = message::request(Op::Set, "bar".into(), Some(message::payload(1, vec![0; 2048])));
+        assert_eq!(call(&cache, set).wait().unwrap().code(), Code::TooLarge);
+
+        let get = message::request(Op::Get, "foo".into(), None);
+        assert_eq!(call(&cache, get).wait().unwrap().code(), Code::Hit);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let dir = TempDir::new();
+        let path = dir.path().join("cache.snap");
+        let snapshot = message::request(Op::Snapshot, vec![], None);
+
+        let cache = Cache::with_shards(Capacity::Entries(100), 1).unwrap();
+        let resp = call(&cache, snapshot.clone()).wait().unwrap();
+        assert_eq!(resp.op(), Op::Snapshot);
+        assert_eq!(*resp.error().unwrap().kind(), error::ErrorKind::Other);
+
+        let cache = Cache::with_snapshots(Capacity::Entries(100), 4, path.clone(), None).unwrap();
+        let sets: Vec<_> = (0..20)
+            .map(|i| {
+                let key = format!("key{}", i).into_bytes();
+                let payload = message::payload(1, vec![i]).with_ttl(if i < 10 { 60 } else { 0 });
+                call(&cache, message::request(Op::Set, key, Some(payload)))
+            })
+            .collect();
+        future::join_all(sets).wait().unwrap();
+
+        let resp = call(&cache, snapshot).wait().unwrap();
+        assert_eq!(resp.payload().unwrap().as_u64(), Some(20));
+
+        // Entries written after the last request are saved as the cache is dropped.
+        let del = message::request(Op::Del, "key0".into(), None);
+        assert_eq!(call(&cache, del).wait().unwrap().code(), Code::Hit);
+        drop(cache);
+
+        // Restore into a different number of shards.
+        let cache = Cache::with_snapshots(Capacity::Entries(100), 2, path.clone(), None).unwrap();
+        let get = message::request(Op::Get, "key0".into(), None);
+        assert_eq!(call(&cache, get).wait().unwrap().code(), Code::Miss);
+        for i in 1..20 {
+            let get = message::request(Op::Get, format!("key{}", i).into_bytes(), None);
+            let resp = call(&cache, get).wait().unwrap();
+            let payload = resp.payload().unwrap();
+            assert_eq!(payload.data(), &[i]);
+            assert_eq!(payload.ttl() > 0, i < 10);
+        }
+    }
+
+    #[test]
+    fn test_snapshot_order() {
+        let dir = TempDir::new();
+        let path = dir.path().join("cache.snap");
+        let cache = Cache::with_snapshots(Capacity::Entries(100), 4, path.clone(), None).unwrap();
+        for key in testing::keys(20) {
+            let set = message::request(Op::Set, key, Some(message::payload(1, "x".into())));
+            call(&cache, set).wait().unwrap();
+        }
+        for key in testing::keys(5) {
+            call(&cache, message::request(Op::Get, key, None)).wait().unwrap();
+        }
+        drop(cache);
+
+        // The most recently used entries of the whole cache are the ones that fit, whichever
+        // shards they were on.
+        let cache = Cache::with_snapshots(Capacity::Entries(5), 1, path, None).unwrap();
+        for (i, key) in testing::keys(20).into_iter().enumerate() {
+            let code = call(&cache, message::request(Op::Get, key, None)).wait().unwrap().code();
+            assert_eq!(code == Code::Hit, i < 5, "key{}", i);
+        }
+    }
+
+    #[test]
+    fn test_drop_with_stopped_worker() {
+        let dir = TempDir::new();
+        let path = dir.path().join("cache.snap");
+        let cache = Cache::with_snapshots(Capacity::Entries(100), 2, path.clone(), None).unwrap();
+
+        // Stop a worker, as one that panicked would, which can't save its part of a snapshot.
+        cache.shards[1].shutdown.store(true, Ordering::SeqCst);
+        cache.shards[1].thread.unpark();
+        while !cache.shards[1].stopped.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        drop(cache);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_snapshot_interval() {
+        let dir = TempDir::new();
+        let path = dir.path().join("cache.snap");
+        let interval = Some(Duration::from_millis(SWEEP_INTERVAL_MS));
+        let cache = Cache::with_snapshots(Capacity::Entries(100), 2, path.clone(), interval)
+            .unwrap();
+        let set = message::request(Op::Set, "foo".into(), Some(message::payload(1, "bar".into())));
+        call(&cache, set).wait().unwrap();
+
+        thread::sleep(Duration::from_millis(SWEEP_INTERVAL_MS * 5));
+        assert_eq!(snapshot::read(&path).unwrap().1.len(), 1);
+    }
+
+    #[test]
+    fn test_oplog() {
+        let dir = TempDir::new();
+        let snapshot_path = dir.path().join("cache.snap");
+        let log_path = dir.path().join("cache.log");
+        let set = |key: &str, value: &str| {
+            message::request(Op::Set, key.into(), Some(message::payload(1, value.into())))
+        };
+        let get = |key: &str| message::request(Op::Get, key.into(), None);
+
+        // With only a log, every write is replayed on restart.
+        let persistence = Persistence {
+            log_path: Some(log_path.clone()),
+            fsync: Fsync::Always,
+            ..Persistence::default()
+        };
+        let cache = Cache::with_persistence(Capacity::Entries(100), 2, persistence.clone())
+            .unwrap();
+        call(&cache, set("a", "1")).wait().unwrap();
+        call(&cache, set("b", "2")).wait().unwrap();
+        call(&cache, message::request(Op::Del, "a".into(), None)).wait().unwrap();
+        let incr = message::request(Op::Incr, "b".into(), Some(message::counter_payload(1, None)));
+        call(&cache, incr).wait().unwrap();
+        let batch = message::batch_request(
+            Op::Set,
+            vec![("c".into(), Some(message::payload(1, "3".into())))],
+        );
+        call(&cache, batch).wait().unwrap();
+        drop(cache);
+
+        let cache = Cache::with_persistence(Capacity::Entries(100), 4, persistence).unwrap();
+        assert_eq!(call(&cache, get("a")).wait().unwrap().code(), Code::Miss);
+        assert_eq!(call(&cache, get("b")).wait().unwrap().payload().unwrap().as_u64(), Some(3));
+        assert_eq!(call(&cache, get("c")).wait().unwrap().payload().unwrap().data(), b"3");
+        drop(cache);
+
+        // With both, a snapshot compacts the log, and writes made after it are replayed over it.
+        let persistence = Persistence {
+            snapshot_path: Some(snapshot_path.clone()),
+            log_path: Some(log_path.clone()),
+            fsync: Fsync::Always,
+            ..Persistence::default()
+        };
+        let cache = Cache::with_persistence(Capacity::Entries(100), 2, persistence.clone())
+            .unwrap();
+        let snapshot = message::request(Op::Snapshot, vec![], None);
+        assert_eq!(call(&cache, snapshot).wait().unwrap().payload().unwrap().as_u64(), Some(2));
+        call(&cache, set("d", "4")).wait().unwrap();
+        call(&cache, message::request(Op::Del, "b".into(), None)).wait().unwrap();
+        // Leak the cache rather than drop it, as a crash would, so no snapshot is saved.
+        mem::forget(cache);
+
+        let cache = Cache::with_persistence(Capacity::Entries(100), 2, persistence).unwrap();
+        assert_eq!(call(&cache, get("b")).wait().unwrap().code(), Code::Miss);
+        assert_eq!(call(&cache, get("c")).wait().unwrap().payload().unwrap().data(), b"3");
+        assert_eq!(call(&cache, get("d")).wait().unwrap().payload().unwrap().data(), b"4");
+    }
+
+    #[test]
+    fn test_unrecorded() {
+        let e = error::Error::new(error::ErrorKind::Other, "disk full");
+        let resp = unrecorded(message::response(Op::Set, Code::Ok, None), &e);
+        assert_eq!(resp.op(), Op::Set);
+        assert_eq!(resp.error().unwrap().to_string(), e.to_string());
+
+        // Only the writes that were made failed to be recorded.
+        let miss = message::response(Op::Del, Code::Miss, None);
+        assert_eq!(unrecorded(miss.clone(), &e), miss);
+        let results = vec![(Code::Ok, None), (Code::TooLarge, None), (Code::Hit, None)];
+        match unrecorded(message::batch_response(Op::Set, results), &e) {
+            Message::BatchResponse(Op::Set, results) => {
+                let codes: Vec<Code> = results.iter().map(|&(code, _)| code).collect();
+                assert_eq!(codes, vec![Code::Error, Code::TooLarge, Code::Error]);
+                assert_eq!(results[0].1.as_ref().unwrap().error_code(), e.kind().code());
+            }
+            other => panic!("expected a batch response, got {}", other),
+        }
+    }
+
+    #[test]
+    fn test_rewind() {
+        let mut store = Store::new(Capacity::Entries(10));
+        let now = Instant::now();
+        let wall = SystemTime::now();
+        store.insert("a".into(), message::payload(1, "1".into()), now).unwrap();
+        store.insert("b".into(), message::payload(1, "2".into()), now).unwrap();
+        let version = store.record(b"a", now, wall).unwrap().payload.version();
+
+        // An Incr, a Set of a new key and a Del that the log failed to record are undone.
+        let keys: Vec<Vec<u8>> = vec!["a".into(), "c".into(), "b".into()];
+        let before: Vec<_> = keys.iter().map(|key| store.record(key, now, wall)).collect();
+        let incr = message::request(Op::Incr, "a".into(), Some(message::counter_payload(1, None)));
+        handle(&mut store, incr).unwrap();
+        let set = message::request(Op::Set, "c".into(), Some(message::payload(1, "3".into())));
+        handle(&mut store, set).unwrap();
+        handle(&mut store, message::request(Op::Del, "b".into(), None)).unwrap();
+        rewind(&mut store, keys, before);
+
+        let a = store.record(b"a", now, wall).unwrap();
+        assert_eq!(a.payload.data(), b"1");
+        assert_eq!(a.payload.version(), version);
+        assert_eq!(store.record(b"b", now, wall).unwrap().payload.data(), b"2");
+        assert!(store.record(b"c", now, wall).is_none());
+    }
+
+    #[test]
+    fn test_counters() {
+        let cache = Cache::new(10).unwrap();
+        let incr = |delta, initial| {
+            message::request(Op::Incr, "n".into(), Some(message::counter_payload(delta, initial)))
+        };
+
+        assert_eq!(call(&cache, incr(1, None)).wait().unwrap().code(), Code::Miss);
+
+        let resp = call(&cache, incr(1, Some(10))).wait().unwrap();
+        assert_eq!(resp.payload().unwrap().as_u64(), Some(10));
+        let resp = call(&cache, incr(5, Some(10))).wait().unwrap();
+        assert_eq!(resp.payload().unwrap().as_u64(), Some(15));
+
+        let decr = message::request(Op::Decr, "n".into(), Some(message::counter_payload(20, None)));
+        let resp = call(&cache, decr).wait().unwrap();
+        assert_eq!(resp.code(), Code::Ok);
+        assert_eq!(resp.payload().unwrap().as_u64(), Some(0));
+
+        let set = message::request(Op::Set, "s".into(), Some(message::payload(1, "41".into())));
+        call(&cache, set).wait().unwrap();
+        let incr = message::request(Op::Incr, "s".into(), Some(message::counter_payload(1, None)));
+        let resp = call(&cache, incr).wait().unwrap();
+        assert_eq!(resp.payload().unwrap().as_u64(), Some(42));
+        let get = message::request(Op::Get, "s".into(), None);
+        let resp = call(&cache, get).wait().unwrap();
+        assert_eq!(resp.payload().unwrap().as_u64(), Some(42));
+    }
+
+    #[test]
+    fn test_conditional_stores() {
+        let cache = Cache::new(10).unwrap();
+        let req = |op, value: &str| {
+            message::request(op, "k".into(), Some(message::payload(1, value.into())))
+        };
+        let get = || message::request(Op::Get, "k".into(), None);
+
+        assert_eq!(call(&cache, req(Op::Replace, "a")).wait().unwrap().code(), Code::NotStored);
+        assert_eq!(call(&cache, req(Op::Append, "a")).wait().unwrap().code(), Code::NotStored);
+        assert_eq!(call(&cache, req(Op::Prepend, "a")).wait().unwrap().code(), Code::NotStored);
+        assert_eq!(call(&cache, get()).wait().unwrap().code(), Code::Miss);
+
+        assert_eq!(call(&cache, req(Op::Add, "b")).wait().unwrap().code(), Code::Ok);
+        assert_eq!(call(&cache, req(Op::Add, "x")).wait().unwrap().code(), Code::NotStored);
+        assert_eq!(call(&cache, req(Op::Append, "c")).wait().unwrap().code(), Code::Ok);
+        assert_eq!(call(&cache, req(Op::Prepend, "a")).wait().unwrap().code(), Code::Ok);
+
+        let resp = call(&cache, get()).wait().unwrap();
+        assert_eq!(resp.payload().unwrap().data(), b"abc");
+
+        assert_eq!(call(&cache, req(Op::Replace, "d")).wait().unwrap().code(), Code::Ok);
+        let resp = call(&cache, get()).wait().unwrap();
+        assert_eq!(resp.payload().unwrap().data(), b"d");
+    }
+
+    #[test]
+    fn test_counter_not_numeric() {
+        let cache = Cache::new(10).unwrap();
+        let set = message::request(Op::Set, "s".into(), Some(message::payload(1, "foo".into())));
+        call(&cache, set).wait().unwrap();
+
+        let incr = message::request(Op::Incr, "s".into(), Some(message::counter_payload(1, None)));
+        let resp = call(&cache, incr).wait().unwrap();
+        assert_eq!(resp.code(), Code::Error);
+
+        let counter = message::payload(message::TYPE_ID_U64, vec![1]);
+        assert_eq!(*counter_value(&counter).unwrap_err().kind(), error::ErrorKind::NotNumeric);
+    }
+
+    /// 1000 requests, alternating sets and gets of 500 keys, all in flight at once. See the
+    /// Performance section of the crate docs for results.
+    #[bench]
+    fn bench_process(b: &mut Bencher) {
+        let cache = Cache::new(100000).unwrap();
+        let requests: Vec<Message> = (0..1000)
+            .map(|i| {
+                let key = format!("key{}", i % 500).into_bytes();
+                if i % 2 == 0 {
+                    message::request(Op::Set, key, Some(message::payload(1, vec![0; 150])))
+                } else {
+                    message::request(Op::Get, key, None)
+                }
+            })
+            .collect();
+
+        b.iter(|| {
+            let rcvs: Vec<_> = requests.iter().map(|msg| call(&cache, msg.clone())).collect();
+            future::join_all(rcvs).wait().unwrap()
+        });
+    }
 }
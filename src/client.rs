@@ -1,20 +1,124 @@
 
-use futures::Future;
+use futures::{future, Future};
 use tokio_core::net::TcpStream;
-use tokio_core::reactor::Handle;
-use tokio_proto::TcpClient;
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_proto::BindClient;
 use tokio_proto::multiplex::ClientService;
 use tokio_service::Service;
 use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use std::error::Error as StdError;
+use std::fmt;
 use std::io;
 
-use proto::CacheProto;
-use message::{self, Message, Op};
+use codec::Limits;
+use proto::{self, CacheProto};
+use message::{self, Message, Op, Code};
+use error;
+use service::Addr;
+use uds::UnixStream;
+use replication::{self, Position};
 
 /// A simple client for interacting with `rcache`, intended for debugging, testing, and benchmarking.
 /// Can be used as a template for implementing a more robust client.
 pub struct Client {
-    inner: ClientService<TcpStream, CacheProto>,
+    inner: Inner,
+    handle: Handle,
+    timeout: Option<Duration>,
+}
+
+/// An error from a `Client` request.
+#[derive(Debug)]
+pub enum Error {
+    /// The connection failed, or the response couldn't be read.
+    Io(io::Error),
+    /// The server answered the request for `Op` with an error response, see
+    /// `message::error_response`.
+    Server(Op, error::Error),
+    /// The request for `Op` wasn't answered in time, see `Client::with_timeout`. Either the
+    /// server didn't get to it before its deadline, or no answer arrived before the client's own
+    /// timer ran out.
+    Timeout(Op),
+    /// The request for `Op` was a write, and the server is a read only replica.
+    ReadOnly(Op),
+    /// The key of the request for `Op` belongs to another node of the cluster, at the address
+    /// given, and the server doesn't forward requests.
+    Moved(Op, String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "{}", e),
+            Error::Server(op, ref e) => write!(f, "{} failed: {}", op, e),
+            Error::Timeout(op) => write!(f, "{} timed out", op),
+            Error::ReadOnly(op) => write!(f, "{} refused by a read only replica", op),
+            Error::Moved(op, ref owner) => write!(f, "{} belongs to node {}", op, owner),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Io(ref e) => e.description(),
+            Error::Server(_, ref e) => e.description(),
+            Error::Timeout(_) => "request timed out",
+            Error::ReadOnly(_) => "server is a read only replica",
+            Error::Moved(..) => "key belongs to another node",
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// The connection, over TCP or a Unix domain socket.
+enum Inner {
+    Tcp(ClientService<TcpStream, CacheProto>),
+    Unix(ClientService<UnixStream, CacheProto>),
+}
+
+/// Open a connection with `connect`, and bind a client to it once its hello is done. A server from
+/// before the hello drops the connection when it reads one, so the connection is then opened again
+/// and the hello skipped.
+fn bind<T, C, F>(
+    connect: C,
+    handle: &Handle,
+) -> Box<Future<Item = ClientService<T, CacheProto>, Error = io::Error>>
+where
+    T: AsyncRead + AsyncWrite + 'static,
+    C: Fn() -> F + 'static,
+    F: Future<Item = T, Error = io::Error> + 'static,
+{
+    let handle = handle.clone();
+    let limits = Limits::hard_only(Limits::default().hard_frame_len);
+    let handshake = connect().and_then(move |io| proto::handshake(io, limits));
+    Box::new(handshake.then(move |result| -> Box<Future<Item = _, Error = _>> {
+        match result {
+            Ok((io, proto)) => Box::new(future::ok(proto.bind_client(&handle, io))),
+            Err(ref e) if closed(e) => {
+                let proto = CacheProto::legacy(limits);
+                Box::new(connect().map(move |io| proto.bind_client(&handle, io)))
+            }
+            Err(e) => Box::new(future::err(e)),
+        }
+    }))
+}
+
+/// Whether `e` means the peer closed the connection.
+fn closed(e: &io::Error) -> bool {
+    match e.kind() {
+        io::ErrorKind::UnexpectedEof |
+        io::ErrorKind::ConnectionReset |
+        io::ErrorKind::BrokenPipe => true,
+        _ => false,
+    }
 }
 
 impl Client {
@@ -22,24 +126,294 @@ impl Client {
         addr: &SocketAddr,
         handle: &Handle,
     ) -> impl Future<Item = Client, Error = io::Error> {
-        TcpClient::new(CacheProto).connect(addr, handle).map(
-            |client_service| Client { inner: client_service },
-        )
+        let addr = *addr;
+        let connect_handle = handle.clone();
+        let connect = move || TcpStream::connect(&addr, &connect_handle);
+        let client_handle = handle.clone();
+        bind(connect, handle).map(move |client_service| {
+            Client {
+                inner: Inner::Tcp(client_service),
+                handle: client_handle,
+                timeout: None,
+            }
+        })
     }
 
-    pub fn get(&self, key: Vec<u8>) -> Box<Future<Item = Message, Error = io::Error>> {
+    /// Connect to a server listening on the Unix domain socket at `path`.
+    pub fn connect_unix(
+        path: &Path,
+        handle: &Handle,
+    ) -> impl Future<Item = Client, Error = io::Error> {
+        let path = path.to_owned();
+        let connect_handle = handle.clone();
+        let connect = move || future::result(UnixStream::connect(&path, &connect_handle));
+        let client_handle = handle.clone();
+        bind(connect, handle).map(move |client_service| {
+            Client {
+                inner: Inner::Unix(client_service),
+                handle: client_handle,
+                timeout: None,
+            }
+        })
+    }
+
+    /// Connect to `addr`, over TCP or a Unix domain socket.
+    pub fn connect_addr(
+        addr: &Addr,
+        handle: &Handle,
+    ) -> Box<Future<Item = Client, Error = io::Error>> {
+        match *addr {
+            Addr::Tcp(ref addr) => Box::new(Client::connect(addr, handle)),
+            Addr::Unix(ref path, _) => Box::new(Client::connect_unix(path, handle)),
+        }
+    }
+
+    /// Give every request `timeout` to be answered in. Each request is sent with the deadline
+    /// it sets, and a server that doesn't get to it in time answers with `Error::Timeout`
+    /// rather than doing work nobody is waiting for. A server that can't read deadlines, or
+    /// doesn't answer at all, fails the request with `Error::Timeout` once the client's own timer
+    /// runs out.
+    pub fn with_timeout(self, timeout: Duration) -> Client {
+        Client {
+            inner: self.inner,
+            handle: self.handle,
+            timeout: Some(timeout),
+        }
+    }
+
+    pub fn get(&self, key: Vec<u8>) -> Box<Future<Item = Message, Error = Error>> {
         let req = message::request(Op::Get, key, None);
-        self.call(req)
+        self.request(req)
     }
 
-    pub fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Box<Future<Item = Message, Error = io::Error>> {
+    pub fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Box<Future<Item = Message, Error = Error>> {
         let req = message::request(Op::Set, key, Some(message::payload(1, value)));
-        self.call(req)
+        self.request(req)
+    }
+
+    /// Set `key` to `value`, expiring it after `ttl` seconds.
+    pub fn set_with_ttl(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: u32,
+    ) -> Box<Future<Item = Message, Error = Error>> {
+        let payload = message::payload(1, value).with_ttl(ttl);
+        let req = message::request(Op::Set, key, Some(payload));
+        self.request(req)
+    }
+
+    /// Get the remaining ttl of `key` in seconds. A `Code::Hit` response carries a `u64` payload,
+    /// which is 0 if the key never expires.
+    pub fn ttl(&self, key: Vec<u8>) -> Box<Future<Item = Message, Error = Error>> {
+        let req = message::request(Op::Ttl, key, None);
+        self.request(req)
+    }
+
+    /// Set `key` to `value` only if it doesn't exist, responding with `Code::NotStored` if it does.
+    /// A `ttl` of 0 means the key never expires.
+    pub fn add(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: u32,
+    ) -> Box<Future<Item = Message, Error = Error>> {
+        let req = message::request(Op::Add, key, Some(message::payload(1, value).with_ttl(ttl)));
+        self.request(req)
+    }
+
+    /// Set `key` to `value` only if it exists, responding with `Code::NotStored` if it doesn't.
+    /// A `ttl` of 0 means the key never expires.
+    pub fn replace(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: u32,
+    ) -> Box<Future<Item = Message, Error = Error>> {
+        let payload = message::payload(1, value).with_ttl(ttl);
+        let req = message::request(Op::Replace, key, Some(payload));
+        self.request(req)
     }
 
-    pub fn stats(&self) -> Box<Future<Item = Message, Error = io::Error>> {
+    /// Add `value` to the end of the existing value at `key`.
+    pub fn append(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Box<Future<Item = Message, Error = Error>> {
+        let req = message::request(Op::Append, key, Some(message::payload(1, value)));
+        self.request(req)
+    }
+
+    /// Add `value` to the start of the existing value at `key`.
+    pub fn prepend(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Box<Future<Item = Message, Error = Error>> {
+        let req = message::request(Op::Prepend, key, Some(message::payload(1, value)));
+        self.request(req)
+    }
+
+    /// Get the value of `key` along with its version, or `None` if the key doesn't exist. The
+    /// version can be passed to `cas` to only update the key if nobody else has in the meantime.
+    pub fn gets(
+        &self,
+        key: Vec<u8>,
+    ) -> Box<Future<Item = Option<(Vec<u8>, u64)>, Error = Error>> {
+        Box::new(self.get(key).map(|resp| match resp {
+            Message::Response(_, Code::Hit, Some(payload)) => {
+                Some((payload.data().to_vec(), payload.version()))
+            }
+            _ => None,
+        }))
+    }
+
+    /// Set `key` to `value` only if its version is still `version`. Responds with `Code::Ok` if the
+    /// value was stored, `Code::Exists` if the key has been written since `version` was read, or
+    /// `Code::Miss` if the key no longer exists.
+    pub fn cas(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        version: u64,
+    ) -> Box<Future<Item = Message, Error = Error>> {
+        let payload = message::payload(1, value).with_version(version);
+        let req = message::request(Op::Cas, key, Some(payload));
+        self.request(req)
+    }
+
+    /// Atomically add `delta` to the counter at `key`. A `Code::Ok` response carries the new value
+    /// as a `u64` payload, a missing key is a `Code::Miss`.
+    pub fn incr(&self, key: Vec<u8>, delta: u64) -> Box<Future<Item = Message, Error = Error>> {
+        let req = message::request(Op::Incr, key, Some(message::counter_payload(delta, None)));
+        self.request(req)
+    }
+
+    /// Atomically add `delta` to the counter at `key`, initializing a missing key to `initial`
+    /// with `ttl`.
+    pub fn incr_or_init(
+        &self,
+        key: Vec<u8>,
+        delta: u64,
+        initial: u64,
+        ttl: u32,
+    ) -> Box<Future<Item = Message, Error = Error>> {
+        let payload = message::counter_payload(delta, Some(initial)).with_ttl(ttl);
+        let req = message::request(Op::Incr, key, Some(payload));
+        self.request(req)
+    }
+
+    /// Atomically subtract `delta` from the counter at `key`, stopping at 0.
+    pub fn decr(&self, key: Vec<u8>, delta: u64) -> Box<Future<Item = Message, Error = Error>> {
+        let req = message::request(Op::Decr, key, Some(message::counter_payload(delta, None)));
+        self.request(req)
+    }
+
+    pub fn del(&self, key: Vec<u8>) -> Box<Future<Item = Message, Error = Error>> {
+        let req = message::request(Op::Del, key, None);
+        self.request(req)
+    }
+
+    /// Get all of `keys` in a single request. Responds with a `Message::BatchResponse` holding a
+    /// (code, payload) result for each key, in the order the keys were given.
+    pub fn get_many(&self, keys: Vec<Vec<u8>>) -> Box<Future<Item = Message, Error = Error>> {
+        let entries = keys.into_iter().map(|key| (key, None)).collect();
+        let req = message::batch_request(Op::Get, entries);
+        self.request(req)
+    }
+
+    /// Set all of the (key, value) `pairs` in a single request. Responds with a
+    /// `Message::BatchResponse` holding a result for each pair, in the order the pairs were given.
+    pub fn set_many(
+        &self,
+        pairs: Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> Box<Future<Item = Message, Error = Error>> {
+        let entries = pairs
+            .into_iter()
+            .map(|(key, value)| (key, Some(message::payload(1, value))))
+            .collect();
+        let req = message::batch_request(Op::Set, entries);
+        self.request(req)
+    }
+
+    pub fn stats(&self) -> Box<Future<Item = Message, Error = Error>> {
         let req = message::request(Op::Stats, vec![], None);
-        self.call(req)
+        self.request(req)
+    }
+
+    /// Ask the server to save its cache to its snapshot file. Responds once the snapshot is on
+    /// disk, with a `TYPE_ID_U64` payload holding the number of entries saved.
+    pub fn snapshot(&self) -> Box<Future<Item = Message, Error = Error>> {
+        let req = message::request(Op::Snapshot, vec![], None);
+        self.request(req)
+    }
+
+    /// Ask the server for the writes after `position`, as a replica does, see
+    /// `replication::parse_response`.
+    pub fn sync(&self, position: Position) -> Box<Future<Item = Message, Error = Error>> {
+        self.request(replication::sync_request(position))
+    }
+
+    /// Send `req` with the client's timeout, if it has one, see `request_with_timeout`.
+    fn request(&self, req: Message) -> Box<Future<Item = Message, Error = Error>> {
+        match self.timeout {
+            Some(timeout) => self.request_with_timeout(req, timeout),
+            None => self.request_with_deadline(req, None),
+        }
+    }
+
+    /// Send `req`, giving it `timeout` to be answered in rather than the client's timeout. An
+    /// error response is an `Error::Server`, a timeout an `Error::Timeout`, a write refused by a
+    /// replica an `Error::ReadOnly` and a key another node owns an `Error::Moved`. The (code,
+    /// payload) results of a batch are left for the caller, as an error for one entry doesn't fail
+    /// the others.
+    pub fn request_with_timeout(
+        &self,
+        req: Message,
+        timeout: Duration,
+    ) -> Box<Future<Item = Message, Error = Error>> {
+        self.request_with_deadline(req, Some(timeout))
+    }
+
+    fn request_with_deadline(
+        &self,
+        req: Message,
+        timeout: Option<Duration>,
+    ) -> Box<Future<Item = Message, Error = Error>> {
+        let op = req.op();
+        let response = match timeout {
+            Some(timeout) => {
+                let req = req.with_deadline(Instant::now() + timeout);
+                let timer = match Timeout::new(timeout, &self.handle) {
+                    Ok(timer) => timer,
+                    Err(e) => return Box::new(future::err(Error::Io(e))),
+                };
+                // The server's own answer to a missed deadline is usually first, the timer covers
+                // a server that can't read deadlines, or never answers.
+                let timer = timer.then(move |_| Err(Error::Timeout(op)));
+                let response = self.call(req).map_err(Error::Io);
+                Box::new(response.select(timer).map(|(resp, _)| resp).map_err(|(e, _)| e))
+                    as Box<Future<Item = Message, Error = Error>>
+            }
+            None => Box::new(self.call(req).map_err(Error::Io)),
+        };
+        Box::new(response.and_then(|resp| {
+            if let Some(e) = resp.error() {
+                return Err(Error::Server(resp.op(), e));
+            }
+            match resp {
+                Message::Response(op, Code::Timeout, _) => Err(Error::Timeout(op)),
+                Message::Response(op, Code::ReadOnly, _) => Err(Error::ReadOnly(op)),
+                Message::Response(op, Code::Moved, payload) => {
+                    let owner = payload
+                        .map(|p| String::from_utf8_lossy(p.data()).into_owned())
+                        .unwrap_or_default();
+                    Err(Error::Moved(op, owner))
+                }
+                resp => Ok(resp),
+            }
+        }))
     }
 }
 
@@ -50,6 +424,378 @@ impl Service for Client {
     type Future = Box<Future<Item = Message, Error = io::Error>>;
 
     fn call(&self, req: Message) -> Self::Future {
-        Box::new(self.inner.call(req))
+        match self.inner {
+            Inner::Tcp(ref inner) => Box::new(inner.call(req)),
+            Inner::Unix(ref inner) => Box::new(inner.call(req)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Read;
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+    use tokio_core::reactor::Core;
+    use cache::{self, Cache};
+    use message::Code;
+    use service::{self, CacheService, Protocol};
+    use testing;
+
+    #[test]
+    fn test_set_get_del() {
+        let addr = testing::spawn_cache(Protocol::Rcache);
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let client = core.run(Client::connect(&addr, &handle)).unwrap();
+
+        let resp = core.run(client.set("foo".into(), "bar".into())).unwrap();
+        assert_eq!(resp.code(), Code::Ok);
+
+        let resp = core.run(client.get("foo".into())).unwrap();
+        assert_eq!(resp.code(), Code::Hit);
+        assert_eq!(resp.payload().unwrap().data(), b"bar");
+
+        let resp = core.run(client.del("foo".into())).unwrap();
+        assert_eq!(resp.op(), Op::Del);
+        assert_eq!(resp.code(), Code::Hit);
+
+        let resp = core.run(client.get("foo".into())).unwrap();
+        assert_eq!(resp.code(), Code::Miss);
+    }
+
+    #[test]
+    fn test_del_missing_key() {
+        let addr = testing::spawn_cache(Protocol::Rcache);
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let client = core.run(Client::connect(&addr, &handle)).unwrap();
+
+        let resp = core.run(client.del("missing".into())).unwrap();
+        assert_eq!(resp.code(), Code::Miss);
+
+        core.run(client.set("foo".into(), "bar".into())).unwrap();
+        core.run(client.del("foo".into())).unwrap();
+        let resp = core.run(client.del("foo".into())).unwrap();
+        assert_eq!(resp.code(), Code::Miss);
+    }
+
+    #[test]
+    fn test_del_leaves_other_keys() {
+        let addr = testing::spawn_cache(Protocol::Rcache);
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let client = core.run(Client::connect(&addr, &handle)).unwrap();
+
+        core.run(client.set("foo".into(), "1".into())).unwrap();
+        core.run(client.set("bar".into(), "2".into())).unwrap();
+        core.run(client.del("foo".into())).unwrap();
+
+        let resp = core.run(client.get("bar".into())).unwrap();
+        assert_eq!(resp.code(), Code::Hit);
+        assert_eq!(resp.payload().unwrap().data(), b"2");
+    }
+
+    #[test]
+    fn test_cas() {
+        let addr = testing::spawn_cache(Protocol::Rcache);
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let client = core.run(Client::connect(&addr, &handle)).unwrap();
+
+        let resp = core.run(client.cas("foo".into(), "bar".into(), 1)).unwrap();
+        assert_eq!(resp.code(), Code::Miss);
+
+        assert_eq!(core.run(client.gets("foo".into())).unwrap(), None);
+        core.run(client.set("foo".into(), "1".into())).unwrap();
+        let (value, version) = core.run(client.gets("foo".into())).unwrap().unwrap();
+        assert_eq!(value, b"1");
+        assert!(version > 0);
+
+        // Someone else writes in between our read and our write.
+        core.run(client.set("foo".into(), "2".into())).unwrap();
+        let resp = core.run(client.cas("foo".into(), "3".into(), version)).unwrap();
+        assert_eq!(resp.code(), Code::Exists);
+
+        let (_, version) = core.run(client.gets("foo".into())).unwrap().unwrap();
+        let resp = core.run(client.cas("foo".into(), "3".into(), version)).unwrap();
+        assert_eq!(resp.code(), Code::Ok);
+
+        let (value, latest) = core.run(client.gets("foo".into())).unwrap().unwrap();
+        assert_eq!(value, b"3");
+        assert!(latest > version);
+    }
+
+    #[test]
+    fn test_incr_decr() {
+        let addr = testing::spawn_cache(Protocol::Rcache);
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let client = core.run(Client::connect(&addr, &handle)).unwrap();
+
+        let resp = core.run(client.incr("hits".into(), 1)).unwrap();
+        assert_eq!(resp.code(), Code::Miss);
+
+        let resp = core.run(client.incr_or_init("hits".into(), 1, 0, 60)).unwrap();
+        assert_eq!(resp.payload().unwrap().as_u64(), Some(0));
+
+        let futs: Vec<_> = (0..100).map(|_| client.incr("hits".into(), 2)).collect();
+        core.run(::futures::future::join_all(futs)).unwrap();
+
+        let resp = core.run(client.decr("hits".into(), 50)).unwrap();
+        assert_eq!(resp.code(), Code::Ok);
+        assert_eq!(resp.payload().unwrap().as_u64(), Some(150));
+
+        let resp = core.run(client.ttl("hits".into())).unwrap();
+        assert!(resp.payload().unwrap().as_u64().unwrap() > 0);
+
+        core.run(client.set("name".into(), "bob".into())).unwrap();
+        match core.run(client.incr("name".into(), 1)) {
+            Err(Error::Server(op, e)) => {
+                assert_eq!(op, Op::Incr);
+                assert_eq!(*e.kind(), error::ErrorKind::NotNumeric);
+            }
+            other => panic!("expected a NotNumeric error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_timeout() {
+        let addr = testing::spawn_cache(Protocol::Rcache);
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let client = core.run(Client::connect(&addr, &handle)).unwrap();
+        let client = client.with_timeout(Duration::from_secs(5));
+        core.run(client.set("foo".into(), "bar".into())).unwrap();
+        assert_eq!(core.run(client.get("foo".into())).unwrap().code(), Code::Hit);
+
+        // No time at all has always passed by the time the worker gets to the request.
+        let client = core.run(Client::connect(&addr, &handle)).unwrap();
+        let client = client.with_timeout(Duration::from_millis(0));
+        match core.run(client.get("foo".into())) {
+            Err(Error::Timeout(Op::Get)) => (),
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_timeout_unanswered() {
+        let listener = testing::bind();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            // A server from before the hello, and so before deadlines, that never answers.
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.read_exact(&mut [0; 34]).unwrap();
+            drop(stream);
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = stream.read_to_end(&mut Vec::new());
+        });
+
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let client = core.run(Client::connect(&addr, &handle)).unwrap();
+        let get = message::request(Op::Get, "foo".into(), None);
+        match core.run(client.request_with_timeout(get, Duration::from_millis(50))) {
+            Err(Error::Timeout(Op::Get)) => (),
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+
+        let client = client.with_timeout(Duration::from_millis(50));
+        match core.run(client.set("foo".into(), "bar".into())) {
+            Err(Error::Timeout(Op::Set)) => (),
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_connection_closed() {
+        let listener = testing::bind();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            // A server from before the hello, that closes the connection on the first request.
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.read_exact(&mut [0; 34]).unwrap();
+            drop(stream);
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.read_exact(&mut [0; 25]).unwrap();
+        });
+
+        // The request in flight fails, rather than waiting for an answer that won't come.
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let client = core.run(Client::connect(&addr, &handle)).unwrap();
+        match core.run(client.get("foo".into())) {
+            Err(Error::Io(_)) => (),
+            other => panic!("expected an io error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_add_as_lock() {
+        let addr = testing::spawn_cache(Protocol::Rcache);
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let client = core.run(Client::connect(&addr, &handle)).unwrap();
+
+        let resp = core.run(client.add("lock".into(), "a".into(), 1)).unwrap();
+        assert_eq!(resp.code(), Code::Ok);
+        let resp = core.run(client.add("lock".into(), "b".into(), 1)).unwrap();
+        assert_eq!(resp.code(), Code::NotStored);
+
+        // The lock expires, and can be taken again.
+        thread::sleep(Duration::from_millis(1100));
+        let resp = core.run(client.add("lock".into(), "b".into(), 1)).unwrap();
+        assert_eq!(resp.code(), Code::Ok);
+
+        core.run(client.append("lock".into(), "c".into())).unwrap();
+        core.run(client.prepend("lock".into(), "a".into())).unwrap();
+        let resp = core.run(client.get("lock".into())).unwrap();
+        assert_eq!(resp.payload().unwrap().data(), b"abc");
+
+        let resp = core.run(client.replace("missing".into(), "a".into(), 0)).unwrap();
+        assert_eq!(resp.code(), Code::NotStored);
+    }
+
+    #[test]
+    fn test_get_set_many() {
+        let addr = testing::spawn_cache(Protocol::Rcache);
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let client = core.run(Client::connect(&addr, &handle)).unwrap();
+
+        let pairs = vec![("a".into(), "1".into()), ("b".into(), "2".into())];
+        let resp = core.run(client.set_many(pairs)).unwrap();
+        assert_eq!(resp, message::batch_response(Op::Set, vec![(Code::Ok, None), (Code::Ok, None)]));
+
+        let resp = core.run(client.get_many(vec!["a".into(), "missing".into(), "b".into()])).unwrap();
+        match resp {
+            Message::BatchResponse(Op::Get, results) => {
+                let codes: Vec<Code> = results.iter().map(|r| r.0).collect();
+                assert_eq!(codes, vec![Code::Hit, Code::Miss, Code::Hit]);
+                assert_eq!(results[0].1.as_ref().unwrap().data(), b"1");
+                assert_eq!(results[2].1.as_ref().unwrap().data(), b"2");
+            }
+            msg => panic!("unexpected response {}", msg),
+        }
+    }
+
+    #[test]
+    fn test_server_without_hello() {
+        let listener = testing::bind();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            // Drop the first connection once its hello arrives, as a server from before the
+            // hello would, then serve the reconnection, which skips it.
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.read_exact(&mut [0; 34]).unwrap();
+            drop(stream);
+            let service = CacheService { cache: Arc::new(Cache::new(1024).unwrap()) };
+            service::serve_bound(listener, Protocol::Rcache, service).unwrap();
+        });
+
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let client = core.run(Client::connect(&addr, &handle)).unwrap();
+
+        core.run(client.set("foo".into(), "bar".into())).unwrap();
+        let resp = core.run(client.get("foo".into())).unwrap();
+        assert_eq!(resp.payload().unwrap().data(), b"bar");
+    }
+
+    #[test]
+    fn test_unix_socket() {
+        let dir = testing::TempDir::new();
+        let path = dir.path().join("rcache.sock");
+        testing::spawn_unix(&path, Some(0o600));
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let client = core.run(Client::connect_addr(&Addr::Unix(path, None), &handle)).unwrap();
+
+        let resp = core.run(client.set("foo".into(), "bar".into())).unwrap();
+        assert_eq!(resp.code(), Code::Ok);
+        let resp = core.run(client.get("foo".into())).unwrap();
+        assert_eq!(resp.payload().unwrap().data(), b"bar");
+    }
+
+    #[test]
+    fn test_set_with_ttl() {
+        let addr = testing::spawn_cache(Protocol::Rcache);
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let client = core.run(Client::connect(&addr, &handle)).unwrap();
+
+        core.run(client.set_with_ttl("foo".into(), "bar".into(), 1)).unwrap();
+        core.run(client.set("baz".into(), "qux".into())).unwrap();
+
+        let resp = core.run(client.get("foo".into())).unwrap();
+        assert_eq!(resp.code(), Code::Hit);
+        assert_eq!(resp.payload().unwrap().ttl(), 1);
+
+        let resp = core.run(client.ttl("foo".into())).unwrap();
+        assert_eq!(resp.code(), Code::Hit);
+        assert_eq!(resp.payload().unwrap().as_u64(), Some(1));
+
+        let resp = core.run(client.ttl("baz".into())).unwrap();
+        assert_eq!(resp.payload().unwrap().as_u64(), Some(0));
+
+        thread::sleep(Duration::from_millis(1100));
+
+        let resp = core.run(client.get("foo".into())).unwrap();
+        assert_eq!(resp.code(), Code::Miss);
+        let resp = core.run(client.ttl("foo".into())).unwrap();
+        assert_eq!(resp.code(), Code::Miss);
+        let resp = core.run(client.get("baz".into())).unwrap();
+        assert_eq!(resp.code(), Code::Hit);
+    }
+
+    #[test]
+    fn test_replica() {
+        let primary_addr = testing::spawn_cache(Protocol::Rcache);
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let primary = core.run(Client::connect(&primary_addr, &handle)).unwrap();
+        core.run(primary.set("before".into(), "1".into())).unwrap();
+
+        let addr = testing::spawn(testing::bind(), Protocol::Rcache, move || {
+            let capacity = cache::Capacity::Entries(1024);
+            let persistence = cache::Persistence::default();
+            let cache = Cache::replica(capacity, 2, persistence, Addr::Tcp(primary_addr)).unwrap();
+            CacheService { cache: Arc::new(cache) }
+        });
+        let replica = core.run(Client::connect(&addr, &handle)).unwrap();
+
+        core.run(primary.set("after".into(), "2".into())).unwrap();
+        core.run(primary.del("before".into())).unwrap();
+        // The writes can arrive in separate pages after the full sync, so wait for all of them.
+        let mut replicated = false;
+        for _ in 0..50 {
+            let after = core.run(replica.get("after".into())).unwrap();
+            let before = core.run(replica.get("before".into())).unwrap();
+            if after.code() == Code::Hit && before.code() == Code::Miss {
+                assert_eq!(after.payload().unwrap().data(), b"2");
+                replicated = true;
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert!(replicated);
+
+        match core.run(replica.set("after".into(), "3".into())) {
+            Err(Error::ReadOnly(Op::Set)) => (),
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(msg) => panic!("unexpected response {}", msg),
+        }
+
+        let resp = core.run(replica.stats()).unwrap();
+        let stats = String::from_utf8_lossy(resp.payload().unwrap().data()).into_owned();
+        assert!(stats.contains("replication_status: connected"), "{}", stats);
+        assert!(stats.contains("replication_lag: "), "{}", stats);
     }
 }
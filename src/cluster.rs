@@ -0,0 +1,504 @@
+use futures::{future, Future};
+use tokio_core::reactor::Handle;
+use std::io;
+use std::time::Duration;
+
+use client::{Client, Error};
+use error;
+use message::{self, Message, Op, Code, Payload};
+use ring::{Ring, DEFAULT_VNODES};
+use service::Addr;
+
+/// A client for a cluster of `rcache` servers, each holding a share of the keys.
+///
+/// Keys are spread over the servers with a consistent-hash `Ring`, in proportion to the weight
+/// each server is given. A request for a single key goes to the server that owns the key, and a
+/// request for many keys is split into a batch for each server involved, whose results are put
+/// back together in the order the keys were given. Removing a server only moves the keys it
+/// owned, which the remaining servers don't have yet, and so miss until they are set again.
+///
+/// A server that can't be reached doesn't take the rest of the cluster down with it: requests
+/// for its keys fail, and in a batch, its keys get error results while the others are served.
+pub struct ClusterClient {
+    ring: Ring<Addr>,
+    clients: Vec<(Addr, Client)>,
+}
+
+impl ClusterClient {
+    /// Connect to every one of `nodes`, given as (address, weight) pairs. The nodes that can't
+    /// be connected to are still on the ring, so that their keys don't move to the others, and
+    /// requests for their keys fail until they are added again with `add_node`. Fails if none of
+    /// the nodes can be connected to.
+    pub fn connect(
+        nodes: Vec<(Addr, u32)>,
+        handle: &Handle,
+    ) -> Box<Future<Item = ClusterClient, Error = io::Error>> {
+        let connections = nodes
+            .into_iter()
+            .map(|(addr, weight)| {
+                Client::connect_addr(&addr, handle).then(move |result| {
+                    let client = match result {
+                        Ok(client) => Some(client),
+                        Err(e) => {
+                            warn!("Can't connect to {}: {}.", addr, e);
+                            None
+                        }
+                    };
+                    Ok::<_, io::Error>((addr, weight, client))
+                })
+            })
+            .collect::<Vec<_>>();
+        Box::new(future::join_all(connections).and_then(|connected| {
+            let mut cluster = ClusterClient {
+                ring: Ring::new(DEFAULT_VNODES),
+                clients: Vec::new(),
+            };
+            for (addr, weight, client) in connected {
+                match client {
+                    Some(client) => cluster.add_node(addr, weight, client),
+                    None => cluster.ring.add(addr, weight),
+                }
+            }
+            if cluster.clients.is_empty() && !cluster.ring.nodes().is_empty() {
+                let description = "can't connect to any node of the cluster";
+                return Err(io::Error::new(io::ErrorKind::NotConnected, description));
+            }
+            Ok(cluster)
+        }))
+    }
+
+    /// Give every request `timeout` to be answered in, see `Client::with_timeout`.
+    pub fn with_timeout(self, timeout: Duration) -> ClusterClient {
+        ClusterClient {
+            ring: self.ring,
+            clients: self.clients
+                .into_iter()
+                .map(|(addr, client)| (addr, client.with_timeout(timeout)))
+                .collect(),
+        }
+    }
+
+    /// Add the server at `addr`, connected to with `client`, with `weight`. If the server is
+    /// already in the cluster, its client and weight are replaced.
+    pub fn add_node(&mut self, addr: Addr, weight: u32, client: Client) {
+        self.clients.retain(|&(ref a, _)| *a != addr);
+        self.clients.push((addr.clone(), client));
+        self.ring.add(addr, weight);
+    }
+
+    /// Remove the server at `addr`, returning false if it wasn't in the cluster. Its keys move to
+    /// the other servers, and no other key moves.
+    pub fn remove_node(&mut self, addr: &Addr) -> bool {
+        self.clients.retain(|&(ref a, _)| a != addr);
+        self.ring.remove(addr)
+    }
+
+    /// The servers in the cluster, and their weights.
+    pub fn nodes(&self) -> &[(Addr, u32)] {
+        self.ring.nodes()
+    }
+
+    /// The address of the server that owns `key`.
+    pub fn node_for(&self, key: &[u8]) -> Option<&Addr> {
+        self.ring.get(key)
+    }
+
+    pub fn get(&self, key: Vec<u8>) -> Box<Future<Item = Message, Error = Error>> {
+        self.route(key, |client, key| client.get(key))
+    }
+
+    pub fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Box<Future<Item = Message, Error = Error>> {
+        self.route(key, |client, key| client.set(key, value))
+    }
+
+    /// See `Client::set_with_ttl`.
+    pub fn set_with_ttl(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: u32,
+    ) -> Box<Future<Item = Message, Error = Error>> {
+        self.route(key, |client, key| client.set_with_ttl(key, value, ttl))
+    }
+
+    /// See `Client::ttl`.
+    pub fn ttl(&self, key: Vec<u8>) -> Box<Future<Item = Message, Error = Error>> {
+        self.route(key, |client, key| client.ttl(key))
+    }
+
+    /// See `Client::add`.
+    pub fn add(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: u32,
+    ) -> Box<Future<Item = Message, Error = Error>> {
+        self.route(key, |client, key| client.add(key, value, ttl))
+    }
+
+    /// See `Client::replace`.
+    pub fn replace(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: u32,
+    ) -> Box<Future<Item = Message, Error = Error>> {
+        self.route(key, |client, key| client.replace(key, value, ttl))
+    }
+
+    /// See `Client::append`.
+    pub fn append(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Box<Future<Item = Message, Error = Error>> {
+        self.route(key, |client, key| client.append(key, value))
+    }
+
+    /// See `Client::prepend`.
+    pub fn prepend(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Box<Future<Item = Message, Error = Error>> {
+        self.route(key, |client, key| client.prepend(key, value))
+    }
+
+    /// See `Client::gets`.
+    pub fn gets(
+        &self,
+        key: Vec<u8>,
+    ) -> Box<Future<Item = Option<(Vec<u8>, u64)>, Error = Error>> {
+        match self.ring.index(&key).ok_or_else(no_nodes).and_then(|i| self.client(i)) {
+            Ok(client) => client.gets(key),
+            Err(e) => Box::new(future::err(e)),
+        }
+    }
+
+    /// See `Client::cas`. Versions are per server, which is fine as a key always lives on the
+    /// same one.
+    pub fn cas(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        version: u64,
+    ) -> Box<Future<Item = Message, Error = Error>> {
+        self.route(key, |client, key| client.cas(key, value, version))
+    }
+
+    /// See `Client::incr`.
+    pub fn incr(&self, key: Vec<u8>, delta: u64) -> Box<Future<Item = Message, Error = Error>> {
+        self.route(key, |client, key| client.incr(key, delta))
+    }
+
+    /// See `Client::incr_or_init`.
+    pub fn incr_or_init(
+        &self,
+        key: Vec<u8>,
+        delta: u64,
+        initial: u64,
+        ttl: u32,
+    ) -> Box<Future<Item = Message, Error = Error>> {
+        self.route(key, |client, key| client.incr_or_init(key, delta, initial, ttl))
+    }
+
+    /// See `Client::decr`.
+    pub fn decr(&self, key: Vec<u8>, delta: u64) -> Box<Future<Item = Message, Error = Error>> {
+        self.route(key, |client, key| client.decr(key, delta))
+    }
+
+    pub fn del(&self, key: Vec<u8>) -> Box<Future<Item = Message, Error = Error>> {
+        self.route(key, |client, key| client.del(key))
+    }
+
+    /// Get all of `keys`, with a request to each server that owns some of them. Responds with a
+    /// `Message::BatchResponse` holding a (code, payload) result for each key, in the order the
+    /// keys were given.
+    pub fn get_many(&self, keys: Vec<Vec<u8>>) -> Box<Future<Item = Message, Error = Error>> {
+        let entries = keys.into_iter().map(|key| (key, ())).collect();
+        self.fan_out(Op::Get, entries, |client, entries| {
+            client.get_many(entries.into_iter().map(|(key, _)| key).collect())
+        })
+    }
+
+    /// Set all of the (key, value) `pairs`, with a request to each server that owns some of
+    /// them. Responds with a `Message::BatchResponse` holding a result for each pair, in the
+    /// order the pairs were given.
+    pub fn set_many(
+        &self,
+        pairs: Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> Box<Future<Item = Message, Error = Error>> {
+        self.fan_out(Op::Set, pairs, |client, pairs| client.set_many(pairs))
+    }
+
+    /// The stats of every server that was connected to, with its address.
+    pub fn stats(&self) -> Box<Future<Item = Vec<(Addr, Message)>, Error = Error>> {
+        let requests = self.clients
+            .iter()
+            .map(|&(ref addr, ref client)| {
+                let addr = addr.clone();
+                client.stats().map(move |resp| (addr, resp))
+            })
+            .collect::<Vec<_>>();
+        Box::new(future::join_all(requests))
+    }
+
+    /// The client for the node at `index` in `Ring::nodes`, or an error if it couldn't be
+    /// connected to.
+    fn client(&self, index: usize) -> Result<&Client, Error> {
+        let addr = &self.ring.nodes()[index].0;
+        match self.clients.iter().find(|&&(ref a, _)| a == addr) {
+            Some(&(_, ref client)) => Ok(client),
+            None => {
+                let description = format!("not connected to {}", addr);
+                Err(Error::Io(io::Error::new(io::ErrorKind::NotConnected, description)))
+            }
+        }
+    }
+
+    /// Send the request `f` makes for `key` to the server that owns it.
+    fn route<F>(&self, key: Vec<u8>, f: F) -> Box<Future<Item = Message, Error = Error>>
+    where
+        F: FnOnce(&Client, Vec<u8>) -> Box<Future<Item = Message, Error = Error>>,
+    {
+        match self.ring.index(&key).ok_or_else(no_nodes).and_then(|i| self.client(i)) {
+            Ok(client) => f(client, key),
+            Err(e) => Box::new(future::err(e)),
+        }
+    }
+
+    /// Split `entries` by the server that owns their keys, send each server the batch `f` makes
+    /// of its share, and put the results back together in the order of `entries`. The keys of a
+    /// server whose batch fails get a result for the failure, see `failed`.
+    fn fan_out<T, F>(
+        &self,
+        op: Op,
+        entries: Vec<(Vec<u8>, T)>,
+        f: F,
+    ) -> Box<Future<Item = Message, Error = Error>>
+    where
+        F: Fn(&Client, Vec<(Vec<u8>, T)>) -> Box<Future<Item = Message, Error = Error>>,
+    {
+        if self.ring.is_empty() {
+            return Box::new(future::err(no_nodes()));
+        }
+
+        let count = entries.len();
+        let requests = self.ring
+            .split(entries)
+            .into_iter()
+            .map(|part| {
+                let positions = part.positions;
+                let request = match self.client(part.node) {
+                    Ok(client) => f(client, part.entries),
+                    Err(e) => Box::new(future::err(e)),
+                };
+                request.then(move |result| {
+                    let resp = result.unwrap_or_else(|e| {
+                        message::batch_response(op, vec![failed(op, &e); positions.len()])
+                    });
+                    Ok((positions, resp))
+                })
+            })
+            .collect::<Vec<_>>();
+
+        Box::new(future::join_all(requests).map(move |parts| merge(op, count, parts)))
+    }
+}
+
+/// Put the `BatchResponse`s to the parts of a batch of `count` entries back together, given the
+/// positions of each part's entries in the batch, see `ring::Ring::split`. The entries of a part
+/// answered with an error response get its error, and those of a part answered with anything
+/// else an `error::ErrorKind::InvalidData` error.
+pub(crate) fn merge(op: Op, count: usize, parts: Vec<(Vec<usize>, Message)>) -> Message {
+    let mut results: Vec<(Code, Option<Payload>)> = vec![(Code::Error, None); count];
+    for (positions, resp) in parts {
+        let part = match resp {
+            Message::BatchResponse(_, part) if part.len() == positions.len() => part,
+            Message::Response(_, Code::Error, payload) => {
+                vec![(Code::Error, payload); positions.len()]
+            }
+            resp => {
+                let description = format!("unexpected response {}", resp);
+                let e = error::Error::new(error::ErrorKind::InvalidData, &description);
+                vec![error_result(op, &e); positions.len()]
+            }
+        };
+        for (i, result) in positions.into_iter().zip(part) {
+            results[i] = result;
+        }
+    }
+    message::batch_response(op, results)
+}
+
+/// The result for each key of a batch for `op` whose request failed with `e`. Refusals keep
+/// their codes, and errors are `Code::Error`, with the kind of the error as the payload's error
+/// code: an error response's own, and `error::ErrorKind::Other` for a failed connection.
+fn failed(op: Op, e: &Error) -> (Code, Option<Payload>) {
+    match *e {
+        Error::Timeout(_) => (Code::Timeout, None),
+        Error::ReadOnly(_) => (Code::ReadOnly, None),
+        Error::Moved(_, ref owner) => {
+            let owner = owner.clone().into_bytes();
+            (Code::Moved, Some(message::payload(message::TYPE_ID_UTF8, owner)))
+        }
+        Error::Server(_, ref e) => error_result(op, e),
+        Error::Io(ref e) => {
+            let e = error::Error::new(error::ErrorKind::Other, &e.to_string());
+            error_result(op, &e)
+        }
+    }
+}
+
+/// The result for an entry of a batch for `op` that failed with `e`.
+fn error_result(op: Op, e: &error::Error) -> (Code, Option<Payload>) {
+    (Code::Error, message::error_response(op, e).payload().cloned())
+}
+
+fn no_nodes() -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::NotConnected, "the cluster has no nodes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_core::reactor::Core;
+    use service::Protocol;
+    use testing;
+
+    fn keys() -> Vec<Vec<u8>> {
+        testing::keys(100)
+    }
+
+    #[test]
+    fn test_cluster() {
+        let addrs: Vec<Addr> = (0..3)
+            .map(|_| Addr::Tcp(testing::spawn_cache(Protocol::Rcache)))
+            .collect();
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let nodes = addrs.iter().map(|addr| (addr.clone(), 1)).collect();
+        let mut cluster = core.run(ClusterClient::connect(nodes, &handle)).unwrap();
+
+        let pairs: Vec<_> = keys().into_iter().map(|key| (key.clone(), key)).collect();
+        let resp = core.run(cluster.set_many(pairs)).unwrap();
+        assert_eq!(resp, message::batch_response(Op::Set, vec![(Code::Ok, None); 100]));
+
+        // Every key is on the server the ring says, and only there.
+        let mut owned = vec![0; addrs.len()];
+        for (i, addr) in addrs.iter().enumerate() {
+            let client = core.run(Client::connect_addr(addr, &handle)).unwrap();
+            let resp = core.run(client.get_many(keys())).unwrap();
+            let results = match resp {
+                Message::BatchResponse(_, results) => results,
+                msg => panic!("unexpected response {}", msg),
+            };
+            for (key, result) in keys().iter().zip(results) {
+                assert_eq!(result.0 == Code::Hit, cluster.node_for(key) == Some(addr));
+                if result.0 == Code::Hit {
+                    owned[i] += 1;
+                }
+            }
+        }
+        assert!(owned.iter().all(|&n| n > 0), "{:?}", owned);
+
+        let resp = core.run(cluster.get(b"key7".to_vec())).unwrap();
+        assert_eq!(resp.payload().unwrap().data(), b"key7");
+        let resp = core.run(cluster.incr_or_init(b"n".to_vec(), 1, 5, 0)).unwrap();
+        assert_eq!(resp.payload().unwrap().as_u64(), Some(5));
+        assert_eq!(core.run(cluster.stats()).unwrap().len(), 3);
+
+        // Removing a server only moves its keys: the others are still found.
+        let removed = addrs[1].clone();
+        let owners: Vec<Option<Addr>> =
+            keys().iter().map(|key| cluster.node_for(key).cloned()).collect();
+        assert!(cluster.remove_node(&removed));
+        let resp = core.run(cluster.get_many(keys())).unwrap();
+        let results = match resp {
+            Message::BatchResponse(Op::Get, results) => results,
+            msg => panic!("unexpected response {}", msg),
+        };
+        for ((key, owner), result) in keys().iter().zip(owners).zip(results) {
+            if owner.as_ref() == Some(&removed) {
+                assert_eq!(result.0, Code::Miss);
+            } else {
+                assert_eq!(cluster.node_for(key), owner.as_ref());
+                assert_eq!(result.1.unwrap().data(), key.as_slice());
+            }
+        }
+    }
+
+    #[test]
+    fn test_unreachable_node() {
+        let live = Addr::Tcp(testing::spawn_cache(Protocol::Rcache));
+        let dead = Addr::Tcp(testing::bind().local_addr().unwrap());
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let nodes = vec![(live.clone(), 1), (dead.clone(), 1)];
+        let cluster = core.run(ClusterClient::connect(nodes, &handle)).unwrap();
+        assert_eq!(cluster.nodes().len(), 2);
+
+        // The keys of the unreachable node fail, and the others are served.
+        let pairs: Vec<_> = keys().into_iter().map(|key| (key.clone(), key)).collect();
+        let resp = core.run(cluster.set_many(pairs)).unwrap();
+        let results = match resp {
+            Message::BatchResponse(Op::Set, results) => results,
+            msg => panic!("unexpected response {}", msg),
+        };
+        for (key, result) in keys().iter().zip(results) {
+            if cluster.node_for(key) == Some(&dead) {
+                assert_eq!(result.0, Code::Error);
+                let payload = result.1.unwrap();
+                assert_eq!(payload.error_code(), error::ErrorKind::Other.code());
+                assert!(String::from_utf8_lossy(payload.data()).contains("not connected"));
+            } else {
+                assert_eq!(result, (Code::Ok, None));
+            }
+        }
+        let key = keys().into_iter().find(|key| cluster.node_for(key) == Some(&dead)).unwrap();
+        match core.run(cluster.get(key)) {
+            Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::NotConnected => (),
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(msg) => panic!("unexpected response {}", msg),
+        }
+
+        // With no node to connect to, there is no cluster.
+        assert!(core.run(ClusterClient::connect(vec![(dead, 1)], &handle)).is_err());
+    }
+
+    #[test]
+    fn test_merge() {
+        let e = error::Error::new(error::ErrorKind::NotNumeric, "not a number");
+        let parts = vec![
+            (vec![1], message::batch_response(Op::Incr, vec![(Code::Ok, None)])),
+            (vec![0, 2], message::error_response(Op::Incr, &e)),
+            (vec![3], message::response(Op::Incr, Code::Ok, None)),
+        ];
+        let results = match merge(Op::Incr, 4, parts) {
+            Message::BatchResponse(Op::Incr, results) => results,
+            msg => panic!("unexpected response {}", msg),
+        };
+        assert_eq!(results[1], (Code::Ok, None));
+        let kinds: Vec<u32> = [0, 2, 3]
+            .iter()
+            .map(|&i| {
+                assert_eq!(results[i].0, Code::Error);
+                results[i].1.as_ref().unwrap().error_code()
+            })
+            .collect();
+        let not_numeric = error::ErrorKind::NotNumeric.code();
+        assert_eq!(kinds, vec![not_numeric, not_numeric, error::ErrorKind::InvalidData.code()]);
+    }
+
+    #[test]
+    fn test_empty_cluster() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let cluster = core.run(ClusterClient::connect(Vec::new(), &handle)).unwrap();
+        match core.run(cluster.get(b"foo".to_vec())) {
+            Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::NotConnected => (),
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(msg) => panic!("unexpected response {}", msg),
+        }
+    }
+}
@@ -1,15 +1,125 @@
 use tokio_io::codec::{Encoder, Decoder};
 use tokio_proto::multiplex::RequestId;
+use std::cmp;
 use std::io;
+use std::time::{Duration, Instant};
+use std::error::Error;
 use std::convert::TryFrom;
 use bytes::{Buf, BufMut, BigEndian, BytesMut};
-use message::{self, Message, Op, Code};
+use message::{self, Message, Op, Code, Payload};
+use error;
+use crc32c;
 
 
-static HEADER_LEN: usize = 8 + 1 + 1 + 8 + 4;
+pub(crate) static HEADER_LEN: usize = 8 + 1 + 1 + 8 + 4;
+
+/// Set on the code byte when the header is followed by an extension byte.
+const FLAG_EXT: u8 = 0x80;
+/// Set on the code byte of a request forwarded by another node of a cluster, see
+/// `Message::forwarded`.
+const FLAG_FORWARDED: u8 = 0x40;
+/// Extension bit: the frame carries the payload's ttl.
+const EXT_TTL: u8 = 0x01;
+/// Extension bit: the frame carries the payload's version.
+const EXT_VERSION: u8 = 0x02;
+/// Extension bit: the frame is a batch, see `put_batch`.
+const EXT_BATCH: u8 = 0x04;
+/// Extension bit: the frame ends with a CRC32C of everything before it.
+const EXT_CRC: u8 = 0x08;
+/// Extension bit: the request carries a timeout, see `Message::with_deadline`.
+const EXT_DEADLINE: u8 = 0x10;
+/// Extension bit: the frame carries the payload's memcached flags.
+const EXT_FLAGS: u8 = 0x20;
+/// Extension bit: the frame carries the kind of error an error response's payload describes.
+const EXT_ERROR_CODE: u8 = 0x40;
+const EXT_KNOWN: u8 = EXT_TTL | EXT_VERSION | EXT_BATCH | EXT_CRC | EXT_DEADLINE | EXT_FLAGS |
+    EXT_ERROR_CODE;
+
+/// Length of the checksum that ends an EXT_CRC frame.
+const CRC_LEN: usize = 4;
+
+/// The protocol version this codec speaks, see `hello`.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Capability: frames end with a CRC32C, see EXT_CRC.
+pub const CAP_CHECKSUM: u32 = 0x01;
+// 0x02 is reserved for compressed payloads, which no version of the codec supports yet.
+/// Capability: frames may carry ttl and version extensions.
+pub const CAP_TTL: u32 = 0x04;
+/// Capability: frames may be batches.
+pub const CAP_BATCH: u32 = 0x08;
+/// Capability: requests may carry a deadline.
+pub const CAP_DEADLINE: u32 = 0x10;
+/// Capability: payloads may carry memcached flags.
+pub const CAP_FLAGS: u32 = 0x20;
+/// Capability: error responses may carry the kind of error, see `error::ErrorKind::code`.
+pub const CAP_ERROR_CODE: u32 = 0x40;
+/// Capability: requests may be marked as forwarded by another node of a cluster.
+pub const CAP_FORWARDED: u32 = 0x80;
+/// Capability: ttls are sent as u64 milliseconds rather than u32 seconds, so that a ttl set to
+/// the millisecond, such as RESP's `PX`, survives being forwarded.
+pub const CAP_TTL_MILLIS: u32 = 0x100;
+
+/// The capabilities this codec supports.
+pub const CAPABILITIES: u32 = CAP_CHECKSUM | CAP_TTL | CAP_BATCH | CAP_DEADLINE | CAP_FLAGS |
+    CAP_ERROR_CODE | CAP_FORWARDED | CAP_TTL_MILLIS;
+/// The capabilities of a connection that doesn't open with a hello, which is everything the
+/// protocol could do before the handshake existed.
+pub(crate) const LEGACY_CAPABILITIES: u32 = CAP_TTL | CAP_BATCH;
+
+/// Size limits on the frames a `CacheCodec` decodes, checked against the lengths in the header
+/// before anything is buffered. A frame over a limit is answered with `Code::TooLarge` and its
+/// bytes are discarded as they arrive. A frame over `hard_frame_len` isn't worth reading past,
+/// and is a decoding error, which closes the connection.
+///
+/// The memcached, RESP and HTTP codecs check keys and values against the same limits.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    pub max_key_len: usize,
+    /// The largest payload of a single key, on its own or in a batch.
+    pub max_value_len: usize,
+    pub max_frame_len: usize,
+    pub hard_frame_len: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_key_len: 64 << 10,
+            max_value_len: 32 << 20,
+            max_frame_len: 64 << 20,
+            hard_frame_len: 1 << 30,
+        }
+    }
+}
+
+impl Limits {
+    /// No limits but `hard_frame_len`. A client decodes with these, as the responses it reads
+    /// are to requests it chose to make.
+    pub fn hard_only(hard_frame_len: usize) -> Limits {
+        Limits {
+            max_key_len: hard_frame_len,
+            max_value_len: hard_frame_len,
+            max_frame_len: hard_frame_len,
+            hard_frame_len: hard_frame_len,
+        }
+    }
+
+    /// Check that the limits make sense together: a frame the hard limit lets through must not
+    /// be answered with `Code::TooLarge` for a lower limit it couldn't have passed.
+    pub fn validate(&self) -> io::Result<()> {
+        if self.hard_frame_len < self.max_frame_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the hard frame limit is below the maximum frame size",
+            ));
+        }
+        Ok(())
+    }
+}
+
 /// A basic, multiplexed byte-protocol for interacting with the cache.
-/// This is my first ever binary/byte protocol and no doubt has numerous issues. At the very
-/// least, there should be a CRC check and support for CAS ops.
+/// This is my first ever binary/byte protocol and no doubt has numerous issues.
 ///
 /// +-- request id ------+- code ---------+----op --+--- payload len ---+---- key len ---
 /// |                    |                |         |                   |
@@ -17,12 +127,234 @@ static HEADER_LEN: usize = 8 + 1 + 1 + 8 + 4;
 /// |                    |                |         |                   |
 /// +--------------------+----------------+---------+-------------------+----------------
 ///
+/// If the high bit of the code byte is set, the header is followed by an extension byte whose
+/// bits announce the optional fields that follow it. Frames without extensions are laid out
+/// exactly as they were before extensions existed.
+///
+/// +--- ext ---------+--- ttl -----------+--- version ---------+--- timeout --------------+
+/// |                 |                   |                     |                          |
+/// | u8, if FLAG_EXT | u32, if EXT_TTL   | u64, if EXT_VERSION | u32 ms, if EXT_DEADLINE  |
+/// |                 |                   |                     |                          |
+/// +-----------------+-------------------+---------------------+--------------------------+
+///
+/// +--- flags ----------+--- error code ----------+
+/// |                    |                         |
+/// | u32, if EXT_FLAGS  | u32, if EXT_ERROR_CODE  |
+/// |                    |                         |
+/// +--------------------+-------------------------+
+///
+/// A request's deadline is sent as the milliseconds left until it, as the peers' clocks needn't
+/// agree, and the decoder turns it back into a deadline on its own clock. If the peer agreed to
+/// CAP_TTL_MILLIS, the ttl is a u64 of milliseconds instead of a u32 of seconds.
+///
+/// A request forwarded by another node of a cluster has FLAG_FORWARDED set on its code byte, if
+/// the peer agreed to CAP_FORWARDED. The flag is ignored on a connection that didn't agree to it.
+///
 /// +--- key --+---type id --+-- payload --+
 /// |          |             |             |
 /// |   [u8]   |   u32       |    [u8]     |
 /// |          |             |             |
 /// +----------+-------------+-------------+
-pub struct CacheCodec;
+///
+/// A frame without a payload has no type id either. An empty payload is sent as no payload,
+/// unless it has a ttl, version or flags, in which case it's sent with its type id.
+///
+/// A batch frame has the EXT_BATCH extension, no key, and a batch body of payload len bytes in
+/// place of the type id and payload, see `put_batch`.
+///
+/// A frame with the EXT_CRC extension ends with a CRC32C over the header, extensions, key and
+/// payload. A peer opts in to checksums simply by sending a checksummed frame: from then on the
+/// codec checksums every frame it encodes. Frames without EXT_CRC are still accepted, so clients
+/// that never send one see the old frame layout. A frame whose checksum doesn't match decodes
+/// into an error response, rather than an error that would drop the connection.
+///
+/// +--- header, ext, key, payload --+--- crc32c --------+
+/// |                                |                   |
+/// |   ...                          | u32, if EXT_CRC   |
+/// |                                |                   |
+/// +--------------------------------+-------------------+
+///
+/// A frame that can't be decoded, say for an unknown op or a truncated batch body, decodes into
+/// an error response to its request id, and the frames around it are unaffected. Only a frame
+/// whose length can't be known, because of an unknown extension, or that is over the hard size
+/// limit is a decoding error, which closes the connection.
+///
+/// A connection may open with a hello, see `hello`, in which client and server agree on a
+/// protocol version and capabilities. The hello is read and answered by `proto`, which then frames
+/// the connection with a codec for what was agreed, see `CacheCodec::negotiated`. A connection that
+/// opens with any other frame gets the legacy protocol, see `CacheCodec::with_limits`.
+pub struct CacheCodec {
+    checksum: bool,
+    capabilities: u32,
+    limits: Limits,
+    /// What's left to discard of an oversize frame.
+    discard: usize,
+}
+
+impl Default for CacheCodec {
+    fn default() -> CacheCodec {
+        CacheCodec::with_limits(Limits::default())
+    }
+}
+
+impl CacheCodec {
+    /// A codec for the legacy protocol, spoken by connections that don't open with a hello.
+    pub fn with_limits(limits: Limits) -> CacheCodec {
+        CacheCodec::negotiated(LEGACY_CAPABILITIES, limits)
+    }
+
+    /// A codec for the capabilities agreed in a hello.
+    pub fn negotiated(capabilities: u32, limits: Limits) -> CacheCodec {
+        CacheCodec {
+            checksum: capabilities & CAP_CHECKSUM != 0,
+            capabilities: capabilities,
+            limits: limits,
+            discard: 0,
+        }
+    }
+
+    /// The reason a frame with these lengths is too large, if it is.
+    fn check_limits(
+        &self,
+        key_len: usize,
+        payload_len: usize,
+        ext: u8,
+        msg_len: usize,
+    ) -> Option<&'static str> {
+        if key_len > self.limits.max_key_len {
+            Some("key too large")
+        } else if ext & EXT_BATCH == 0 && payload_len > self.limits.max_value_len {
+            // A batch's entries are checked as it's decoded, see `get_batch`.
+            Some("value too large")
+        } else if msg_len > self.limits.max_frame_len {
+            Some("frame too large")
+        } else {
+            None
+        }
+    }
+
+    /// Discard what's left of an oversize frame, returning whether there's more to come.
+    fn skip(&mut self, buf: &mut BytesMut) -> bool {
+        let len = cmp::min(self.discard, buf.len());
+        buf.split_to(len);
+        self.discard -= len;
+        self.discard > 0
+    }
+
+    /// The deadline to send with `msg`, if it has one and the peer can read it.
+    fn deadline(&self, msg: &Message) -> Option<Instant> {
+        if self.capabilities & CAP_DEADLINE == 0 {
+            return None;
+        }
+        msg.deadline()
+    }
+
+    /// Whether to mark `msg` as forwarded, if it is and the peer can read the mark.
+    fn forwarded(&self, msg: &Message) -> bool {
+        self.capabilities & CAP_FORWARDED != 0 && msg.is_forwarded()
+    }
+
+    /// Whether ttls are sent in milliseconds, see `CAP_TTL_MILLIS`.
+    fn ttl_millis(&self) -> bool {
+        self.capabilities & CAP_TTL_MILLIS != 0
+    }
+}
+
+/// The response to a request that was over a size limit, as `description` says.
+fn too_large(op: Op, description: &str) -> Message {
+    let payload = message::payload(0, description.as_bytes().to_vec());
+    message::response(op, Code::TooLarge, Some(payload))
+}
+
+/// Whether `buf` starts with the header of a hello request. The first frame of a connection
+/// decides which protocol it speaks, so `proto` looks at its header before picking a codec.
+pub fn is_hello(buf: &[u8]) -> bool {
+    buf.len() >= HEADER_LEN && buf[8] & !FLAG_EXT == 0 && buf[9] == Op::Hello as u8
+}
+
+/// Answer a client's hello with the highest version both sides speak and the capabilities both
+/// sides support. Returns the answer, and the capabilities the connection goes on with, which are
+/// the legacy ones if the hello is rejected.
+pub fn answer_hello(hello: &Message) -> (Message, u32) {
+    let (version, capabilities) = match hello.payload().and_then(get_hello) {
+        Some(hello) => hello,
+        None => return (hello_error("malformed hello"), LEGACY_CAPABILITIES),
+    };
+
+    let version = version.min(PROTOCOL_VERSION);
+    if version == 0 {
+        return (hello_error("unsupported protocol version"), LEGACY_CAPABILITIES);
+    }
+
+    let capabilities = capabilities & CAPABILITIES;
+    let answer = message::response(Op::Hello, Code::Ok, Some(hello_payload(version, capabilities)));
+    (answer, capabilities)
+}
+
+/// The hello a client opens a connection with, offering a protocol version and capabilities.
+/// Its frame has no extensions, so that every version of the server can read it. The server
+/// answers with an Ok response whose payload holds the agreed version and capabilities in the
+/// same layout, or with an Error response.
+///
+/// +--- version --+--- capabilities --+
+/// | u32          | u32, CAP_* bits   |
+/// +--------------+-------------------+
+pub fn hello(version: u32, capabilities: u32) -> Message {
+    message::request(Op::Hello, vec![], Some(hello_payload(version, capabilities)))
+}
+
+fn hello_payload(version: u32, capabilities: u32) -> Payload {
+    let mut data = Vec::with_capacity(8);
+    data.put_u32::<BigEndian>(version);
+    data.put_u32::<BigEndian>(capabilities);
+    message::payload(0, data)
+}
+
+/// The version and capabilities of a hello or its answer.
+pub fn get_hello(payload: &Payload) -> Option<(u32, u32)> {
+    if payload.data().len() != 8 {
+        return None;
+    }
+    let mut cursor = io::Cursor::new(payload.data());
+    let version = cursor.get_u32::<BigEndian>();
+    Some((version, cursor.get_u32::<BigEndian>()))
+}
+
+fn hello_error(description: &str) -> Message {
+    message::error_response(
+        Op::Hello,
+        &error::Error::new(error::ErrorKind::BadMessage, description),
+    )
+}
+
+/// Length of the extension byte and the fields it announces, with the ttl in milliseconds if
+/// `ttl_millis`.
+fn ext_len(ext: u8, ttl_millis: bool) -> usize {
+    let mut len = 1;
+    if ext & EXT_TTL != 0 {
+        len += if ttl_millis { 8 } else { 4 };
+    }
+    if ext & EXT_VERSION != 0 {
+        len += 8;
+    }
+    if ext & EXT_DEADLINE != 0 {
+        len += 4;
+    }
+    if ext & EXT_FLAGS != 0 {
+        len += 4;
+    }
+    if ext & EXT_ERROR_CODE != 0 {
+        len += 4;
+    }
+    len
+}
+
+/// Whether a frame has a type id. Any payload but an empty one without a ttl, version, flags or
+/// error code has one, as it did before extensions existed. A batch body has no type id.
+fn has_type_id(payload_len: usize, ext: u8) -> bool {
+    let payload_ext = EXT_TTL | EXT_VERSION | EXT_FLAGS | EXT_ERROR_CODE;
+    ext & EXT_BATCH == 0 && (payload_len > 0 || ext & payload_ext != 0)
+}
 
 impl Encoder for CacheCodec {
     type Item = (RequestId, Message);
@@ -31,38 +363,152 @@ impl Encoder for CacheCodec {
     fn encode(&mut self, msg: (RequestId, Message), buf: &mut BytesMut) -> io::Result<()> {
         let (request_id, msg) = msg;
 
+        if msg.is_batch() {
+            let mut body = Vec::new();
+            put_batch(&msg, &mut body, self.capabilities);
+
+            let mut ext = EXT_BATCH;
+            if self.checksum {
+                ext |= EXT_CRC;
+            }
+            let deadline = self.deadline(&msg);
+            if deadline.is_some() {
+                ext |= EXT_DEADLINE;
+            }
+            let forwarded = if self.forwarded(&msg) { FLAG_FORWARDED } else { 0 };
+
+            buf.reserve(HEADER_LEN + ext_len(ext, self.ttl_millis()) + body.len() + CRC_LEN);
+            let start = buf.len();
+            buf.put_u64::<BigEndian>(request_id as u64);
+            buf.put_u8(msg.code() as u8 | FLAG_EXT | forwarded);
+            buf.put_u8(msg.op() as u8);
+            buf.put_u64::<BigEndian>(body.len() as u64);
+            buf.put_u32::<BigEndian>(0);
+            buf.put_u8(ext);
+            if let Some(deadline) = deadline {
+                buf.put_u32::<BigEndian>(millis_until(deadline));
+            }
+            buf.put_slice(&body);
+            if self.checksum {
+                put_crc(buf, start);
+            }
+            return Ok(());
+        }
+
         let key = msg.key().unwrap_or_else(|| &[]);
         let payload = msg.payload().map(|p| p.data()).unwrap_or_else(|| &[]);
         let type_id = msg.type_id().unwrap_or(0 as u32);
-
-        let type_id_len = if payload.is_empty() { 0 } else { 4 };
+        let ttl = msg.payload().map(|p| p.ttl_millis()).unwrap_or(0);
+        let version = msg.payload().map(|p| p.version()).unwrap_or(0);
+        let flags = msg.payload().map(|p| p.flags()).unwrap_or(0);
+        let error_code = msg.payload().map(|p| p.error_code()).unwrap_or(0);
 
         let payload_len = payload.len();
 
-        let min_size = HEADER_LEN + key.len() + payload_len + type_id_len;
+        // Without CAP_TTL the peer can't read the ttl and version, so they're left out.
+        let has_ttl = self.capabilities & CAP_TTL != 0 && msg.payload().is_some();
+
+        let mut ext = 0;
+        if ttl > 0 && has_ttl {
+            ext |= EXT_TTL;
+        }
+        if version > 0 && has_ttl {
+            ext |= EXT_VERSION;
+        }
+        if flags > 0 && self.capabilities & CAP_FLAGS != 0 {
+            ext |= EXT_FLAGS;
+        }
+        if error_code > 0 && self.capabilities & CAP_ERROR_CODE != 0 {
+            ext |= EXT_ERROR_CODE;
+        }
+        if self.checksum {
+            ext |= EXT_CRC;
+        }
+        let deadline = self.deadline(&msg);
+        if deadline.is_some() {
+            ext |= EXT_DEADLINE;
+        }
+        let type_id_len = if has_type_id(payload_len, ext) { 4 } else { 0 };
+
+        let (code, ext_size) = if ext == 0 {
+            (msg.code() as u8, 0)
+        } else {
+            (msg.code() as u8 | FLAG_EXT, ext_len(ext, self.ttl_millis()))
+        };
+
+        let min_size = HEADER_LEN + ext_size + key.len() + payload_len + type_id_len + CRC_LEN;
         buf.reserve(min_size);
 
+        let start = buf.len();
         buf.put_u64::<BigEndian>(request_id as u64);
-        buf.put_u8(msg.code() as u8);
+        buf.put_u8(if self.forwarded(&msg) { code | FLAG_FORWARDED } else { code });
         buf.put_u8(msg.op() as u8);
         buf.put_u64::<BigEndian>(payload_len as u64);
         buf.put_u32::<BigEndian>(key.len() as u32);
+
+        if ext != 0 {
+            buf.put_u8(ext);
+            if ext & EXT_TTL != 0 {
+                // EXT_TTL is only set on a frame with a payload.
+                put_ttl(buf, msg.payload().unwrap(), self.ttl_millis());
+            }
+            if ext & EXT_VERSION != 0 {
+                buf.put_u64::<BigEndian>(version);
+            }
+            if let Some(deadline) = deadline {
+                buf.put_u32::<BigEndian>(millis_until(deadline));
+            }
+            if ext & EXT_FLAGS != 0 {
+                buf.put_u32::<BigEndian>(flags);
+            }
+            if ext & EXT_ERROR_CODE != 0 {
+                buf.put_u32::<BigEndian>(error_code);
+            }
+        }
+
         buf.put_slice(key);
 
-        if payload_len > 0 {
+        if type_id_len > 0 {
             buf.put_u32::<BigEndian>(type_id);
             buf.put_slice(payload);
         }
 
+        if self.checksum {
+            put_crc(buf, start);
+        }
+
         Ok(())
     }
 }
 
+/// The milliseconds left until `deadline`, 0 if it has passed.
+fn millis_until(deadline: Instant) -> u32 {
+    let now = Instant::now();
+    if deadline <= now {
+        return 0;
+    }
+    let left = deadline - now;
+    let millis = left.as_secs()
+        .saturating_mul(1000)
+        .saturating_add(u64::from(left.subsec_nanos() / 1_000_000));
+    cmp::min(millis, u64::from(u32::max_value())) as u32
+}
+
+/// Appends the checksum of the frame that starts at `start`.
+fn put_crc(buf: &mut BytesMut, start: usize) {
+    let crc = crc32c::crc32c(&buf[start..]);
+    buf.put_u32::<BigEndian>(crc);
+}
+
 impl Decoder for CacheCodec {
     type Item = (RequestId, Message);
     type Error = io::Error;
 
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<(RequestId, Message)>, io::Error> {
+        if self.discard > 0 && self.skip(buf) {
+            return Ok(None);
+        }
+
         // Check that at least the header is complete
         if buf.len() < HEADER_LEN {
             return Ok(None);
@@ -72,10 +518,44 @@ impl Decoder for CacheCodec {
         let payload_len = io::Cursor::new(&buf.as_ref()[10..18]).get_u64::<BigEndian>() as usize;
         let key_len = io::Cursor::new(&buf.as_ref()[18..22]).get_u32::<BigEndian>() as usize;
 
+        // If the frame has extensions, their length is determined by the extension byte.
+        let ext = if buf.as_ref()[8] & FLAG_EXT == 0 {
+            0
+        } else if buf.len() < HEADER_LEN + 1 {
+            return Ok(None);
+        } else {
+            let ext = buf.as_ref()[HEADER_LEN];
+            if ext & !EXT_KNOWN != 0 {
+                return Err(
+                    error::Error::new(error::ErrorKind::InvalidData, "unknown frame extension").into(),
+                );
+            }
+            ext
+        };
+        let ext_size = if ext == 0 { 0 } else { ext_len(ext, self.ttl_millis()) };
+
         // If we have a payload, then we have a type_id to include in the total message length.
-        let type_id_len = if payload_len == 0 { 0 } else { 4 };
+        let type_id_len = if has_type_id(payload_len, ext) { 4 } else { 0 };
+
+        let crc_len = if ext & EXT_CRC != 0 { CRC_LEN } else { 0 };
 
-        let msg_len = HEADER_LEN + payload_len + key_len + type_id_len;
+        // The lengths are untrusted, so guard against overflow.
+        let msg_len = (HEADER_LEN + ext_size + type_id_len + crc_len)
+            .saturating_add(payload_len)
+            .saturating_add(key_len);
+
+        if msg_len > self.limits.hard_frame_len {
+            let description = "frame exceeds the hard size limit";
+            return Err(error::Error::new(error::ErrorKind::TooLarge, description).into());
+        }
+        if let Some(description) = self.check_limits(key_len, payload_len, ext, msg_len) {
+            let request_id = io::Cursor::new(&buf.as_ref()[..8]).get_u64::<BigEndian>();
+            let op = Op::try_from(buf.as_ref()[9]).unwrap_or(Op::Get);
+            self.discard = msg_len;
+            self.skip(buf);
+
+            return Ok(Some((request_id as RequestId, too_large(op, description))));
+        }
 
         // Buffer not ready.
         if (buf.len()) < msg_len {
@@ -83,47 +563,321 @@ impl Decoder for CacheCodec {
         }
 
         // Split off the complete message.
-        let msg = buf.split_to(msg_len);
+        let mut msg = buf.split_to(msg_len);
 
-        // Instantiate the cursor.
+        // Check and strip the checksum. The peer sent one, so it gets checksums back.
+        if crc_len > 0 {
+            self.checksum = true;
+            let body_len = msg_len - crc_len;
+            let expected = io::Cursor::new(&msg.as_ref()[body_len..]).get_u32::<BigEndian>();
+            if crc32c::crc32c(&msg.as_ref()[..body_len]) != expected {
+                let request_id = io::Cursor::new(&msg.as_ref()[..8]).get_u64::<BigEndian>();
+                let op = Op::try_from(msg.as_ref()[9]).unwrap_or(Op::Get);
+                return Ok(Some((request_id as RequestId, checksum_mismatch(op))));
+            }
+            msg.truncate(body_len);
+        }
+
+        let request_id = io::Cursor::new(&msg.as_ref()[..8]).get_u64::<BigEndian>();
+        let op = msg.as_ref()[9];
+
+        // The frame's bounds are known, so a malformed frame only fails its own request, and the
+        // connection carries on with the next frame.
         let mut cursor = io::Cursor::new(msg);
+        let op = Op::try_from(op).unwrap_or(Op::Get);
+        let msg = match self.decode_frame(ext, key_len, payload_len, &mut cursor) {
+            Ok(msg) => msg,
+            Err(ref e) if *e.kind() == error::ErrorKind::TooLarge => {
+                too_large(op, e.description())
+            }
+            Err(e) => message::error_response(op, &e),
+        };
+
+        Ok(Some((request_id as RequestId, msg)))
+    }
+}
+
+impl CacheCodec {
+    /// Decode a complete frame, without its checksum.
+    fn decode_frame(
+        &mut self,
+        ext: u8,
+        key_len: usize,
+        payload_len: usize,
+        cursor: &mut io::Cursor<BytesMut>,
+    ) -> Result<Message, error::Error> {
+        // Skip the request id, it's been read already.
+        cursor.advance(8);
 
-        // Read the first 3 fields.
-        let request_id = cursor.get_u64::<BigEndian>();
+        // Read the code and op.
         let code = cursor.get_u8();
-        let op = cursor.get_u8();
+        // Only a peer that agreed to the mark may set it, see `CAP_FORWARDED`.
+        let forwarded = code & FLAG_FORWARDED != 0 && self.capabilities & CAP_FORWARDED != 0;
+        let code = code & !(FLAG_EXT | FLAG_FORWARDED);
+        let op = Op::try_from(cursor.get_u8())?;
 
         // Skip the payload_len and key_len as they've been read already.
         cursor.advance(12);
 
+        // Read the extensions.
+        let mut ttl = 0;
+        let mut version = 0;
+        if ext != 0 {
+            cursor.advance(1);
+            if ext & EXT_TTL != 0 {
+                ttl = if self.ttl_millis() {
+                    cursor.get_u64::<BigEndian>()
+                } else {
+                    u64::from(cursor.get_u32::<BigEndian>()) * 1000
+                };
+            }
+            if ext & EXT_VERSION != 0 {
+                version = cursor.get_u64::<BigEndian>();
+            }
+        }
+        let deadline = if ext & EXT_DEADLINE != 0 {
+            let timeout = cursor.get_u32::<BigEndian>();
+            Some(Instant::now() + Duration::from_millis(u64::from(timeout)))
+        } else {
+            None
+        };
+        let flags = if ext & EXT_FLAGS != 0 {
+            cursor.get_u32::<BigEndian>()
+        } else {
+            0
+        };
+        let error_code = if ext & EXT_ERROR_CODE != 0 {
+            cursor.get_u32::<BigEndian>()
+        } else {
+            0
+        };
+
+        if ext & EXT_BATCH != 0 {
+            if self.capabilities & CAP_BATCH == 0 {
+                return Err(error::Error::new(
+                    error::ErrorKind::BadMessage,
+                    "batching was not negotiated",
+                ));
+            }
+            let msg = get_batch(code, op, &self.limits, self.capabilities, cursor)?;
+            let msg = match deadline {
+                Some(deadline) => msg.with_deadline(deadline),
+                None => msg,
+            };
+            return Ok(if forwarded { msg.forwarded() } else { msg });
+        }
+
         // Read the key.
         let mut key = Vec::with_capacity(key_len);
         key.resize(key_len, 0);
         cursor.copy_to_slice(&mut key);
 
         // Read the payload.
-        let payload = if payload_len > 0 {
+        let payload = if has_type_id(payload_len, ext) {
             let type_id = cursor.get_u32::<BigEndian>();
-            Some(message::payload(type_id, cursor.collect()))
+            Some(
+                message::payload(type_id, cursor.collect())
+                    .with_ttl_millis(ttl)
+                    .with_version(version)
+                    .with_flags(flags)
+                    .with_error_code(error_code),
+            )
         } else {
             None
         };
 
-        let msg = if code == 0 {
-            message::request(Op::try_from(op)?, key.to_vec(), payload)
+        if code == 0 {
+            Ok(Message::Request(op, key, payload, deadline, forwarded))
         } else {
-            message::response(Op::try_from(op)?, Code::try_from(code)?, payload)
-        };
+            Ok(message::response(op, Code::try_from(code)?, payload))
+        }
+    }
+}
 
-        Ok(Some((request_id as RequestId, msg)))
+/// The response to a frame whose checksum doesn't match.
+fn checksum_mismatch(op: Op) -> Message {
+    message::error_response(
+        op,
+        &error::Error::new(error::ErrorKind::InvalidData, "checksum mismatch"),
+    )
+}
+
+/// Writes the body of a batch frame. The body is the number of entries followed by the entries,
+/// each of which ends with an optional payload.
+///
+/// +--- count --+--- key len --+--- key --+--- payload ...
+/// | u32        | u32          | [u8]     |                  (request entries)
+/// +------------+--------------+----------+----------------
+///
+/// +--- count --+--- code --+--- payload ...
+/// | u32        | u8        |                  (response entries)
+/// +------------+-----------+----------------
+///
+/// +--- present --+--- type id --+--- ttl --+--- version --+--- data len --+--- data --+
+/// | u8, 0 = none | u32          | u32      | u64          | u32           | [u8]      |
+/// +--------------+--------------+----------+--------------+---------------+-----------+
+///
+/// A payload with memcached flags, sent only if the peer agreed to CAP_FLAGS, is marked present
+/// with a 2 rather than a 1, and has the u32 flags after its version. A payload with an error
+/// code, sent only if the peer agreed to CAP_ERROR_CODE, has 4 added to its mark, and the u32
+/// error code after its flags. The ttl is a u64 of milliseconds if the peer agreed to
+/// CAP_TTL_MILLIS, as in the extensions.
+fn put_batch(msg: &Message, body: &mut Vec<u8>, capabilities: u32) {
+    match *msg {
+        Message::BatchRequest(_, ref entries, ..) => {
+            body.put_u32::<BigEndian>(entries.len() as u32);
+            for &(ref key, ref payload) in entries {
+                body.put_u32::<BigEndian>(key.len() as u32);
+                body.put_slice(key);
+                put_batch_payload(payload.as_ref(), body, capabilities);
+            }
+        }
+        Message::BatchResponse(_, ref results) => {
+            body.put_u32::<BigEndian>(results.len() as u32);
+            for &(code, ref payload) in results {
+                body.put_u8(code as u8);
+                put_batch_payload(payload.as_ref(), body, capabilities);
+            }
+        }
+        Message::Request(..) |
+        Message::Response(..) => (),
     }
 }
 
+fn put_batch_payload(payload: Option<&Payload>, body: &mut Vec<u8>, capabilities: u32) {
+    match payload {
+        Some(payload) => {
+            let flags = capabilities & CAP_FLAGS != 0 && payload.flags() > 0;
+            let error_code = capabilities & CAP_ERROR_CODE != 0 && payload.error_code() > 0;
+            let mark = if flags { 2 } else { 1 };
+            body.put_u8(if error_code { mark + 4 } else { mark });
+            body.put_u32::<BigEndian>(payload.type_id());
+            put_ttl(body, payload, capabilities & CAP_TTL_MILLIS != 0);
+            body.put_u64::<BigEndian>(payload.version());
+            if flags {
+                body.put_u32::<BigEndian>(payload.flags());
+            }
+            if error_code {
+                body.put_u32::<BigEndian>(payload.error_code());
+            }
+            body.put_u32::<BigEndian>(payload.data().len() as u32);
+            body.put_slice(payload.data());
+        }
+        None => body.put_u8(0),
+    }
+}
+
+/// Reads the body of a batch frame, see `put_batch`. The lengths in the body are checked against
+/// the frame, so a malformed body is an error rather than a panic. The keys and values of a
+/// request are held to `limits`, as they would be if each were sent on its own.
+fn get_batch(
+    code: u8,
+    op: Op,
+    limits: &Limits,
+    capabilities: u32,
+    cursor: &mut io::Cursor<BytesMut>,
+) -> Result<Message, error::Error> {
+    let ttl_millis = capabilities & CAP_TTL_MILLIS != 0;
+    check_remaining(cursor, 4)?;
+    let count = cursor.get_u32::<BigEndian>() as usize;
+
+    if code == 0 {
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            check_remaining(cursor, 4)?;
+            let key_len = cursor.get_u32::<BigEndian>() as usize;
+            if key_len > limits.max_key_len {
+                return Err(error::Error::new(error::ErrorKind::TooLarge, "key too large"));
+            }
+            check_remaining(cursor, key_len)?;
+            let mut key = vec![0; key_len];
+            cursor.copy_to_slice(&mut key);
+            let payload = get_batch_payload(cursor, ttl_millis)?;
+            if payload.as_ref().map_or(0, |p| p.data().len()) > limits.max_value_len {
+                return Err(error::Error::new(error::ErrorKind::TooLarge, "value too large"));
+            }
+            entries.push((key, payload));
+        }
+        Ok(message::batch_request(op, entries))
+    } else {
+        let mut results = Vec::new();
+        for _ in 0..count {
+            check_remaining(cursor, 1)?;
+            let code = Code::try_from(cursor.get_u8())?;
+            results.push((code, get_batch_payload(cursor, ttl_millis)?));
+        }
+        Ok(message::batch_response(op, results))
+    }
+}
+
+fn get_batch_payload(
+    cursor: &mut io::Cursor<BytesMut>,
+    ttl_millis: bool,
+) -> Result<Option<Payload>, error::Error> {
+    check_remaining(cursor, 1)?;
+    let present = cursor.get_u8();
+    if present == 0 {
+        return Ok(None);
+    }
+
+    check_remaining(cursor, if ttl_millis { 4 + 8 + 8 } else { 4 + 4 + 8 })?;
+    let type_id = cursor.get_u32::<BigEndian>();
+    let ttl = if ttl_millis {
+        cursor.get_u64::<BigEndian>()
+    } else {
+        u64::from(cursor.get_u32::<BigEndian>()) * 1000
+    };
+    let version = cursor.get_u64::<BigEndian>();
+    let flags = if present & 3 == 2 {
+        check_remaining(cursor, 4)?;
+        cursor.get_u32::<BigEndian>()
+    } else {
+        0
+    };
+    let error_code = if present & 4 != 0 {
+        check_remaining(cursor, 4)?;
+        cursor.get_u32::<BigEndian>()
+    } else {
+        0
+    };
+    check_remaining(cursor, 4)?;
+    let data_len = cursor.get_u32::<BigEndian>() as usize;
+    check_remaining(cursor, data_len)?;
+    let mut data = vec![0; data_len];
+    cursor.copy_to_slice(&mut data);
+
+    Ok(Some(
+        message::payload(type_id, data)
+            .with_ttl_millis(ttl)
+            .with_version(version)
+            .with_flags(flags)
+            .with_error_code(error_code),
+    ))
+}
+
+/// Write the ttl of `payload`, as a u64 of milliseconds if `ttl_millis`, or else as a u32 of
+/// seconds.
+fn put_ttl<B: BufMut>(buf: &mut B, payload: &Payload, ttl_millis: bool) {
+    if ttl_millis {
+        buf.put_u64::<BigEndian>(payload.ttl_millis());
+    } else {
+        buf.put_u32::<BigEndian>(payload.ttl());
+    }
+}
+
+fn check_remaining(cursor: &io::Cursor<BytesMut>, len: usize) -> Result<(), error::Error> {
+    if cursor.remaining() < len {
+        Err(error::Error::new(error::ErrorKind::BadMessage, "batch body is truncated"))
+    } else {
+        Ok(())
+    }
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use message::Op;
+    use rand::{Rng, SeedableRng, XorShiftRng};
     use test::Bencher;
 
     #[test]
@@ -146,7 +900,7 @@ mod tests {
         );
         let req_id = 123 as RequestId;
         let mut buf = BytesMut::new();
-        let mut codec = CacheCodec;
+        let mut codec = CacheCodec::default();
 
         codec.encode((req_id, msg.clone()), &mut buf).unwrap();
         let (decoded_req, decoded_message) = codec.decode(&mut buf).unwrap().unwrap();
@@ -164,7 +918,7 @@ mod tests {
         );
         let req_id = 123 as RequestId;
         let mut buf = BytesMut::new();
-        let mut codec = CacheCodec;
+        let mut codec = CacheCodec::default();
 
         codec.encode((req_id, msg.clone()), &mut buf).unwrap();
         let (decoded_req, decoded_message) = codec.decode(&mut buf).unwrap().unwrap();
@@ -178,7 +932,7 @@ mod tests {
         let msg = message::request(Op::Get, "foo".into(), None);
         let req_id = 123 as RequestId;
         let mut buf = BytesMut::new();
-        let mut codec = CacheCodec;
+        let mut codec = CacheCodec::default();
 
         codec.encode((req_id, msg.clone()), &mut buf).unwrap();
         let (decoded_req, decoded_message) = codec.decode(&mut buf).unwrap().unwrap();
@@ -194,7 +948,7 @@ mod tests {
 
         let req_id = 123 as RequestId;
         let mut buf = BytesMut::new();
-        let mut codec = CacheCodec;
+        let mut codec = CacheCodec::default();
 
         codec.encode((req_id, msg.clone()), &mut buf).unwrap();
         let (decoded_req, decoded_message) = codec.decode(&mut buf).unwrap().unwrap();
@@ -203,6 +957,749 @@ mod tests {
         assert_eq!(decoded_message, msg);
     }
 
+    #[test]
+    fn test_request_with_ttl() {
+        let msg = message::request(
+            Op::Set,
+            "foo".into(),
+            Some(message::payload(3, "123124125".into()).with_ttl(30)),
+        );
+        let req_id = 123 as RequestId;
+        let mut buf = BytesMut::new();
+        let mut codec = CacheCodec::default();
+
+        codec.encode((req_id, msg.clone()), &mut buf).unwrap();
+        assert_eq!(buf.len(), HEADER_LEN + 1 + 4 + 3 + 4 + 9);
+        let (decoded_req, decoded_message) = codec.decode(&mut buf).unwrap().unwrap();
+
+        assert_eq!(decoded_req, req_id);
+        assert_eq!(decoded_message, msg);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_response_with_version() {
+        let msg = message::response(
+            Op::Get,
+            Code::Hit,
+            Some(
+                message::payload(3, "bar".into())
+                    .with_ttl(30)
+                    .with_version(42),
+            ),
+        );
+        let mut buf = BytesMut::new();
+        let mut codec = CacheCodec::default();
+
+        codec.encode((7, msg.clone()), &mut buf).unwrap();
+        assert_eq!(buf.len(), HEADER_LEN + 1 + 4 + 8 + 4 + 3);
+        let (decoded_req, decoded_message) = codec.decode(&mut buf).unwrap().unwrap();
+
+        assert_eq!(decoded_req, 7);
+        assert_eq!(decoded_message, msg);
+    }
+
+    #[test]
+    fn test_empty_payload_with_version() {
+        let msg = message::response(
+            Op::Get,
+            Code::Hit,
+            Some(message::payload(3, vec![]).with_ttl(30).with_version(42)),
+        );
+        let mut buf = BytesMut::new();
+        let mut codec = CacheCodec::default();
+
+        codec.encode((7, msg.clone()), &mut buf).unwrap();
+        assert_eq!(buf.len(), HEADER_LEN + 1 + 4 + 8 + 4);
+        let (_, decoded_message) = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(decoded_message, msg);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_batch_request() {
+        let msg = message::batch_request(
+            Op::Set,
+            vec![
+                ("foo".into(), Some(message::payload(1, "bar".into()).with_ttl(30))),
+                ("baz".into(), Some(message::payload(2, "qux".into()))),
+                ("empty".into(), None),
+            ],
+        );
+        let mut buf = BytesMut::new();
+        let mut codec = CacheCodec::default();
+
+        codec.encode((5, msg.clone()), &mut buf).unwrap();
+        codec.encode((6, message::request(Op::Get, "foo".into(), None)), &mut buf).unwrap();
+
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), (5, msg));
+        assert_eq!(
+            codec.decode(&mut buf).unwrap().unwrap(),
+            (6, message::request(Op::Get, "foo".into(), None))
+        );
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_flags() {
+        let payload = message::payload(0, "bar".into()).with_flags(8);
+        let msg = message::response(Op::Get, Code::Hit, Some(payload.clone()));
+        let batch = message::batch_response(Op::Get, vec![(Code::Hit, Some(payload))]);
+        let mut buf = BytesMut::new();
+        let mut codec = CacheCodec::negotiated(CAPABILITIES, Limits::default());
+
+        codec.encode((1, msg.clone()), &mut buf).unwrap();
+        codec.encode((2, batch.clone()), &mut buf).unwrap();
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), (1, msg.clone()));
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), (2, batch));
+
+        // A peer that hasn't agreed to CAP_FLAGS gets the payload without them.
+        let mut legacy = CacheCodec::default();
+        legacy.encode((3, msg), &mut buf).unwrap();
+        let (_, decoded) = legacy.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(decoded.payload().unwrap().flags(), 0);
+        assert_eq!(decoded.payload().unwrap().data(), b"bar");
+    }
+
+    #[test]
+    fn test_error_code() {
+        let err = error::Error::new(error::ErrorKind::InvalidData, "checksum mismatch");
+        let msg = message::error_response(Op::Set, &err);
+        let batch = message::batch_response(Op::Get, vec![(Code::Error, msg.payload().cloned())]);
+        assert_eq!(msg.type_id(), Some(message::TYPE_ID_UTF8));
+
+        let mut buf = BytesMut::new();
+        let mut codec = CacheCodec::negotiated(CAPABILITIES, Limits::default());
+        codec.encode((1, msg.clone()), &mut buf).unwrap();
+        codec.encode((2, batch.clone()), &mut buf).unwrap();
+        let (_, decoded) = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(decoded, msg);
+        assert_eq!(*decoded.error().unwrap().kind(), error::ErrorKind::InvalidData);
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), (2, batch));
+
+        // A peer that hasn't agreed to CAP_ERROR_CODE gets the description alone.
+        let mut legacy = CacheCodec::default();
+        legacy.encode((3, msg), &mut buf).unwrap();
+        let (_, decoded) = legacy.decode(&mut buf).unwrap().unwrap();
+        let decoded = decoded.error().unwrap();
+        assert_eq!(*decoded.kind(), error::ErrorKind::Other);
+        assert_eq!(decoded.to_string(), "Other: checksum mismatch");
+    }
+
+    #[test]
+    fn test_batch_response() {
+        let msg = message::batch_response(
+            Op::Get,
+            vec![
+                (Code::Hit, Some(message::payload(1, "bar".into()).with_version(3))),
+                (Code::Miss, None),
+            ],
+        );
+        let mut buf = BytesMut::new();
+        let mut codec = CacheCodec::default();
+
+        codec.encode((5, msg.clone()), &mut buf).unwrap();
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), (5, msg));
+
+        let empty = message::batch_response(Op::Get, vec![]);
+        codec.encode((6, empty.clone()), &mut buf).unwrap();
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), (6, empty));
+    }
+
+    #[test]
+    fn test_truncated_batch() {
+        let msg = message::batch_request(Op::Get, vec![("foo".into(), None), ("bar".into(), None)]);
+        let mut buf = BytesMut::new();
+        let mut codec = CacheCodec::default();
+        codec.encode((5, msg), &mut buf).unwrap();
+
+        // Claim a third entry that isn't there.
+        buf[HEADER_LEN + 1 + 3] = 3;
+        let (id, response) = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(id, 5);
+        assert_eq!(*response.error().unwrap().kind(), error::ErrorKind::BadMessage);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_partial_ext() {
+        let msg = message::request(
+            Op::Set,
+            "foo".into(),
+            Some(message::payload(3, "bar".into()).with_ttl(30)),
+        );
+        let mut buf = BytesMut::new();
+        let mut codec = CacheCodec::default();
+        codec.encode((1, msg.clone()), &mut buf).unwrap();
+
+        let mut partial = BytesMut::from(&buf[..HEADER_LEN]);
+        assert!(codec.decode(&mut partial).unwrap().is_none());
+        let mut partial = BytesMut::from(&buf[..HEADER_LEN + 3]);
+        assert!(codec.decode(&mut partial).unwrap().is_none());
+
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap().1, msg);
+    }
+
+    #[test]
+    fn test_unknown_ext() {
+        let msg = message::request(
+            Op::Set,
+            "foo".into(),
+            Some(message::payload(3, "bar".into()).with_ttl(30)),
+        );
+        let mut buf = BytesMut::new();
+        let mut codec = CacheCodec::default();
+        codec.encode((1, msg), &mut buf).unwrap();
+        buf[HEADER_LEN] |= 0x80;
+
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_checksum() {
+        let msg = message::request(
+            Op::Set,
+            "foo".into(),
+            Some(message::payload(3, "bar".into()).with_ttl(30)),
+        );
+        let mut buf = BytesMut::new();
+        let mut client = checksummed();
+        client.encode((1, msg.clone()), &mut buf).unwrap();
+        assert_eq!(buf.len(), HEADER_LEN + 1 + 4 + 3 + 4 + 3 + CRC_LEN);
+
+        // A server starts without checksums, and switches them on once the client sends one.
+        let mut server = CacheCodec::default();
+        assert_eq!(server.decode(&mut buf).unwrap().unwrap(), (1, msg));
+        assert!(buf.is_empty());
+
+        let response = message::response(Op::Set, Code::Ok, None);
+        server.encode((1, response.clone()), &mut buf).unwrap();
+        assert_eq!(buf.len(), HEADER_LEN + 1 + CRC_LEN);
+        assert_eq!(client.decode(&mut buf).unwrap().unwrap(), (1, response));
+
+        let batch = message::batch_request(Op::Get, vec![("foo".into(), None)]);
+        client.encode((2, batch.clone()), &mut buf).unwrap();
+        assert_eq!(server.decode(&mut buf).unwrap().unwrap(), (2, batch));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_checksum_mismatch() {
+        let msg = message::request(Op::Set, "foo".into(), Some(message::payload(3, "bar".into())));
+        let mut buf = BytesMut::new();
+        let mut codec = checksummed();
+        codec.encode((9, msg.clone()), &mut buf).unwrap();
+        codec.encode((10, msg.clone()), &mut buf).unwrap();
+
+        // Flip a bit in the first frame's payload.
+        buf[HEADER_LEN + 1 + 3 + 4] ^= 0x01;
+
+        let (request_id, response) = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(request_id, 9);
+        assert_eq!(response.op(), Op::Set);
+        assert_eq!(response.code(), Code::Error);
+
+        // The connection carries on with the next frame.
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), (10, msg));
+    }
+
+    #[test]
+    fn test_unchecksummed_frame() {
+        let msg = message::request(Op::Get, "foo".into(), None);
+        let mut buf = BytesMut::new();
+        CacheCodec::default().encode((1, msg.clone()), &mut buf).unwrap();
+        assert_eq!(buf.len(), HEADER_LEN + 3);
+
+        let mut codec = CacheCodec::default();
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), (1, msg));
+
+        // Having seen no checksum, the codec doesn't send one.
+        codec.encode((1, message::response(Op::Get, Code::Miss, None)), &mut buf).unwrap();
+        assert_eq!(buf.len(), HEADER_LEN);
+    }
+
+    /// A codec that checksums every frame it encodes, without negotiating it in a hello.
+    fn checksummed() -> CacheCodec {
+        CacheCodec { checksum: true, ..CacheCodec::default() }
+    }
+
+    /// Pass `msg` from one codec to the other.
+    fn relay(
+        from: &mut CacheCodec,
+        to: &mut CacheCodec,
+        msg: (RequestId, Message),
+    ) -> (RequestId, Message) {
+        let mut buf = BytesMut::new();
+        from.encode(msg, &mut buf).unwrap();
+        let decoded = to.decode(&mut buf).unwrap().unwrap();
+        assert!(buf.is_empty());
+        decoded
+    }
+
+    #[test]
+    fn test_hello() {
+        // Compression, 0x02, isn't supported, so it isn't agreed.
+        let hello = hello(PROTOCOL_VERSION + 1, CAP_CHECKSUM | 0x02 | CAP_BATCH);
+        let mut buf = BytesMut::new();
+        CacheCodec::default().encode((0, hello), &mut buf).unwrap();
+        assert!(is_hello(&buf));
+        let (_, hello) = CacheCodec::default().decode(&mut buf).unwrap().unwrap();
+
+        let (answer, capabilities) = answer_hello(&hello);
+        assert_eq!(answer.code(), Code::Ok);
+        assert_eq!(capabilities, CAP_CHECKSUM | CAP_BATCH);
+        assert_eq!(
+            get_hello(answer.payload().unwrap()),
+            Some((PROTOCOL_VERSION, CAP_CHECKSUM | CAP_BATCH))
+        );
+
+        // Both sides now checksum, and leave out the ttl as CAP_TTL wasn't agreed.
+        let mut client = CacheCodec::negotiated(capabilities, Limits::default());
+        let mut server = CacheCodec::negotiated(capabilities, Limits::default());
+        let msg = message::request(
+            Op::Set,
+            "foo".into(),
+            Some(message::payload(3, "bar".into()).with_ttl(30)),
+        );
+        client.encode((1, msg), &mut buf).unwrap();
+        assert_eq!(buf.len(), HEADER_LEN + 1 + 3 + 4 + 3 + CRC_LEN);
+        assert!(!is_hello(&buf));
+        assert_eq!(
+            server.decode(&mut buf).unwrap().unwrap(),
+            (1, message::request(Op::Set, "foo".into(), Some(message::payload(3, "bar".into()))))
+        );
+
+        let response = message::response(Op::Set, Code::Ok, None);
+        server.encode((1, response), &mut buf).unwrap();
+        assert_eq!(buf.len(), HEADER_LEN + 1 + CRC_LEN);
+    }
+
+    #[test]
+    fn test_hello_rejected() {
+        let (answer, capabilities) = answer_hello(&hello(0, CAPABILITIES));
+        assert_eq!(answer.code(), Code::Error);
+        assert_eq!(capabilities, LEGACY_CAPABILITIES);
+
+        let malformed = message::request(Op::Hello, vec![], None);
+        let (answer, capabilities) = answer_hello(&malformed);
+        assert_eq!(answer.code(), Code::Error);
+        assert_eq!(capabilities, LEGACY_CAPABILITIES);
+    }
+
+    #[test]
+    fn test_batch_not_negotiated() {
+        let mut client = CacheCodec::default();
+        let mut server = CacheCodec::negotiated(CAP_TTL, Limits::default());
+
+        let batch = message::batch_request(Op::Get, vec![("foo".into(), None)]);
+        let (id, answer) = relay(&mut client, &mut server, (1, batch));
+        assert_eq!(id, 1);
+        let err = answer.error().unwrap();
+        assert_eq!(*err.kind(), error::ErrorKind::BadMessage);
+        assert_eq!(err.to_string(), "Bad Message: batching was not negotiated");
+    }
+
+    /// Encode `msgs` with request ids 1, 2, 3 and so on, corrupting the second frame's header
+    /// with `corrupt`, and decode them back.
+    fn decode_with_bad_neighbour<F>(msgs: Vec<Message>, corrupt: F) -> Vec<(RequestId, Message)>
+    where
+        F: Fn(&mut [u8]),
+    {
+        let mut codec = CacheCodec::default();
+        let mut buf = BytesMut::new();
+        for (i, msg) in msgs.into_iter().enumerate() {
+            let mut frame = BytesMut::new();
+            codec.encode((i as RequestId + 1, msg), &mut frame).unwrap();
+            if i == 1 {
+                corrupt(&mut frame);
+            }
+            buf.extend_from_slice(&frame);
+        }
+
+        let mut decoded = Vec::new();
+        while let Some(item) = codec.decode(&mut buf).unwrap() {
+            decoded.push(item);
+        }
+        assert!(buf.is_empty());
+        decoded
+    }
+
+    #[test]
+    fn test_unknown_op() {
+        let get = message::request(Op::Get, "foo".into(), None);
+        let set = message::request(Op::Set, "foo".into(), Some(message::payload(1, "bar".into())));
+        let decoded = decode_with_bad_neighbour(vec![get.clone(), set, get.clone()], |frame| {
+            frame[9] = 0xee;
+        });
+
+        assert_eq!(decoded.len(), 3);
+        assert_eq!(decoded[0], (1, get.clone()));
+        assert_eq!(decoded[1].0, 2);
+        assert_eq!(*decoded[1].1.error().unwrap().kind(), error::ErrorKind::UnknownOp);
+        assert_eq!(decoded[2], (3, get));
+    }
+
+    #[test]
+    fn test_unknown_code() {
+        let get = message::request(Op::Get, "foo".into(), None);
+        let hit = message::response(Op::Get, Code::Hit, Some(message::payload(1, "bar".into())));
+        let msgs = vec![hit.clone(), hit.clone(), get.clone()];
+        let decoded = decode_with_bad_neighbour(msgs, |frame| frame[8] = 0x7f);
+
+        assert_eq!(decoded[0], (1, hit));
+        assert_eq!(decoded[1].1.op(), Op::Get);
+        assert_eq!(*decoded[1].1.error().unwrap().kind(), error::ErrorKind::InvalidData);
+        assert_eq!(decoded[2], (3, get));
+    }
+
+    #[test]
+    fn test_bad_batch_neighbours() {
+        let get = message::request(Op::Get, "foo".into(), None);
+        let batch = message::batch_request(Op::Get, vec![("foo".into(), None)]);
+        let decoded = decode_with_bad_neighbour(vec![get.clone(), batch, get.clone()], |frame| {
+            // Claim more entries than the body holds.
+            frame[HEADER_LEN + 1 + 3] = 9;
+        });
+
+        assert_eq!(decoded[0], (1, get.clone()));
+        assert_eq!(decoded[1].1.code(), Code::Error);
+        assert_eq!(decoded[2], (3, get));
+    }
+
+    #[test]
+    fn test_deadline() {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let msg = message::request(Op::Get, "foo".into(), None).with_deadline(deadline);
+        let batch = message::batch_request(Op::Get, vec![("foo".into(), None)])
+            .with_deadline(deadline);
+
+        let mut client = CacheCodec::negotiated(CAPABILITIES, Limits::default());
+        let mut server = CacheCodec::negotiated(CAPABILITIES, Limits::default());
+
+        for msg in vec![msg, batch] {
+            let (_, decoded) = relay(&mut client, &mut server, (1, msg));
+            let decoded = decoded.deadline().unwrap();
+            assert!(decoded <= deadline + Duration::from_millis(1));
+            assert!(decoded > deadline - Duration::from_secs(1));
+        }
+
+        // Without CAP_DEADLINE the deadline is left out.
+        let msg = message::request(Op::Get, "foo".into(), None);
+        let (_, decoded) = relay(
+            &mut CacheCodec::default(),
+            &mut CacheCodec::default(),
+            (1, msg.clone().with_deadline(deadline)),
+        );
+        assert_eq!(decoded, msg);
+    }
+
+    #[test]
+    fn test_forwarded() {
+        let msg = message::request(Op::Set, "foo".into(), Some(message::payload(1, "bar".into())));
+        let batch = message::batch_request(Op::Get, vec![("foo".into(), None)]);
+
+        let mut client = CacheCodec::negotiated(CAPABILITIES, Limits::default());
+        let mut server = CacheCodec::negotiated(CAPABILITIES, Limits::default());
+        for msg in vec![msg.clone(), batch] {
+            let (_, decoded) = relay(&mut client, &mut server, (1, msg.clone()));
+            assert!(!decoded.is_forwarded());
+            let (_, decoded) = relay(&mut client, &mut server, (1, msg.clone().forwarded()));
+            assert_eq!(decoded, msg.forwarded());
+        }
+
+        // Without CAP_FORWARDED the mark is left out.
+        let (_, decoded) = relay(
+            &mut CacheCodec::default(),
+            &mut CacheCodec::default(),
+            (1, msg.clone().forwarded()),
+        );
+        assert_eq!(decoded, msg);
+
+        // A mark from a peer that didn't agree to it is ignored.
+        let (_, decoded) = relay(
+            &mut CacheCodec::negotiated(CAP_FORWARDED, Limits::default()),
+            &mut CacheCodec::default(),
+            (1, msg.clone().forwarded()),
+        );
+        assert_eq!(decoded, msg);
+    }
+
+    #[test]
+    fn test_ttl_millis() {
+        let payload = message::payload(1, "bar".into()).with_ttl_millis(1500);
+        let msg = message::request(Op::Set, "foo".into(), Some(payload.clone()));
+        let batch = message::batch_request(Op::Set, vec![("foo".into(), Some(payload))]);
+
+        let mut client = CacheCodec::negotiated(CAPABILITIES, Limits::default());
+        let mut server = CacheCodec::negotiated(CAPABILITIES, Limits::default());
+        for msg in vec![msg.clone(), batch.clone()] {
+            assert_eq!(relay(&mut client, &mut server, (1, msg.clone())), (1, msg));
+        }
+
+        // Without CAP_TTL_MILLIS the ttl is rounded up to whole seconds.
+        let capabilities = CAPABILITIES & !CAP_TTL_MILLIS;
+        let mut client = CacheCodec::negotiated(capabilities, Limits::default());
+        let mut server = CacheCodec::negotiated(capabilities, Limits::default());
+        for msg in vec![msg, batch] {
+            let (_, decoded) = relay(&mut client, &mut server, (1, msg));
+            let payload = match decoded {
+                Message::BatchRequest(_, ref entries, ..) => entries[0].1.clone(),
+                ref decoded => decoded.payload().cloned(),
+            };
+            assert_eq!(payload.unwrap().ttl_millis(), 2000);
+        }
+    }
+
+    fn small_limits() -> Limits {
+        Limits {
+            max_key_len: 8,
+            max_value_len: 16,
+            max_frame_len: 64,
+            hard_frame_len: 1024,
+        }
+    }
+
+    #[test]
+    fn test_key_too_large() {
+        let mut codec = CacheCodec::with_limits(small_limits());
+        let mut buf = BytesMut::new();
+        let get = message::request(Op::Get, "foo".into(), None);
+        let long = message::request(Op::Get, "too long a key".into(), None);
+        codec.encode((1, long), &mut buf).unwrap();
+        codec.encode((2, get.clone()), &mut buf).unwrap();
+
+        let (id, response) = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(id, 1);
+        assert_eq!(response.op(), Op::Get);
+        assert_eq!(response.code(), Code::TooLarge);
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), (2, get));
+    }
+
+    #[test]
+    fn test_value_too_large_discarded() {
+        let mut codec = CacheCodec::with_limits(small_limits());
+        let mut frames = BytesMut::new();
+        let set = message::request(Op::Set, "foo".into(), Some(message::payload(1, vec![7; 100])));
+        let get = message::request(Op::Get, "foo".into(), None);
+        codec.encode((1, set), &mut frames).unwrap();
+        codec.encode((2, get.clone()), &mut frames).unwrap();
+
+        // The oversize frame is answered from its header alone, then discarded as it arrives.
+        let mut buf = frames.split_to(HEADER_LEN);
+        let (id, response) = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!((id, response.code()), (1, Code::TooLarge));
+        while frames.len() > HEADER_LEN + 3 {
+            buf.extend_from_slice(&frames.split_to(10));
+            assert!(codec.decode(&mut buf).unwrap().is_none());
+        }
+        buf.extend_from_slice(&frames);
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), (2, get));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_batch_too_large() {
+        let mut codec = CacheCodec::with_limits(small_limits());
+        let mut buf = BytesMut::new();
+        let keys = (0..10).map(|i| (vec![i], None)).collect();
+        codec.encode((1, message::batch_request(Op::Get, keys)), &mut buf).unwrap();
+
+        let (_, response) = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(response.code(), Code::TooLarge);
+        assert!(buf.is_empty());
+        // Each entry is held to the key and value limits, even when the frame is within its own.
+        let mut codec = CacheCodec::with_limits(Limits {
+            max_frame_len: 1024,
+            ..small_limits()
+        });
+        let set = |len| {
+            let payload = message::payload(1, vec![7; len]);
+            message::batch_request(Op::Set, vec![("k".into(), Some(payload))])
+        };
+        let (value, fits) = (set(17), set(16));
+        let key = message::batch_request(Op::Get, vec![("too long a key".into(), None)]);
+        codec.encode((1, value), &mut buf).unwrap();
+        codec.encode((2, key), &mut buf).unwrap();
+        codec.encode((3, fits.clone()), &mut buf).unwrap();
+        for id in 1..3 {
+            let (decoded_id, response) = codec.decode(&mut buf).unwrap().unwrap();
+            assert_eq!((decoded_id, response.code()), (id, Code::TooLarge));
+        }
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), (3, fits));
+    }
+
+    #[test]
+    fn test_hard_only() {
+        let mut codec = CacheCodec::with_limits(Limits::hard_only(1024));
+        let mut buf = BytesMut::new();
+        let hit = message::response(Op::Get, Code::Hit, Some(message::payload(1, vec![7; 100])));
+        codec.encode((1, hit.clone()), &mut buf).unwrap();
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), (1, hit));
+
+        assert!(small_limits().validate().is_ok());
+        assert!(Limits { hard_frame_len: 32, ..small_limits() }.validate().is_err());
+    }
+
+    #[test]
+    fn test_hard_limit() {
+        let mut codec = CacheCodec::with_limits(small_limits());
+        let mut buf = BytesMut::new();
+        codec.encode((1, message::request(Op::Get, vec![0; 2048], None)), &mut buf).unwrap();
+        assert!(codec.decode(&mut buf).is_err());
+
+        // Lengths that would overflow are over the hard limit too.
+        let mut buf = BytesMut::new();
+        buf.put_u64::<BigEndian>(1);
+        buf.put_u8(0);
+        buf.put_u8(Op::Set as u8);
+        buf.put_u64::<BigEndian>(u64::max_value());
+        buf.put_u32::<BigEndian>(u32::max_value());
+        assert!(CacheCodec::default().decode(&mut buf).is_err());
+    }
+
+    fn random_payload<R: Rng>(rng: &mut R) -> Option<Payload> {
+        if rng.gen() {
+            return None;
+        }
+        let len = rng.gen_range(1, 40);
+        let data = rng.gen_iter().take(len).collect();
+        let mut payload = message::payload(rng.gen(), data);
+        if rng.gen() {
+            payload = payload.with_ttl(rng.gen());
+        }
+        if rng.gen() {
+            payload = payload.with_version(rng.gen());
+        }
+        Some(payload)
+    }
+
+    fn random_message<R: Rng>(rng: &mut R) -> Message {
+        let op = Op::try_from(rng.gen_range(0, 12)).unwrap();
+        let key_len = rng.gen_range(0, 20);
+        let key = rng.gen_iter().take(key_len).collect();
+        match rng.gen_range(0, 4) {
+            0 => message::request(op, key, random_payload(rng)),
+            1 => {
+                let code = Code::try_from(rng.gen_range(1, 8)).unwrap();
+                message::response(op, code, random_payload(rng))
+            }
+            2 => {
+                let len = rng.gen_range(0, 4);
+                let entries = (0..len).map(|i| (vec![i], random_payload(rng))).collect();
+                message::batch_request(op, entries)
+            }
+            _ => {
+                let len = rng.gen_range(0, 4);
+                let results = (0..len).map(|_| (Code::Hit, random_payload(rng))).collect();
+                message::batch_response(op, results)
+            }
+        }
+    }
+
+    /// Every proper prefix of a frame is incomplete, and the whole frame decodes to what was
+    /// encoded.
+    #[test]
+    fn test_truncated_frames() {
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        for i in 0..500 {
+            let msg = random_message(&mut rng);
+            let mut codec = if i % 2 == 0 {
+                CacheCodec::default()
+            } else {
+                checksummed()
+            };
+            let mut frame = BytesMut::new();
+            codec.encode((i, msg.clone()), &mut frame).unwrap();
+
+            for len in 0..frame.len() {
+                let mut partial = BytesMut::from(&frame[..len]);
+                assert!(codec.decode(&mut partial).unwrap().is_none(), "{} of {}", len, msg);
+                assert_eq!(partial.len(), len);
+            }
+            assert_eq!(codec.decode(&mut frame).unwrap().unwrap(), (i, msg));
+            assert!(frame.is_empty());
+        }
+    }
+
+    /// Headers with arbitrary lengths are answered, rejected or waited on according to the
+    /// limits, without buffering more than a frame within them.
+    #[test]
+    fn test_hostile_headers() {
+        let mut rng = XorShiftRng::from_seed([5, 6, 7, 8]);
+        let limits = small_limits();
+        for _ in 0..2000 {
+            let payload_len = match rng.gen_range(0, 3) {
+                0 => rng.gen_range(0, 32),
+                1 => rng.gen_range(0, 4096),
+                _ => rng.gen(),
+            };
+            let key_len = match rng.gen_range(0, 3) {
+                0 => rng.gen_range(0, 16),
+                1 => rng.gen_range(0, 4096),
+                _ => rng.gen(),
+            };
+            let mut buf = BytesMut::with_capacity(HEADER_LEN + 64);
+            buf.put_u64::<BigEndian>(rng.gen());
+            buf.put_u8(rng.gen_range(0, 8));
+            buf.put_u8(rng.gen_range(0, 12));
+            buf.put_u64::<BigEndian>(payload_len);
+            buf.put_u32::<BigEndian>(key_len);
+            let tail: Vec<u8> = rng.gen_iter().take(64).collect();
+            buf.extend_from_slice(&tail);
+
+            let payload_len = payload_len as usize;
+            let key_len = key_len as usize;
+            let type_id_len = if payload_len > 0 { 4 } else { 0 };
+            let msg_len = (HEADER_LEN + type_id_len)
+                .saturating_add(payload_len)
+                .saturating_add(key_len);
+
+            let mut codec = CacheCodec::with_limits(limits);
+            match codec.decode(&mut buf) {
+                Err(_) => assert!(msg_len > limits.hard_frame_len),
+                Ok(None) => assert!(msg_len > buf.len() && msg_len <= limits.max_frame_len),
+                Ok(Some((_, msg))) => {
+                    if key_len > limits.max_key_len || payload_len > limits.max_value_len {
+                        assert_eq!(msg.code(), Code::TooLarge);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Garbage, fed in arbitrary chunks, never panics the decoder or makes it buffer more than a
+    /// frame within the limits.
+    #[test]
+    fn test_garbage() {
+        let mut rng = XorShiftRng::from_seed([9, 10, 11, 12]);
+        let limits = small_limits();
+        for _ in 0..200 {
+            let mut codec = CacheCodec::with_limits(limits);
+            let mut buf = BytesMut::new();
+            for _ in 0..50 {
+                let len = rng.gen_range(0, 64);
+                let chunk: Vec<u8> = rng.gen_iter().take(len).collect();
+                buf.extend_from_slice(&chunk);
+
+                let mut closed = false;
+                loop {
+                    match codec.decode(&mut buf) {
+                        Ok(Some(_)) => continue,
+                        Ok(None) => break,
+                        Err(_) => {
+                            closed = true;
+                            break;
+                        }
+                    }
+                }
+                if closed {
+                    break;
+                }
+                assert!(buf.len() <= limits.max_frame_len);
+            }
+        }
+    }
+
     #[bench]
     #[allow(unused_must_use)]
     fn bench_encoding(b: &mut Bencher) {
@@ -211,7 +1708,7 @@ mod tests {
             Code::Ok,
             Some(message::payload(3, "123124125".into())),
         );
-        let mut codec = CacheCodec;
+        let mut codec = CacheCodec::default();
         let req_id = 123 as RequestId;
 
         b.iter(|| {
@@ -227,7 +1724,7 @@ mod tests {
             Code::Ok,
             Some(message::payload(3, "123124125".into())),
         );
-        let mut codec = CacheCodec;
+        let mut codec = CacheCodec::default();
         let req_id = 123 as RequestId;
         let mut buf = BytesMut::new();
         codec.encode((req_id, msg.clone()), &mut buf).unwrap();
@@ -3,11 +3,13 @@ use std::fmt;
 use std::io;
 
 /// `ErrorKind`
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum ErrorKind {
     InvalidData,
     UnknownOp,
     BadMessage,
+    TooLarge,
+    NotNumeric,
     Other,
 }
 
@@ -18,11 +20,40 @@ impl fmt::Display for ErrorKind {
             ErrorKind::InvalidData => "InvalidData",
             ErrorKind::UnknownOp => "Unknown Op",
             ErrorKind::BadMessage => "Bad Message",
+            ErrorKind::TooLarge => "Too Large",
+            ErrorKind::NotNumeric => "Not Numeric",
         };
         write!(f, "{}", s)
     }
 }
 
+impl ErrorKind {
+    /// The number that identifies the kind in an error response, see `message::error_response`.
+    /// `Other` is 0, so that the untyped error responses of older servers read as `Other`.
+    pub fn code(&self) -> u32 {
+        match *self {
+            ErrorKind::Other => 0,
+            ErrorKind::InvalidData => 1,
+            ErrorKind::UnknownOp => 2,
+            ErrorKind::BadMessage => 3,
+            ErrorKind::TooLarge => 4,
+            ErrorKind::NotNumeric => 5,
+        }
+    }
+
+    /// The kind identified by `code`. Kinds this version doesn't know are `Other`.
+    pub fn from_code(code: u32) -> ErrorKind {
+        match code {
+            1 => ErrorKind::InvalidData,
+            2 => ErrorKind::UnknownOp,
+            3 => ErrorKind::BadMessage,
+            4 => ErrorKind::TooLarge,
+            5 => ErrorKind::NotNumeric,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
 /// `Error`
 #[derive(Debug)]
 pub struct Error {
@@ -37,6 +68,10 @@ impl Error {
             description: description.to_owned(),
         }
     }
+
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
 }
 
 impl error::Error for Error {
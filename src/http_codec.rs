@@ -0,0 +1,510 @@
+use tokio_io::codec::{Encoder, Decoder};
+use std::io;
+use std::str;
+use bytes::BytesMut;
+use codec::Limits;
+use message::{self, Message, Op, Code};
+use text_codec;
+use error;
+
+/// The largest request line and headers we will buffer, besides the key in the path.
+const MAX_HEAD_LEN: usize = 8 * 1024;
+
+/// The header carrying the payload's `type_id`.
+const TYPE_ID_HEADER: &str = "X-Type-Id";
+/// The header carrying the payload's ttl in seconds.
+const TTL_HEADER: &str = "X-Ttl";
+/// The header carrying the entry's version on a `GET`.
+const VERSION_HEADER: &str = "X-Version";
+
+/// A codec for a small HTTP/1.1 API over the cache.
+///
+/// - `GET /keys/{key}` gets a key. The body is the value, and the `X-Type-Id`, `X-Ttl` and
+/// `X-Version` headers carry the rest of the payload.
+/// - `PUT /keys/{key}` sets a key to the request body, with the `type_id` and ttl taken from the
+/// `X-Type-Id` and `X-Ttl` headers. `type_id` defaults to `TYPE_ID_UTF8`, and ttl to none.
+/// - `DELETE /keys/{key}` deletes a key.
+/// - `GET /stats` responds with the stats as a JSON object.
+///
+/// Keys are percent-decoded. Connections are always kept alive, and chunked request bodies aren't
+/// supported. Requests that don't map onto the cache are decoded into a `Message::Response`
+/// carrying the error, which `serve` writes back without calling the service.
+///
+/// A key may be up to `max_key_len` long, and a body up to `max_value_len`, see `Limits`.
+pub struct HttpCodec {
+    limits: Limits,
+}
+
+impl Default for HttpCodec {
+    fn default() -> HttpCodec {
+        HttpCodec::with_limits(Limits::default())
+    }
+}
+
+impl HttpCodec {
+    pub fn with_limits(limits: Limits) -> HttpCodec {
+        HttpCodec { limits: limits }
+    }
+}
+
+/// How to write the HTTP response to a decoded request.
+#[derive(Debug, PartialEq, Clone)]
+pub enum HttpRequest {
+    Get,
+    Put,
+    Delete,
+    Stats,
+    /// A request the codec rejected with this status, described by the response's payload.
+    Error(u16),
+}
+
+impl Decoder for HttpCodec {
+    type Item = (HttpRequest, Message);
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<(HttpRequest, Message)>, io::Error> {
+        let head_len = match buf.as_ref().windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(i) => i + 4,
+            // A percent-encoded key takes up to three bytes of the path for each of its own.
+            None if buf.len() > MAX_HEAD_LEN + 3 * self.limits.max_key_len => {
+                return Err(
+                    error::Error::new(error::ErrorKind::TooLarge, "request head too large").into(),
+                )
+            }
+            None => return Ok(None),
+        };
+
+        let head = match parse_head(&buf.as_ref()[..head_len]) {
+            Ok(head) => head,
+            Err(description) => {
+                // Without a head we can't tell how long the body is, so we assume there is none
+                // and carry on with whatever follows as the next request.
+                buf.split_to(head_len);
+                return Ok(Some(rejected(Op::Get, 400, description)));
+            }
+        };
+        if head.chunked {
+            // The end of a chunked body can't be found without decoding it, so nothing after it
+            // can be read either.
+            return Err(
+                error::Error::new(error::ErrorKind::InvalidData, "chunked bodies are not supported")
+                    .into(),
+            );
+        }
+        if head.content_length > self.limits.max_value_len {
+            return Err(
+                error::Error::new(error::ErrorKind::TooLarge, "request body too large").into(),
+            );
+        }
+        if buf.len() < head_len + head.content_length {
+            return Ok(None);
+        }
+
+        let frame = buf.split_to(head_len + head.content_length);
+        let body = frame[head_len..].to_vec();
+        Ok(Some(request(head, body, &self.limits)))
+    }
+}
+
+/// The request line and the headers we care about.
+struct Head {
+    method: String,
+    path: String,
+    content_length: usize,
+    chunked: bool,
+    type_id: Option<String>,
+    ttl: Option<String>,
+}
+
+/// Parse a request head, or describe why it's malformed.
+fn parse_head(head: &[u8]) -> Result<Head, &'static str> {
+    let head = str::from_utf8(head).map_err(|_| "request head is not utf8")?;
+    let mut lines = head.split("\r\n");
+
+    let request_line = lines.next().unwrap_or("");
+    let mut parts = request_line.split(' ');
+    let (method, path, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(path), Some(version)) => (method, path, version),
+        _ => return Err("malformed request line"),
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err("unsupported http version");
+    }
+
+    let mut parsed = Head {
+        method: method.to_owned(),
+        path: path.to_owned(),
+        content_length: 0,
+        chunked: false,
+        type_id: None,
+        ttl: None,
+    };
+    for line in lines.filter(|line| !line.is_empty()) {
+        let (name, value) = match line.find(':') {
+            Some(i) => (line[..i].to_lowercase(), line[i + 1..].trim()),
+            None => return Err("malformed header"),
+        };
+        if name == "content-length" {
+            parsed.content_length = value.parse().map_err(|_| "invalid content length")?;
+        } else if name == "transfer-encoding" {
+            parsed.chunked = true;
+        } else if name == TYPE_ID_HEADER.to_lowercase() {
+            parsed.type_id = Some(value.to_owned());
+        } else if name == TTL_HEADER.to_lowercase() {
+            parsed.ttl = Some(value.to_owned());
+        }
+    }
+    Ok(parsed)
+}
+
+/// Route a request onto a `Message`, or onto an error response.
+fn request(head: Head, body: Vec<u8>, limits: &Limits) -> (HttpRequest, Message) {
+    if head.path == "/stats" {
+        return match head.method.as_str() {
+            "GET" => (HttpRequest::Stats, message::request(Op::Stats, vec![], None)),
+            _ => rejected(Op::Stats, 405, "method not allowed"),
+        };
+    }
+
+    let op = method_op(&head.method);
+
+    if !head.path.starts_with("/keys/") {
+        return rejected(op, 404, "not found");
+    }
+    let key = match percent_decode(&head.path["/keys/".len()..]) {
+        Some(ref key) if key.is_empty() => return rejected(op, 404, "not found"),
+        Some(ref key) if key.len() > limits.max_key_len => return rejected(op, 414, "key too long"),
+        Some(key) => key,
+        None => return rejected(op, 400, "invalid percent-encoding in key"),
+    };
+
+    match head.method.as_str() {
+        "GET" => (HttpRequest::Get, message::request(Op::Get, key, None)),
+        "DELETE" => (HttpRequest::Delete, message::request(Op::Del, key, None)),
+        "PUT" => {
+            let type_id = match head.type_id.map(|t| t.parse::<u32>()) {
+                None => message::TYPE_ID_UTF8,
+                Some(Ok(type_id)) => type_id,
+                Some(Err(_)) => return rejected(op, 400, "invalid X-Type-Id header"),
+            };
+            let ttl = match head.ttl.map(|t| t.parse::<u32>()) {
+                None => 0,
+                Some(Ok(ttl)) => ttl,
+                Some(Err(_)) => return rejected(op, 400, "invalid X-Ttl header"),
+            };
+            let payload = message::payload(type_id, body).with_ttl(ttl);
+            (HttpRequest::Put, message::request(Op::Set, key, Some(payload)))
+        }
+        _ => rejected(op, 405, "method not allowed"),
+    }
+}
+
+/// The op a method maps onto. A method we don't serve has none, and is answered as a get.
+fn method_op(method: &str) -> Op {
+    match method {
+        "PUT" => Op::Set,
+        "DELETE" => Op::Del,
+        _ => Op::Get,
+    }
+}
+
+/// The response to a request rejected with `status`, answered as `op`.
+fn rejected(op: Op, status: u16, description: &str) -> (HttpRequest, Message) {
+    let err = error::Error::new(error::ErrorKind::BadMessage, description);
+    (HttpRequest::Error(status), message::error_response(op, &err))
+}
+
+fn percent_decode(s: &str) -> Option<Vec<u8>> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let byte = bytes
+                .get(i + 1..i + 3)
+                .and_then(|hex| str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            match byte {
+                Some(byte) => decoded.push(byte),
+                None => return None,
+            }
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Some(decoded)
+}
+
+impl Encoder for HttpCodec {
+    type Item = (HttpRequest, Message);
+    type Error = io::Error;
+
+    fn encode(&mut self, item: (HttpRequest, Message), buf: &mut BytesMut) -> io::Result<()> {
+        let (req, msg) = item;
+
+        if let HttpRequest::Error(status) = req {
+            put_response(buf, status, &[], description(&msg).as_bytes());
+            return Ok(());
+        }
+        if msg.code() == Code::Error {
+            put_response(buf, 500, &[], description(&msg).as_bytes());
+            return Ok(());
+        }
+
+        match (req, msg.code(), msg.payload()) {
+            (HttpRequest::Get, Code::Hit, Some(payload)) => {
+                let headers = [
+                    (TYPE_ID_HEADER, payload.type_id().to_string()),
+                    (TTL_HEADER, payload.ttl().to_string()),
+                    (VERSION_HEADER, payload.version().to_string()),
+                ];
+                // Counters are shown in decimal, as the other frontends do.
+                match payload.as_u64() {
+                    Some(n) => put_response(buf, 200, &headers, n.to_string().as_bytes()),
+                    None => put_response(buf, 200, &headers, payload.data()),
+                }
+            }
+            (HttpRequest::Put, Code::Ok, _) |
+            (HttpRequest::Delete, Code::Hit, _) => put_response(buf, 204, &[], &[]),
+            (HttpRequest::Put, Code::TooLarge, _) => put_response(buf, 413, &[], b"too large"),
+            (_, Code::ReadOnly, _) => put_response(buf, 403, &[], b"read only replica"),
+            (_, Code::Moved, _) => {
+                let body = format!("moved to {}", description(&msg));
+                put_response(buf, 421, &[], body.as_bytes());
+            }
+            (HttpRequest::Get, Code::Miss, _) |
+            (HttpRequest::Delete, Code::Miss, _) => put_response(buf, 404, &[], b"not found"),
+            (HttpRequest::Stats, _, Some(payload)) => {
+                let json = stats_json(&String::from_utf8_lossy(payload.data()));
+                let headers = [("Content-Type", "application/json".to_owned())];
+                put_response(buf, 200, &headers, json.as_bytes());
+            }
+            _ => put_response(buf, 500, &[], format!("unexpected response {}", msg).as_bytes()),
+        }
+        Ok(())
+    }
+}
+
+fn put_response(buf: &mut BytesMut, status: u16, headers: &[(&str, String)], body: &[u8]) {
+    let reason = match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        414 => "URI Too Long",
+        421 => "Misdirected Request",
+        _ => "Internal Server Error",
+    };
+
+    let mut head = format!("HTTP/1.1 {} {}\r\nContent-Length: {}\r\n", status, reason, body.len());
+    for &(name, ref value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+
+    buf.extend_from_slice(head.as_bytes());
+    buf.extend_from_slice(body);
+}
+
+/// The description carried by an error response.
+fn description(msg: &Message) -> String {
+    match msg.payload() {
+        Some(payload) => String::from_utf8_lossy(payload.data()).into_owned(),
+        None => format!("unexpected response {}", msg),
+    }
+}
+
+/// Format a stats string as a JSON object. Decimal numbers and lists of them are written as is,
+/// anything else, including `NaN` and `inf`, as a string.
+fn stats_json(stats: &str) -> String {
+    let fields: Vec<String> = text_codec::parse_stats(stats)
+        .into_iter()
+        .map(|(name, value)| {
+            let value = if is_decimal(&value) {
+                value
+            } else if value.starts_with('[') && value.ends_with(']') &&
+                       value[1..value.len() - 1].split(',').all(|n| is_decimal(n) || n.is_empty())
+            {
+                value.replace(",", ", ")
+            } else {
+                json_string(&value)
+            };
+            format!("{}: {}", json_string(&name), value)
+        })
+        .collect();
+    format!("{{{}}}", fields.join(", "))
+}
+
+/// Whether `s` is a plain decimal number, such as `-12` or `3.5`, which JSON can carry as is.
+fn is_decimal(s: &str) -> bool {
+    let unsigned = s.trim_left_matches('-');
+    let mut parts = unsigned.splitn(2, '.');
+    let int = parts.next().unwrap_or("");
+    let frac = parts.next().unwrap_or("0");
+    s.len() - unsigned.len() <= 1 && !int.is_empty() && !frac.is_empty() &&
+        int.chars().chain(frac.chars()).all(|c| c.is_ascii_digit())
+}
+
+fn json_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c < ' ' => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::time::Duration;
+    use service::Protocol;
+    use testing::{self, exchange};
+
+    fn decode(input: &[u8]) -> (HttpRequest, Message) {
+        let mut buf = BytesMut::from(input);
+        let item = HttpCodec::default().decode(&mut buf).unwrap().unwrap();
+        assert!(buf.is_empty());
+        item
+    }
+
+    #[test]
+    fn test_decode() {
+        let (req, msg) = decode(
+            b"PUT /keys/foo%20bar HTTP/1.1\r\nHost: x\r\nx-type-id: 7\r\nX-Ttl: 60\r\n\
+              Content-Length: 3\r\n\r\nbaz",
+        );
+        assert_eq!(req, HttpRequest::Put);
+        assert_eq!(
+            msg,
+            message::request(Op::Set, "foo bar".into(), Some(message::payload(7, "baz".into()).with_ttl(60)))
+        );
+
+        let (req, msg) = decode(b"GET /keys/foo HTTP/1.1\r\n\r\n");
+        assert_eq!(req, HttpRequest::Get);
+        assert_eq!(msg, message::request(Op::Get, "foo".into(), None));
+
+        assert_eq!(decode(b"GET /nope HTTP/1.1\r\n\r\n").0, HttpRequest::Error(404));
+        assert_eq!(decode(b"POST /keys/foo HTTP/1.1\r\n\r\n").0, HttpRequest::Error(405));
+        assert_eq!(decode(b"GET /keys/%zz HTTP/1.1\r\n\r\n").0, HttpRequest::Error(400));
+        let (req, _) = decode(b"PUT /keys/foo HTTP/1.1\r\nX-Ttl: soon\r\n\r\n");
+        assert_eq!(req, HttpRequest::Error(400));
+    }
+
+    #[test]
+    fn test_decode_partial() {
+        let mut codec = HttpCodec::default();
+        let request = b"PUT /keys/foo HTTP/1.1\r\nContent-Length: 3\r\n\r\nbar";
+        for i in 1..request.len() {
+            let mut buf = BytesMut::from(&request[..i]);
+            assert!(codec.decode(&mut buf).unwrap().is_none());
+        }
+
+        let mut buf = BytesMut::from(&b"garbage\r\n\r\nGET /keys/foo HTTP/1.1\r\n\r\n"[..]);
+        let (req, _) = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(req, HttpRequest::Error(400));
+        let (req, _) = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(req, HttpRequest::Get);
+
+        let chunked = b"PUT /keys/foo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let mut buf = BytesMut::from(&chunked[..]);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut codec = HttpCodec::with_limits(Limits {
+            max_key_len: 4,
+            max_value_len: 8,
+            ..Limits::default()
+        });
+
+        let mut buf = BytesMut::from(&b"GET /keys/fooba HTTP/1.1\r\n\r\n"[..]);
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap().0, HttpRequest::Error(414));
+        let mut buf = BytesMut::from(&b"GET /keys/%66%6f%6f%62 HTTP/1.1\r\n\r\n"[..]);
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap().0, HttpRequest::Get);
+
+        let mut buf = BytesMut::from(&b"PUT /keys/foo HTTP/1.1\r\nContent-Length: 9\r\n\r\n"[..]);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_encode_counter() {
+        let msg = message::response(Op::Get, Code::Hit, Some(message::u64_payload(42)));
+        let mut buf = BytesMut::new();
+        HttpCodec::default().encode((HttpRequest::Get, msg), &mut buf).unwrap();
+        let expected = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nX-Type-Id: 8\r\nX-Ttl: 0\r\n\
+                        X-Version: 0\r\n\r\n42";
+        assert_eq!(String::from_utf8_lossy(&buf), expected);
+    }
+
+    #[test]
+    fn test_stats_json() {
+        assert_eq!(
+            stats_json("keys: 2, shard_keys: [1, 1], avg_request_time: 3 μs, name: \"x\""),
+            "{\"keys\": 2, \"shard_keys\": [1, 1], \"avg_request_time\": 3, \"name\": \"\\\"x\\\"\"}"
+        );
+        assert_eq!(
+            stats_json("a: -1.5, b: NaN, c: inf, d: 1e3, e: [1, NaN]"),
+            "{\"a\": -1.5, \"b\": \"NaN\", \"c\": \"inf\", \"d\": \"1e3\", \"e\": \"[1,NaN]\"}"
+        );
+    }
+
+    #[test]
+    fn test_transcript() {
+        let addr = testing::spawn_cache(Protocol::Http);
+
+        let mut stream = TcpStream::connect(&addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        exchange(
+            &mut stream,
+            "GET /keys/foo HTTP/1.1\r\n\r\n",
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 9\r\n\r\nnot found",
+        );
+        exchange(
+            &mut stream,
+            "PUT /keys/foo HTTP/1.1\r\nContent-Length: 3\r\n\r\nbar",
+            "HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n",
+        );
+        exchange(
+            &mut stream,
+            "GET /keys/foo HTTP/1.1\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nContent-Length: 3\r\nX-Type-Id: 1\r\nX-Ttl: 0\r\nX-Version: 1\r\n\r\nbar",
+        );
+        exchange(
+            &mut stream,
+            "DELETE /keys/foo HTTP/1.1\r\n\r\n",
+            "HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n",
+        );
+        exchange(
+            &mut stream,
+            "DELETE /keys/foo HTTP/1.1\r\n\r\n",
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 9\r\n\r\nnot found",
+        );
+        exchange(
+            &mut stream,
+            "garbage\r\n\r\n",
+            "HTTP/1.1 400 Bad Request\r\nContent-Length: 22\r\n\r\nmalformed request line",
+        );
+
+        stream.write_all(b"GET /stats HTTP/1.1\r\n\r\n").unwrap();
+        let expected = "HTTP/1.1 200 OK\r\nContent-Length: ";
+        let mut buf = vec![0; expected.len()];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(String::from_utf8_lossy(&buf), expected);
+    }
+}
@@ -11,26 +11,84 @@
 //! achieve with Rust.
 //!
 //! TODOs include better benchmarks, more featureful clients (for rust and the command line), support
-//! for a more complete set of commands, and improving the binary protocol to at least include CRC checks.
+//! for a more complete set of commands, and improving the binary protocol.
 //!
 //! ## Features
 //!
 //! - Based on `tokio`
 //! - The TCP frontend speaks a multiplexed-binary protocol, detailed (poorly) in src/codec.rs.
-//! - Currently supports GET, SET, and DEL commands. CAS is conspicuously absent, but will be along eventually.
-//! - Storage is backed by an LRU cached based on a Linked Hash Map (provided by the lru-cache crate),
-//! all operations are threaded through a single worker, which has unsynchronized access to the store.
+//! It can also serve the memcached ASCII and binary protocols, and a subset of Redis' RESP protocol,
+//! on other ports, for existing memcached and Redis clients. There is also an HTTP API for
+//! shell scripts and services that only have an HTTP client.
+//! - Currently supports GET, SET, ADD, REPLACE, APPEND, PREPEND, DEL, TTL, CAS, INCR and DECR commands. Every write stamps the entry with a new
+//! version, which is returned on GET and can be passed to CAS for optimistic read-modify-write loops.
+//! - GET and SET can be batched, many keys to a frame, with a result per key.
+//! - Frames can carry a CRC32C checksum; a corrupted frame gets an error response instead of a
+//! dropped connection.
+//! - Connections open with a hello in which client and server agree on a protocol version and
+//! capabilities, such as checksums. Clients that skip the hello get the original protocol.
+//! - Keys can be set with a time to live; expired keys are removed when read and by a periodic sweep.
+//! - The cache can be saved to a snapshot file on request, on a timer and on shutdown, and loaded
+//! from it on startup, so that a restarted server doesn't start cold.
+//! - Every write can also be appended to an operation log, which is replayed on startup, so that
+//! writes made since the last snapshot survive a crash. Each snapshot compacts the log.
+//! - A server can be a read only replica of another, which it copies and then follows write by
+//! write, resyncing whenever it reconnects. Its lag behind the primary is in its stats.
+//! - `ClusterClient` spreads keys over several servers with a consistent-hash ring, splitting
+//! batches by server, so that adding or removing a server only moves the keys it owns.
+//! - Servers can also form a cluster themselves, from a config file listing its nodes. Any node
+//! takes any key, forwarding requests for keys it doesn't own to their owner, or answering them
+//! with the owner's address if forwarding is disabled.
+//! - Storage is backed by an LRU cached based on a Linked Hash Map (provided by the linked-hash-map
+//! crate), all operations are threaded through a single worker, which has unsynchronized access to
+//! the store.
+//! The store can optionally be split into shards, each with its own worker, to make use of more cores.
 //!
 //! ## Usage
 //!
 //! Start a server: `cargo run -- 127.0.0.1:12345 server`
 //!
+//! Start a server bounded to 512MB instead of 2,000,000 entries: `cargo run -- 127.0.0.1:12345 server --memory_limit 512M`
+//!
+//! Start a server with 4 shards: `cargo run -- 127.0.0.1:12345 server --shards 4`
+//!
+//! Also serve memcached clients: `cargo run -- 127.0.0.1:12345 server --memcached_addr 127.0.0.1:11211`
+//! or, for the binary protocol, `--memcached_binary_addr 127.0.0.1:11212`
+//!
+//! Also serve Redis clients: `cargo run -- 127.0.0.1:12345 server --resp_addr 127.0.0.1:6379`
+//!
+//! Also serve HTTP: `cargo run -- 127.0.0.1:12345 server --http_addr 127.0.0.1:8080`, then
+//! `curl -X PUT -H 'X-Ttl: 60' -d bar localhost:8080/keys/foo` and `curl localhost:8080/stats`
+//!
 //! Set a key: `cargo run -- 127.0.0.1:12345 client SET foo bar`
 //!
 //! Get a key: `cargo run -- 127.0.0.1:12345 client GET foo`
 //!
+//! Set a key that expires after 60 seconds: `cargo run -- 127.0.0.1:12345 client SET foo bar --ttl 60`
+//!
+//! Delete a key: `cargo run -- 127.0.0.1:12345 client DEL foo`
+//!
 //! Get stats: `cargo run -- 127.0.0.1:12345 client STATS`
 //!
+//! Give up on a command the server hasn't got to within 50ms: `cargo run -- 127.0.0.1:12345 client --timeout 50 GET foo`
+//!
+//! Save the cache every 5 minutes and on shutdown, and load it on startup: `cargo run -- 127.0.0.1:12345 server --snapshot_path /var/lib/rcache.snap --snapshot_interval 300`
+//!
+//! Save the cache now: `cargo run -- 127.0.0.1:12345 client SNAPSHOT`
+//!
+//! Also log every write, syncing the log before answering: `cargo run -- 127.0.0.1:12345 server --snapshot_path /var/lib/rcache.snap --log_path /var/lib/rcache.log --fsync always`
+//!
+//! Serve a read only replica of that server: `cargo run -- 127.0.0.1:12346 server --replica_of 127.0.0.1:12345`
+//!
+//! Serve a node of a cluster, with one node to a line of cluster.conf, such as `127.0.0.1:12345 2`
+//! for a node of weight 2: `cargo run -- 127.0.0.1:12345 server --cluster_config cluster.conf`.
+//! Add `--no_forwarding` to answer requests for other nodes' keys with their address instead.
+//!
+//! Limit the size of keys, values and frames: `cargo run -- 127.0.0.1:12345 server --max_key_size 1K --max_value_size 1M`
+//!
+//! Serve and connect over a Unix domain socket instead of TCP: `cargo run -- unix:/tmp/rcache.sock server --socket_mode 660`,
+//! then `cargo run -- unix:/tmp/rcache.sock client GET foo`
+//!
 //!
 //! ## Performance
 //!
@@ -39,6 +97,10 @@
 //! clients making 500 requests each. However, I've no doubt that there were numerous issues in my
 //! benchmarking methodology. Even so, it's neat that a weekend implementation project can get into
 //! the same ballpark as memcached.
+//!
+//! The cache's request pipeline is benchmarked by `bench_process` in `cache.rs`, run with
+//! `cargo bench bench_process`, which times a batch of 1000 gets and sets, all in flight at once.
+//! An idle worker parks rather than spinning on its deque, so an idle server uses no CPU.
 
 extern crate time;
 extern crate futures;
@@ -51,15 +113,32 @@ extern crate tokio_service;
 extern crate deque;
 extern crate bytes;
 extern crate rand;
-extern crate lru_cache;
+extern crate linked_hash_map;
+#[macro_use]
+extern crate log;
 extern crate test;
 
 pub mod client;
+pub mod cluster;
+pub mod membership;
 pub mod message;
 pub mod cache;
 pub mod stats;
 pub mod service;
+pub mod error;
 
 mod codec;
+mod crc32c;
+mod text_codec;
+mod binary_codec;
+mod resp_codec;
+mod http_codec;
+mod uds;
+mod store;
+mod snapshot;
+mod oplog;
+mod replication;
+mod ring;
 mod proto;
-mod error;
+#[cfg(test)]
+mod testing;
@@ -0,0 +1,520 @@
+use futures::{future, Future, Stream};
+use futures::future::Shared;
+use futures::sync::{mpsc, oneshot};
+use tokio_core::reactor::{Core, Handle, Timeout};
+use tokio_service::Service;
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use client::Client;
+use cluster;
+use error;
+use message::{self, Message, Op, Code, Payload};
+use ring::{Ring, DEFAULT_VNODES};
+use service::Addr;
+
+/// How long a node waits for the owner of a key to answer a request it forwarded.
+const FORWARD_TIMEOUT_MS: u64 = 5000;
+/// The most requests waiting for the forwarding thread to send them on. A request that finds the
+/// queue full is answered with an error rather than queued.
+const FORWARD_QUEUE_LEN: usize = 1024;
+
+/// A request to forward to the node at an index of `Ring::nodes`, and where to send the answer.
+type Forward = (usize, Message, oneshot::Sender<io::Result<Message>>);
+
+/// A node's view of the cluster it is part of: every node, and the ring that maps each key to
+/// the node that owns it, see `ring::Ring`.
+///
+/// The nodes are listed in a config file, see `parse`, and every node has to be given the same
+/// list so that they agree on who owns each key. At least one node needs a positive weight.
+///
+/// Any node takes requests for any key. Those for keys another node owns are forwarded to it over
+/// the rcache protocol by a thread of its own, or, with forwarding disabled, answered with
+/// `Code::Moved` and the address of the owner, for the client to go there itself. A batch is
+/// split by owner, and its results put back together in order. A forwarded request is marked as
+/// such, see `Message::forwarded`, and served by the node it was forwarded to, so that nodes whose
+/// lists differ disagree on where some keys live, but don't bounce requests between them.
+pub struct Membership {
+    /// The index of this node in `Ring::nodes`.
+    this: usize,
+    ring: Ring<Addr>,
+    forwarder: Option<Mutex<mpsc::Sender<Forward>>>,
+}
+
+impl Membership {
+    /// The cluster of the nodes listed in the config file at `path`, in which this node is the
+    /// one at `this`.
+    pub fn load(path: &Path, this: &Addr, forward: bool) -> io::Result<Membership> {
+        let mut config = String::new();
+        File::open(path)?.read_to_string(&mut config)?;
+        let nodes = parse(&config).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e))
+        })?;
+        Membership::new(nodes, this, forward)
+    }
+
+    /// The cluster of `nodes`, given as (address, weight) pairs, in which this node is the one
+    /// at `this`. Requests for other nodes' keys are forwarded to them if `forward` is set.
+    pub fn new(nodes: Vec<(Addr, u32)>, this: &Addr, forward: bool) -> io::Result<Membership> {
+        let mut ring = Ring::new(DEFAULT_VNODES);
+        for (addr, weight) in nodes {
+            ring.add(addr, weight);
+        }
+        if ring.is_empty() {
+            let description = "no node of the cluster has a positive weight";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, description));
+        }
+        // Compare names, which leave out the permissions of a Unix domain socket.
+        let name = this.to_string();
+        let this = match ring.nodes().iter().position(|&(ref addr, _)| addr.to_string() == name) {
+            Some(i) => i,
+            None => {
+                let description = format!("{} isn't one of the nodes of the cluster", name);
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, description));
+            }
+        };
+
+        let forwarder = if forward {
+            let (snd, rcv) = mpsc::channel(FORWARD_QUEUE_LEN);
+            let nodes = ring.nodes().iter().map(|&(ref addr, _)| addr.clone()).collect();
+            thread::Builder::new()
+                .name("rcache-forward".to_owned())
+                .spawn(move || if let Err(e) = Forwarder::run(nodes, rcv) {
+                    warn!("Stopped forwarding: {}.", e);
+                })?;
+            Some(Mutex::new(snd))
+        } else {
+            None
+        };
+
+        Ok(Membership {
+            this: this,
+            ring: ring,
+            forwarder: forwarder,
+        })
+    }
+
+    /// The address of this node.
+    pub fn this(&self) -> &Addr {
+        &self.ring.nodes()[self.this].0
+    }
+
+    /// The nodes of the cluster, and their weights.
+    pub fn nodes(&self) -> &[(Addr, u32)] {
+        self.ring.nodes()
+    }
+
+    /// The address of the node that owns `key`.
+    pub fn owner(&self, key: &[u8]) -> &Addr {
+        self.ring.get(key).unwrap()
+    }
+
+    /// Answer `req`, serving the keys this node owns, and every key of a request forwarded to it,
+    /// with `local`, see the type docs.
+    pub fn call<S>(&self, req: Message, local: &S) -> Box<Future<Item = Message, Error = io::Error>>
+    where
+        S: Service<Request = Message, Response = Message, Error = io::Error>,
+        S::Future: 'static,
+    {
+        if req.is_forwarded() {
+            return Box::new(local.call(req));
+        }
+        match req {
+            req @ Message::Request(..) => {
+                if !has_key(req.op()) {
+                    return Box::new(local.call(req));
+                }
+                let node = self.ring.index(req.key().unwrap()).unwrap();
+                self.send(node, req, local)
+            }
+            Message::BatchRequest(op, entries, deadline, forwarded) => {
+                let count = entries.len();
+                let parts = self.ring
+                    .split(entries)
+                    .into_iter()
+                    .map(|part| {
+                        let positions = part.positions;
+                        let req = Message::BatchRequest(op, part.entries, deadline, forwarded);
+                        self.send(part.node, req, local).map(move |resp| (positions, resp))
+                    })
+                    .collect::<Vec<_>>();
+                Box::new(future::join_all(parts).map(move |parts| cluster::merge(op, count, parts)))
+            }
+            req => Box::new(local.call(req)),
+        }
+    }
+
+    /// Answer `req`, all of whose keys `node` owns.
+    fn send<S>(
+        &self,
+        node: usize,
+        req: Message,
+        local: &S,
+    ) -> Box<Future<Item = Message, Error = io::Error>>
+    where
+        S: Service<Request = Message, Response = Message, Error = io::Error>,
+        S::Future: 'static,
+    {
+        if node == self.this {
+            return Box::new(local.call(req));
+        }
+        let owner = self.ring.nodes()[node].0.to_string();
+        let (op, entries) = (req.op(), entries(&req));
+        let forwarder = match self.forwarder {
+            Some(ref forwarder) => forwarder,
+            None => {
+                let payload = message::payload(message::TYPE_ID_UTF8, owner.into_bytes());
+                return Box::new(future::ok(answer(op, entries, Code::Moved, payload)));
+            }
+        };
+
+        let (snd, rcv) = oneshot::channel();
+        if let Err(e) = forwarder.lock().unwrap().try_send((node, req.forwarded(), snd)) {
+            let description = if e.is_full() {
+                format!("failed to forward to {}: too many requests to forward", owner)
+            } else {
+                format!("failed to forward to {}: not forwarding", owner)
+            };
+            return Box::new(future::ok(failed(op, entries, &description)));
+        }
+        Box::new(rcv.then(move |result| {
+            let description = match result {
+                Ok(Ok(resp)) => return Ok(resp),
+                Ok(Err(e)) => format!("failed to forward to {}: {}", owner, e),
+                Err(_) => format!("failed to forward to {}: not forwarding", owner),
+            };
+            Ok(failed(op, entries, &description))
+        }))
+    }
+}
+
+/// Parse a cluster config: a node to a line, as its address and an optional weight, which is 1 by
+/// default, such as `127.0.0.1:12345 2`. Blank lines, and lines that start with `#`, are skipped.
+pub fn parse(config: &str) -> Result<Vec<(Addr, u32)>, String> {
+    let mut nodes = Vec::new();
+    for (i, line) in config.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let weight = match fields.len() {
+            1 => 1,
+            2 => {
+                fields[1].parse().map_err(|_| {
+                    format!("line {}: Failed to parse weight: {}", i + 1, fields[1])
+                })?
+            }
+            _ => return Err(format!("line {}: Expected an address and a weight", i + 1)),
+        };
+        let addr = fields[0].parse().map_err(|e| format!("line {}: {}", i + 1, e))?;
+        nodes.push((addr, weight));
+    }
+    if nodes.is_empty() {
+        return Err("No nodes.".to_owned());
+    }
+    Ok(nodes)
+}
+
+/// Whether requests for `op` are for a key, rather than for the node.
+fn has_key(op: Op) -> bool {
+    match op {
+        Op::Stats | Op::Hello | Op::Snapshot | Op::Sync => false,
+        Op::Get | Op::Set | Op::Del | Op::Ttl | Op::Cas | Op::Incr | Op::Decr | Op::Add |
+        Op::Replace | Op::Append | Op::Prepend => true,
+    }
+}
+
+/// The number of entries of `req`, if it's a batch.
+fn entries(req: &Message) -> Option<usize> {
+    match *req {
+        Message::BatchRequest(_, ref entries, ..) => Some(entries.len()),
+        _ => None,
+    }
+}
+
+/// A response for `op` with the result (`code`, `payload`), repeated for each of the `entries`
+/// of a batch.
+fn answer(op: Op, entries: Option<usize>, code: Code, payload: Payload) -> Message {
+    match entries {
+        Some(count) => message::batch_response(op, vec![(code, Some(payload)); count]),
+        None => message::response(op, code, Some(payload)),
+    }
+}
+
+/// An error response for `op`, with the error repeated for each of the `entries` of a batch.
+fn failed(op: Op, entries: Option<usize>, description: &str) -> Message {
+    let e = error::Error::new(error::ErrorKind::Other, description);
+    let payload = message::error_response(op, &e).payload().cloned().unwrap();
+    answer(op, entries, Code::Error, payload)
+}
+
+/// A connection to a node, shared by the requests forwarded to it.
+type Connection = Shared<Box<Future<Item = Client, Error = io::Error>>>;
+
+/// Forwards requests to the other nodes, on an event loop of its own. Each node is connected to
+/// on the first request for it, and again on the next request after one failed.
+struct Forwarder {
+    nodes: Vec<Addr>,
+    connections: RefCell<Vec<Option<Connection>>>,
+    handle: Handle,
+}
+
+impl Forwarder {
+    /// Forward the `requests`, until every sender is dropped.
+    fn run(nodes: Vec<Addr>, requests: mpsc::Receiver<Forward>) -> io::Result<()> {
+        let mut core = Core::new()?;
+        let handle = core.handle();
+        let forwarder = Rc::new(Forwarder {
+            connections: RefCell::new(nodes.iter().map(|_| None).collect()),
+            nodes: nodes,
+            handle: handle.clone(),
+        });
+
+        let requests = requests.for_each(move |(node, req, snd)| {
+            handle.spawn(Forwarder::forward(&forwarder, node, req).then(|result| {
+                let _ = snd.send(result);
+                Ok(())
+            }));
+            Ok(())
+        });
+        let _ = core.run(requests);
+        Ok(())
+    }
+
+    fn forward(
+        this: &Rc<Self>,
+        node: usize,
+        req: Message,
+    ) -> Box<Future<Item = Message, Error = io::Error>> {
+        let timeout = match Timeout::new(Duration::from_millis(FORWARD_TIMEOUT_MS), &this.handle) {
+            Ok(timeout) => {
+                timeout.and_then(|_| -> io::Result<Message> {
+                    Err(io::Error::new(io::ErrorKind::TimedOut, "no response"))
+                })
+            }
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let request = this.connection(node)
+            .map_err(|e| io::Error::new(e.kind(), e.to_string()))
+            .and_then(move |client| client.call(req));
+
+        let forwarder = this.clone();
+        Box::new(request.select(timeout).then(move |result| match result {
+            Ok((resp, _)) => Ok(resp),
+            Err((e, _)) => {
+                // Reconnect for the next request, in case the connection is what failed.
+                forwarder.connections.borrow_mut()[node] = None;
+                Err(e)
+            }
+        }))
+    }
+
+    fn connection(&self, node: usize) -> Connection {
+        let mut connections = self.connections.borrow_mut();
+        if connections[node].is_none() {
+            let connect = Client::connect_addr(&self.nodes[node], &self.handle);
+            connections[node] = Some(connect.shared());
+        }
+        connections[node].clone().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use client;
+    use cache::Cache;
+    use service::{CacheService, ClusterService, Protocol};
+    use testing;
+    use text_codec;
+
+    /// Start a cluster of `count` nodes, each with a fresh cache.
+    fn spawn_cluster(count: usize, forward: bool) -> Vec<Addr> {
+        let listeners: Vec<_> = (0..count).map(|_| testing::bind()).collect();
+        let addrs: Vec<Addr> = listeners
+            .iter()
+            .map(|listener| Addr::Tcp(listener.local_addr().unwrap()))
+            .collect();
+        for listener in listeners {
+            let nodes = addrs.iter().map(|addr| (addr.clone(), 1)).collect();
+            let this = Addr::Tcp(listener.local_addr().unwrap());
+            testing::spawn(listener, Protocol::Rcache, move || {
+                let membership = Membership::new(nodes, &this, forward).unwrap();
+                ClusterService {
+                    inner: CacheService { cache: Arc::new(Cache::new(1024).unwrap()) },
+                    membership: Arc::new(membership),
+                }
+            });
+        }
+        addrs
+    }
+
+    fn keys() -> Vec<Vec<u8>> {
+        testing::keys(100)
+    }
+
+    #[test]
+    fn test_parse() {
+        let config = "# The cluster\n127.0.0.1:12345\n\n  127.0.0.1:12346 2\n\
+                      unix:/tmp/rcache.sock\n";
+        let nodes = parse(config).unwrap();
+        assert_eq!(
+            nodes,
+            vec![
+                (Addr::Tcp("127.0.0.1:12345".parse().unwrap()), 1),
+                (Addr::Tcp("127.0.0.1:12346".parse().unwrap()), 2),
+                ("unix:/tmp/rcache.sock".parse().unwrap(), 1),
+            ]
+        );
+
+        assert!(parse("# Nothing\n").is_err());
+        assert!(parse("127.0.0.1:12345 heavy").unwrap_err().starts_with("line 1"));
+        assert!(parse("127.0.0.1:12345\nlocalhost").unwrap_err().starts_with("line 2"));
+        assert!(parse("127.0.0.1:12345 1 2").is_err());
+
+        let nodes = parse("127.0.0.1:12345").unwrap();
+        let other = "127.0.0.1:12346".parse().unwrap();
+        assert!(Membership::new(nodes, &other, false).is_err());
+
+        // A cluster in which no node owns any keys.
+        let nodes = parse("127.0.0.1:12345 0\n127.0.0.1:12346 0").unwrap();
+        assert!(Membership::new(nodes, &other, false).is_err());
+    }
+
+    #[test]
+    fn test_forwarding() {
+        let nodes = spawn_cluster(3, true);
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let clients: Vec<Client> = nodes
+            .iter()
+            .map(|addr| core.run(Client::connect_addr(addr, &handle)).unwrap())
+            .collect();
+
+        // Any node takes any key.
+        let pairs: Vec<_> = keys().into_iter().map(|key| (key.clone(), key)).collect();
+        let resp = core.run(clients[0].set_many(pairs)).unwrap();
+        assert_eq!(resp, message::batch_response(Op::Set, vec![(Code::Ok, None); 100]));
+        for key in keys() {
+            let resp = core.run(clients[1].get(key.clone())).unwrap();
+            assert_eq!(resp.payload().unwrap().data(), key.as_slice());
+        }
+        let resp = core.run(clients[2].get_many(keys())).unwrap();
+        let results = match resp {
+            Message::BatchResponse(Op::Get, results) => results,
+            msg => panic!("unexpected response {}", msg),
+        };
+        for (key, result) in keys().iter().zip(results) {
+            assert_eq!(result.1.unwrap().data(), key.as_slice());
+        }
+        let resp = core.run(clients[2].incr_or_init(b"n".to_vec(), 1, 5, 0)).unwrap();
+        assert_eq!(resp.payload().unwrap().as_u64(), Some(5));
+        let resp = core.run(clients[0].incr(b"n".to_vec(), 1)).unwrap();
+        assert_eq!(resp.payload().unwrap().as_u64(), Some(6));
+
+        // Each key is only stored on its owner, so the nodes' stats add up to the keys set.
+        let mut total = 0;
+        for client in &clients {
+            let resp = core.run(client.stats()).unwrap();
+            let stats = String::from_utf8_lossy(resp.payload().unwrap().data()).into_owned();
+            let keys = text_codec::parse_stats(&stats)
+                .into_iter()
+                .find(|&(ref name, _)| name == "keys")
+                .map(|(_, value)| value.parse::<usize>().unwrap())
+                .unwrap();
+            assert!(keys > 0, "{}", stats);
+            total += keys;
+        }
+        assert_eq!(total, 101);
+    }
+
+    #[test]
+    fn test_config_mismatch() {
+        // Each node thinks the other owns every key.
+        let listeners: Vec<_> = (0..2).map(|_| testing::bind()).collect();
+        let addrs: Vec<Addr> = listeners
+            .iter()
+            .map(|listener| Addr::Tcp(listener.local_addr().unwrap()))
+            .collect();
+        for (i, listener) in listeners.into_iter().enumerate() {
+            let nodes = vec![(addrs[0].clone(), i as u32), (addrs[1].clone(), 1 - i as u32)];
+            let this = addrs[i].clone();
+            testing::spawn(listener, Protocol::Rcache, move || {
+                let membership = Membership::new(nodes, &this, true).unwrap();
+                ClusterService {
+                    inner: CacheService { cache: Arc::new(Cache::new(1024).unwrap()) },
+                    membership: Arc::new(membership),
+                }
+            });
+        }
+
+        // A request is forwarded once, and served where it was forwarded to.
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let client = core.run(Client::connect_addr(&addrs[0], &handle)).unwrap();
+        let client = client.with_timeout(Duration::from_millis(1000));
+        core.run(client.set(b"foo".to_vec(), b"bar".to_vec())).unwrap();
+        let resp = core.run(client.get(b"foo".to_vec())).unwrap();
+        assert_eq!(resp.payload().unwrap().data(), b"bar");
+        let resp = core.run(client.get_many(vec![b"foo".to_vec()])).unwrap();
+        match resp {
+            Message::BatchResponse(Op::Get, ref results) => assert_eq!(results[0].0, Code::Hit),
+            ref msg => panic!("unexpected response {}", msg),
+        }
+        let keys: Vec<usize> = addrs
+            .iter()
+            .map(|addr| {
+                let client = core.run(Client::connect_addr(addr, &handle)).unwrap();
+                let resp = core.run(client.stats()).unwrap();
+                let stats = String::from_utf8_lossy(resp.payload().unwrap().data()).into_owned();
+                text_codec::parse_stats(&stats)
+                    .into_iter()
+                    .find(|&(ref name, _)| name == "keys")
+                    .map(|(_, value)| value.parse::<usize>().unwrap())
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(keys, vec![0, 1]);
+    }
+
+    #[test]
+    fn test_moved() {
+        let nodes = spawn_cluster(2, false);
+        let weights = nodes.iter().map(|addr| (addr.clone(), 1)).collect();
+        let membership = Membership::new(weights, &nodes[0], false).unwrap();
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let client = core.run(Client::connect_addr(&nodes[0], &handle)).unwrap();
+
+        let (local, remote): (Vec<_>, Vec<_>) =
+            keys().into_iter().partition(|key| membership.owner(key) == &nodes[0]);
+        assert!(!local.is_empty() && !remote.is_empty());
+        core.run(client.set(local[0].clone(), b"bar".to_vec())).unwrap();
+        match core.run(client.set(remote[0].clone(), b"bar".to_vec())) {
+            Err(client::Error::Moved(Op::Set, ref owner)) if *owner == nodes[1].to_string() => (),
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(msg) => panic!("unexpected response {}", msg),
+        }
+
+        let resp = core.run(client.get_many(vec![local[0].clone(), remote[0].clone()])).unwrap();
+        let owner = message::payload(1, nodes[1].to_string().into_bytes());
+        assert_eq!(
+            resp,
+            message::batch_response(
+                Op::Get,
+                vec![
+                    (Code::Hit, core.run(client.get(local[0].clone())).unwrap().payload().cloned()),
+                    (Code::Moved, Some(owner)),
+                ],
+            )
+        );
+    }
+}
@@ -1,61 +1,173 @@
+use std::cmp;
 use std::convert::TryFrom;
+use std::error::Error;
 use error;
 use std::fmt;
+use std::io;
+use std::time::Instant;
+use bytes::{Buf, BufMut, BigEndian};
 
 /// `Message`
+///
+/// The batch variants apply one op to many keys in a single frame. The store executes a batch as
+/// a single unit of work, and answers with one (code, payload) result per entry, in order.
+///
+/// A request may have a deadline, see `with_deadline`, after which the caller has given up on it,
+/// and may have been forwarded by another node of a cluster, see `forwarded`.
 #[derive(Debug, PartialEq, Clone)]
 pub enum Message {
-    Request(Op, Vec<u8>, Option<Payload>),
+    Request(Op, Vec<u8>, Option<Payload>, Option<Instant>, bool),
     Response(Op, Code, Option<Payload>),
+    BatchRequest(Op, Vec<(Vec<u8>, Option<Payload>)>, Option<Instant>, bool),
+    BatchResponse(Op, Vec<(Code, Option<Payload>)>),
 }
 
 pub fn request(op: Op, key: Vec<u8>, payload: Option<Payload>) -> Message {
-    Message::Request(op, key, payload)
+    Message::Request(op, key, payload, None, false)
 }
 
 pub fn response(op: Op, code: Code, payload: Option<Payload>) -> Message {
     Message::Response(op, code, payload)
 }
 
+pub fn batch_request(op: Op, entries: Vec<(Vec<u8>, Option<Payload>)>) -> Message {
+    Message::BatchRequest(op, entries, None, false)
+}
+
+pub fn batch_response(op: Op, results: Vec<(Code, Option<Payload>)>) -> Message {
+    Message::BatchResponse(op, results)
+}
+
+/// A `Code::Error` response to a request for `op`. The payload is the UTF8-encoded description,
+/// and its error code is the error's kind, see `error::ErrorKind::code`, so that clients can both
+/// act on the kind and show the description to a human.
+pub fn error_response(op: Op, err: &error::Error) -> Message {
+    let description = err.description().to_owned().into_bytes();
+    let payload = payload(TYPE_ID_UTF8, description).with_error_code(err.kind().code());
+    Message::Response(op, Code::Error, Some(payload))
+}
+
 impl Message {
     pub fn key(&self) -> Option<&[u8]> {
         match *self {
-            Message::Request(_, ref key, _) => Some(key.as_slice()),
-            Message::Response(..) => None,
+            Message::Request(_, ref key, ..) => Some(key.as_slice()),
+            Message::Response(..) |
+            Message::BatchRequest(..) |
+            Message::BatchResponse(..) => None,
         }
     }
 
     pub fn op(&self) -> Op {
         match *self {
             Message::Request(op, ..) |
-            Message::Response(op, ..) => op,
+            Message::Response(op, ..) |
+            Message::BatchRequest(op, ..) |
+            Message::BatchResponse(op, ..) => op,
         }
     }
 
     pub fn code(&self) -> Code {
         match *self {
-            Message::Request(..) => Code::Req,
+            Message::Request(..) |
+            Message::BatchRequest(..) => Code::Req,
             Message::Response(_, code, ..) => code,
+            Message::BatchResponse(..) => Code::Ok,
         }
     }
     pub fn type_id(&self) -> Option<u32> {
-        match *self {
-            Message::Request(_, _, ref payload) => payload.as_ref().map(|p| p.type_id),
-            Message::Response(_, _, ref payload) => payload.as_ref().map(|p| p.type_id),
-        }
+        self.payload().map(|p| p.type_id)
     }
 
     pub fn payload(&self) -> Option<&Payload> {
         match *self {
-            Message::Request(_, _, ref payload) |
+            Message::Request(_, _, ref payload, ..) |
             Message::Response(_, _, ref payload) => payload.as_ref(),
+            Message::BatchRequest(..) |
+            Message::BatchResponse(..) => None,
+        }
+    }
+
+    /// The error an error response carries, see `error_response`.
+    pub fn error(&self) -> Option<error::Error> {
+        match *self {
+            Message::Response(_, Code::Error, ref payload) => {
+                let (kind, description) = match *payload {
+                    Some(ref payload) => (
+                        error::ErrorKind::from_code(payload.error_code()),
+                        String::from_utf8_lossy(payload.data()).into_owned(),
+                    ),
+                    None => (error::ErrorKind::Other, String::new()),
+                };
+                Some(error::Error::new(kind, &description))
+            }
+            _ => None,
+        }
+    }
+
+    /// Set the deadline of a request, after which the store won't bother with it and answers
+    /// with `Code::Timeout` instead. Responses have no deadline.
+    pub fn with_deadline(self, deadline: Instant) -> Message {
+        match self {
+            Message::Request(op, key, payload, _, forwarded) => {
+                Message::Request(op, key, payload, Some(deadline), forwarded)
+            }
+            Message::BatchRequest(op, entries, _, forwarded) => {
+                Message::BatchRequest(op, entries, Some(deadline), forwarded)
+            }
+            msg => msg,
+        }
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        match *self {
+            Message::Request(.., deadline, _) |
+            Message::BatchRequest(.., deadline, _) => deadline,
+            Message::Response(..) |
+            Message::BatchResponse(..) => None,
+        }
+    }
+
+    /// Mark a request as forwarded by another node of a cluster, which the node it is forwarded
+    /// to serves itself, see `membership::Membership`. Responses can't be forwarded.
+    pub fn forwarded(self) -> Message {
+        match self {
+            Message::Request(op, key, payload, deadline, _) => {
+                Message::Request(op, key, payload, deadline, true)
+            }
+            Message::BatchRequest(op, entries, deadline, _) => {
+                Message::BatchRequest(op, entries, deadline, true)
+            }
+            msg => msg,
+        }
+    }
+
+    pub fn is_forwarded(&self) -> bool {
+        match *self {
+            Message::Request(.., forwarded) |
+            Message::BatchRequest(.., forwarded) => forwarded,
+            Message::Response(..) |
+            Message::BatchResponse(..) => false,
+        }
+    }
+
+    pub fn is_batch(&self) -> bool {
+        match *self {
+            Message::BatchRequest(..) |
+            Message::BatchResponse(..) => true,
+            Message::Request(..) |
+            Message::Response(..) => false,
         }
     }
 
     pub fn consume_request(self) -> Result<(Vec<u8>, Option<Payload>), error::Error> {
         match self {
-            Message::Request(_, key, payload) => Ok((key, payload)),
-            Message::Response(..) => Err(error::Error::new(
+            Message::Request(_, key, payload, ..) => Ok((key, payload)),
+            Message::BatchRequest(..) => Err(error::Error::new(
+                error::ErrorKind::BadMessage,
+                "expected a request, got a batch",
+            )),
+            Message::Response(..) |
+            Message::BatchResponse(..) => Err(error::Error::new(
                 error::ErrorKind::BadMessage,
                 "expected a request, got a response",
             )),
@@ -64,9 +176,14 @@ impl Message {
     pub fn consume_response(self) -> Result<(Op, Code, Option<Payload>), error::Error> {
         match self {
             Message::Response(op, code, payload) => Ok((op, code, payload)),
-            Message::Request(..) => Err(error::Error::new(
+            Message::BatchResponse(..) => Err(error::Error::new(
                 error::ErrorKind::BadMessage,
-                "expected a request, got a response",
+                "expected a response, got a batch",
+            )),
+            Message::Request(..) |
+            Message::BatchRequest(..) => Err(error::Error::new(
+                error::ErrorKind::BadMessage,
+                "expected a response, got a request",
             )),
         }
     }
@@ -75,7 +192,7 @@ impl Message {
 impl fmt::Display for Message {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            Message::Request(ref op, ref key, ref payload) => {
+            Message::Request(ref op, ref key, ref payload, ..) => {
                 match *payload {
                     Some(ref payload) => write!(f, "Request[Op={}, Key={:?}] {}", op, key, payload.clone()),
                     None => write!(f, "Request[Op={}, Key={:?}]", op, key),
@@ -84,18 +201,43 @@ impl fmt::Display for Message {
             Message::Response(ref op, ref code, ref payload) => {
                 match *payload {
                     Some(ref payload) => write!(f, "Response[Op={}, Code={}] {:?}", op, code, payload.clone()),
-                    None => write!(f, "Request[Op={}, Code={}]", op, code),
+                    None => write!(f, "Response[Op={}, Code={}]", op, code),
+                }
+            }
+            Message::BatchRequest(ref op, ref entries, ..) => {
+                let keys: Vec<&Vec<u8>> = entries.iter().map(|entry| &entry.0).collect();
+                write!(f, "BatchRequest[Op={}, Keys={:?}]", op, keys)
+            }
+            Message::BatchResponse(ref op, ref results) => {
+                write!(f, "BatchResponse[Op={}]", op)?;
+                for &(ref code, ref payload) in results {
+                    match *payload {
+                        Some(ref payload) => write!(f, " [Code={}] {}", code, payload)?,
+                        None => write!(f, " [Code={}]", code)?,
+                    }
                 }
+                Ok(())
             }
         }
     }
 }
 
+/// `type_id` of payloads holding a UTF8-encoded string.
+pub const TYPE_ID_UTF8: u32 = 1;
+/// `type_id` of payloads holding a big endian `u64`.
+pub const TYPE_ID_U64: u32 = 8;
+/// `type_id` of the payloads of `Op::Sync` requests and responses, see `replication`.
+pub const TYPE_ID_SYNC: u32 = 9;
+
 /// `Payload`
 #[derive(Debug, PartialEq, Clone)]
 pub struct Payload {
     type_id: u32,
     data: Vec<u8>,
+    ttl_millis: u64,
+    version: u64,
+    flags: u32,
+    error_code: u32,
 }
 
 impl Payload {
@@ -105,18 +247,114 @@ impl Payload {
     pub fn type_id(&self) -> u32 {
         self.type_id
     }
+
+    /// Time to live in seconds, 0 means the entry never expires. On a `Get` response this is
+    /// the time remaining until the entry expires. A ttl set in milliseconds is rounded up.
+    pub fn ttl(&self) -> u32 {
+        cmp::min((self.ttl_millis + 999) / 1000, u64::from(u32::max_value())) as u32
+    }
+
+    pub fn with_ttl(mut self, ttl: u32) -> Self {
+        self.ttl_millis = u64::from(ttl) * 1000;
+        self
+    }
+
+    /// Time to live in milliseconds, for the protocols that can set one more precisely than in
+    /// seconds.
+    pub fn ttl_millis(&self) -> u64 {
+        self.ttl_millis
+    }
+
+    pub fn with_ttl_millis(mut self, ttl: u64) -> Self {
+        self.ttl_millis = ttl;
+        self
+    }
+
+    /// The version of a stored entry, which changes every time the entry is written. Returned on
+    /// `Get` hits, and given to `Cas` as the version the caller expects the entry to still have.
+    /// 0 means no version.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn with_version(mut self, version: u64) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Memcached's client flags, which the cache stores with the payload but otherwise ignores.
+    /// They're kept apart from the `type_id`, so that any flags a memcached client sets can't be
+    /// mistaken for one of the type ids the cache gives meaning to.
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+
+    pub fn with_flags(mut self, flags: u32) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// The kind of error the payload of an error response describes, see
+    /// `error::ErrorKind::code`. 0, `ErrorKind::Other`, for any other payload.
+    pub fn error_code(&self) -> u32 {
+        self.error_code
+    }
+
+    pub fn with_error_code(mut self, error_code: u32) -> Self {
+        self.error_code = error_code;
+        self
+    }
+
+    /// Decode a `TYPE_ID_U64` payload.
+    pub fn as_u64(&self) -> Option<u64> {
+        if self.type_id == TYPE_ID_U64 && self.data.len() == 8 {
+            Some(io::Cursor::new(&self.data).get_u64::<BigEndian>())
+        } else {
+            None
+        }
+    }
 }
 
 pub fn payload(type_id: u32, data: Vec<u8>) -> Payload {
     Payload {
         type_id: type_id,
         data: data,
+        ttl_millis: 0,
+        version: 0,
+        flags: 0,
+        error_code: 0,
     }
 }
 
+/// A `TYPE_ID_U64` payload holding `n`.
+pub fn u64_payload(n: u64) -> Payload {
+    let mut data = Vec::with_capacity(8);
+    data.put_u64::<BigEndian>(n);
+    payload(TYPE_ID_U64, data)
+}
+
+/// The payload of an `Incr` or `Decr` request: the delta, followed by the value to initialize a
+/// missing key with, if there is one.
+pub fn counter_payload(delta: u64, initial: Option<u64>) -> Payload {
+    let mut data = Vec::with_capacity(16);
+    data.put_u64::<BigEndian>(delta);
+    if let Some(initial) = initial {
+        data.put_u64::<BigEndian>(initial);
+    }
+    payload(TYPE_ID_U64, data)
+}
+
 impl fmt::Display for Payload {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "type_id: {}, data: {:?}", self.type_id, self.data)
+        write!(
+            f,
+            "type_id: {}, ttl: {}, version: {}, flags: {}, data: {:?}",
+            self.type_id,
+            self.ttl(),
+            self.version,
+            self.flags,
+            self.data
+        )
     }
 }
 
@@ -127,6 +365,34 @@ pub enum Op {
     Get = 1,
     Del = 2,
     Stats = 3,
+    Ttl = 4,
+    Cas = 5,
+    Incr = 6,
+    Decr = 7,
+    Add = 8,
+    Replace = 9,
+    Append = 10,
+    Prepend = 11,
+    /// Opens a connection, see `codec::hello`. Answered by `proto::accept`, never by the store.
+    Hello = 12,
+    /// Saves the cache to its snapshot file, see `cache::Cache::with_snapshots`. Responds with
+    /// the number of entries saved.
+    Snapshot = 13,
+    /// Replicates the cache to a replica: a full copy of the store, then the writes made since,
+    /// see `cache::Cache::replica`.
+    Sync = 14,
+}
+
+impl Op {
+    /// Whether the op can change the store. The outcome of every write is recorded in the
+    /// operation log, if the cache has one.
+    pub fn is_write(&self) -> bool {
+        match *self {
+            Op::Set | Op::Add | Op::Replace | Op::Append | Op::Prepend | Op::Cas | Op::Incr |
+            Op::Decr | Op::Del => true,
+            Op::Get | Op::Stats | Op::Ttl | Op::Hello | Op::Snapshot | Op::Sync => false,
+        }
+    }
 }
 
 impl fmt::Display for Op {
@@ -136,6 +402,17 @@ impl fmt::Display for Op {
             Op::Get => "Get",
             Op::Del => "Del",
             Op::Stats => "Stats",
+            Op::Ttl => "Ttl",
+            Op::Cas => "Cas",
+            Op::Incr => "Incr",
+            Op::Decr => "Decr",
+            Op::Add => "Add",
+            Op::Replace => "Replace",
+            Op::Append => "Append",
+            Op::Prepend => "Prepend",
+            Op::Hello => "Hello",
+            Op::Snapshot => "Snapshot",
+            Op::Sync => "Sync",
         };
 
         write!(f, "{}", s)
@@ -151,6 +428,17 @@ impl TryFrom<u8> for Op {
             1 => Ok(Op::Get),
             2 => Ok(Op::Del),
             3 => Ok(Op::Stats),
+            4 => Ok(Op::Ttl),
+            5 => Ok(Op::Cas),
+            6 => Ok(Op::Incr),
+            7 => Ok(Op::Decr),
+            8 => Ok(Op::Add),
+            9 => Ok(Op::Replace),
+            10 => Ok(Op::Append),
+            11 => Ok(Op::Prepend),
+            12 => Ok(Op::Hello),
+            13 => Ok(Op::Snapshot),
+            14 => Ok(Op::Sync),
             _ => Err(error::Error::new(
                 error::ErrorKind::UnknownOp,
                 "got an unknown op code",
@@ -167,6 +455,15 @@ pub enum Code {
     Miss = 2,
     Error = 3,
     Hit = 4,
+    TooLarge = 5,
+    Exists = 6,
+    NotStored = 7,
+    /// The request's deadline passed before the store got to it.
+    Timeout = 8,
+    /// The request was a write, which a read only replica doesn't take.
+    ReadOnly = 9,
+    /// The key belongs to another node of the cluster, whose address is the payload.
+    Moved = 10,
 }
 
 impl fmt::Display for Code {
@@ -177,6 +474,12 @@ impl fmt::Display for Code {
             Code::Miss => "Miss",
             Code::Error => "Error",
             Code::Hit => "Hit",
+            Code::TooLarge => "TooLarge",
+            Code::Exists => "Exists",
+            Code::NotStored => "NotStored",
+            Code::Timeout => "Timeout",
+            Code::ReadOnly => "ReadOnly",
+            Code::Moved => "Moved",
         };
         write!(f, "{}", s)
     }
@@ -192,6 +495,12 @@ impl TryFrom<u8> for Code {
             2 => Ok(Code::Miss),
             3 => Ok(Code::Error),
             4 => Ok(Code::Hit),
+            5 => Ok(Code::TooLarge),
+            6 => Ok(Code::Exists),
+            7 => Ok(Code::NotStored),
+            8 => Ok(Code::Timeout),
+            9 => Ok(Code::ReadOnly),
+            10 => Ok(Code::Moved),
             _ => Err(error::Error::new(
                 error::ErrorKind::InvalidData,
                 "unknown code",
@@ -0,0 +1,679 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+use bytes::{Buf, BufMut, BigEndian};
+use crc32c;
+use snapshot::{self, Record};
+
+/// Identifies an operation log file.
+const MAGIC: &'static [u8] = b"RCOLOG";
+/// The version of the log format, bumped whenever the layout of an entry changes.
+pub const FORMAT_VERSION: u32 = 1;
+
+static HEADER_LEN: usize = 6 + 4 + 8;
+/// The length and checksum that precede each entry.
+static ENTRY_HEADER_LEN: usize = 4 + 4;
+
+const TAG_PUT: u8 = 0;
+const TAG_DEL: u8 = 1;
+
+/// How often `Fsync::EverySecond` syncs the log.
+const SYNC_INTERVAL_MS: u64 = 1000;
+
+/// When the operation log is synced to disk.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fsync {
+    /// After every unit of work, before it is answered, so that no write that was answered is
+    /// lost in a crash.
+    Always,
+    /// Once a second, in the background. Up to a second of writes can be lost if the machine
+    /// crashes.
+    EverySecond,
+    /// Whenever the OS gets to it. Writes survive the process crashing, but not the machine.
+    Never,
+}
+
+impl Default for Fsync {
+    fn default() -> Fsync {
+        Fsync::EverySecond
+    }
+}
+
+impl FromStr for Fsync {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Fsync, String> {
+        match s {
+            "always" => Ok(Fsync::Always),
+            "everysec" => Ok(Fsync::EverySecond),
+            "no" => Ok(Fsync::Never),
+            _ => Err(format!("Unknown fsync policy: {}, expected always, everysec or no", s)),
+        }
+    }
+}
+
+/// An entry of the operation log: what a write left at its key. Logging the outcome of a write,
+/// rather than the request, makes replaying the log independent of when it is replayed, and of
+/// what was in the store when the write was made.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Entry {
+    /// The key's entry after the write.
+    Put(Record),
+    /// The write removed the key.
+    Del(Vec<u8>),
+}
+
+impl Entry {
+    pub fn key(&self) -> &[u8] {
+        match *self {
+            Entry::Put(ref record) => &record.key,
+            Entry::Del(ref key) => key,
+        }
+    }
+}
+
+/// An append-only log of the writes made to a cache's stores, replayed on startup to rebuild
+/// them. The shards of a cache share the log, each appending the writes of a unit of work at
+/// once, see `append`.
+///
+/// The log is a header of the magic bytes `RCOLOG`, a u32 format version and the u64 generation
+/// of the snapshot that the log follows, or 0. Each entry that follows is laid out as
+///
+/// `[len: u32][crc: u32][tag: u8][entry]`
+///
+/// where `crc` is the CRC32C of the `len` bytes of the tag and entry. A `Put` entry is tagged 0
+/// and encoded as a snapshot record, a `Del` entry is tagged 1 and encoded as a u32 key length
+/// followed by the key. A crash can leave a partly written entry at the end of the log. Reading
+/// stops at the first entry that is truncated or fails its checksum, and the log is truncated
+/// there before anything else is appended.
+///
+/// Each snapshot compacts the log: while the snapshot is taken, writes move to a new log that
+/// follows the new snapshot, which replaces the old log once the snapshot is on disk. See
+/// `start_compaction`, `switch` and `finish_compaction`.
+///
+/// With `Fsync::Always`, the shards commit their appends as a group: a shard syncs outside the
+/// lock the others append under, and a shard whose append was covered by another's sync doesn't
+/// sync again. See `append`.
+pub struct OpLog {
+    path: PathBuf,
+    fsync: Fsync,
+    state: Mutex<LogState>,
+    /// How many appends are known to be on disk. Held while syncing, so that one sync at a time
+    /// runs, and those waiting for it can tell whether it covered their append.
+    synced: Mutex<u64>,
+}
+
+struct LogState {
+    current: File,
+    /// The log that follows the snapshot in progress, if any.
+    next: Option<File>,
+    /// Whether each shard has moved on to `next`.
+    switched: Vec<bool>,
+    /// How many appends have been made.
+    appended: u64,
+    /// Whether a failed append was left partly written, see `append`.
+    broken: bool,
+}
+
+/// A log file, as far as it could be read.
+struct LogFile {
+    base: usize,
+    entries: Vec<Entry>,
+    /// The length of the file up to the end of the last entry that could be read.
+    len: u64,
+}
+
+/// The log of a cache that is starting up, see `recover`.
+pub struct Recovered {
+    pub log: Arc<OpLog>,
+    /// The writes to replay over the snapshot, in order.
+    pub entries: Vec<Entry>,
+    /// The generation the next snapshot should follow.
+    pub generation: usize,
+}
+
+/// Open the log at `path` for a cache of `shards` shards that restored the snapshot of
+/// `generation`, or 0 if there is no snapshot, and read the writes to replay.
+///
+/// A crash can happen at any point of a compaction, leaving the old log, the new log at
+/// `path.next`, or both. Only a log that follows the restored snapshot, with a base generation
+/// at least the snapshot's, has writes the snapshot doesn't; the others are dropped. What is left
+/// is merged back into a single log at `path`.
+pub fn recover(
+    path: &Path,
+    generation: usize,
+    shards: usize,
+    fsync: Fsync,
+) -> io::Result<Recovered> {
+    let next_path = next_path(path);
+    let current = read_if_follows(path, generation)?;
+    let next = read_if_follows(&next_path, generation)?;
+    let mut latest = generation;
+
+    let mut entries = Vec::new();
+    let file = match (current, next) {
+        (Some(current), Some(next)) => {
+            let mut file = open(path, current.len)?;
+            let mut data = Vec::new();
+            File::open(&next_path)?.read_to_end(&mut data)?;
+            file.write_all(&data[HEADER_LEN..next.len as usize])?;
+            file.sync_data()?;
+            fs::remove_file(&next_path)?;
+            latest = next.base;
+            entries.extend(current.entries);
+            entries.extend(next.entries);
+            file
+        }
+        (None, Some(next)) => {
+            fs::rename(&next_path, path)?;
+            latest = next.base;
+            entries = next.entries;
+            open(path, next.len)?
+        }
+        (Some(current), None) => {
+            remove_if_exists(&next_path)?;
+            latest = current.base;
+            entries = current.entries;
+            open(path, current.len)?
+        }
+        (None, None) => {
+            remove_if_exists(&next_path)?;
+            create(path, generation)?
+        }
+    };
+
+    let log = Arc::new(OpLog {
+        path: path.to_owned(),
+        fsync: fsync,
+        state: Mutex::new(LogState {
+            current: file,
+            next: None,
+            switched: vec![false; shards],
+            appended: 0,
+            broken: false,
+        }),
+        synced: Mutex::new(0),
+    });
+    if fsync == Fsync::EverySecond {
+        sync_periodically(&log)?;
+    }
+
+    Ok(Recovered {
+        log: log,
+        entries: entries,
+        generation: latest,
+    })
+}
+
+impl OpLog {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append `entries`, the writes of one unit of `shard`'s work. With `Fsync::Always`, the log
+    /// is synced before returning: by this shard, unless a sync that started after the append
+    /// has already covered it.
+    ///
+    /// An append that fails is cut back off the log, so that the writes appended after it can
+    /// still be read. If that fails too, the log refuses any more appends.
+    pub fn append(&self, shard: usize, entries: &[Entry]) -> io::Result<()> {
+        let mut buf = Vec::new();
+        for entry in entries {
+            put_entry(&mut buf, entry);
+        }
+
+        let appended = {
+            let mut state = self.state.lock().unwrap();
+            let state = &mut *state;
+            if state.broken {
+                return Err(io::Error::new(io::ErrorKind::Other, "a failed append broke the log"));
+            }
+            {
+                let file = match state.next {
+                    Some(ref mut next) if state.switched[shard] => next,
+                    _ => &mut state.current,
+                };
+                if let Err(e) = write_or_rewind(file, &buf) {
+                    state.broken = e.rewound.is_err();
+                    return Err(e.error);
+                }
+            }
+            state.appended += 1;
+            state.appended
+        };
+        if self.fsync == Fsync::Always {
+            let mut synced = self.synced.lock().unwrap();
+            if *synced < appended {
+                *synced = self.sync_files()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sync the log to disk.
+    pub fn sync(&self) -> io::Result<()> {
+        self.sync_files().map(|_| ())
+    }
+
+    /// Sync the log files to disk, returning how many appends that covers. The lock is only held
+    /// to get at the files, so that shards can keep appending while the sync runs.
+    fn sync_files(&self) -> io::Result<u64> {
+        let (files, appended) = {
+            let state = self.state.lock().unwrap();
+            let mut files = vec![state.current.try_clone()?];
+            if let Some(ref next) = state.next {
+                files.push(next.try_clone()?);
+            }
+            (files, state.appended)
+        };
+        for file in files {
+            file.sync_data()?;
+        }
+        Ok(appended)
+    }
+
+    /// Start the log that follows the snapshot of `generation`. Each shard keeps appending to the
+    /// current log until it `switch`es, as it hands over its part of the snapshot.
+    pub fn start_compaction(&self, generation: usize) -> io::Result<()> {
+        let next = create(&next_path(&self.path), generation)?;
+        let mut state = self.state.lock().unwrap();
+        state.next = Some(next);
+        for switched in &mut state.switched {
+            *switched = false;
+        }
+        Ok(())
+    }
+
+    /// Append `shard`'s writes to the new log from now on.
+    pub fn switch(&self, shard: usize) {
+        let mut state = self.state.lock().unwrap();
+        if state.next.is_some() {
+            state.switched[shard] = true;
+        }
+    }
+
+    /// Finish the compaction, once the snapshot has been written, or has failed to be. If it was
+    /// `saved`, the new log replaces the current one, all of whose writes are in the snapshot.
+    /// Otherwise the writes of the new log are moved to the end of the current one, which
+    /// carries on as if the compaction never started. If they can't be moved, the log refuses any
+    /// more appends, as they would follow writes it has lost.
+    pub fn finish_compaction(&self, saved: bool) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let next = match state.next.take() {
+            Some(next) => next,
+            None => return Ok(()),
+        };
+        for switched in &mut state.switched {
+            *switched = false;
+        }
+
+        let next_path = next_path(&self.path);
+        if saved {
+            next.sync_data()?;
+            state.current = next;
+            return fs::rename(&next_path, &self.path);
+        }
+
+        let mut data = Vec::new();
+        File::open(&next_path)?.read_to_end(&mut data)?;
+        if let Err(e) = write_or_rewind(&mut state.current, &data[HEADER_LEN..]) {
+            state.broken = true;
+            return Err(e.error);
+        }
+        state.current.sync_data()?;
+        fs::remove_file(&next_path)
+    }
+}
+
+/// Sync `log` every `SYNC_INTERVAL_MS` on a thread of its own, until the log is dropped.
+fn sync_periodically(log: &Arc<OpLog>) -> io::Result<()> {
+    let log: Weak<OpLog> = Arc::downgrade(log);
+    thread::Builder::new()
+        .name("rcache-log-sync".to_owned())
+        .spawn(move || loop {
+            thread::sleep(Duration::from_millis(SYNC_INTERVAL_MS));
+            match log.upgrade() {
+                Some(log) => {
+                    if let Err(e) = log.sync() {
+                        error!("Failed to sync {}: {}", log.path().display(), e);
+                    }
+                }
+                None => break,
+            }
+        })
+        .map(|_| ())
+}
+
+fn next_path(path: &Path) -> PathBuf {
+    snapshot::with_suffix(path, ".next")
+}
+
+/// Create a log at `path` that follows the snapshot of `base`, replacing any log there.
+fn create(path: &Path, base: usize) -> io::Result<File> {
+    let mut file = File::create(path)?;
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(MAGIC);
+    header.put_u32::<BigEndian>(FORMAT_VERSION);
+    header.put_u64::<BigEndian>(base as u64);
+    file.write_all(&header)?;
+    file.sync_data()?;
+    Ok(file)
+}
+
+/// Open the log at `path` for appending, cutting it off at `len`, the end of its last good entry.
+fn open(path: &Path, len: u64) -> io::Result<File> {
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    file.set_len(len)?;
+    file.seek(SeekFrom::End(0))?;
+    Ok(file)
+}
+
+/// A failed `write_or_rewind`, and how cutting off what it wrote went.
+struct WriteError {
+    error: io::Error,
+    rewound: io::Result<()>,
+}
+
+/// Write `buf` at the end of `file`, cutting the file back to its old length if that fails.
+fn write_or_rewind(file: &mut File, buf: &[u8]) -> Result<(), WriteError> {
+    let len = file.seek(SeekFrom::Current(0)).map_err(|e| {
+        WriteError {
+            error: e,
+            rewound: Ok(()),
+        }
+    })?;
+    file.write_all(buf).map_err(|e| {
+        WriteError {
+            error: e,
+            rewound: file.set_len(len).and_then(|_| file.seek(SeekFrom::Start(len))).map(|_| ()),
+        }
+    })
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Read the log at `path`, if there is one and it follows the snapshot of `generation`.
+fn read_if_follows(path: &Path, generation: usize) -> io::Result<Option<LogFile>> {
+    match read(path) {
+        Ok(Some(ref log)) if log.base < generation => Ok(None),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        result => result,
+    }
+}
+
+/// Read the log at `path`, up to the first entry that is truncated or corrupt. A log too short
+/// to have a header, which a crash just after creating it can leave, has nothing to read.
+fn read(path: &Path) -> io::Result<Option<LogFile>> {
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+
+    if data.len() < HEADER_LEN {
+        return Ok(None);
+    }
+    if &data[..MAGIC.len()] != MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not an rcache log"));
+    }
+    let mut header = io::Cursor::new(&data[MAGIC.len()..HEADER_LEN]);
+    let version = header.get_u32::<BigEndian>();
+    if version != FORMAT_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported log version {}", version),
+        ));
+    }
+    let base = header.get_u64::<BigEndian>() as usize;
+
+    let mut entries = Vec::new();
+    let mut offset = HEADER_LEN;
+    while offset < data.len() {
+        match get_entry(&data[offset..]) {
+            Some((entry, len)) => {
+                entries.push(entry);
+                offset += len;
+            }
+            None => {
+                warn!(
+                    "Skipping {} bytes of truncated or corrupt log at the end of {}.",
+                    data.len() - offset,
+                    path.display()
+                );
+                break;
+            }
+        }
+    }
+
+    Ok(Some(LogFile {
+        base: base,
+        entries: entries,
+        len: offset as u64,
+    }))
+}
+
+/// Encode `entry`, preceded by its length and checksum. Replication sends entries encoded the
+/// same way.
+pub fn put_entry(buf: &mut Vec<u8>, entry: &Entry) {
+    let mut body = Vec::new();
+    match *entry {
+        Entry::Put(ref record) => {
+            body.put_u8(TAG_PUT);
+            snapshot::put_record(&mut body, record);
+        }
+        Entry::Del(ref key) => {
+            body.put_u8(TAG_DEL);
+            body.put_u32::<BigEndian>(key.len() as u32);
+            body.extend_from_slice(key);
+        }
+    }
+    buf.put_u32::<BigEndian>(body.len() as u32);
+    buf.put_u32::<BigEndian>(crc32c::crc32c(&body));
+    buf.extend_from_slice(&body);
+}
+
+/// Decode the entry at the start of `data`, and its length, or None if it is truncated or
+/// corrupt.
+pub fn get_entry(data: &[u8]) -> Option<(Entry, usize)> {
+    if data.len() < ENTRY_HEADER_LEN {
+        return None;
+    }
+    let mut header = io::Cursor::new(&data[..ENTRY_HEADER_LEN]);
+    let len = header.get_u32::<BigEndian>() as usize;
+    let crc = header.get_u32::<BigEndian>();
+    if len == 0 || data.len() - ENTRY_HEADER_LEN < len {
+        return None;
+    }
+    let body = &data[ENTRY_HEADER_LEN..ENTRY_HEADER_LEN + len];
+    if crc32c::crc32c(body) != crc {
+        return None;
+    }
+
+    let mut cursor = io::Cursor::new(&body[1..]);
+    let entry = match body[0] {
+        TAG_PUT => snapshot::get_record(&mut cursor).map(Entry::Put),
+        TAG_DEL => snapshot::get_bytes(&mut cursor).map(Entry::Del),
+        _ => return None,
+    };
+    match entry {
+        Ok(entry) if !cursor.has_remaining() => Some((entry, ENTRY_HEADER_LEN + len)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use message;
+    use testing::TempDir;
+
+    fn put(key: &str, value: &str) -> Entry {
+        Entry::Put(Record {
+            key: key.into(),
+            payload: message::payload(1, value.into()).with_version(1),
+            expires: None,
+        })
+    }
+
+    #[test]
+    fn test_append_recover() {
+        let dir = TempDir::new();
+        let path = dir.path().join("cache.log");
+        let recovered = recover(&path, 0, 2, Fsync::Always).unwrap();
+        assert!(recovered.entries.is_empty());
+        recovered.log.append(0, &[put("a", "1"), put("b", "2")]).unwrap();
+        recovered.log.append(1, &[Entry::Del("a".into())]).unwrap();
+        drop(recovered);
+
+        let recovered = recover(&path, 0, 2, Fsync::Never).unwrap();
+        assert_eq!(
+            recovered.entries,
+            vec![put("a", "1"), put("b", "2"), Entry::Del("a".into())]
+        );
+        recovered.log.append(0, &[put("c", "3")]).unwrap();
+        drop(recovered);
+
+        assert_eq!(recover(&path, 0, 2, Fsync::Never).unwrap().entries.len(), 4);
+    }
+
+    #[test]
+    fn test_group_commit() {
+        let dir = TempDir::new();
+        let path = dir.path().join("cache.log");
+        let log = recover(&path, 0, 4, Fsync::Always).unwrap().log;
+        let threads: Vec<_> = (0..4)
+            .map(|shard| {
+                let log = log.clone();
+                thread::spawn(move || for i in 0..50 {
+                    log.append(shard, &[put(&format!("{}-{}", shard, i), "x")]).unwrap();
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(*log.synced.lock().unwrap(), 200);
+        drop(log);
+
+        assert_eq!(recover(&path, 0, 4, Fsync::Never).unwrap().entries.len(), 200);
+    }
+
+    #[test]
+    fn test_failed_append() {
+        let dir = TempDir::new();
+        let path = dir.path().join("cache.log");
+        let log = recover(&path, 0, 1, Fsync::Never).unwrap().log;
+        log.append(0, &[put("a", "1")]).unwrap();
+
+        // A log that can't be written to, or cut back, takes no more appends.
+        log.state.lock().unwrap().current = File::open(&path).unwrap();
+        assert!(log.append(0, &[put("b", "2")]).is_err());
+        assert!(log.state.lock().unwrap().broken);
+        log.state.lock().unwrap().current = OpenOptions::new().append(true).open(&path).unwrap();
+        assert!(log.append(0, &[put("c", "3")]).is_err());
+        drop(log);
+
+        assert_eq!(recover(&path, 0, 1, Fsync::Never).unwrap().entries, vec![put("a", "1")]);
+    }
+
+    #[test]
+    fn test_corrupt_tail() {
+        let dir = TempDir::new();
+        let path = dir.path().join("cache.log");
+        let recovered = recover(&path, 0, 1, Fsync::Never).unwrap();
+        recovered.log.append(0, &[put("a", "1"), put("b", "2")]).unwrap();
+        drop(recovered);
+        let len = fs::metadata(&path).unwrap().len();
+
+        // A torn write, and then garbage, at the end of the log.
+        let mut data = Vec::new();
+        put_entry(&mut data, &put("c", "3"));
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&data[..data.len() - 2]).unwrap();
+        let recovered = recover(&path, 0, 1, Fsync::Never).unwrap();
+        assert_eq!(recovered.entries, vec![put("a", "1"), put("b", "2")]);
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+
+        // The tail is cut off, so what's appended next can be read back.
+        recovered.log.append(0, &[put("d", "4")]).unwrap();
+        drop(recovered);
+        let mut data = Vec::new();
+        File::open(&path).unwrap().read_to_end(&mut data).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0x01;
+        File::create(&path).unwrap().write_all(&data).unwrap();
+        let recovered = recover(&path, 0, 1, Fsync::Never).unwrap();
+        assert_eq!(recovered.entries, vec![put("a", "1"), put("b", "2")]);
+    }
+
+    #[test]
+    fn test_compaction() {
+        let dir = TempDir::new();
+        let path = dir.path().join("cache.log");
+        let log = recover(&path, 0, 2, Fsync::Never).unwrap().log;
+        log.append(0, &[put("a", "1")]).unwrap();
+        log.append(1, &[put("b", "1")]).unwrap();
+
+        // Shard 0 has handed over its part of snapshot 1, shard 1 hasn't yet.
+        log.start_compaction(1).unwrap();
+        log.switch(0);
+        log.append(0, &[put("a", "2")]).unwrap();
+        log.append(1, &[put("b", "2")]).unwrap();
+        log.switch(1);
+        log.append(1, &[put("b", "3")]).unwrap();
+
+        // A crash before snapshot 1 is written replays both logs over snapshot 0.
+        let recovered = recover(&path, 0, 2, Fsync::Never).unwrap();
+        assert_eq!(
+            recovered.entries,
+            vec![put("a", "1"), put("b", "1"), put("b", "2"), put("a", "2"), put("b", "3")]
+        );
+        assert_eq!(recovered.generation, 1);
+        assert!(!next_path(&path).exists());
+        drop(recovered);
+
+        // A crash after snapshot 1 is written, before the logs are swapped, only replays the
+        // writes made after each shard's part was taken.
+        let log = recover(&path, 0, 2, Fsync::Never).unwrap().log;
+        log.start_compaction(1).unwrap();
+        log.switch(0);
+        log.switch(1);
+        log.append(0, &[put("a", "3")]).unwrap();
+        let recovered = recover(&path, 1, 2, Fsync::Never).unwrap();
+        assert_eq!(recovered.entries, vec![put("a", "3")]);
+        drop(recovered);
+
+        // A finished compaction leaves the new log in place.
+        let log = recover(&path, 1, 2, Fsync::Never).unwrap().log;
+        log.start_compaction(2).unwrap();
+        log.switch(0);
+        log.switch(1);
+        log.append(1, &[put("b", "4")]).unwrap();
+        log.finish_compaction(true).unwrap();
+        log.append(0, &[put("a", "4")]).unwrap();
+        assert!(!next_path(&path).exists());
+        let recovered = recover(&path, 2, 2, Fsync::Never).unwrap();
+        assert_eq!(recovered.entries, vec![put("b", "4"), put("a", "4")]);
+        drop(recovered);
+
+        // A failed snapshot moves the new log's writes back to the current one.
+        let log = recover(&path, 2, 2, Fsync::Never).unwrap().log;
+        log.start_compaction(3).unwrap();
+        log.switch(0);
+        log.append(0, &[put("a", "5")]).unwrap();
+        log.finish_compaction(false).unwrap();
+        log.append(0, &[put("a", "6")]).unwrap();
+        let recovered = recover(&path, 2, 2, Fsync::Never).unwrap();
+        assert_eq!(
+            recovered.entries,
+            vec![put("b", "4"), put("a", "4"), put("a", "5"), put("a", "6")]
+        );
+        assert_eq!(recovered.generation, 2);
+    }
+}
@@ -1,22 +1,86 @@
-use codec::CacheCodec;
-use message::Message;
-use tokio_io::codec::Framed;
+use bytes::BytesMut;
+use codec::{self, CacheCodec, Limits};
+use futures::{future, Async, Future, Poll, Sink, StartSend, Stream};
+use message::{Message, Op, Code};
+use tokio_io::codec::{Framed, FramedParts};
 use tokio_io::{AsyncRead, AsyncWrite};
-use tokio_proto::multiplex::{ClientProto, ServerProto};
+use tokio_proto::multiplex::{ClientProto, RequestId, ServerProto};
 use std::io;
 
 /// `CacheProto`
-pub struct CacheProto;
+///
+/// As a client protocol, it frames a connection whose hello is already done, see `handshake`,
+/// with a codec for the capabilities agreed in it. As a server protocol, each connection gets the
+/// codec its own hello asks for, see `accept`.
+pub struct CacheProto {
+    capabilities: u32,
+    limits: Limits,
+}
+
+impl CacheProto {
+    /// The protocol of a connection that agreed on `capabilities` in its hello.
+    pub fn negotiated(capabilities: u32, limits: Limits) -> CacheProto {
+        CacheProto {
+            capabilities: capabilities,
+            limits: limits,
+        }
+    }
+
+    /// The protocol of a connection that skipped the hello, or whose hello was rejected.
+    pub fn legacy(limits: Limits) -> CacheProto {
+        CacheProto::negotiated(codec::LEGACY_CAPABILITIES, limits)
+    }
+}
 
 impl<T: AsyncRead + AsyncWrite + 'static> ClientProto<T> for CacheProto {
     type Request = Message;
     type Response = Message;
 
-    type Transport = Framed<T, CacheCodec>;
+    type Transport = ClientTransport<T>;
     type BindTransport = Result<Self::Transport, io::Error>;
 
     fn bind_transport(&self, io: T) -> Self::BindTransport {
-        Ok(io.framed(CacheCodec))
+        let framed = io.framed(CacheCodec::negotiated(self.capabilities, self.limits));
+        Ok(ClientTransport { inner: framed })
+    }
+}
+
+/// The frames of a client connection, which fail once the server closes it. The multiplexer
+/// only fails the requests in flight when its transport fails, and would have them wait for
+/// answers forever after a clean close.
+pub struct ClientTransport<T> {
+    inner: Framed<T, CacheCodec>,
+}
+
+impl<T: AsyncRead + AsyncWrite> Stream for ClientTransport<T> {
+    type Item = (RequestId, Message);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
+        match self.inner.poll()? {
+            Async::Ready(None) => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed by the server",
+            )),
+            frame => Ok(frame),
+        }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite> Sink for ClientTransport<T> {
+    type SinkItem = (RequestId, Message);
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, io::Error> {
+        self.inner.start_send(item)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        self.inner.poll_complete()
+    }
+
+    fn close(&mut self) -> Poll<(), io::Error> {
+        self.inner.close()
     }
 }
 
@@ -25,9 +89,208 @@ impl<T: AsyncRead + AsyncWrite + 'static> ServerProto<T> for CacheProto {
     type Response = Message;
 
     type Transport = Framed<T, CacheCodec>;
-    type BindTransport = Result<Self::Transport, io::Error>;
+    type BindTransport = Box<Future<Item = Self::Transport, Error = io::Error>>;
 
     fn bind_transport(&self, io: T) -> Self::BindTransport {
-        Ok(io.framed(CacheCodec))
+        accept(io, self.limits)
+    }
+}
+
+/// Open a client connection with a hello, offering everything the codec supports, and resolve to
+/// the connection and the protocol to go on with. A server that answers the hello with anything
+/// but Ok doesn't know it, and gets the legacy protocol. A server that predates any handshake drops
+/// the connection when it reads a hello, which is an `UnexpectedEof` error.
+pub fn handshake<T>(io: T, limits: Limits) -> Box<Future<Item = (T, CacheProto), Error = io::Error>>
+where
+    T: AsyncRead + AsyncWrite + 'static,
+{
+    let hello = codec::hello(codec::PROTOCOL_VERSION, codec::CAPABILITIES);
+    Box::new(
+        io.framed(CacheCodec::with_limits(limits))
+            .send((0, hello))
+            .and_then(|transport| transport.into_future().map_err(|(e, _)| e))
+            .and_then(move |(answer, transport)| {
+                let capabilities = match answer {
+                    Some((_, Message::Response(Op::Hello, Code::Ok, Some(payload)))) => {
+                        match codec::get_hello(&payload) {
+                            Some((_, capabilities)) => capabilities,
+                            None => {
+                                return Err(io::Error::new(
+                                    io::ErrorKind::InvalidData,
+                                    "malformed answer to hello",
+                                ))
+                            }
+                        }
+                    }
+                    Some(_) => codec::LEGACY_CAPABILITIES,
+                    None => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "connection closed during handshake",
+                        ))
+                    }
+                };
+                // The server sends nothing more until it's asked, so no frame is left buffered.
+                let io = transport.into_inner();
+                Ok((io, CacheProto::negotiated(capabilities, limits)))
+            }),
+    )
+}
+
+/// Frame a server connection with the codec its first frame asks for: a connection that opens
+/// with a hello gets it answered and the capabilities agreed in it, any other gets the legacy
+/// protocol, starting with that first frame.
+pub fn accept<T>(
+    io: T,
+    limits: Limits,
+) -> Box<Future<Item = Framed<T, CacheCodec>, Error = io::Error>>
+where
+    T: AsyncRead + AsyncWrite + 'static,
+{
+    let header = ReadHeader {
+        io: Some(io),
+        buf: BytesMut::with_capacity(codec::HEADER_LEN),
+    };
+    Box::new(header.and_then(move |(io, buf)| {
+        let hello = codec::is_hello(&buf);
+        let parts = FramedParts {
+            inner: io,
+            readbuf: buf,
+            writebuf: BytesMut::new(),
+        };
+        let legacy = Framed::from_parts(parts, CacheCodec::with_limits(limits));
+        if !hello {
+            return Box::new(future::ok(legacy)) as Box<Future<Item = _, Error = _>>;
+        }
+
+        Box::new(legacy.into_future().map_err(|(e, _)| e).and_then(
+            move |(hello, transport)| {
+                let (id, hello) = match hello {
+                    Some(hello) => hello,
+                    None => {
+                        let e = io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "connection closed during handshake",
+                        );
+                        return Box::new(future::err(e)) as Box<Future<Item = _, Error = _>>;
+                    }
+                };
+                let (answer, capabilities) = match hello {
+                    Message::Request(Op::Hello, ..) => codec::answer_hello(&hello),
+                    // A hello too large or malformed to decode, which the codec has answered
+                    // itself. The connection carries on with that codec, which may still have
+                    // the rest of an oversize frame to discard.
+                    answer => return Box::new(transport.send((id, answer))),
+                };
+                // The answer has no extensions, so that the client can read it before it switches
+                // codecs.
+                Box::new(transport.send((id, answer)).map(move |transport| {
+                    let codec = CacheCodec::negotiated(capabilities, limits);
+                    Framed::from_parts(transport.into_parts(), codec)
+                }))
+            },
+        ))
+    }))
+}
+
+/// Reads from a connection until it holds a frame header, or the peer closes it.
+struct ReadHeader<T> {
+    io: Option<T>,
+    buf: BytesMut,
+}
+
+impl<T: AsyncRead> Future for ReadHeader<T> {
+    type Item = (T, BytesMut);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(T, BytesMut), io::Error> {
+        while self.buf.len() < codec::HEADER_LEN {
+            let read = match self.io {
+                Some(ref mut io) => io.read_buf(&mut self.buf)?,
+                None => panic!("ReadHeader polled after it resolved"),
+            };
+            match read {
+                Async::Ready(0) => break,
+                Async::Ready(_) => {}
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+        let io = self.io.take().unwrap();
+        Ok(Async::Ready((io, self.buf.split_off(0))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::time::Duration;
+    use message;
+    use service::Protocol;
+    use testing;
+    use tokio_io::codec::{Decoder, Encoder};
+
+    /// Write `frames` to `stream`, and read back `count` responses with `codec`.
+    fn exchange(
+        stream: &mut TcpStream,
+        frames: &[u8],
+        codec: &mut CacheCodec,
+        count: usize,
+    ) -> Vec<(u64, Message)> {
+        stream.write_all(frames).unwrap();
+
+        let mut responses = Vec::new();
+        let mut buf = BytesMut::new();
+        while responses.len() < count {
+            let mut chunk = [0; 1024];
+            let len = stream.read(&mut chunk).unwrap();
+            assert!(len > 0, "connection closed");
+            buf.extend_from_slice(&chunk[..len]);
+            while let Some(response) = codec.decode(&mut buf).unwrap() {
+                responses.push(response);
+            }
+        }
+        responses
+    }
+
+    fn connect(addr: &::std::net::SocketAddr) -> TcpStream {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream
+    }
+
+    #[test]
+    fn test_accept() {
+        let addr = testing::spawn_cache(Protocol::Rcache);
+        let get = message::request(Op::Get, "foo".into(), None);
+        let miss = message::response(Op::Get, Code::Miss, None);
+
+        // A request sent straight after the hello is read with the agreed codec.
+        let mut stream = connect(&addr);
+        let mut legacy = CacheCodec::with_limits(Limits::default());
+        let mut negotiated = CacheCodec::negotiated(codec::CAP_CHECKSUM, Limits::default());
+        let mut buf = BytesMut::new();
+        let hello = codec::hello(codec::PROTOCOL_VERSION, codec::CAP_CHECKSUM);
+        legacy.encode((0, hello), &mut buf).unwrap();
+        negotiated.encode((1, get.clone()), &mut buf).unwrap();
+        let responses = exchange(&mut stream, &buf, &mut negotiated, 2);
+        assert_eq!(responses[0].1.code(), Code::Ok);
+        assert_eq!(
+            codec::get_hello(responses[0].1.payload().unwrap()),
+            Some((codec::PROTOCOL_VERSION, codec::CAP_CHECKSUM))
+        );
+        assert_eq!(responses[1], (1, miss.clone()));
+
+        // A connection without a hello gets the legacy protocol, and a later hello is an error.
+        let mut stream = connect(&addr);
+        let mut buf = BytesMut::new();
+        legacy.encode((1, get), &mut buf).unwrap();
+        let hello = codec::hello(codec::PROTOCOL_VERSION, codec::CAPABILITIES);
+        legacy.encode((2, hello), &mut buf).unwrap();
+        let responses = exchange(&mut stream, &buf, &mut legacy, 2);
+        assert_eq!(responses[0], (1, miss));
+        assert_eq!(responses[1].0, 2);
+        assert_eq!(responses[1].1.code(), Code::Error);
     }
 }
@@ -0,0 +1,832 @@
+use std::collections::VecDeque;
+use std::io;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use futures::Future;
+use futures::sync::oneshot::Sender;
+use tokio_core::reactor::{Core, Timeout};
+use bytes::{Buf, BufMut, BigEndian};
+use message::{self, Message, Op, Code};
+use client::Client;
+use service::Addr;
+use snapshot::Record;
+use oplog::{self, Entry};
+use cache;
+use error;
+
+/// Roughly the most bytes of writes the backlog keeps for replicas to catch up from, see
+/// `entry_len`. A replica that falls further behind has to sync from scratch.
+const BACKLOG_BYTES: usize = 64 << 20;
+/// How long the backlog keeps recording writes after the last `Sync` request from any replica.
+const IDLE_MS: u64 = 60_000;
+/// The most full syncs whose copies of the store are being taken or sent at once.
+const MAX_TRANSFERS: usize = 4;
+/// How long a `Sync` request from a replica that has caught up waits for a write, before it is
+/// answered with none.
+const WAIT_MS: u64 = 1000;
+/// Roughly the most bytes of entries in a `Sync` response, so that a full sync of a large cache
+/// is sent in pages that stay well within the frame limits.
+const PAGE_LEN: usize = 1 << 20;
+/// How long the copy of the store for a full sync is kept after it was last asked for, if the
+/// replica doesn't read it to the end.
+const TRANSFER_TTL_MS: u64 = 60_000;
+/// How long a replica waits for a response before it gives up on the primary. This is also how
+/// a replica notices that the primary closed the connection, as the client doesn't fail the
+/// requests in flight when it does.
+const RESPONSE_TIMEOUT_MS: u64 = 5000;
+/// How long a replica waits before reconnecting to a primary it lost.
+const RETRY_MS: u64 = 1000;
+
+static POSITION_LEN: usize = 8 + 8 + 8 + 8;
+
+/// Where a replica is in a primary's stream of writes.
+///
+/// `run_id` identifies the run of the primary, and `offset` counts the writes it has made in
+/// that run, so that a replica that reconnects to a primary that has since restarted knows its
+/// offset means nothing. A replica in the middle of a full sync reads the copy of the store
+/// identified by `transfer`, and `cursor` is how far through it the replica is. Both are 0
+/// otherwise.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Position {
+    pub run_id: u64,
+    pub offset: u64,
+    pub transfer: u64,
+    pub cursor: u64,
+}
+
+/// A page of writes from the primary, see `parse_response`.
+#[derive(Debug, PartialEq)]
+pub struct Sync {
+    /// Where the replica is once it has applied `entries`.
+    pub position: Position,
+    /// The offset of the primary's latest write.
+    pub head: u64,
+    pub entries: Vec<Entry>,
+}
+
+/// An `Op::Sync` request for the writes after `position`. A replica that has nothing to go on
+/// sends `Position::default()`, which starts a full sync.
+pub fn sync_request(position: Position) -> Message {
+    let mut data = Vec::with_capacity(POSITION_LEN);
+    put_position(&mut data, position);
+    message::request(Op::Sync, vec![], Some(message::payload(message::TYPE_ID_SYNC, data)))
+}
+
+/// Decode the response to a `sync_request`. A `Code::Miss` means the primary no longer has the
+/// writes after the requested position, and the replica has to sync from scratch: that is None.
+pub fn parse_response(msg: &Message) -> io::Result<Option<Sync>> {
+    let payload = match (msg.op(), msg.code(), msg.payload()) {
+        (Op::Sync, Code::Miss, _) => return Ok(None),
+        (Op::Sync, Code::Ok, Some(payload)) if payload.type_id() == message::TYPE_ID_SYNC => {
+            payload
+        }
+        _ => return Err(invalid(&format!("unexpected response {}", msg))),
+    };
+
+    let data = payload.data();
+    if data.len() < POSITION_LEN + 8 {
+        return Err(invalid("sync response too short"));
+    }
+    let mut cursor = io::Cursor::new(data);
+    let position = get_position(&mut cursor);
+    let head = cursor.get_u64::<BigEndian>();
+
+    let mut entries = Vec::new();
+    let mut rest = &data[POSITION_LEN + 8..];
+    while !rest.is_empty() {
+        match oplog::get_entry(rest) {
+            Some((entry, len)) => {
+                entries.push(entry);
+                rest = &rest[len..];
+            }
+            None => return Err(invalid("corrupt entry in sync response")),
+        }
+    }
+    Ok(Some(Sync {
+        position: position,
+        head: head,
+        entries: entries,
+    }))
+}
+
+fn sync_response(position: Position, head: u64, entries: &[u8]) -> Message {
+    let mut data = Vec::with_capacity(POSITION_LEN + 8 + entries.len());
+    put_position(&mut data, position);
+    data.put_u64::<BigEndian>(head);
+    data.extend_from_slice(entries);
+    message::response(Op::Sync, Code::Ok, Some(message::payload(message::TYPE_ID_SYNC, data)))
+}
+
+fn put_position(buf: &mut Vec<u8>, position: Position) {
+    buf.put_u64::<BigEndian>(position.run_id);
+    buf.put_u64::<BigEndian>(position.offset);
+    buf.put_u64::<BigEndian>(position.transfer);
+    buf.put_u64::<BigEndian>(position.cursor);
+}
+
+fn get_position(cursor: &mut io::Cursor<&[u8]>) -> Position {
+    Position {
+        run_id: cursor.get_u64::<BigEndian>(),
+        offset: cursor.get_u64::<BigEndian>(),
+        transfer: cursor.get_u64::<BigEndian>(),
+        cursor: cursor.get_u64::<BigEndian>(),
+    }
+}
+
+/// Roughly the bytes `entry` takes up in the backlog.
+fn entry_len(entry: &Entry) -> usize {
+    match *entry {
+        Entry::Put(ref record) => record.key.len() + record.payload.data().len() + 64,
+        Entry::Del(ref key) => key.len() + 32,
+    }
+}
+
+fn invalid(description: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, description)
+}
+
+/// The writes a primary has made recently, for replicas to follow.
+///
+/// A replica starts with a full sync: every shard copies its store a chunk at a time, as for a
+/// snapshot, and the copy is sent in pages. The copy is taken at an offset recorded before any
+/// shard makes it, so it may already hold some of the writes after that offset. As the backlog
+/// holds what each write left at its key, rather than the request, applying those writes again
+/// changes nothing. The replica then asks for the writes after the offset, and keeps asking. A
+/// replica that has caught up waits for the next write, or up to `WAIT_MS`, for its response.
+///
+/// Each full sync has a copy of its own, which is dropped once the replica has read it. At most
+/// `MAX_TRANSFERS` are kept at once, and a replica that asks for another is answered with an
+/// error, and retries.
+///
+/// A cache that no replica has synced from doesn't record its writes, so that the backlog costs
+/// nothing until a replica connects. Once no replica has asked for writes in `IDLE_MS`, it stops
+/// again, and drops the writes it has.
+pub struct Backlog {
+    run_id: u64,
+    shards: usize,
+    active: AtomicBool,
+    state: Mutex<BacklogState>,
+}
+
+struct BacklogState {
+    /// The offset of the first write in `entries`.
+    start: u64,
+    entries: VecDeque<Entry>,
+    /// The bytes `entries` take up, see `entry_len`.
+    bytes: usize,
+    waiting: Vec<Waiter>,
+    transfers: Vec<Transfer>,
+    /// The number of full syncs whose copies of the store are still being taken.
+    copying: usize,
+    /// The id of the latest transfer.
+    last_transfer: u64,
+    /// When a replica last asked for writes.
+    last_sync: Instant,
+}
+
+/// A replica that has caught up, waiting for the next write.
+struct Waiter {
+    offset: u64,
+    deadline: Instant,
+    snd: Sender<Message>,
+}
+
+/// The copy of the store for a full sync, taken at `offset`.
+struct Transfer {
+    id: u64,
+    offset: u64,
+    records: Vec<Record>,
+    last_used: Instant,
+}
+
+impl Backlog {
+    pub fn new(shards: usize) -> Self {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        Backlog {
+            // Any id but 0, which a replica that has never synced sends.
+            run_id: ((now.as_secs() << 30) ^ now.subsec_nanos() as u64) | 1,
+            shards: shards,
+            active: AtomicBool::new(false),
+            state: Mutex::new(BacklogState {
+                start: 0,
+                entries: VecDeque::new(),
+                bytes: 0,
+                waiting: Vec::new(),
+                transfers: Vec::new(),
+                copying: 0,
+                last_transfer: 0,
+                last_sync: Instant::now(),
+            }),
+        }
+    }
+
+    /// Whether a replica is syncing from the cache, so that its writes have to be recorded.
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    /// The offset of the latest write.
+    pub fn head(&self) -> u64 {
+        let state = self.state.lock().unwrap();
+        state.start + state.entries.len() as u64
+    }
+
+    /// Record writes, in the order they were made, and pass them on to the replicas waiting for
+    /// them.
+    pub fn append(&self, entries: Vec<Entry>) {
+        let mut state = self.state.lock().unwrap();
+        for entry in entries {
+            state.bytes += entry_len(&entry);
+            state.entries.push_back(entry);
+        }
+        while state.bytes > BACKLOG_BYTES {
+            match state.entries.pop_front() {
+                Some(entry) => {
+                    state.bytes -= entry_len(&entry);
+                    state.start += 1;
+                }
+                None => break,
+            }
+        }
+
+        let waiting = state.waiting.split_off(0);
+        for waiter in waiting {
+            let response = self.page(&state, waiter.offset);
+            let _ = waiter.snd.send(response);
+        }
+    }
+
+    /// Answer the `Op::Sync` request `msg` on `snd`. A request that starts a full sync returns
+    /// the `Dump` that the cache has to hand to every shard, which answers it once each has
+    /// contributed its copy of the store.
+    pub fn sync(this: &Arc<Self>, msg: &Message, snd: Sender<Message>) -> Option<Arc<Dump>> {
+        let position = match msg.payload() {
+            Some(payload) if payload.type_id() == message::TYPE_ID_SYNC &&
+                                 payload.data().len() == POSITION_LEN => {
+                get_position(&mut io::Cursor::new(payload.data()))
+            }
+            _ => {
+                let e = error::Error::new(error::ErrorKind::BadMessage, "invalid sync request");
+                let _ = snd.send(message::error_response(Op::Sync, &e));
+                return None;
+            }
+        };
+
+        let mut state = this.state.lock().unwrap();
+        this.active.store(true, Ordering::SeqCst);
+        state.last_sync = Instant::now();
+        let head = state.start + state.entries.len() as u64;
+
+        if position.run_id != this.run_id {
+            if state.copying + state.transfers.len() >= MAX_TRANSFERS {
+                let e = error::Error::new(error::ErrorKind::Other, "too many full syncs at once");
+                let _ = snd.send(message::error_response(Op::Sync, &e));
+                return None;
+            }
+            state.copying += 1;
+            state.last_transfer += 1;
+            return Some(Arc::new(Dump {
+                backlog: this.clone(),
+                id: state.last_transfer,
+                offset: head,
+                state: Mutex::new(DumpState {
+                    parts: (0..this.shards).map(|_| Vec::new()).collect(),
+                    remaining: this.shards,
+                    snd: Some(snd),
+                }),
+            }));
+        }
+
+        let response = if position.cursor > 0 {
+            this.transfer_page(&mut state, position, head)
+        } else if position.offset < state.start || position.offset > head {
+            message::response(Op::Sync, Code::Miss, None)
+        } else if position.offset == head {
+            state.waiting.push(Waiter {
+                offset: position.offset,
+                deadline: Instant::now() + Duration::from_millis(WAIT_MS),
+                snd: snd,
+            });
+            return None;
+        } else {
+            this.page(&state, position.offset)
+        };
+        let _ = snd.send(response);
+        None
+    }
+
+    /// Answer the replicas that have waited `WAIT_MS` without a write, drop the copies of the
+    /// store that no replica has asked for in `TRANSFER_TTL_MS`, and stop recording writes if no
+    /// replica has asked for any in `IDLE_MS`.
+    pub fn tick(&self, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        let (expired, waiting) = state.waiting.drain(..).partition::<Vec<_>, _>(|waiter| {
+            waiter.deadline <= now
+        });
+        state.waiting = waiting;
+        for waiter in expired {
+            let response = self.page(&state, waiter.offset);
+            let _ = waiter.snd.send(response);
+        }
+
+        let ttl = Duration::from_millis(TRANSFER_TTL_MS);
+        state.transfers.retain(|transfer| now - transfer.last_used < ttl);
+
+        let idle = Duration::from_millis(IDLE_MS);
+        if self.is_active() && now >= state.last_sync + idle {
+            // The writes made from now on aren't recorded, so the next offset is skipped: a
+            // replica that was caught up can't take the writes it missed for none.
+            self.active.store(false, Ordering::SeqCst);
+            state.start += state.entries.len() as u64 + 1;
+            state.entries.clear();
+            state.bytes = 0;
+        }
+    }
+
+    /// The writes after `offset`, up to `PAGE_LEN` bytes of them, or a `Code::Miss` if they
+    /// have been dropped.
+    fn page(&self, state: &BacklogState, offset: u64) -> Message {
+        if offset < state.start {
+            return message::response(Op::Sync, Code::Miss, None);
+        }
+        let head = state.start + state.entries.len() as u64;
+        let mut buf = Vec::new();
+        let mut next = offset;
+        for entry in state.entries.iter().skip((offset - state.start) as usize) {
+            if buf.len() >= PAGE_LEN {
+                break;
+            }
+            oplog::put_entry(&mut buf, entry);
+            next += 1;
+        }
+        let position = Position {
+            run_id: self.run_id,
+            offset: next,
+            transfer: 0,
+            cursor: 0,
+        };
+        sync_response(position, head, &buf)
+    }
+
+    /// The page of the full sync at `position`, or a `Code::Miss` if its copy of the store has
+    /// been dropped. The copy is dropped once its last page has been sent.
+    fn transfer_page(&self, state: &mut BacklogState, position: Position, head: u64) -> Message {
+        let index = match state.transfers.iter().position(
+            |transfer| transfer.id == position.transfer,
+        ) {
+            Some(index) => index,
+            None => return message::response(Op::Sync, Code::Miss, None),
+        };
+        let (response, done) = {
+            let transfer = &mut state.transfers[index];
+            transfer.last_used = Instant::now();
+
+            let mut buf = Vec::new();
+            let mut cursor = position.cursor as usize - 1;
+            while cursor < transfer.records.len() && buf.len() < PAGE_LEN {
+                oplog::put_entry(&mut buf, &Entry::Put(transfer.records[cursor].clone()));
+                cursor += 1;
+            }
+            let done = cursor >= transfer.records.len();
+            let position = Position {
+                run_id: self.run_id,
+                offset: transfer.offset,
+                transfer: if done { 0 } else { transfer.id },
+                // Cursors start at 1, so that 0 can mean the transfer is done.
+                cursor: if done { 0 } else { cursor as u64 + 1 },
+            };
+            (sync_response(position, head, &buf), done)
+        };
+        if done {
+            state.transfers.swap_remove(index);
+        }
+        response
+    }
+}
+
+/// Collects the copies of each shard's store for a full sync, and sends its first page once the
+/// last shard has contributed.
+pub struct Dump {
+    backlog: Arc<Backlog>,
+    id: u64,
+    offset: u64,
+    state: Mutex<DumpState>,
+}
+
+struct DumpState {
+    parts: Vec<Vec<Record>>,
+    remaining: usize,
+    snd: Option<Sender<Message>>,
+}
+
+impl Dump {
+    /// Hand over `shard`'s copy of its store.
+    pub fn contribute(&self, shard: usize, records: Vec<Record>) {
+        let (parts, snd) = {
+            let mut state = self.state.lock().unwrap();
+            state.parts[shard] = records;
+            state.remaining -= 1;
+            if state.remaining > 0 {
+                return;
+            }
+            (state.parts.split_off(0), state.snd.take())
+        };
+
+        let mut records = Vec::new();
+        for part in parts {
+            records.extend(part);
+        }
+        let mut state = self.backlog.state.lock().unwrap();
+        let head = state.start + state.entries.len() as u64;
+        state.copying -= 1;
+        state.transfers.push(Transfer {
+            id: self.id,
+            offset: self.offset,
+            records: records,
+            last_used: Instant::now(),
+        });
+        let position = Position {
+            run_id: self.backlog.run_id,
+            offset: self.offset,
+            transfer: self.id,
+            cursor: 1,
+        };
+        let response = self.backlog.transfer_page(&mut state, position, head);
+        if let Some(snd) = snd {
+            let _ = snd.send(response);
+        }
+    }
+}
+
+/// Keeps the stores of a read only replica in step with its primary.
+///
+/// A thread of its own follows the primary, see `Backlog`, and hands each shard the writes to
+/// its keys. The workers pick them up between units of work. After losing the primary, the
+/// replica reconnects and carries on from where it was if the primary still has the writes it
+/// missed, and syncs from scratch if it doesn't.
+pub struct Replica {
+    primary: Addr,
+    shards: usize,
+    pending: Mutex<Vec<Pending>>,
+    ready: Vec<AtomicBool>,
+    offset: AtomicUsize,
+    head: AtomicUsize,
+    connected: AtomicBool,
+    syncing: AtomicBool,
+    stop: AtomicBool,
+}
+
+/// Writes for a shard to apply.
+#[derive(Default)]
+pub struct Pending {
+    /// Whether to clear the store first, as a full sync starts.
+    pub reset: bool,
+    pub entries: Vec<Entry>,
+}
+
+impl Replica {
+    pub fn new(primary: Addr, shards: usize) -> Self {
+        Replica {
+            primary: primary,
+            shards: shards,
+            pending: Mutex::new((0..shards).map(|_| Pending::default()).collect()),
+            ready: (0..shards).map(|_| AtomicBool::new(false)).collect(),
+            offset: AtomicUsize::new(0),
+            head: AtomicUsize::new(0),
+            connected: AtomicBool::new(false),
+            syncing: AtomicBool::new(true),
+            stop: AtomicBool::new(false),
+        }
+    }
+
+    /// The offset of the primary's writes the replica has received.
+    pub fn offset(&self) -> u64 {
+        self.offset.load(Ordering::SeqCst) as u64
+    }
+
+    /// Whether the replica is `"connected"` to the primary, `"syncing"` from scratch, or
+    /// `"disconnected"`.
+    pub fn status(&self) -> &'static str {
+        if !self.connected.load(Ordering::SeqCst) {
+            "disconnected"
+        } else if self.syncing.load(Ordering::SeqCst) {
+            "syncing"
+        } else {
+            "connected"
+        }
+    }
+
+    /// How many writes the replica is behind the primary, as of the last response, or `None`
+    /// if it isn't connected or is still syncing from scratch.
+    pub fn lag(&self) -> Option<u64> {
+        if self.status() != "connected" {
+            return None;
+        }
+        let head = self.head.load(Ordering::SeqCst) as u64;
+        Some(head.saturating_sub(self.offset()))
+    }
+
+    /// Start following the primary, waking the worker `threads` as there are writes for them.
+    pub fn start(this: &Arc<Self>, threads: Vec<thread::Thread>) -> io::Result<()> {
+        let replica = this.clone();
+        thread::Builder::new()
+            .name("rcache-replica".to_owned())
+            .spawn(move || replica.run(&threads))
+            .map(|_| ())
+    }
+
+    /// Stop following the primary, once the request in flight is answered.
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+
+    /// The writes for `shard` to apply, if there are any.
+    pub fn take(&self, shard: usize) -> Option<Pending> {
+        if !self.ready[shard].swap(false, Ordering::SeqCst) {
+            return None;
+        }
+        let mut pending = self.pending.lock().unwrap();
+        Some(mem::replace(&mut pending[shard], Pending::default()))
+    }
+
+    fn run(&self, threads: &[thread::Thread]) {
+        let mut position = Position::default();
+        while !self.stop.load(Ordering::SeqCst) {
+            let result = self.follow(&mut position, threads);
+            self.connected.store(false, Ordering::SeqCst);
+            if let Err(e) = result {
+                warn!("Lost primary {}: {}.", self.primary, e);
+                thread::sleep(Duration::from_millis(RETRY_MS));
+            }
+        }
+    }
+
+    /// Connect to the primary and apply its writes from `position` on, until stopped.
+    fn follow(&self, position: &mut Position, threads: &[thread::Thread]) -> io::Result<()> {
+        let mut core = Core::new()?;
+        let handle = core.handle();
+        let client = core.run(Client::connect_addr(&self.primary, &handle))?;
+        info!("Replicating {}.", self.primary);
+        self.connected.store(true, Ordering::SeqCst);
+
+        while !self.stop.load(Ordering::SeqCst) {
+            // A replica is syncing from scratch until it has the last page of the copy.
+            self.syncing.store(position.run_id == 0 || position.cursor != 0, Ordering::SeqCst);
+            let request = client.sync(*position).map_err(|e| {
+                io::Error::new(io::ErrorKind::Other, e.to_string())
+            });
+            let timeout = Timeout::new(Duration::from_millis(RESPONSE_TIMEOUT_MS), &handle)?
+                .and_then(|_| -> io::Result<Message> {
+                    Err(io::Error::new(io::ErrorKind::TimedOut, "no response"))
+                });
+            let response = core.run(request.select(timeout))
+                .map(|(response, _)| response)
+                .map_err(|(e, _)| e)?;
+
+            let sync = match parse_response(&response)? {
+                Some(sync) => sync,
+                None => {
+                    *position = Position::default();
+                    continue;
+                }
+            };
+            // The first page of a full sync is the first from a new run of the primary.
+            let reset = sync.position.run_id != position.run_id;
+            self.deposit(reset, sync.entries, threads);
+            *position = sync.position;
+            self.offset.store(sync.position.offset as usize, Ordering::SeqCst);
+            self.head.store(sync.head as usize, Ordering::SeqCst);
+        }
+        self.syncing.store(position.run_id == 0 || position.cursor != 0, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Hand each shard the writes to its keys, and wake their workers.
+    fn deposit(&self, reset: bool, entries: Vec<Entry>, threads: &[thread::Thread]) {
+        if !reset && entries.is_empty() {
+            return;
+        }
+        {
+            let mut pending = self.pending.lock().unwrap();
+            if reset {
+                for pending in pending.iter_mut() {
+                    pending.reset = true;
+                    pending.entries.clear();
+                }
+            }
+            for entry in entries {
+                pending[cache::shard_for(entry.key(), self.shards)].entries.push(entry);
+            }
+        }
+        for (ready, thread) in self.ready.iter().zip(threads) {
+            ready.store(true, Ordering::SeqCst);
+            thread.unpark();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::sync::oneshot;
+
+    fn put(key: &str, value: &str) -> Entry {
+        Entry::Put(Record {
+            key: key.into(),
+            payload: message::payload(1, value.into()),
+            expires: None,
+        })
+    }
+
+    fn sync(backlog: &Arc<Backlog>, position: Position) -> oneshot::Receiver<Message> {
+        let (snd, rcv) = oneshot::channel();
+        assert!(Backlog::sync(backlog, &sync_request(position), snd).is_none());
+        rcv
+    }
+
+    #[test]
+    fn test_full_sync_and_stream() {
+        let backlog = Arc::new(Backlog::new(2));
+        assert!(!backlog.is_active());
+
+        let (snd, rcv) = oneshot::channel();
+        let dump = Backlog::sync(&backlog, &sync_request(Position::default()), snd).unwrap();
+        assert!(backlog.is_active());
+        dump.contribute(1, vec![Record {
+            key: "a".into(),
+            payload: message::payload(1, "1".into()),
+            expires: None,
+        }]);
+        // A write made while the shards are copying their stores is sent after the copy.
+        backlog.append(vec![put("b", "2")]);
+        dump.contribute(0, Vec::new());
+
+        let first = parse_response(&rcv.wait().unwrap()).unwrap().unwrap();
+        assert_eq!(first.position.offset, 0);
+        assert_eq!(first.position.cursor, 0);
+        assert_eq!(first.head, 1);
+        assert_eq!(first.entries, vec![put("a", "1")]);
+
+        let next = parse_response(&sync(&backlog, first.position).wait().unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(next.entries, vec![put("b", "2")]);
+        assert_eq!(next.position.offset, 1);
+
+        // A replica that has caught up waits for the next write.
+        let mut rcv = sync(&backlog, next.position);
+        assert_eq!(rcv.try_recv(), Ok(None));
+        backlog.append(vec![Entry::Del("a".into())]);
+        let last = parse_response(&rcv.wait().unwrap()).unwrap().unwrap();
+        assert_eq!(last.entries, vec![Entry::Del("a".into())]);
+        assert_eq!((last.position.offset, last.head), (2, 2));
+
+        // Or for `WAIT_MS`, if there is none.
+        let rcv = sync(&backlog, last.position);
+        backlog.tick(Instant::now() + Duration::from_millis(WAIT_MS));
+        let empty = parse_response(&rcv.wait().unwrap()).unwrap().unwrap();
+        assert!(empty.entries.is_empty());
+        assert_eq!(empty.position, last.position);
+    }
+
+    #[test]
+    fn test_pages() {
+        let backlog = Arc::new(Backlog::new(1));
+        let (snd, rcv) = oneshot::channel();
+        let dump = Backlog::sync(&backlog, &sync_request(Position::default()), snd).unwrap();
+        let value = vec![0; PAGE_LEN / 2];
+        let records: Vec<Record> = (0..5)
+            .map(|i| {
+                Record {
+                    key: vec![i],
+                    payload: message::payload(1, value.clone()),
+                    expires: None,
+                }
+            })
+            .collect();
+        dump.contribute(0, records.clone());
+
+        let mut received = Vec::new();
+        let mut page = parse_response(&rcv.wait().unwrap()).unwrap().unwrap();
+        loop {
+            assert!(page.entries.len() <= 2);
+            received.extend(page.entries);
+            if page.position.cursor == 0 {
+                break;
+            }
+            page = parse_response(&sync(&backlog, page.position).wait().unwrap())
+                .unwrap()
+                .unwrap();
+        }
+        let expected: Vec<Entry> = records.into_iter().map(Entry::Put).collect();
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn test_resync() {
+        let backlog = Arc::new(Backlog::new(1));
+        let (snd, _rcv) = oneshot::channel();
+        backlog.active.store(true, Ordering::SeqCst);
+        let value = String::from_utf8(vec![b'x'; 1 << 20]).unwrap();
+        let writes = BACKLOG_BYTES / value.len() + 1;
+        for i in 0..writes {
+            backlog.append(vec![put(&i.to_string(), &value)]);
+        }
+        assert!(backlog.state.lock().unwrap().bytes <= BACKLOG_BYTES);
+
+        // The writes after offset 0 have been dropped from the backlog.
+        let position = Position {
+            run_id: backlog.run_id,
+            offset: 0,
+            transfer: 0,
+            cursor: 0,
+        };
+        let resp = sync(&backlog, position).wait().unwrap();
+        assert_eq!(parse_response(&resp).unwrap(), None);
+
+        // A replica of a previous run of the primary has to sync from scratch.
+        let position = Position {
+            run_id: backlog.run_id + 1,
+            offset: writes as u64,
+            transfer: 0,
+            cursor: 0,
+        };
+        assert!(Backlog::sync(&backlog, &sync_request(position), snd).is_some());
+    }
+
+    #[test]
+    fn test_concurrent_full_syncs() {
+        let backlog = Arc::new(Backlog::new(1));
+        let value = vec![0; PAGE_LEN];
+        let records: Vec<Record> = (0..2)
+            .map(|i| {
+                Record {
+                    key: vec![i],
+                    payload: message::payload(1, value.clone()),
+                    expires: None,
+                }
+            })
+            .collect();
+
+        // Two replicas syncing from the same head each read a copy of their own.
+        let mut pages = Vec::new();
+        for _ in 0..2 {
+            let (snd, rcv) = oneshot::channel();
+            let dump = Backlog::sync(&backlog, &sync_request(Position::default()), snd).unwrap();
+            dump.contribute(0, records.clone());
+            pages.push(parse_response(&rcv.wait().unwrap()).unwrap().unwrap());
+        }
+        assert_eq!(pages[0].position.offset, pages[1].position.offset);
+        assert!(pages[0].position.transfer != pages[1].position.transfer);
+        for page in pages {
+            let last = parse_response(&sync(&backlog, page.position).wait().unwrap())
+                .unwrap()
+                .unwrap();
+            assert_eq!(last.entries, vec![Entry::Put(records[1].clone())]);
+            assert_eq!(last.position.cursor, 0);
+        }
+        // The copies are dropped once they have been read.
+        assert!(backlog.state.lock().unwrap().transfers.is_empty());
+
+        // Beyond `MAX_TRANSFERS`, a replica is refused.
+        let mut rcvs = Vec::new();
+        for _ in 0..MAX_TRANSFERS {
+            let (snd, rcv) = oneshot::channel();
+            assert!(Backlog::sync(&backlog, &sync_request(Position::default()), snd).is_some());
+            rcvs.push(rcv);
+        }
+        let (snd, rcv) = oneshot::channel();
+        assert!(Backlog::sync(&backlog, &sync_request(Position::default()), snd).is_none());
+        assert!(parse_response(&rcv.wait().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_idle() {
+        let backlog = Arc::new(Backlog::new(1));
+        let (snd, _rcv) = oneshot::channel();
+        let dump = Backlog::sync(&backlog, &sync_request(Position::default()), snd).unwrap();
+        dump.contribute(0, Vec::new());
+        backlog.append(vec![put("a", "1")]);
+        let position = Position {
+            run_id: backlog.run_id,
+            offset: backlog.head(),
+            transfer: 0,
+            cursor: 0,
+        };
+
+        backlog.tick(Instant::now());
+        assert!(backlog.is_active());
+        backlog.tick(Instant::now() + Duration::from_millis(IDLE_MS));
+        assert!(!backlog.is_active());
+        assert_eq!(backlog.state.lock().unwrap().entries.len(), 0);
+
+        // A replica that was caught up has missed the writes made since, so it syncs from
+        // scratch.
+        let resp = sync(&backlog, position).wait().unwrap();
+        assert_eq!(parse_response(&resp).unwrap(), None);
+        assert!(backlog.is_active());
+    }
+}
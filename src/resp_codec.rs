@@ -0,0 +1,616 @@
+use tokio_io::codec::{Encoder, Decoder};
+use std::cmp;
+use std::io;
+use std::str;
+use std::str::FromStr;
+use bytes::BytesMut;
+use message::{self, Message, Op, Code};
+use codec::Limits;
+use text_codec;
+use error;
+
+/// The longest line (array or bulk string header, or inline command) we will buffer.
+const MAX_LINE_LEN: usize = 64 * 1024;
+/// The fewest bytes an element of a command array takes, `$0\r\n\r\n`.
+const MIN_ELEMENT_LEN: usize = 6;
+
+/// A codec for the Redis RESP2 protocol, covering a subset of the string commands.
+///
+/// Commands are arrays of bulk strings, or inline commands separated by spaces, and are decoded
+/// into a `Message` request along with a `RespRequest` that remembers how to write the reply.
+/// Supported commands are `GET`, `SET` (with `EX`, `PX`, `NX` and `XX`), `DEL`, `INCR`, `INCRBY`,
+/// `MGET`, `PING` and `INFO`. Anything else is decoded into a `Message::Response` carrying a RESP
+/// error, which `serve` writes back without calling the service.
+///
+/// A bulk string may be up to `max_value_len` long, and a whole command up to `max_frame_len`, see
+/// `Limits`. A command that is read in pieces is picked up where the last piece ended, rather than
+/// parsed again from its start.
+///
+/// Counters are stored as `TYPE_ID_U64` payloads, which are written back as decimal strings.
+/// They're unsigned, so a negative `INCRBY` stops at 0.
+pub struct RespCodec {
+    limits: Limits,
+    /// The command array read so far, if only part of it has arrived.
+    partial: Option<Partial>,
+    /// How far into the buffer the line being read has been searched for its end, if it hasn't
+    /// fully arrived.
+    scanned: usize,
+}
+
+/// A command array that has partly arrived.
+struct Partial {
+    /// The number of elements in the array.
+    count: usize,
+    /// The elements read so far.
+    args: Vec<Vec<u8>>,
+    /// Where the next element starts.
+    pos: usize,
+}
+
+impl Default for RespCodec {
+    fn default() -> RespCodec {
+        RespCodec::with_limits(Limits::default())
+    }
+}
+
+impl RespCodec {
+    pub fn with_limits(limits: Limits) -> RespCodec {
+        RespCodec {
+            limits: limits,
+            partial: None,
+            scanned: 0,
+        }
+    }
+}
+
+/// How to write the reply to a decoded RESP command.
+#[derive(Debug, PartialEq, Clone)]
+pub enum RespRequest {
+    /// `GET`, replied with a bulk string or a null bulk string.
+    Get,
+    /// `SET`, replied with `+OK`, or a null bulk string if an `NX` or `XX` condition failed.
+    Set,
+    /// `DEL`, replied with the number of keys removed.
+    Del,
+    /// `INCR` or `INCRBY`, replied with the new value.
+    Incr,
+    /// `MGET`, replied with an array of bulk strings.
+    MGet,
+    /// `PING`, replied with `+PONG` or the message it was given.
+    Ping(Option<Vec<u8>>),
+    Info,
+    /// An unsupported or malformed command, replied with the error in the response's payload.
+    Error,
+    /// An empty command, which gets no reply.
+    Empty,
+}
+
+impl Decoder for RespCodec {
+    type Item = (RespRequest, Message);
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<(RespRequest, Message)>, io::Error> {
+        if buf.is_empty() {
+            return Ok(None);
+        }
+
+        let parsed = self.parse_command(buf.as_ref())?;
+        match parsed {
+            Some((len, args)) => {
+                buf.split_to(len);
+                Ok(Some(command(args)))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl RespCodec {
+    /// Read a complete command from the start of `buf`, returning its length and arguments, or
+    /// `None` if it hasn't fully arrived.
+    fn parse_command(&mut self, buf: &[u8]) -> io::Result<Option<(usize, Vec<Vec<u8>>)>> {
+        let mut partial = match self.partial.take() {
+            Some(partial) => partial,
+            None if buf[0] != b'*' => {
+                // An inline command.
+                return Ok(self.read_line(buf, 0)?.map(|(line, next)| {
+                    let args = line.split(|&b| b == b' ')
+                        .filter(|arg| !arg.is_empty())
+                        .map(|arg| arg.to_vec())
+                        .collect();
+                    (next, args)
+                }));
+            }
+            None => {
+                match self.read_line(buf, 0)? {
+                    Some((line, next)) => {
+                        // A null array, `*-1`, is as empty as `*0`.
+                        let count = cmp::max(number::<i64>(&line[1..])?, 0) as u64;
+                        if count > (self.limits.max_frame_len / MIN_ELEMENT_LEN) as u64 {
+                            return Err(too_large("invalid multibulk length"));
+                        }
+                        Partial {
+                            count: count as usize,
+                            args: Vec::new(),
+                            pos: next,
+                        }
+                    }
+                    None => return Ok(None),
+                }
+            }
+        };
+
+        while partial.args.len() < partial.count {
+            let (len, next) = match self.read_line(buf, partial.pos)? {
+                Some((line, next)) => {
+                    if line.first() != Some(&b'$') {
+                        return Err(protocol_error("expected '$'"));
+                    }
+                    (number::<usize>(&line[1..])?, next)
+                }
+                None => {
+                    self.partial = Some(partial);
+                    return Ok(None);
+                }
+            };
+            if len > self.limits.max_value_len {
+                return Err(too_large("invalid bulk length"));
+            }
+            if next + len + 2 > self.limits.max_frame_len {
+                return Err(too_large("command too large"));
+            }
+            if buf.len() < next + len + 2 {
+                self.partial = Some(partial);
+                return Ok(None);
+            }
+            if &buf[next + len..next + len + 2] != b"\r\n" {
+                return Err(protocol_error("expected CRLF after bulk string"));
+            }
+            partial.args.push(buf[next..next + len].to_vec());
+            partial.pos = next + len + 2;
+        }
+        Ok(Some((partial.pos, partial.args)))
+    }
+
+    /// The line starting at `start`, without its line ending, and the position after it. If the
+    /// line hasn't fully arrived, the next call, which reads the same line, only searches what
+    /// arrived since.
+    fn read_line<'a>(
+        &mut self,
+        buf: &'a [u8],
+        start: usize,
+    ) -> io::Result<Option<(&'a [u8], usize)>> {
+        let from = cmp::max(start, self.scanned);
+        match buf[from..].iter().position(|&b| b == b'\n') {
+            Some(i) => {
+                let end = from + i;
+                self.scanned = 0;
+                let line_end = if end > start && buf[end - 1] == b'\r' {
+                    end - 1
+                } else {
+                    end
+                };
+                Ok(Some((&buf[start..line_end], end + 1)))
+            }
+            None if buf.len() - start > MAX_LINE_LEN => Err(too_large("line too long")),
+            None => {
+                self.scanned = buf.len();
+                Ok(None)
+            }
+        }
+    }
+}
+
+fn too_large(description: &str) -> io::Error {
+    error::Error::new(error::ErrorKind::TooLarge, description).into()
+}
+
+fn number<T: FromStr>(token: &[u8]) -> io::Result<T> {
+    str::from_utf8(token)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| protocol_error("invalid length"))
+}
+
+fn protocol_error(description: &str) -> io::Error {
+    error::Error::new(error::ErrorKind::InvalidData, description).into()
+}
+
+/// Translate a command into a `Message`, or into an error reply if it isn't supported.
+fn command(args: Vec<Vec<u8>>) -> (RespRequest, Message) {
+    if args.is_empty() {
+        return (RespRequest::Empty, message::response(Op::Get, Code::Ok, None));
+    }
+
+    let name = String::from_utf8_lossy(&args[0]).to_lowercase();
+    let mut args = args.into_iter().skip(1);
+    let arity = args.len();
+
+    match (name.as_str(), arity) {
+        ("get", 1) => {
+            let key = args.next().unwrap();
+            (RespRequest::Get, message::request(Op::Get, key, None))
+        }
+        ("set", n) if n >= 2 => {
+            let key = args.next().unwrap();
+            let value = args.next().unwrap();
+            match set_options(args.collect()) {
+                Ok((op, ttl)) => {
+                    let payload =
+                        message::payload(message::TYPE_ID_UTF8, value).with_ttl_millis(ttl);
+                    (RespRequest::Set, message::request(op, key, Some(payload)))
+                }
+                Err(e) => error_reply(Op::Set, e),
+            }
+        }
+        ("del", n) if n >= 1 => {
+            let entries = args.map(|key| (key, None)).collect();
+            (RespRequest::Del, message::batch_request(Op::Del, entries))
+        }
+        ("incr", 1) => {
+            let key = args.next().unwrap();
+            let payload = message::counter_payload(1, Some(1));
+            (RespRequest::Incr, message::request(Op::Incr, key, Some(payload)))
+        }
+        ("incrby", 2) => {
+            let key = args.next().unwrap();
+            let delta = str::from_utf8(&args.next().unwrap()).ok().and_then(|s| {
+                s.parse::<i64>().ok()
+            });
+            match delta {
+                Some(delta) if delta >= 0 => {
+                    let payload = message::counter_payload(delta as u64, Some(delta as u64));
+                    (RespRequest::Incr, message::request(Op::Incr, key, Some(payload)))
+                }
+                Some(delta) => {
+                    let payload = message::counter_payload((delta as u64).wrapping_neg(), Some(0));
+                    (RespRequest::Incr, message::request(Op::Decr, key, Some(payload)))
+                }
+                None => {
+                    let description = "ERR value is not an integer or out of range".to_owned();
+                    error_reply(Op::Incr, description)
+                }
+            }
+        }
+        ("mget", n) if n >= 1 => {
+            let entries = args.map(|key| (key, None)).collect();
+            (RespRequest::MGet, message::batch_request(Op::Get, entries))
+        }
+        ("ping", 0) => (RespRequest::Ping(None), message::response(Op::Get, Code::Ok, None)),
+        ("ping", 1) => {
+            let msg = args.next();
+            (RespRequest::Ping(msg), message::response(Op::Get, Code::Ok, None))
+        }
+        ("info", n) if n <= 1 => (RespRequest::Info, message::request(Op::Stats, vec![], None)),
+        ("get", _) | ("set", _) | ("del", _) | ("incr", _) | ("incrby", _) | ("mget", _) |
+        ("ping", _) | ("info", _) => {
+            let description = format!("ERR wrong number of arguments for '{}' command", name);
+            error_reply(command_op(&name), description)
+        }
+        // An unknown command has no op, and is answered as a get.
+        _ => error_reply(Op::Get, format!("ERR unknown command '{}'", name)),
+    }
+}
+
+/// The op and ttl in milliseconds for the options of a `SET`.
+fn set_options(options: Vec<Vec<u8>>) -> Result<(Op, u64), String> {
+    let syntax_error = || "ERR syntax error".to_owned();
+    let mut op = Op::Set;
+    let mut ttl = 0;
+
+    let mut options = options.into_iter();
+    while let Some(option) = options.next() {
+        match String::from_utf8_lossy(&option).to_lowercase().as_str() {
+            "nx" if op == Op::Set => op = Op::Add,
+            "xx" if op == Op::Set => op = Op::Replace,
+            unit @ "ex" | unit @ "px" if ttl == 0 => {
+                let n = options.next().ok_or_else(&syntax_error)?;
+                let n: u64 = str::from_utf8(&n)
+                    .ok()
+                    .and_then(|s| s.parse().ok())
+                    .ok_or_else(|| "ERR value is not an integer or out of range".to_owned())?;
+                let millis = if unit == "px" { n } else { n.saturating_mul(1000) };
+                if millis == 0 || millis > u64::from(u32::max_value()) * 1000 {
+                    return Err("ERR invalid expire time in 'set' command".to_owned());
+                }
+                ttl = millis;
+            }
+            _ => return Err(syntax_error()),
+        }
+    }
+    Ok((op, ttl))
+}
+
+/// The op of a known command.
+fn command_op(name: &str) -> Op {
+    match name {
+        "set" => Op::Set,
+        "del" => Op::Del,
+        "incr" | "incrby" => Op::Incr,
+        "info" => Op::Stats,
+        _ => Op::Get,
+    }
+}
+
+fn error_reply(op: Op, description: String) -> (RespRequest, Message) {
+    let err = error::Error::new(error::ErrorKind::BadMessage, &description);
+    (RespRequest::Error, message::error_response(op, &err))
+}
+
+impl Encoder for RespCodec {
+    type Item = (RespRequest, Message);
+    type Error = io::Error;
+
+    fn encode(&mut self, item: (RespRequest, Message), buf: &mut BytesMut) -> io::Result<()> {
+        let (req, msg) = item;
+
+        if msg.code() == Code::Error {
+            let description = msg.payload()
+                .map(|p| String::from_utf8_lossy(p.data()).into_owned())
+                .unwrap_or_default();
+            match req {
+                RespRequest::Error => put_line(buf, &format!("-{}", description)),
+                RespRequest::Incr => put_line(buf, "-ERR value is not an integer or out of range"),
+                _ => put_line(buf, &format!("-ERR {}", description)),
+            }
+            return Ok(());
+        }
+        if read_only(&msg) {
+            put_line(buf, "-READONLY You can't write against a read only replica.");
+            return Ok(());
+        }
+        if let Some(owner) = moved(&msg) {
+            put_line(buf, &format!("-MOVED {}", owner));
+            return Ok(());
+        }
+
+        match req {
+            RespRequest::Get => put_bulk(buf, msg.payload().map(value).as_ref()),
+            RespRequest::Set => {
+                match msg.code() {
+                    Code::Ok => put_line(buf, "+OK"),
+                    Code::NotStored => put_bulk(buf, None),
+                    Code::TooLarge => put_line(buf, "-ERR value too large"),
+                    code => put_line(buf, &format!("-ERR unexpected response {}", code)),
+                }
+            }
+            RespRequest::Del => {
+                let removed = match msg {
+                    Message::BatchResponse(_, ref results) => {
+                        results.iter().filter(|r| r.0 == Code::Hit).count()
+                    }
+                    _ => 0,
+                };
+                put_line(buf, &format!(":{}", removed));
+            }
+            RespRequest::Incr => {
+                match msg.payload().and_then(|p| p.as_u64()) {
+                    Some(n) => put_line(buf, &format!(":{}", n)),
+                    None => put_line(buf, &format!("-ERR unexpected response {}", msg.code())),
+                }
+            }
+            RespRequest::MGet => {
+                match msg {
+                    Message::BatchResponse(_, ref results) => {
+                        put_line(buf, &format!("*{}", results.len()));
+                        for result in results {
+                            match *result {
+                                (Code::Hit, Some(ref payload)) => {
+                                    put_bulk(buf, Some(&value(payload)))
+                                }
+                                _ => put_bulk(buf, None),
+                            }
+                        }
+                    }
+                    _ => put_line(buf, "*0"),
+                }
+            }
+            RespRequest::Ping(None) => put_line(buf, "+PONG"),
+            RespRequest::Ping(Some(ref msg)) => put_bulk(buf, Some(msg)),
+            RespRequest::Info => {
+                let stats = msg.payload()
+                    .map(|p| String::from_utf8_lossy(p.data()).into_owned())
+                    .unwrap_or_default();
+                let mut info = format!(
+                    "# Server\r\nrcache_version:{}\r\n\r\n# Stats\r\n",
+                    env!("CARGO_PKG_VERSION")
+                );
+                for (name, value) in text_codec::parse_stats(&stats) {
+                    info.push_str(&format!("{}:{}\r\n", name, value));
+                }
+                put_bulk(buf, Some(&info.into_bytes()));
+            }
+            RespRequest::Error | RespRequest::Empty => (),
+        }
+        Ok(())
+    }
+}
+
+/// Whether `msg` refuses a write because the server is a replica.
+fn read_only(msg: &Message) -> bool {
+    match *msg {
+        Message::Response(_, Code::ReadOnly, _) => true,
+        Message::BatchResponse(_, ref results) => results.iter().any(|r| r.0 == Code::ReadOnly),
+        _ => false,
+    }
+}
+
+/// The node that owns the key of `msg`, or the first of its keys that another node owns, if the
+/// key isn't this node's.
+fn moved(msg: &Message) -> Option<String> {
+    let payload = match *msg {
+        Message::Response(_, Code::Moved, ref payload) => payload.as_ref(),
+        Message::BatchResponse(_, ref results) => {
+            match results.iter().find(|r| r.0 == Code::Moved) {
+                Some(result) => result.1.as_ref(),
+                None => return None,
+            }
+        }
+        _ => return None,
+    };
+    Some(payload.map(|p| String::from_utf8_lossy(p.data()).into_owned()).unwrap_or_default())
+}
+
+/// The value of a payload as a redis client expects it, counters as decimal strings.
+fn value(payload: &message::Payload) -> Vec<u8> {
+    match payload.as_u64() {
+        Some(n) => n.to_string().into_bytes(),
+        None => payload.data().to_vec(),
+    }
+}
+
+fn put_bulk(buf: &mut BytesMut, data: Option<&Vec<u8>>) {
+    match data {
+        Some(data) => {
+            put_line(buf, &format!("${}", data.len()));
+            buf.extend_from_slice(data);
+            buf.extend_from_slice(b"\r\n");
+        }
+        None => buf.extend_from_slice(b"$-1\r\n"),
+    }
+}
+
+fn put_line(buf: &mut BytesMut, line: &str) {
+    buf.extend_from_slice(line.as_bytes());
+    buf.extend_from_slice(b"\r\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::time::Duration;
+    use service::Protocol;
+    use testing::{self, exchange};
+
+    #[test]
+    fn test_decode() {
+        let items = testing::decode_all(
+            &mut RespCodec::default(),
+            b"*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n\
+              *6\r\n$3\r\nset\r\n$1\r\nk\r\n$1\r\nv\r\n$2\r\nPX\r\n$4\r\n1500\r\n$2\r\nnx\r\n\
+              MGET a b\r\n",
+        );
+
+        assert_eq!(items[0].0, RespRequest::Set);
+        assert_eq!(
+            items[0].1,
+            message::request(Op::Set, "foo".into(), Some(message::payload(1, "bar".into())))
+        );
+        assert_eq!(
+            items[1].1,
+            message::request(
+                Op::Add,
+                "k".into(),
+                Some(message::payload(1, "v".into()).with_ttl_millis(1500)),
+            )
+        );
+        assert_eq!(items[2].0, RespRequest::MGet);
+        assert_eq!(
+            items[2].1,
+            message::batch_request(Op::Get, vec![("a".into(), None), ("b".into(), None)])
+        );
+    }
+
+    #[test]
+    fn test_decode_partial() {
+        let command = b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\nPING\r\n";
+        let mut codec = RespCodec::default();
+        for i in 1..command.len() - 6 {
+            let mut codec = RespCodec::default();
+            let mut buf = BytesMut::from(&command[..i]);
+            assert!(codec.decode(&mut buf).unwrap().is_none());
+        }
+
+        // The same command, arriving a byte at a time.
+        let mut buf = BytesMut::new();
+        let mut decoded = Vec::new();
+        for &b in command.iter() {
+            buf.extend_from_slice(&[b]);
+            while let Some((req, _)) = codec.decode(&mut buf).unwrap() {
+                decoded.push(req);
+            }
+        }
+        assert_eq!(decoded, vec![RespRequest::Get, RespRequest::Ping(None)]);
+        assert!(buf.is_empty());
+
+        let mut buf = BytesMut::from(&b"*1\r\n$3\r\nGETX\r\n"[..]);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_limits() {
+        let limits = Limits {
+            max_value_len: 4,
+            max_frame_len: 64,
+            ..Limits::default()
+        };
+        let too_large = |input: &[u8]| {
+            let mut buf = BytesMut::from(input);
+            RespCodec::with_limits(limits).decode(&mut buf).is_err()
+        };
+        assert!(!too_large(b"*2\r\n$3\r\nGET\r\n$4\r\nfoo"));
+        assert!(too_large(b"*2\r\n$3\r\nGET\r\n$5\r\n"));
+        assert!(too_large(b"*11\r\n"));
+        assert!(too_large(b"*1000000000000\r\n"));
+
+        // Every element is small enough, but not all of them together.
+        let mut command = b"*10\r\n".to_vec();
+        for _ in 0..10 {
+            command.extend_from_slice(b"$4\r\nabcd\r\n");
+        }
+        assert!(too_large(&command));
+    }
+
+    #[test]
+    fn test_errors() {
+        let input = b"HSET h f v\r\nGET\r\nSET k v EX 0\r\nSET k v XX NX\r\n";
+        let items = testing::decode_all(&mut RespCodec::default(), input);
+        assert!(items.iter().all(|item| item.0 == RespRequest::Error));
+        let ops: Vec<Op> = items.iter().map(|item| item.1.op()).collect();
+        assert_eq!(ops, vec![Op::Get, Op::Get, Op::Set, Op::Set]);
+
+        let mut buf = BytesMut::new();
+        for item in items {
+            RespCodec::default().encode(item, &mut buf).unwrap();
+        }
+        assert_eq!(
+            &buf[..],
+            &b"-ERR unknown command 'hset'\r\n\
+               -ERR wrong number of arguments for 'get' command\r\n\
+               -ERR invalid expire time in 'set' command\r\n\
+               -ERR syntax error\r\n"[..]
+        );
+    }
+
+    #[test]
+    fn test_transcript() {
+        let addr = testing::spawn_cache(Protocol::Resp);
+
+        let mut stream = TcpStream::connect(&addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        exchange(&mut stream, "*1\r\n$4\r\nPING\r\n", "+PONG\r\n");
+        exchange(&mut stream, "*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n", "$-1\r\n");
+        exchange(&mut stream, "*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n", "+OK\r\n");
+        exchange(&mut stream, "*4\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$1\r\nx\r\n$2\r\nNX\r\n", "$-1\r\n");
+        exchange(&mut stream, "*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n", "$3\r\nbar\r\n");
+
+        exchange(&mut stream, "INCR n\r\n", ":1\r\n");
+        exchange(&mut stream, "INCRBY n 41\r\n", ":42\r\n");
+        exchange(&mut stream, "INCRBY n -40\r\n", ":2\r\n");
+        exchange(&mut stream, "INCRBY n 40\r\n", ":42\r\n");
+        exchange(&mut stream, "INCR foo\r\n", "-ERR value is not an integer or out of range\r\n");
+        exchange(&mut stream, "MGET foo missing n\r\n", "*3\r\n$3\r\nbar\r\n$-1\r\n$2\r\n42\r\n");
+
+        exchange(&mut stream, "DEL foo n missing\r\n", ":2\r\n");
+        exchange(&mut stream, "FLUSHALL\r\n", "-ERR unknown command 'flushall'\r\n");
+        exchange(&mut stream, "PING hello\r\n", "$5\r\nhello\r\n");
+
+        stream.write_all(b"INFO\r\n").unwrap();
+        let mut buf = [0; 1];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"$");
+    }
+}
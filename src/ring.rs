@@ -0,0 +1,238 @@
+use std::fmt;
+
+/// The number of points a node of weight 1 gets on the ring, by default.
+pub const DEFAULT_VNODES: usize = 160;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// A consistent-hash ring, which maps keys to the nodes of a cluster.
+///
+/// Each node is hashed onto the ring at `vnodes` points for each unit of its weight, and a key
+/// belongs to the node of the first point at or after the key's hash, wrapping around. The many
+/// virtual nodes spread each node's share of the keys evenly around the ring, and a node's share
+/// is in proportion to its weight. A node's points only depend on its name and weight, so adding
+/// or removing a node only moves the keys that it gains or loses: every other key stays where it
+/// was.
+///
+/// Nodes are named by their `Display`, which has to be unique within a ring.
+pub struct Ring<T> {
+    vnodes: usize,
+    nodes: Vec<(T, u32)>,
+    /// The hash of each point, and the index of its node in `nodes`, in order of the hashes.
+    points: Vec<(u64, usize)>,
+}
+
+impl<T: fmt::Display + PartialEq> Ring<T> {
+    /// An empty ring, giving each node `vnodes` points for each unit of its weight.
+    pub fn new(vnodes: usize) -> Self {
+        Ring {
+            vnodes: vnodes,
+            nodes: Vec::new(),
+            points: Vec::new(),
+        }
+    }
+
+    /// Whether no node owns any keys, as when there are no nodes, or every node has weight 0.
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// The nodes on the ring, and their weights.
+    pub fn nodes(&self) -> &[(T, u32)] {
+        &self.nodes
+    }
+
+    /// Add `node` with `weight`, replacing its weight if it's already on the ring. A node of
+    /// weight 0 owns no keys.
+    pub fn add(&mut self, node: T, weight: u32) {
+        match self.nodes.iter().position(|&(ref n, _)| *n == node) {
+            Some(i) => self.nodes[i].1 = weight,
+            None => self.nodes.push((node, weight)),
+        }
+        self.build();
+    }
+
+    /// Remove `node`, returning false if it wasn't on the ring.
+    pub fn remove(&mut self, node: &T) -> bool {
+        match self.nodes.iter().position(|&(ref n, _)| n == node) {
+            Some(i) => {
+                self.nodes.remove(i);
+                self.build();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The node that owns `key`, or None if the ring is empty.
+    pub fn get(&self, key: &[u8]) -> Option<&T> {
+        self.index(key).map(|i| &self.nodes[i].0)
+    }
+
+    /// The index in `nodes` of the node that owns `key`.
+    pub fn index(&self, key: &[u8]) -> Option<usize> {
+        if self.is_empty() {
+            return None;
+        }
+        let hash = hash(key);
+        let point = match self.points.binary_search_by(|&(h, _)| h.cmp(&hash)) {
+            Ok(i) => i,
+            Err(i) if i == self.points.len() => 0,
+            Err(i) => i,
+        };
+        Some(self.points[point].1)
+    }
+
+    /// Split a batch of (key, entry) `entries` by the node that owns each key, in the order of
+    /// the nodes. Empty if the ring is.
+    pub fn split<E>(&self, entries: Vec<(Vec<u8>, E)>) -> Vec<Part<E>> {
+        if self.is_empty() {
+            return Vec::new();
+        }
+        let mut parts: Vec<Part<E>> = (0..self.nodes.len())
+            .map(|node| {
+                Part {
+                    node: node,
+                    positions: Vec::new(),
+                    entries: Vec::new(),
+                }
+            })
+            .collect();
+        for (position, entry) in entries.into_iter().enumerate() {
+            let node = self.index(&entry.0).unwrap();
+            parts[node].positions.push(position);
+            parts[node].entries.push(entry);
+        }
+        parts.retain(|part| !part.entries.is_empty());
+        parts
+    }
+
+    fn build(&mut self) {
+        self.points.clear();
+        let names: Vec<String> = self.nodes.iter().map(|&(ref node, _)| node.to_string()).collect();
+        for (i, &(_, weight)) in self.nodes.iter().enumerate() {
+            for vnode in 0..self.vnodes * weight as usize {
+                let point = format!("{}#{}", names[i], vnode);
+                self.points.push((hash(point.as_bytes()), i));
+            }
+        }
+        // Ties between the points of different nodes are broken by name rather than by position
+        // in `nodes`, so that they don't depend on the order the nodes were added in.
+        self.points.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| names[a.1].cmp(&names[b.1])));
+    }
+}
+
+/// The share of a batch that belongs to a node, see `Ring::split`.
+pub struct Part<E> {
+    /// The index of the node in `Ring::nodes`.
+    pub node: usize,
+    /// The position of each entry in the batch.
+    pub positions: Vec<usize>,
+    pub entries: Vec<(Vec<u8>, E)>,
+}
+
+/// 64-bit FNV-1a, with a final avalanche so that similar inputs, such as the names of a node's
+/// points, land far apart on the ring.
+pub fn hash(data: &[u8]) -> u64 {
+    let mut hash = FNV_OFFSET_BASIS;
+    for &byte in data {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing;
+
+    fn keys() -> Vec<Vec<u8>> {
+        testing::keys(10000)
+    }
+
+    fn shares(ring: &Ring<&'static str>) -> Vec<usize> {
+        let mut shares = vec![0; ring.nodes().len()];
+        for key in keys() {
+            shares[ring.index(&key).unwrap()] += 1;
+        }
+        shares
+    }
+
+    #[test]
+    fn test_empty() {
+        let mut ring: Ring<&'static str> = Ring::new(DEFAULT_VNODES);
+        assert!(ring.is_empty());
+        assert_eq!(ring.get(b"foo"), None);
+        assert!(ring.split(vec![(b"foo".to_vec(), ())]).is_empty());
+
+        // Nodes of weight 0 own no keys.
+        ring.add("a", 0);
+        assert!(ring.is_empty());
+        assert_eq!(ring.get(b"foo"), None);
+    }
+
+    #[test]
+    fn test_distribution() {
+        let mut ring = Ring::new(DEFAULT_VNODES);
+        ring.add("a", 1);
+        ring.add("b", 1);
+        ring.add("c", 2);
+        let shares = shares(&ring);
+        // Each unit of weight should get about a quarter of the keys.
+        assert!(shares[0] > 2000 && shares[0] < 3000, "{:?}", shares);
+        assert!(shares[1] > 2000 && shares[1] < 3000, "{:?}", shares);
+        assert!(shares[2] > 4000 && shares[2] < 6000, "{:?}", shares);
+
+        // The order nodes are added in doesn't matter.
+        let mut reversed = Ring::new(DEFAULT_VNODES);
+        reversed.add("c", 2);
+        reversed.add("b", 1);
+        reversed.add("a", 1);
+        for key in keys() {
+            assert_eq!(ring.get(&key), reversed.get(&key));
+        }
+    }
+
+    #[test]
+    fn test_split() {
+        let mut ring = Ring::new(DEFAULT_VNODES);
+        ring.add("a", 1);
+        ring.add("b", 1);
+        let entries: Vec<_> = keys().into_iter().take(100).map(|key| (key, ())).collect();
+        let parts = ring.split(entries.clone());
+        assert_eq!(parts.len(), 2);
+        for part in parts {
+            assert_eq!(part.positions.len(), part.entries.len());
+            for (&position, entry) in part.positions.iter().zip(part.entries) {
+                assert_eq!(entries[position], entry);
+                assert_eq!(ring.index(&entry.0), Some(part.node));
+            }
+        }
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut ring = Ring::new(DEFAULT_VNODES);
+        for node in &["a", "b", "c", "d"] {
+            ring.add(*node, 1);
+        }
+        let before: Vec<&str> = keys().iter().map(|key| *ring.get(key).unwrap()).collect();
+
+        assert!(ring.remove(&"b"));
+        assert!(!ring.remove(&"b"));
+        for (key, owner) in keys().iter().zip(before) {
+            let now = *ring.get(key).unwrap();
+            if owner == "b" {
+                assert!(now != "b");
+            } else {
+                assert_eq!(now, owner);
+            }
+        }
+    }
+}
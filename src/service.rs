@@ -1,56 +1,283 @@
-use futures::{Future, Stream, Sink};
+use futures::{future, Future, Stream, Sink};
 
-use tokio_core::reactor::Core;
+use tokio_core::reactor::{Core, Handle};
 use tokio_core::net::TcpListener;
 
-use tokio_io::AsyncRead;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::codec::{Encoder, Decoder, Framed};
 
 use tokio_service::{Service, NewService};
 
+use std::fmt;
 use std::io;
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::str::FromStr;
 
 use message::{self, Message, Op, Code};
 use cache;
-use codec::CacheCodec;
+use proto;
+use text_codec::TextCodec;
+use binary_codec::BinaryCodec;
+use resp_codec::RespCodec;
+use http_codec::HttpCodec;
+use uds::UnixListener;
+pub use codec::Limits;
 use std::sync::Arc;
 use std::error::Error;
 use futures::sync::oneshot;
 use stats::Stats;
+use membership::Membership;
 use time;
 
+/// The wire protocols `serve_listeners` can speak.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    /// rcache's own multiplexed binary protocol, see `CacheCodec`.
+    Rcache,
+    /// The memcached ASCII protocol, see `TextCodec`.
+    MemcachedText,
+    /// The memcached binary protocol, see `BinaryCodec`.
+    MemcachedBinary,
+    /// The Redis RESP2 protocol, for a subset of the string commands, see `RespCodec`.
+    Resp,
+    /// A small HTTP/1.1 API, see `HttpCodec`.
+    Http,
+}
+
+/// An address to listen on or connect to: a TCP socket address, or the path of a Unix domain
+/// socket written as `unix:/path/to/sock`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Addr {
+    Tcp(SocketAddr),
+    /// A Unix domain socket, and the permissions to give the socket file when listening on it.
+    Unix(PathBuf, Option<u32>),
+}
+
+impl FromStr for Addr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Addr, String> {
+        if s.starts_with("unix:") {
+            let path = &s["unix:".len()..];
+            if path.is_empty() {
+                return Err("Missing unix socket path.".to_owned());
+            }
+            Ok(Addr::Unix(PathBuf::from(path), None))
+        } else {
+            s.parse().map(Addr::Tcp).map_err(
+                |_| format!("Failed to parse socket address: {}", s),
+            )
+        }
+    }
+}
+
+impl fmt::Display for Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Addr::Tcp(ref addr) => write!(f, "{}", addr),
+            Addr::Unix(ref path, _) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// An address to accept connections on, and the protocol they speak.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Listener {
+    pub protocol: Protocol,
+    pub addr: Addr,
+}
+
 /// Takes a `NewService<Request=Message, Response=Message>` and servces it at `addr`.
 pub fn serve<T>(addr: SocketAddr, s: T) -> io::Result<()>
 where
     T: NewService<Request = Message, Response = Message, Error = io::Error> + 'static,
+    T::Instance: 'static,
+    <T::Instance as Service>::Future: 'static,
+{
+    let listener = Listener {
+        protocol: Protocol::Rcache,
+        addr: Addr::Tcp(addr),
+    };
+    serve_listeners(vec![listener], s)
+}
+
+/// Serves a `NewService<Request=Message, Response=Message>` on each of `listeners`, so that every
+/// protocol shares the same service stack.
+pub fn serve_listeners<T>(listeners: Vec<Listener>, s: T) -> io::Result<()>
+where
+    T: NewService<Request = Message, Response = Message, Error = io::Error> + 'static,
+    T::Instance: 'static,
+    <T::Instance as Service>::Future: 'static,
+{
+    serve_with_limits(listeners, Limits::default(), s)
+}
+
+/// Like `serve_listeners`, with `limits` on the size of the keys, values and frames clients send,
+/// whichever protocol they speak.
+pub fn serve_with_limits<T>(listeners: Vec<Listener>, limits: Limits, s: T) -> io::Result<()>
+where
+    T: NewService<Request = Message, Response = Message, Error = io::Error> + 'static,
+    T::Instance: 'static,
+    <T::Instance as Service>::Future: 'static,
+{
+    serve_until(listeners, limits, s, future::empty())
+}
+
+/// Like `serve_with_limits`, until `stop` resolves. The listeners, open connections and the
+/// service are then dropped before returning, so that a `cache::Cache` behind the service gets to
+/// save its snapshot.
+pub fn serve_until<T, F>(listeners: Vec<Listener>, limits: Limits, s: T, stop: F) -> io::Result<()>
+where
+    T: NewService<Request = Message, Response = Message, Error = io::Error> + 'static,
+    T::Instance: 'static,
     <T::Instance as Service>::Future: 'static,
+    F: Future<Item = (), Error = io::Error>,
 {
+    limits.validate()?;
+
     // The primary event loop
     let mut core = Core::new()?;
     let handle = core.handle();
+    let s = Rc::new(s);
+
+    let mut servers = Vec::with_capacity(listeners.len());
+    for listener in listeners {
+        // Bind to the socket
+        let server = match listener.addr {
+            Addr::Tcp(ref addr) => {
+                let incoming = TcpListener::bind(addr, &handle)?.incoming().map(
+                    |(socket, _peer_addr)| socket,
+                );
+                accept(incoming, listener.protocol, limits, s.clone(), handle.clone())
+            }
+            Addr::Unix(ref path, mode) => {
+                let incoming = UnixListener::bind(path, mode, &handle)?.incoming();
+                accept(incoming, listener.protocol, limits, s.clone(), handle.clone())
+            }
+        };
+        servers.push(server);
+    }
 
-    // Bind to the socket
-    let listener = TcpListener::bind(&addr, &handle)?;
+    let servers = future::join_all(servers).map(|_| ());
+    core.run(servers.select(stop).map(|_| ()).map_err(|(e, _)| e))
+}
 
-    let connections = listener.incoming();
-    // Iterate over the the stream of connections.
-    let server = connections.for_each(move |(socket, _peer_addr)| {
-        // Split the connection into a Sink and a Stream.
-        let (writer, reader) = socket.framed(CacheCodec).split();
-        let service = s.new_service().unwrap();
+/// Serve `s`, speaking `protocol`, on a TCP `listener` that is already bound. A test can bind to a
+/// port the OS picks, and connect to it as soon as the server's thread is started.
+#[cfg(test)]
+pub(crate) fn serve_bound<T>(
+    listener: ::std::net::TcpListener,
+    protocol: Protocol,
+    s: T,
+) -> io::Result<()>
+where
+    T: NewService<Request = Message, Response = Message, Error = io::Error> + 'static,
+    T::Instance: 'static,
+    <T::Instance as Service>::Future: 'static,
+{
+    let mut core = Core::new()?;
+    let handle = core.handle();
+    let addr = listener.local_addr()?;
+    let incoming = TcpListener::from_listener(listener, &addr, &handle)?
+        .incoming()
+        .map(|(socket, _peer_addr)| socket);
+    core.run(accept(incoming, protocol, Limits::default(), Rc::new(s), handle.clone()))
+}
 
-        // Map the service function onto each element in the stream.
-        let responses = reader.and_then(move |(req_id, msg)| {
-            service.call(msg).map(move |resp| (req_id, resp))
-        });
+/// Like `serve_bound`, on a Unix domain socket `listener` that is already bound.
+#[cfg(test)]
+pub(crate) fn serve_bound_unix<T>(
+    listener: ::mio_uds::UnixListener,
+    protocol: Protocol,
+    s: T,
+) -> io::Result<()>
+where
+    T: NewService<Request = Message, Response = Message, Error = io::Error> + 'static,
+    T::Instance: 'static,
+    <T::Instance as Service>::Future: 'static,
+{
+    let mut core = Core::new()?;
+    let handle = core.handle();
+    let incoming = UnixListener::from_listener(listener, &handle)?.incoming();
+    core.run(accept(incoming, protocol, Limits::default(), Rc::new(s), handle.clone()))
+}
 
-        // Finally, write out all of the responses.
-        let server = writer.send_all(responses).then(|_| Ok(()));
-        handle.spawn(server);
+/// Iterate over the the stream of connections, serving each with a new instance of the service.
+fn accept<I, S, T>(
+    incoming: I,
+    protocol: Protocol,
+    limits: Limits,
+    s: Rc<T>,
+    handle: Handle,
+) -> Box<Future<Item = (), Error = io::Error>>
+where
+    I: Stream<Item = S, Error = io::Error> + 'static,
+    S: AsyncRead + AsyncWrite + 'static,
+    T: NewService<Request = Message, Response = Message, Error = io::Error> + 'static,
+    T::Instance: 'static,
+    <T::Instance as Service>::Future: 'static,
+{
+    Box::new(incoming.for_each(move |socket| {
+        let service = s.new_service()?;
+        let connection = match protocol {
+            Protocol::Rcache => {
+                // The codec depends on the hello the connection opens with, if any.
+                let transport = proto::accept(socket, limits).map_err(|_| ());
+                Box::new(transport.and_then(move |transport| serve_connection(transport, service)))
+            }
+            Protocol::MemcachedText => {
+                serve_connection(socket.framed(TextCodec::with_limits(limits)), service)
+            }
+            Protocol::MemcachedBinary => {
+                serve_connection(socket.framed(BinaryCodec::with_limits(limits)), service)
+            }
+            Protocol::Resp => {
+                serve_connection(socket.framed(RespCodec::with_limits(limits)), service)
+            }
+            Protocol::Http => {
+                serve_connection(socket.framed(HttpCodec::with_limits(limits)), service)
+            }
+        };
+        handle.spawn(connection);
         Ok(())
+    }))
+}
+
+/// Answer the requests read from `transport` with `service`, writing responses in request order.
+/// Each codec decodes a request into a `Message` and some context, such as the request id, that
+/// it needs to encode the response. A codec can also decode a `Message::Response`, for example for
+/// a malformed request, which is written back as is without calling the service.
+fn serve_connection<I, C, X, S>(
+    transport: Framed<I, C>,
+    service: S,
+) -> Box<Future<Item = (), Error = ()>>
+where
+    I: AsyncRead + AsyncWrite + 'static,
+    C: Decoder<Item = (X, Message), Error = io::Error>
+        + Encoder<Item = (X, Message), Error = io::Error>
+        + 'static,
+    X: 'static,
+    S: Service<Request = Message, Response = Message, Error = io::Error> + 'static,
+    S::Future: 'static,
+{
+    // Split the connection into a Sink and a Stream.
+    let (writer, reader) = transport.split();
+
+    // Map the service function onto each element in the stream.
+    let responses = reader.and_then(move |(ctx, msg)| {
+        let response: Box<Future<Item = (X, Message), Error = io::Error>> = match msg {
+            Message::Response(..) |
+            Message::BatchResponse(..) => Box::new(future::ok((ctx, msg))),
+            Message::Request(..) |
+            Message::BatchRequest(..) => Box::new(service.call(msg).map(move |resp| (ctx, resp))),
+        };
+        response
     });
 
-    core.run(server)
+    // Finally, write out all of the responses.
+    Box::new(writer.send_all(responses).then(|_| Ok(())))
 }
 
 /// A service middleware that dispatches requests to `cache::Cache`.
@@ -65,6 +292,11 @@ impl Service for CacheService {
     type Future = Box<Future<Item = Message, Error = io::Error>>;
 
     fn call(&self, req: Self::Request) -> Self::Future {
+        // A request that arrives past its deadline isn't worth queueing behind the others.
+        if cache::expired(req.deadline()) {
+            return Box::new(future::ok(cache::refused(&req, Code::Timeout)));
+        }
+
         let (snd, rcv) = oneshot::channel();
 
         self.cache.process(req, snd);
@@ -86,6 +318,46 @@ impl NewService for CacheService {
     }
 }
 
+/// A service middleware that serves the requests for keys this node of a cluster owns with
+/// `inner`, and forwards the rest to their owners, see `membership::Membership`.
+pub struct ClusterService<T> {
+    pub inner: T,
+    pub membership: Arc<Membership>,
+}
+
+impl<T> Service for ClusterService<T>
+where
+    T: Service<Request = Message, Response = Message, Error = io::Error>,
+    T::Future: 'static,
+{
+    type Request = Message;
+    type Response = Message;
+    type Error = io::Error;
+    type Future = Box<Future<Item = Message, Error = io::Error>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        self.membership.call(req, &self.inner)
+    }
+}
+
+impl<T> NewService for ClusterService<T>
+where
+    T: NewService<Request = Message, Response = Message, Error = io::Error>,
+    <T::Instance as Service>::Future: 'static,
+{
+    type Request = Message;
+    type Response = Message;
+    type Error = io::Error;
+    type Instance = ClusterService<T::Instance>;
+
+    fn new_service(&self) -> io::Result<Self::Instance> {
+        Ok(ClusterService {
+            inner: self.inner.new_service()?,
+            membership: self.membership.clone(),
+        })
+    }
+}
+
 /// A simplistic stat collecting middleware that counts total number of requests and tracks
 /// average request time.
 pub struct StatService<T> {
@@ -108,8 +380,7 @@ impl<T> Service for StatService<T>
                 let data = self.stats.get_stats();
                 Box::new(self.inner.call(req).map(|resp| match resp {
                     message::Message::Response(_, _, Some(payload)) => {
-                        let len = payload.type_id();
-                        let s = format!("keys: {} ", len) + data.as_ref();
+                        let s = String::from_utf8_lossy(payload.data()).into_owned() + ", " + data.as_ref();
                         message::response(Op::Stats, Code::Ok, Some(
                             message::payload(1, s.into_bytes())))
                     }
@@ -168,9 +439,9 @@ impl<T> Service for LogService<T>
     type Future = Box<Future<Item = Message, Error = io::Error>>;
 
     fn call(&self, req: Self::Request) -> Self::Future {
-        println!("{}", req);
+        info!("{}", req);
         Box::new(self.inner.call(req).and_then(|resp| {
-            println!("{}", resp);
+            info!("{}", resp);
             Ok(resp)
         }))
     }
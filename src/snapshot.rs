@@ -0,0 +1,547 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use futures::sync::oneshot::Sender;
+use bytes::{Buf, BufMut, BigEndian};
+use message::{self, Message, Op, Code, Payload};
+use error;
+use crc32c;
+use oplog::OpLog;
+
+/// Identifies a snapshot file.
+const MAGIC: &'static [u8] = b"RCSNAP";
+/// The version of the snapshot format, bumped whenever the layout of a record changes.
+pub const FORMAT_VERSION: u32 = 1;
+
+static HEADER_LEN: usize = 6 + 4 + 8 + 8;
+const CRC_LEN: usize = 4;
+
+/// An entry of a snapshot: its key, its payload, whose type id, flags, data and version are saved,
+/// and the wall clock time it expires at, if it has a ttl. A wall clock time, unlike an `Instant`,
+/// still means something to the process that loads the snapshot.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Record {
+    pub key: Vec<u8>,
+    pub payload: Payload,
+    pub expires: Option<SystemTime>,
+}
+
+/// Write the records of every part to a snapshot at `path`, returning the number written.
+///
+/// The snapshot is a header of the magic bytes `RCSNAP`, a u32 format version, the u64
+/// generation of the snapshot and a u64 record count, followed by the records and a CRC32C of
+/// everything before it. The generation tells which operation logs the snapshot already includes,
+/// see `oplog::recover`. Each record is laid out as
+///
+/// `[key_len: u32][key][type_id: u32][flags: u32][version: u64][expires: u64][data_len: u32][data]`
+///
+/// where `expires` is in milliseconds since the unix epoch, or 0 if the entry never expires. All
+/// integers are big endian. Records are loaded in the order they were written, so each part
+/// should go from least to most recently used.
+///
+/// The snapshot is written to a temporary file next to `path`, which replaces `path` once it is
+/// complete, so that a crash part way through leaves the previous snapshot in place.
+pub fn write(path: &Path, generation: usize, parts: &[Vec<Record>]) -> io::Result<usize> {
+    let count: usize = parts.iter().map(|part| part.len()).sum();
+    let tmp = tmp_path(path);
+    let mut out = BufWriter::new(File::create(&tmp)?);
+
+    let mut buf = Vec::with_capacity(HEADER_LEN);
+    buf.extend_from_slice(MAGIC);
+    buf.put_u32::<BigEndian>(FORMAT_VERSION);
+    buf.put_u64::<BigEndian>(generation as u64);
+    buf.put_u64::<BigEndian>(count as u64);
+    let mut crc = crc32c::crc32c(&buf);
+    out.write_all(&buf)?;
+
+    for record in parts.iter().flat_map(|part| part.iter()) {
+        buf.clear();
+        put_record(&mut buf, record);
+        crc = crc32c::update(crc, &buf);
+        out.write_all(&buf)?;
+    }
+
+    buf.clear();
+    buf.put_u32::<BigEndian>(crc);
+    out.write_all(&buf)?;
+    out.flush()?;
+    out.get_ref().sync_all()?;
+
+    fs::rename(&tmp, path)?;
+    Ok(count)
+}
+
+/// Read the generation and the records of the snapshot at `path`, in the order they were
+/// written. A snapshot that is truncated, fails its checksum or has an unknown format version is
+/// an `InvalidData` error.
+pub fn read(path: &Path) -> io::Result<(usize, Vec<Record>)> {
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+
+    if data.len() < HEADER_LEN + CRC_LEN || &data[..MAGIC.len()] != MAGIC {
+        return Err(invalid("not an rcache snapshot"));
+    }
+    let (body, crc) = data.split_at(data.len() - CRC_LEN);
+    if crc32c::crc32c(body) != io::Cursor::new(crc).get_u32::<BigEndian>() {
+        return Err(invalid("snapshot checksum mismatch"));
+    }
+
+    let mut cursor = io::Cursor::new(&body[MAGIC.len()..]);
+    let version = cursor.get_u32::<BigEndian>();
+    if version != FORMAT_VERSION {
+        return Err(invalid(&format!("unsupported snapshot version {}", version)));
+    }
+    let generation = cursor.get_u64::<BigEndian>() as usize;
+
+    // The count isn't trusted to size the vector, the records have to actually be there.
+    let count = cursor.get_u64::<BigEndian>();
+    let mut records = Vec::new();
+    for _ in 0..count {
+        records.push(get_record(&mut cursor)?);
+    }
+    if cursor.has_remaining() {
+        return Err(invalid("trailing bytes after the last snapshot record"));
+    }
+    Ok((generation, records))
+}
+
+/// Encode `record` as laid out in a snapshot, see `write`.
+pub fn put_record(buf: &mut Vec<u8>, record: &Record) {
+    let expires = match record.expires {
+        Some(expires) => {
+            let since_epoch = expires.duration_since(UNIX_EPOCH).unwrap_or_default();
+            since_epoch.as_secs() * 1000 + u64::from(since_epoch.subsec_nanos() / 1_000_000)
+        }
+        None => 0,
+    };
+    buf.put_u32::<BigEndian>(record.key.len() as u32);
+    buf.extend_from_slice(&record.key);
+    buf.put_u32::<BigEndian>(record.payload.type_id());
+    buf.put_u32::<BigEndian>(record.payload.flags());
+    buf.put_u64::<BigEndian>(record.payload.version());
+    buf.put_u64::<BigEndian>(expires);
+    buf.put_u32::<BigEndian>(record.payload.data().len() as u32);
+    buf.extend_from_slice(record.payload.data());
+}
+
+/// Decode a record encoded by `put_record`.
+pub fn get_record(cursor: &mut io::Cursor<&[u8]>) -> io::Result<Record> {
+    let key = get_bytes(cursor)?;
+    check_remaining(cursor, 4 + 4 + 8 + 8)?;
+    let type_id = cursor.get_u32::<BigEndian>();
+    let flags = cursor.get_u32::<BigEndian>();
+    let version = cursor.get_u64::<BigEndian>();
+    let expires = match cursor.get_u64::<BigEndian>() {
+        0 => None,
+        millis => Some(UNIX_EPOCH + Duration::from_millis(millis)),
+    };
+    let data = get_bytes(cursor)?;
+    Ok(Record {
+        key: key,
+        payload: message::payload(type_id, data).with_version(version).with_flags(flags),
+        expires: expires,
+    })
+}
+
+/// Read a u32 length and that many bytes.
+pub fn get_bytes(cursor: &mut io::Cursor<&[u8]>) -> io::Result<Vec<u8>> {
+    check_remaining(cursor, 4)?;
+    let len = cursor.get_u32::<BigEndian>() as usize;
+    check_remaining(cursor, len)?;
+    let mut bytes = vec![0; len];
+    cursor.copy_to_slice(&mut bytes);
+    Ok(bytes)
+}
+
+fn check_remaining(cursor: &io::Cursor<&[u8]>, len: usize) -> io::Result<()> {
+    if cursor.remaining() < len {
+        return Err(invalid("snapshot record is truncated"));
+    }
+    Ok(())
+}
+
+fn invalid(description: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, description)
+}
+
+/// The records of every shard in the order the whole cache last used them in, so that a cache
+/// split into a different number of shards loads them in the same order. Each part is already
+/// in that order, and records used at the same instant keep their order within a part.
+fn merge(parts: Vec<Vec<(Instant, Record)>>) -> Vec<Record> {
+    let mut records = Vec::new();
+    for part in parts {
+        records.extend(part);
+    }
+    records.sort_by_key(|&(used, _)| used);
+    records.into_iter().map(|(_, record)| record).collect()
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".tmp")
+}
+
+/// `path` with `suffix` appended to its file name.
+pub fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut suffixed = path.as_os_str().to_owned();
+    suffixed.push(suffix);
+    PathBuf::from(suffixed)
+}
+
+/// Coordinates the snapshots of a cache's shards.
+///
+/// A snapshot is started by `request`, or by `tick` once `interval` has passed since the last
+/// one finished. Starting a snapshot bumps the generation, which each shard's worker checks
+/// between units of work. A worker that sees a new generation calls `begin`, lists its keys with
+/// `Store::keys`, and copies their entries a chunk at a time between units of work, then hands
+/// the copy over with `contribute`. Copying is all a worker does for a snapshot: once every shard
+/// has contributed, the records are merged into the order the whole cache used them in, encoded
+/// and written out on a thread of their own, while the workers carry on serving requests.
+/// Everyone who requested the snapshot is then answered with the number of entries saved.
+///
+/// Only one snapshot runs at a time. A request made while one is in progress may have missed
+/// writes the snapshot has already copied, so it is answered by a fresh snapshot, started once
+/// the one in progress finishes.
+///
+/// If the cache has an operation log, each snapshot compacts it: a worker moves on to a new log
+/// as it begins its copy, and the new log replaces the old one once the snapshot is on disk. An
+/// entry written after its shard moved on may be copied either way, as replaying the new log over
+/// the snapshot leaves it as it was last written.
+pub struct Snapshotter {
+    path: PathBuf,
+    interval: Option<Duration>,
+    shards: usize,
+    generation: AtomicUsize,
+    log: Option<Arc<OpLog>>,
+    state: Mutex<SnapshotState>,
+}
+
+struct SnapshotState {
+    in_progress: bool,
+    parts: Vec<Option<Vec<(Instant, Record)>>>,
+    contributed: usize,
+    waiting: Vec<Sender<Message>>,
+    /// Whether to start another snapshot once the one in progress finishes, and who to answer
+    /// when that one does.
+    again: bool,
+    queued: Vec<Sender<Message>>,
+    next: Option<Instant>,
+}
+
+impl Snapshotter {
+    /// A `Snapshotter` whose next snapshot is the one after `generation`.
+    pub fn new(
+        path: PathBuf,
+        interval: Option<Duration>,
+        shards: usize,
+        generation: usize,
+        log: Option<Arc<OpLog>>,
+    ) -> Self {
+        Snapshotter {
+            path: path,
+            interval: interval,
+            shards: shards,
+            generation: AtomicUsize::new(generation),
+            log: log,
+            state: Mutex::new(SnapshotState {
+                in_progress: false,
+                parts: Vec::new(),
+                contributed: 0,
+                waiting: Vec::new(),
+                again: false,
+                queued: Vec::new(),
+                next: interval.map(|interval| Instant::now() + interval),
+            }),
+        }
+    }
+
+    /// The generation of the latest snapshot. Workers contribute to a snapshot once, when they
+    /// see its generation for the first time.
+    pub fn generation(&self) -> usize {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// Start a snapshot, or if one is already in progress, another once it finishes. If `snd` is
+    /// given, the `Op::Snapshot` response is sent on it once the snapshot is on disk.
+    pub fn request(&self, snd: Option<Sender<Message>>) {
+        let mut state = self.state.lock().unwrap();
+        if state.in_progress {
+            state.again = true;
+            state.queued.extend(snd);
+            return;
+        }
+        state.waiting.extend(snd);
+        self.start(&mut state);
+    }
+
+    /// Start a snapshot if the interval has passed since the last one.
+    pub fn tick(&self, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        let due = match state.next {
+            Some(next) => next <= now,
+            None => false,
+        };
+        if due && !state.in_progress {
+            self.start(&mut state);
+        }
+    }
+
+    /// Begin copying `shard`'s store for the snapshot of `generation`, after which the shard's
+    /// writes go to the new log. Returns false if that snapshot is no longer in progress.
+    pub fn begin(&self, generation: usize, shard: usize) -> bool {
+        let state = self.state.lock().unwrap();
+        if !state.in_progress || generation != self.generation() || state.parts[shard].is_some() {
+            return false;
+        }
+        if let Some(ref log) = self.log {
+            log.switch(shard);
+        }
+        true
+    }
+
+    /// Hand over `shard`'s records for the snapshot of `generation`, each with the instant it was
+    /// last used at, from least to most recently used. The last shard to contribute starts the
+    /// thread that writes the snapshot.
+    pub fn contribute(
+        this: &Arc<Self>,
+        generation: usize,
+        shard: usize,
+        records: Vec<(Instant, Record)>,
+    ) {
+        let parts = {
+            let mut state = this.state.lock().unwrap();
+            if !state.in_progress || generation != this.generation() ||
+                state.parts[shard].is_some()
+            {
+                return;
+            }
+            state.parts[shard] = Some(records);
+            state.contributed += 1;
+            if state.contributed < this.shards {
+                return;
+            }
+            state.parts.drain(..).map(|part| part.unwrap_or_default()).collect::<Vec<_>>()
+        };
+
+        let snapshotter = this.clone();
+        let spawned = thread::Builder::new()
+            .name("rcache-snapshot".to_owned())
+            .spawn(move || {
+                let records = merge(parts);
+                let result = write(&snapshotter.path, generation, &[records]);
+                if let Some(ref log) = snapshotter.log {
+                    if let Err(e) = log.finish_compaction(result.is_ok()) {
+                        error!("Failed to compact the log: {}", e);
+                    }
+                }
+                snapshotter.finish(result);
+            });
+        if let Err(e) = spawned {
+            this.finish(Err(e));
+        }
+    }
+
+    /// Start the snapshot that everyone in `state.waiting` is waiting on. If the log can't be
+    /// compacted, the snapshot is given up on, and they are answered with the error: the new
+    /// generation would make the current log, with the writes made while the snapshot is taken,
+    /// look older than the snapshot, and recovery would drop it.
+    fn start(&self, state: &mut SnapshotState) {
+        // The new log has to be ready before any worker sees the new generation.
+        if let Some(ref log) = self.log {
+            if let Err(e) = log.start_compaction(self.generation() + 1) {
+                error!("Failed to start compacting the log: {}", e);
+                state.next = self.interval.map(|interval| Instant::now() + interval);
+                let waiting = state.waiting.split_off(0);
+                let e = io::Error::new(e.kind(), format!("can't compact the log: {}", e));
+                self.respond(waiting, Err(e));
+                return;
+            }
+        }
+        state.in_progress = true;
+        state.parts = (0..self.shards).map(|_| None).collect();
+        state.contributed = 0;
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Answer everyone waiting on the snapshot with its `result`, and start the next one if it
+    /// was asked for while this one was in progress, or schedule it if not.
+    fn finish(&self, result: io::Result<usize>) {
+        let waiting = {
+            let mut state = self.state.lock().unwrap();
+            state.in_progress = false;
+            let waiting = state.waiting.split_off(0);
+            if state.again {
+                state.again = false;
+                state.waiting = state.queued.split_off(0);
+                self.start(&mut state);
+            } else {
+                state.next = self.interval.map(|interval| Instant::now() + interval);
+            }
+            waiting
+        };
+        self.respond(waiting, result);
+    }
+
+    /// Answer `waiting` with the `result` of a snapshot.
+    fn respond(&self, waiting: Vec<Sender<Message>>, result: io::Result<usize>) {
+        let response = match result {
+            Ok(count) => {
+                message::response(Op::Snapshot, Code::Ok, Some(message::u64_payload(count as u64)))
+            }
+            Err(e) => {
+                let description = format!("failed to write {}: {}", self.path.display(), e);
+                error!("Snapshot failed: {}", description);
+                message::error_response(
+                    Op::Snapshot,
+                    &error::Error::new(error::ErrorKind::Other, &description),
+                )
+            }
+        };
+        for snd in waiting {
+            if snd.send(response.clone()).is_err() {
+                warn!("Failed to send snapshot response.");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Future;
+    use futures::sync::oneshot;
+    use oplog::{self, Fsync};
+    use testing::TempDir;
+
+    fn records() -> Vec<Record> {
+        vec![
+            Record {
+                key: "foo".into(),
+                payload: message::payload(1, "bar".into()).with_version(3),
+                expires: None,
+            },
+            Record {
+                key: "baz".into(),
+                payload: message::payload(8, vec![0; 1000]).with_version(7).with_flags(5),
+                expires: Some(UNIX_EPOCH + Duration::from_millis(1_500_000_000_123)),
+            },
+            Record {
+                key: vec![],
+                payload: message::payload(0, vec![]),
+                expires: None,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let dir = TempDir::new();
+        let path = dir.path().join("cache.snap");
+        let records = records();
+        let parts = vec![records[..1].to_vec(), vec![], records[1..].to_vec()];
+
+        assert_eq!(write(&path, 5, &parts).unwrap(), 3);
+        assert!(!tmp_path(&path).exists());
+        assert_eq!(read(&path).unwrap(), (5, records));
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let dir = TempDir::new();
+        let path = dir.path().join("cache.snap");
+        write(&path, 1, &[records()]).unwrap();
+        let mut data = Vec::new();
+        File::open(&path).unwrap().read_to_end(&mut data).unwrap();
+
+        // Every truncation, and every flipped byte, is detected.
+        for len in 0..data.len() {
+            File::create(&path).unwrap().write_all(&data[..len]).unwrap();
+            assert_eq!(read(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+        for i in 0..data.len() {
+            let mut corrupt = data.clone();
+            corrupt[i] ^= 0x20;
+            File::create(&path).unwrap().write_all(&corrupt).unwrap();
+            assert_eq!(read(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(read(&path).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let dir = TempDir::new();
+        let path = dir.path().join("cache.snap");
+        let mut data = Vec::new();
+        data.extend_from_slice(MAGIC);
+        data.put_u32::<BigEndian>(FORMAT_VERSION + 1);
+        data.put_u64::<BigEndian>(0);
+        data.put_u64::<BigEndian>(0);
+        let crc = crc32c::crc32c(&data);
+        data.put_u32::<BigEndian>(crc);
+        File::create(&path).unwrap().write_all(&data).unwrap();
+
+        let err = read(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("unsupported snapshot version"));
+    }
+
+    #[test]
+    fn test_request_in_progress() {
+        let dir = TempDir::new();
+        let path = dir.path().join("cache.snap");
+        let snapshots = Arc::new(Snapshotter::new(path.clone(), None, 1, 0, None));
+        let records = records();
+        let used = Instant::now();
+
+        let (snd, first) = oneshot::channel();
+        snapshots.request(Some(snd));
+        assert!(snapshots.begin(1, 0));
+        // Asked for after the shard began its copy, so it may not have the latest writes.
+        let (snd, second) = oneshot::channel();
+        snapshots.request(Some(snd));
+        Snapshotter::contribute(&snapshots, 1, 0, vec![(used, records[0].clone())]);
+        assert_eq!(first.wait().unwrap().payload().unwrap().as_u64(), Some(1));
+
+        // A fresh snapshot was started for the second request.
+        assert_eq!(snapshots.generation(), 2);
+        assert!(snapshots.begin(2, 0));
+        let parts = records.iter().map(|record| (used, record.clone())).collect();
+        Snapshotter::contribute(&snapshots, 2, 0, parts);
+        assert_eq!(second.wait().unwrap().payload().unwrap().as_u64(), Some(3));
+        assert_eq!(read(&path).unwrap(), (2, records));
+    }
+
+    #[test]
+    fn test_compaction_fails() {
+        let dir = TempDir::new();
+        let log = oplog::recover(&dir.path().join("cache.log"), 0, 1, Fsync::Never).unwrap().log;
+        let snapshots = Snapshotter::new(dir.path().join("cache.snap"), None, 1, 0, Some(log));
+
+        // The new log can't be created, so the snapshot is given up on, keeping the generation
+        // of the current log.
+        fs::remove_dir_all(dir.path()).unwrap();
+        let (snd, rcv) = oneshot::channel();
+        snapshots.request(Some(snd));
+        assert_eq!(rcv.wait().unwrap().code(), Code::Error);
+        assert_eq!(snapshots.generation(), 0);
+        assert!(!snapshots.begin(1, 0));
+    }
+
+    #[test]
+    fn test_merge() {
+        let now = Instant::now();
+        let at = |millis| now + Duration::from_millis(millis);
+        let records = records();
+        let parts = vec![
+            vec![(at(1), records[0].clone()), (at(5), records[2].clone())],
+            vec![(at(3), records[1].clone())],
+        ];
+        let merged = merge(parts);
+        assert_eq!(merged, vec![records[0].clone(), records[1].clone(), records[2].clone()]);
+    }
+}
@@ -1,4 +1,5 @@
 use std::sync::{Arc, atomic};
+use store::Store;
 /// `Stats` middleware
 ///
 #[derive(Default)]
@@ -37,3 +38,37 @@ impl Stats {
         )
     }
 }
+
+/// Stats published by a cache worker about its store.
+#[derive(Default)]
+pub struct StoreStats {
+    keys: atomic::AtomicUsize,
+    bytes: atomic::AtomicUsize,
+    expired: atomic::AtomicUsize,
+    evicted: atomic::AtomicUsize,
+}
+
+impl StoreStats {
+    pub(crate) fn update(&self, store: &Store) {
+        self.keys.store(store.len(), atomic::Ordering::SeqCst);
+        self.bytes.store(store.bytes(), atomic::Ordering::SeqCst);
+        self.expired.store(store.expired(), atomic::Ordering::SeqCst);
+        self.evicted.store(store.evicted(), atomic::Ordering::SeqCst);
+    }
+
+    pub fn keys(&self) -> usize {
+        self.keys.load(atomic::Ordering::SeqCst)
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes.load(atomic::Ordering::SeqCst)
+    }
+
+    pub fn evicted(&self) -> usize {
+        self.evicted.load(atomic::Ordering::SeqCst)
+    }
+
+    pub fn expired(&self) -> usize {
+        self.expired.load(atomic::Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,627 @@
+use std::collections::BTreeSet;
+use std::mem;
+use std::cmp;
+use std::time::{Duration, Instant, SystemTime};
+use linked_hash_map::LinkedHashMap;
+use message::Payload;
+use cache::Capacity;
+use snapshot::Record;
+use error;
+
+/// A stored payload, the instant it expires at, if it was given a ttl, and the instant it was last
+/// used at.
+struct Entry {
+    payload: Payload,
+    expires: Option<Instant>,
+    used: Instant,
+}
+
+/// The number of bytes an entry for `key` and `payload` is accounted as. Besides the key and data,
+/// this is an estimate of the overhead of the entry: the key and entry structs themselves, plus
+/// the hash and the links of the `LinkedHashMap` node. An entry that expires also has its key
+/// copied into the expiry index.
+fn entry_size(key: &[u8], payload: &Payload, expires: Option<Instant>) -> usize {
+    let size = key.len() + payload.data().len() + mem::size_of::<Vec<u8>>() +
+        mem::size_of::<Entry>() + 4 * mem::size_of::<usize>();
+    match expires {
+        Some(_) => size + key.len() + mem::size_of::<(Instant, Vec<u8>)>(),
+        None => size,
+    }
+}
+
+impl Entry {
+    fn is_expired(&self, now: Instant) -> bool {
+        match self.expires {
+            Some(expires) => expires <= now,
+            None => false,
+        }
+    }
+
+    /// The entry at `key` as a snapshot record, with its expiry converted to wall clock time using
+    /// `now` and `wall`, which should be the same moment.
+    fn record(&self, key: &[u8], now: Instant, wall: SystemTime) -> Record {
+        Record {
+            key: key.to_vec(),
+            payload: self.payload.clone(),
+            expires: self.expires.map(|expires| wall + (expires - now)),
+        }
+    }
+
+    /// Remaining time to live in whole seconds, rounded up so that a live entry never reports 0.
+    fn ttl(&self, now: Instant) -> u32 {
+        match self.expires {
+            Some(expires) if expires > now => {
+                let remaining = expires - now;
+                let secs = remaining.as_secs() + if remaining.subsec_nanos() > 0 { 1 } else { 0 };
+                secs as u32
+            }
+            _ => 0,
+        }
+    }
+}
+
+/// The LRU backed store owned by the cache worker.
+///
+/// Entries with a ttl are expired lazily, when a read finds them stale, and actively by `sweep`,
+/// which the worker calls periodically. `sweep` walks an index of the entries with an expiry,
+/// ordered by expiry instant, so it only ever looks at entries that are due. The index holds
+/// exactly the live entries' expiries: an entry's is removed whenever the entry is overwritten,
+/// removed or evicted.
+///
+/// Entries are kept in a `LinkedHashMap` from least to most recently used. Reads move an entry to
+/// the back; expiry checks and bookkeeping don't. The store is bounded either by a number of
+/// entries or by a byte budget, see `Capacity`, and evicts from the front of the map itself so
+/// that it can account for the bytes it holds.
+pub struct Store {
+    entries: LinkedHashMap<Vec<u8>, Entry>,
+    expiry: BTreeSet<(Instant, Vec<u8>)>,
+    capacity: Capacity,
+    bytes: usize,
+    expired: usize,
+    evicted: usize,
+    last_version: u64,
+}
+
+impl Store {
+    pub fn new(capacity: Capacity) -> Self {
+        Store {
+            entries: LinkedHashMap::new(),
+            expiry: BTreeSet::new(),
+            capacity: capacity,
+            bytes: 0,
+            expired: 0,
+            evicted: 0,
+            last_version: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Estimated number of bytes held by the store's entries.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Total number of entries that have been expired, lazily or by `sweep`.
+    pub fn expired(&self) -> usize {
+        self.expired
+    }
+
+    /// Total number of live entries that have been evicted to make room for new ones.
+    pub fn evicted(&self) -> usize {
+        self.evicted
+    }
+
+    /// Get a copy of the payload stored at `key`. The returned payload's ttl is the time remaining
+    /// until the entry expires.
+    pub fn get(&mut self, key: &[u8], now: Instant) -> Option<Payload> {
+        if self.expire_stale(key, now) {
+            return None;
+        }
+        self.entries.get_refresh(key).map(|entry| {
+            entry.used = now;
+            let ttl = entry.ttl(now);
+            entry.payload.clone().with_ttl(ttl)
+        })
+    }
+
+    /// Whether there is a live entry at `key`.
+    pub fn contains(&mut self, key: &[u8], now: Instant) -> bool {
+        !self.expire_stale(key, now) && self.entries.contains_key(key)
+    }
+
+    /// The version of the entry at `key`.
+    pub fn version(&mut self, key: &[u8], now: Instant) -> Option<u64> {
+        if self.expire_stale(key, now) {
+            return None;
+        }
+        self.entries.get_refresh(key).map(|entry| {
+            entry.used = now;
+            entry.payload.version()
+        })
+    }
+
+    /// Store `payload` at `key`, replacing any existing entry. If the payload has a ttl, the entry
+    /// expires that long after `now`. Least recently used entries are evicted until the new
+    /// entry fits. An entry that is larger than the whole byte budget, or any entry if the store
+    /// has room for none, is rejected with `ErrorKind::TooLarge`, leaving the store untouched.
+    ///
+    /// Every insert stamps the entry with a new version, greater than any the store has used.
+    pub fn insert(
+        &mut self,
+        key: Vec<u8>,
+        payload: Payload,
+        now: Instant,
+    ) -> Result<(), error::Error> {
+        let expires = if payload.ttl_millis() > 0 {
+            Some(now + Duration::from_millis(payload.ttl_millis()))
+        } else {
+            None
+        };
+        self.insert_entry(key, payload, expires, now)
+    }
+
+    /// Replace the payload of the live entry at `key`, keeping its expiry. Returns false, leaving
+    /// the store untouched, if there is no live entry at `key`.
+    pub fn replace(
+        &mut self,
+        key: &[u8],
+        payload: Payload,
+        now: Instant,
+    ) -> Result<bool, error::Error> {
+        if self.expire_stale(key, now) {
+            return Ok(false);
+        }
+        let expires = match self.entries.get(key) {
+            Some(entry) => entry.expires,
+            None => return Ok(false),
+        };
+        self.insert_entry(key.to_vec(), payload, expires, now).map(|_| true)
+    }
+
+    /// Remove the entry at `key`. Returns false if there was no live entry to remove.
+    pub fn remove(&mut self, key: &[u8], now: Instant) -> bool {
+        if self.expire_stale(key, now) {
+            return false;
+        }
+        self.remove_entry(key)
+    }
+
+    /// The remaining time to live of the entry at `key` in seconds, 0 if the entry never expires.
+    pub fn ttl(&mut self, key: &[u8], now: Instant) -> Option<u32> {
+        if self.expire_stale(key, now) {
+            return None;
+        }
+        self.entries.get_refresh(key).map(|entry| {
+            entry.used = now;
+            entry.ttl(now)
+        })
+    }
+
+    /// Remove every entry, as a replica does before it copies its primary's store.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.expiry.clear();
+        self.bytes = 0;
+    }
+
+    /// Remove up to `limit` entries that have expired by `now`, returning the number removed.
+    pub fn sweep(&mut self, now: Instant, limit: usize) -> usize {
+        let mut swept = 0;
+        while swept < limit {
+            let key = match self.expiry.iter().next() {
+                Some(&(expires, ref key)) if expires <= now => key.clone(),
+                _ => break,
+            };
+            self.remove_entry(&key);
+            self.expired += 1;
+            swept += 1;
+        }
+        swept
+    }
+
+    /// The keys of the live entries, from least to most recently used, and the instant each was
+    /// last used at, which orders the entries of different stores. A copy of the store, for a
+    /// snapshot or a replica, lists the keys up front and copies their entries a few at a time
+    /// with `record`, so that it doesn't hold up the requests in between.
+    pub fn keys(&self, now: Instant) -> Vec<(Vec<u8>, Instant)> {
+        self.entries
+            .iter()
+            .filter(|&(_, entry)| !entry.is_expired(now))
+            .map(|(key, entry)| (key.clone(), entry.used))
+            .collect()
+    }
+
+    /// The live entry at `key` as a snapshot record, without touching its recency. Its expiry
+    /// instant is converted to wall clock time using `now` and `wall`, which should be the same
+    /// moment.
+    pub fn record(&mut self, key: &[u8], now: Instant, wall: SystemTime) -> Option<Record> {
+        if self.expire_stale(key, now) {
+            return None;
+        }
+        self.entries.get(key).map(|entry| entry.record(key, now, wall))
+    }
+
+    /// Load a snapshot record as the most recently used entry, keeping the version it was saved
+    /// with. Returns false if the record expired at or before `wall`, removing any entry at its
+    /// key, as a record replayed from the operation log replaces what was there.
+    ///
+    /// Later writes are stamped with versions greater than any restored one, so that a version a
+    /// client read before a restart can't match a different write after it.
+    pub fn restore(
+        &mut self,
+        record: Record,
+        now: Instant,
+        wall: SystemTime,
+    ) -> Result<bool, error::Error> {
+        let expires = match record.expires {
+            Some(expires) => {
+                match expires.duration_since(wall) {
+                    Ok(remaining) if remaining > Duration::from_secs(0) => Some(now + remaining),
+                    _ => {
+                        self.remove_entry(&record.key);
+                        return Ok(false);
+                    }
+                }
+            }
+            None => None,
+        };
+        self.last_version = cmp::max(self.last_version, record.payload.version());
+        self.put_entry(record.key, record.payload, expires, now).map(|_| true)
+    }
+
+    /// Remove the entry at `key` if it has expired, returning true if it was removed.
+    fn expire_stale(&mut self, key: &[u8], now: Instant) -> bool {
+        let stale = match self.entries.get(key) {
+            Some(entry) => entry.is_expired(now),
+            None => false,
+        };
+        if stale {
+            self.remove_entry(key);
+            self.expired += 1;
+        }
+        stale
+    }
+
+    /// Store `payload` at `key`, stamped with a new version.
+    fn insert_entry(
+        &mut self,
+        key: Vec<u8>,
+        payload: Payload,
+        expires: Option<Instant>,
+        now: Instant,
+    ) -> Result<(), error::Error> {
+        self.last_version += 1;
+        let payload = payload.with_version(self.last_version);
+        self.put_entry(key, payload, expires, now)
+    }
+
+    /// Store `payload` at `key` as is, used at `now`, evicting entries until it fits.
+    fn put_entry(
+        &mut self,
+        key: Vec<u8>,
+        payload: Payload,
+        expires: Option<Instant>,
+        now: Instant,
+    ) -> Result<(), error::Error> {
+        let size = entry_size(&key, &payload, expires);
+        let fits = match self.capacity {
+            Capacity::Entries(limit) => limit > 0,
+            Capacity::Bytes(limit) => size <= limit,
+        };
+        if !fits {
+            return Err(error::Error::new(
+                error::ErrorKind::TooLarge,
+                "value is larger than the cache",
+            ));
+        }
+
+        self.remove_entry(key.as_slice());
+        self.make_room(size);
+
+        if let Some(expires) = expires {
+            self.expiry.insert((expires, key.clone()));
+        }
+
+        self.entries.insert(
+            key,
+            Entry {
+                payload: payload,
+                expires: expires,
+                used: now,
+            },
+        );
+        self.bytes += size;
+        Ok(())
+    }
+
+    /// Remove the entry at `key` regardless of its expiry, returning true if there was one.
+    fn remove_entry(&mut self, key: &[u8]) -> bool {
+        match self.entries.remove(key) {
+            Some(entry) => {
+                self.forget(key, &entry);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Evict least recently used entries until an entry of `size` bytes fits.
+    fn make_room(&mut self, size: usize) {
+        loop {
+            let full = match self.capacity {
+                Capacity::Entries(limit) => self.entries.len() >= limit,
+                Capacity::Bytes(limit) => self.bytes + size > limit,
+            };
+            if !full {
+                break;
+            }
+
+            match self.entries.pop_front() {
+                Some((key, entry)) => {
+                    self.forget(&key, &entry);
+                    self.evicted += 1;
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Drop the accounting for an entry that has been taken out of the map.
+    fn forget(&mut self, key: &[u8], entry: &Entry) {
+        self.bytes -= entry_size(key, &entry.payload, entry.expires);
+        if let Some(expires) = entry.expires {
+            self.expiry.remove(&(expires, key.to_vec()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use message;
+
+    #[test]
+    fn test_get_expired() {
+        let mut store = Store::new(Capacity::Entries(10));
+        let now = Instant::now();
+        store.insert("foo".into(), message::payload(1, "bar".into()).with_ttl(10), now).unwrap();
+
+        let payload = store.get(b"foo", now + Duration::from_millis(500)).unwrap();
+        assert_eq!(payload.data(), b"bar");
+        assert_eq!(payload.ttl(), 10);
+
+        assert!(store.get(b"foo", now + Duration::from_secs(10)).is_none());
+        assert_eq!(store.len(), 0);
+        assert_eq!(store.expired(), 1);
+    }
+
+    #[test]
+    fn test_ttl() {
+        let mut store = Store::new(Capacity::Entries(10));
+        let now = Instant::now();
+        store.insert("foo".into(), message::payload(1, "bar".into()).with_ttl(10), now).unwrap();
+        store.insert("baz".into(), message::payload(1, "bar".into()), now).unwrap();
+
+        assert_eq!(store.ttl(b"foo", now + Duration::from_millis(2500)), Some(8));
+        assert_eq!(store.ttl(b"baz", now), Some(0));
+        assert_eq!(store.ttl(b"qux", now), None);
+        assert_eq!(store.ttl(b"foo", now + Duration::from_secs(11)), None);
+    }
+
+    #[test]
+    fn test_sweep() {
+        let mut store = Store::new(Capacity::Entries(10));
+        let now = Instant::now();
+        store.insert("a".into(), message::payload(1, "1".into()).with_ttl(1), now).unwrap();
+        store.insert("b".into(), message::payload(1, "2".into()).with_ttl(2), now).unwrap();
+        store.insert("c".into(), message::payload(1, "3".into()).with_ttl(3), now).unwrap();
+        store.insert("d".into(), message::payload(1, "4".into()), now).unwrap();
+
+        assert_eq!(store.sweep(now, 10), 0);
+        assert_eq!(store.sweep(now + Duration::from_secs(2), 10), 2);
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.sweep(now + Duration::from_secs(5), 10), 1);
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.expired(), 3);
+    }
+
+    #[test]
+    fn test_sweep_skips_overwritten() {
+        let mut store = Store::new(Capacity::Entries(10));
+        let now = Instant::now();
+        store.insert("a".into(), message::payload(1, "1".into()).with_ttl(1), now).unwrap();
+        store.insert("a".into(), message::payload(1, "2".into()).with_ttl(5), now).unwrap();
+        store.insert("b".into(), message::payload(1, "3".into()).with_ttl(1), now).unwrap();
+        store.insert("b".into(), message::payload(1, "4".into()), now).unwrap();
+
+        assert_eq!(store.expiry.len(), 1);
+        assert_eq!(store.sweep(now + Duration::from_secs(2), 10), 0);
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.sweep(now + Duration::from_secs(5), 10), 1);
+        assert_eq!(store.get(b"b", now).unwrap().data(), b"4");
+    }
+
+    #[test]
+    fn test_expiry_index_bounded() {
+        let mut store = Store::new(Capacity::Entries(2));
+        let now = Instant::now();
+        for i in 0..100 {
+            let payload = message::payload(1, "1".into()).with_ttl(10 + i);
+            store.insert("a".into(), payload, now).unwrap();
+        }
+        store.insert("b".into(), message::payload(1, "2".into()).with_ttl(1), now).unwrap();
+        store.remove(b"b", now);
+        store.insert("c".into(), message::payload(1, "3".into()).with_ttl(1), now).unwrap();
+        store.insert("d".into(), message::payload(1, "4".into()).with_ttl(1), now).unwrap();
+
+        // "a" was evicted and "b" removed, leaving only the expiries of "c" and "d".
+        assert_eq!(store.expiry.len(), 2);
+        assert_eq!(store.sweep(now + Duration::from_secs(1), 1), 1);
+        assert_eq!(store.len(), 1);
+    }
+
+    #[test]
+    fn test_expiry_checks_keep_recency() {
+        let mut store = Store::new(Capacity::Entries(2));
+        let now = Instant::now();
+        store.insert("a".into(), message::payload(1, "1".into()).with_ttl(10), now).unwrap();
+        store.insert("b".into(), message::payload(1, "2".into()), now).unwrap();
+        assert!(store.contains(b"a", now));
+        assert_eq!(store.sweep(now, 10), 0);
+        store.insert("c".into(), message::payload(1, "3".into()), now).unwrap();
+
+        assert!(store.get(b"a", now).is_none());
+        assert!(store.get(b"b", now).is_some());
+    }
+
+    #[test]
+    fn test_versions() {
+        let mut store = Store::new(Capacity::Entries(10));
+        let now = Instant::now();
+        store.insert("a".into(), message::payload(1, "1".into()), now).unwrap();
+        store.insert("b".into(), message::payload(1, "2".into()), now).unwrap();
+        let a = store.get(b"a", now).unwrap().version();
+        let b = store.version(b"b", now).unwrap();
+        assert!(a > 0);
+        assert!(b > a);
+
+        store.insert("a".into(), message::payload(1, "3".into()), now).unwrap();
+        assert!(store.version(b"a", now).unwrap() > b);
+        assert_eq!(store.version(b"c", now), None);
+    }
+
+    #[test]
+    fn test_replace_keeps_expiry() {
+        let mut store = Store::new(Capacity::Entries(10));
+        let now = Instant::now();
+        store.insert("a".into(), message::payload(1, "1".into()).with_ttl(10), now).unwrap();
+        let version = store.version(b"a", now).unwrap();
+
+        let later = now + Duration::from_secs(5);
+        assert!(store.replace(b"a", message::payload(1, "2".into()), later).unwrap());
+        assert!(!store.replace(b"b", message::payload(1, "2".into()), later).unwrap());
+
+        let payload = store.get(b"a", later).unwrap();
+        assert_eq!(payload.data(), b"2");
+        assert_eq!(payload.ttl(), 5);
+        assert!(payload.version() > version);
+        assert!(store.get(b"b", later).is_none());
+
+        assert_eq!(store.sweep(now + Duration::from_secs(10), 10), 1);
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn test_entries_capacity() {
+        let mut store = Store::new(Capacity::Entries(2));
+        let now = Instant::now();
+        store.insert("a".into(), message::payload(1, "1".into()), now).unwrap();
+        store.insert("b".into(), message::payload(1, "2".into()), now).unwrap();
+        store.get(b"a", now);
+        store.insert("c".into(), message::payload(1, "3".into()), now).unwrap();
+
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.evicted(), 1);
+        assert!(store.get(b"b", now).is_none());
+        assert!(store.get(b"a", now).is_some());
+    }
+
+    #[test]
+    fn test_bytes_capacity() {
+        let size = entry_size(b"a", &message::payload(1, vec![0; 100]), None);
+        let mut store = Store::new(Capacity::Bytes(size * 3));
+        let now = Instant::now();
+
+        for key in &["a", "b", "c"] {
+            store.insert(key.as_bytes().to_vec(), message::payload(1, vec![0; 100]), now).unwrap();
+        }
+        assert_eq!(store.bytes(), size * 3);
+
+        // Overwriting a key only accounts for the new value.
+        store.insert("c".into(), message::payload(1, vec![0; 50]), now).unwrap();
+        assert_eq!(store.bytes(), size * 3 - 50);
+        assert_eq!(store.evicted(), 0);
+
+        // A value twice the size needs two entries evicted.
+        store.insert("d".into(), message::payload(1, vec![0; 200]), now).unwrap();
+        assert_eq!(store.evicted(), 2);
+        assert!(store.get(b"a", now).is_none());
+        assert!(store.get(b"b", now).is_none());
+        assert!(store.get(b"c", now).is_some());
+        assert!(store.bytes() <= size * 3);
+
+        store.remove(b"d", now);
+        store.remove(b"c", now);
+        assert_eq!(store.bytes(), 0);
+
+        // The copy of the key in the expiry index counts too.
+        store.insert("a".into(), message::payload(1, vec![0; 100]).with_ttl(10), now).unwrap();
+        assert!(store.bytes() > size);
+        store.remove(b"a", now);
+        assert_eq!(store.bytes(), 0);
+    }
+
+    #[test]
+    fn test_dump_restore() {
+        let mut store = Store::new(Capacity::Entries(10));
+        let now = Instant::now();
+        let wall = SystemTime::now();
+        store.insert("a".into(), message::payload(1, "1".into()).with_ttl(10), now).unwrap();
+        store.insert("b".into(), message::payload(2, "2".into()), now).unwrap();
+        store.insert("c".into(), message::payload(1, "3".into()).with_ttl(1), now).unwrap();
+        store.get(b"a", now);
+        let version = store.version(b"b", now).unwrap();
+
+        let later = now + Duration::from_millis(500);
+        let keys = store.keys(later);
+        let records: Vec<Record> = keys.iter()
+            .filter_map(|&(ref key, _)| store.record(key, later, wall))
+            .collect();
+        let keys: Vec<&[u8]> = records.iter().map(|record| record.key.as_slice()).collect();
+        assert_eq!(keys, vec![&b"c"[..], b"a", b"b"]);
+        assert_eq!(store.len(), 3);
+
+        // Restore into a store with room for two, a second after the dump, by which time "c" has
+        // expired.
+        let mut restored = Store::new(Capacity::Entries(2));
+        let now = Instant::now();
+        let wall = wall + Duration::from_secs(1);
+        let loaded: Vec<bool> = records
+            .into_iter()
+            .map(|record| restored.restore(record, now, wall).unwrap())
+            .collect();
+        assert_eq!(loaded, vec![false, true, true]);
+
+        let b = restored.get(b"b", now).unwrap();
+        assert_eq!((b.type_id(), b.data(), b.ttl(), b.version()), (2, &b"2"[..], 0, version));
+        assert_eq!(restored.ttl(b"a", now), Some(9));
+        assert!(restored.version(b"c", now).is_none());
+
+        restored.insert("d".into(), message::payload(1, "4".into()), now).unwrap();
+        assert!(restored.version(b"d", now).unwrap() > version);
+    }
+
+    #[test]
+    fn test_too_large() {
+        let mut store = Store::new(Capacity::Bytes(1024));
+        let now = Instant::now();
+        store.insert("a".into(), message::payload(1, vec![0; 100]), now).unwrap();
+
+        let err = store.insert("b".into(), message::payload(1, vec![0; 1024]), now).unwrap_err();
+        assert_eq!(*err.kind(), error::ErrorKind::TooLarge);
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.evicted(), 0);
+
+        let mut store = Store::new(Capacity::Entries(0));
+        let err = store.insert("a".into(), message::payload(1, vec![0; 1]), now).unwrap_err();
+        assert_eq!(*err.kind(), error::ErrorKind::TooLarge);
+        assert!(store.is_empty());
+    }
+}
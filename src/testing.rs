@@ -0,0 +1,106 @@
+//! Helpers shared by the tests.
+
+use std::env;
+use std::fmt::Debug;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use bytes::BytesMut;
+use rand;
+use tokio_io::codec::Decoder;
+use tokio_service::{Service, NewService};
+
+use cache::Cache;
+use message::Message;
+use service::{self, CacheService, Protocol};
+use uds;
+use std::io;
+
+/// A listener on a loopback port the OS picks, so that tests running in parallel never share one.
+pub fn bind() -> TcpListener {
+    TcpListener::bind("127.0.0.1:0").unwrap()
+}
+
+/// Serve the service `make` builds, speaking `protocol`, on `listener` from a background thread.
+/// The service is built on that thread, as a `Cache` can't be sent between threads. The address
+/// can be connected to straight away, as the listener is already bound.
+pub fn spawn<T, F>(listener: TcpListener, protocol: Protocol, make: F) -> SocketAddr
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: NewService<Request = Message, Response = Message, Error = io::Error> + 'static,
+    T::Instance: 'static,
+    <T::Instance as Service>::Future: 'static,
+{
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || service::serve_bound(listener, protocol, make()).unwrap());
+    addr
+}
+
+/// Serve a fresh cache of 1024 entries, speaking `protocol`, on a port of its own.
+pub fn spawn_cache(protocol: Protocol) -> SocketAddr {
+    spawn(bind(), protocol, || {
+        CacheService { cache: Arc::new(Cache::new(1024).unwrap()) }
+    })
+}
+
+/// Write `request` and check the server answers with exactly `response`.
+pub fn exchange(stream: &mut TcpStream, request: &str, response: &str) {
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut buf = vec![0; response.len()];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(String::from_utf8_lossy(&buf), response);
+}
+
+/// Decode every item of `input` with `codec`, checking that none of it is left over.
+pub fn decode_all<C: Decoder>(codec: &mut C, input: &[u8]) -> Vec<C::Item>
+where
+    C::Error: Debug,
+{
+    let mut buf = BytesMut::from(input);
+    let mut items = Vec::new();
+    while let Some(item) = codec.decode(&mut buf).unwrap() {
+        items.push(item);
+    }
+    assert!(buf.is_empty());
+    items
+}
+
+/// Serve a fresh cache of 1024 entries over the rcache protocol on a Unix domain socket at `path`,
+/// with the socket file's permissions set to `mode`. The socket is bound before returning.
+pub fn spawn_unix(path: &Path, mode: Option<u32>) {
+    let listener = uds::bind(path, mode).unwrap();
+    thread::spawn(move || {
+        let service = CacheService { cache: Arc::new(Cache::new(1024).unwrap()) };
+        service::serve_bound_unix(listener, Protocol::Rcache, service).unwrap()
+    });
+}
+
+/// A directory of its own under the system's temp directory, removed with everything in it when
+/// dropped, so that tests running in parallel never share a file.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> TempDir {
+        let path = env::temp_dir().join(format!("rcache-test-{:016x}", rand::random::<u64>()));
+        fs::create_dir(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// `count` distinct keys.
+pub fn keys(count: usize) -> Vec<Vec<u8>> {
+    (0..count).map(|i| format!("key{}", i).into_bytes()).collect()
+}
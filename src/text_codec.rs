@@ -0,0 +1,617 @@
+use tokio_io::codec::{Encoder, Decoder};
+use std::io;
+use std::cmp;
+use std::str;
+use std::str::FromStr;
+use bytes::BytesMut;
+use codec::Limits;
+use message::{self, Message, Op, Code, Payload};
+use error;
+use time;
+
+/// The longest command line we will buffer before giving up on the connection, besides its key.
+const MAX_LINE_LEN: usize = 2048;
+/// Exptimes larger than 30 days are unix timestamps rather than a number of seconds.
+const MAX_RELATIVE_EXPTIME: i64 = 60 * 60 * 24 * 30;
+
+/// A codec for the memcached ASCII protocol.
+///
+/// Commands are decoded into a `Message` request, along with a `TextRequest` that remembers how
+/// the response to that request has to be written. Supported commands are `get`, `gets`, `set`,
+/// `add`, `replace`, `append`, `prepend`, `cas`, `delete`, `incr`, `decr`, `stats` and `version`,
+/// with `noreply` on the commands that take it.
+///
+/// Values are stored with type id 0, and memcached's client flags as the payload's flags.
+/// Counters are stored as `TYPE_ID_U64` payloads, which are written back as decimal strings.
+///
+/// Malformed commands are decoded into a `Message::Response` carrying the error, which `serve`
+/// writes back without calling the service. A key may be up to `max_key_len` long, and a data
+/// block up to `max_value_len`, see `Limits`.
+pub struct TextCodec {
+    limits: Limits,
+}
+
+impl Default for TextCodec {
+    fn default() -> TextCodec {
+        TextCodec::with_limits(Limits::default())
+    }
+}
+
+impl TextCodec {
+    pub fn with_limits(limits: Limits) -> TextCodec {
+        TextCodec { limits: limits }
+    }
+}
+
+/// How to write the response to a decoded text request.
+#[derive(Debug, PartialEq, Clone)]
+pub enum TextRequest {
+    /// `get` or `gets` of `keys`, which is answered with a `Message::BatchResponse`.
+    Get { keys: Vec<Vec<u8>>, cas: bool },
+    /// A storage command, answered with `STORED`, `NOT_STORED`, `EXISTS` or `NOT_FOUND`.
+    Store { noreply: bool },
+    Delete { noreply: bool },
+    /// `incr` or `decr`, answered with the new value.
+    Counter { noreply: bool },
+    Stats,
+    Version,
+    /// An unknown command, answered with `ERROR`.
+    Error,
+    /// A malformed command, answered with `CLIENT_ERROR` and the description in the response.
+    ClientError,
+}
+
+impl Decoder for TextCodec {
+    type Item = (TextRequest, Message);
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<(TextRequest, Message)>, io::Error> {
+        let line_len = match buf.as_ref().iter().position(|&b| b == b'\n') {
+            Some(i) => i + 1,
+            None if buf.len() > MAX_LINE_LEN + self.limits.max_key_len => {
+                return Err(
+                    error::Error::new(error::ErrorKind::TooLarge, "command line too long").into(),
+                )
+            }
+            None => return Ok(None),
+        };
+
+        let parsed = {
+            let (line, rest) = buf.as_ref().split_at(line_len);
+            parse(line, rest, &self.limits)?
+        };
+
+        match parsed {
+            Some((data_len, req, msg)) => {
+                buf.split_to(line_len + data_len);
+                Ok(Some((req, msg)))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// Parse a command `line`. Storage commands are followed by a data block at the start of `rest`.
+/// Returns `None` if the data block hasn't fully arrived, otherwise the number of bytes of `rest`
+/// the command consumed.
+fn parse(
+    line: &[u8],
+    rest: &[u8],
+    limits: &Limits,
+) -> io::Result<Option<(usize, TextRequest, Message)>> {
+    let line = trim_newline(line);
+    let tokens: Vec<&[u8]> = line.split(|&b| b == b' ').filter(|t| !t.is_empty()).collect();
+    if tokens.is_empty() {
+        return Ok(Some((0, TextRequest::Error, error_response(Op::Get, "empty command"))));
+    }
+
+    let noreply = tokens.len() > 1 && tokens[tokens.len() - 1] == b"noreply";
+    let args = if noreply {
+        &tokens[1..tokens.len() - 1]
+    } else {
+        &tokens[1..]
+    };
+
+    let command = str::from_utf8(tokens[0]).unwrap_or("");
+    let op = command_op(command);
+    let result = match command {
+        "get" | "gets" => {
+            parse_get(args, command == "gets", limits).map(|(req, msg)| (0, req, msg))
+        }
+        "set" | "add" | "replace" | "append" | "prepend" | "cas" => {
+            let (data_len, key, payload) = match parse_storage(command, args, limits) {
+                Ok(storage) => storage,
+                Err(e) => return Ok(Some((0, TextRequest::ClientError, error_response(op, e)))),
+            };
+            if data_len > limits.max_value_len {
+                return Err(
+                    error::Error::new(error::ErrorKind::TooLarge, "data block too large").into(),
+                );
+            }
+            if rest.len() < data_len + 2 {
+                return Ok(None);
+            }
+            // Like memcached, skip only the declared length, whatever follows is the next command.
+            if &rest[data_len..data_len + 2] != b"\r\n" {
+                let resp = error_response(op, "bad data chunk");
+                return Ok(Some((data_len + 2, TextRequest::ClientError, resp)));
+            }
+
+            let msg = match payload {
+                Some((op, flags, ttl, version)) => {
+                    let payload = message::payload(0, rest[..data_len].to_vec())
+                        .with_flags(flags)
+                        .with_ttl(ttl)
+                        .with_version(version);
+                    message::request(op, key, Some(payload))
+                }
+                // The item has already expired, which is the same as removing it.
+                None => message::request(Op::Del, key, None),
+            };
+            Ok((data_len + 2, TextRequest::Store { noreply: noreply }, msg))
+        }
+        "delete" => {
+            // `delete <key> 0` is an old form of delete that some clients still send.
+            if args.len() == 1 || (args.len() == 2 && args[1] == b"0") {
+                key(args[0], limits).map(|key| {
+                    let msg = message::request(Op::Del, key, None);
+                    (0, TextRequest::Delete { noreply: noreply }, msg)
+                })
+            } else {
+                Err("usage: delete <key> [noreply]")
+            }
+        }
+        "incr" | "decr" => {
+            if args.len() != 2 {
+                Err("usage: incr|decr <key> <value> [noreply]")
+            } else {
+                let op = if command == "incr" { Op::Incr } else { Op::Decr };
+                key(args[0], limits).and_then(|key| {
+                    let delta = number(args[1])?;
+                    let msg = message::request(op, key, Some(message::counter_payload(delta, None)));
+                    Ok((0, TextRequest::Counter { noreply: noreply }, msg))
+                })
+            }
+        }
+        "stats" => Ok((0, TextRequest::Stats, message::request(Op::Stats, vec![], None))),
+        "version" => Ok((0, TextRequest::Version, message::response(Op::Stats, Code::Ok, None))),
+        _ => return Ok(Some((0, TextRequest::Error, error_response(op, "unknown command")))),
+    };
+
+    match result {
+        Ok(request) => Ok(Some(request)),
+        Err(e) => Ok(Some((0, TextRequest::ClientError, error_response(op, e)))),
+    }
+}
+
+fn parse_get(
+    args: &[&[u8]],
+    cas: bool,
+    limits: &Limits,
+) -> Result<(TextRequest, Message), &'static str> {
+    if args.is_empty() {
+        return Err("usage: get <key>*");
+    }
+    let mut keys = Vec::with_capacity(args.len());
+    for arg in args {
+        keys.push(key(arg, limits)?);
+    }
+    let entries = keys.iter().map(|key| (key.clone(), None)).collect();
+    Ok((
+        TextRequest::Get {
+            keys: keys,
+            cas: cas,
+        },
+        message::batch_request(Op::Get, entries),
+    ))
+}
+
+/// Parse `<key> <flags> <exptime> <bytes> [<cas unique>]`, returning the length of the data
+/// block, the key, and the op, flags, ttl and version to store it with. The op is `None` if the
+/// exptime has already passed.
+fn parse_storage(
+    command: &str,
+    args: &[&[u8]],
+    limits: &Limits,
+) -> Result<(usize, Vec<u8>, Option<(Op, u32, u32, u64)>), &'static str> {
+    let expected = if command == "cas" { 5 } else { 4 };
+    if args.len() != expected {
+        return Err("bad command line format");
+    }
+
+    let key = key(args[0], limits)?;
+    let flags: u32 = number(args[1])?;
+    let exptime: i64 = number(args[2])?;
+    let data_len: usize = number(args[3])?;
+    let version: u64 = if command == "cas" { number(args[4])? } else { 0 };
+
+    let op = match command {
+        "set" => Op::Set,
+        "add" => Op::Add,
+        "replace" => Op::Replace,
+        "append" => Op::Append,
+        "prepend" => Op::Prepend,
+        _ => Op::Cas,
+    };
+    let payload = ttl(exptime, time::get_time().sec).map(|ttl| (op, flags, ttl, version));
+    Ok((data_len, key, payload))
+}
+
+/// Translate a memcached exptime into a ttl, or `None` if it has already passed.
+pub(crate) fn ttl(exptime: i64, now: i64) -> Option<u32> {
+    if exptime < 0 {
+        None
+    } else if exptime <= MAX_RELATIVE_EXPTIME {
+        Some(exptime as u32)
+    } else if exptime <= now {
+        None
+    } else {
+        Some(cmp::min(exptime - now, i64::from(u32::max_value())) as u32)
+    }
+}
+
+fn key(token: &[u8], limits: &Limits) -> Result<Vec<u8>, &'static str> {
+    if token.len() > limits.max_key_len {
+        Err("key too long")
+    } else {
+        Ok(token.to_vec())
+    }
+}
+
+fn number<T: FromStr>(token: &[u8]) -> Result<T, &'static str> {
+    str::from_utf8(token).ok().and_then(|s| s.parse().ok()).ok_or(
+        "invalid numeric argument",
+    )
+}
+
+fn trim_newline(line: &[u8]) -> &[u8] {
+    let mut end = line.len();
+    while end > 0 && (line[end - 1] == b'\n' || line[end - 1] == b'\r') {
+        end -= 1;
+    }
+    &line[..end]
+}
+
+/// The op a command maps onto. An empty or unknown command has none, and is answered as a get.
+fn command_op(command: &str) -> Op {
+    match command {
+        "set" => Op::Set,
+        "add" => Op::Add,
+        "replace" => Op::Replace,
+        "append" => Op::Append,
+        "prepend" => Op::Prepend,
+        "cas" => Op::Cas,
+        "delete" => Op::Del,
+        "incr" => Op::Incr,
+        "decr" => Op::Decr,
+        "stats" | "version" => Op::Stats,
+        _ => Op::Get,
+    }
+}
+
+fn error_response(op: Op, description: &str) -> Message {
+    message::error_response(
+        op,
+        &error::Error::new(error::ErrorKind::BadMessage, description),
+    )
+}
+
+impl Encoder for TextCodec {
+    type Item = (TextRequest, Message);
+    type Error = io::Error;
+
+    fn encode(&mut self, item: (TextRequest, Message), buf: &mut BytesMut) -> io::Result<()> {
+        let (req, msg) = item;
+
+        match req {
+            TextRequest::Get { ref keys, cas } => {
+                match msg {
+                    Message::BatchResponse(_, ref results) => {
+                        for (key, result) in keys.iter().zip(results) {
+                            if let (Code::Hit, Some(payload)) = (result.0, result.1.as_ref()) {
+                                put_value(buf, key, payload, cas);
+                            }
+                        }
+                        buf.extend_from_slice(b"END\r\n");
+                    }
+                    ref msg => put_server_error(buf, msg),
+                }
+            }
+            TextRequest::Store { noreply } => {
+                if noreply {
+                    return Ok(());
+                }
+                match (msg.op(), msg.code()) {
+                    // A store with an exptime in the past.
+                    (Op::Del, _) => buf.extend_from_slice(b"STORED\r\n"),
+                    (_, Code::Ok) => buf.extend_from_slice(b"STORED\r\n"),
+                    (_, Code::NotStored) => buf.extend_from_slice(b"NOT_STORED\r\n"),
+                    (_, Code::Exists) => buf.extend_from_slice(b"EXISTS\r\n"),
+                    (_, Code::Miss) => buf.extend_from_slice(b"NOT_FOUND\r\n"),
+                    (_, Code::TooLarge) => {
+                        buf.extend_from_slice(b"SERVER_ERROR object too large for cache\r\n")
+                    }
+                    _ => put_server_error(buf, &msg),
+                }
+            }
+            TextRequest::Delete { noreply } => {
+                if noreply {
+                    return Ok(());
+                }
+                match msg.code() {
+                    Code::Hit => buf.extend_from_slice(b"DELETED\r\n"),
+                    Code::Miss => buf.extend_from_slice(b"NOT_FOUND\r\n"),
+                    _ => put_server_error(buf, &msg),
+                }
+            }
+            TextRequest::Counter { noreply } => {
+                if noreply {
+                    return Ok(());
+                }
+                match (msg.code(), msg.payload().and_then(|p| p.as_u64())) {
+                    (Code::Ok, Some(value)) => put_line(buf, &value.to_string()),
+                    (Code::Miss, _) => buf.extend_from_slice(b"NOT_FOUND\r\n"),
+                    (Code::Error, _) => {
+                        put_line(buf, &format!("CLIENT_ERROR {}", description(&msg)))
+                    }
+                    _ => put_server_error(buf, &msg),
+                }
+            }
+            TextRequest::Stats => {
+                let stats = msg.payload()
+                    .map(|p| String::from_utf8_lossy(p.data()).into_owned())
+                    .unwrap_or_default();
+                for (name, value) in parse_stats(&stats) {
+                    put_line(buf, &format!("STAT {} {}", name, value));
+                }
+                buf.extend_from_slice(b"END\r\n");
+            }
+            TextRequest::Version => put_line(buf, &format!("VERSION {}", env!("CARGO_PKG_VERSION"))),
+            TextRequest::Error => buf.extend_from_slice(b"ERROR\r\n"),
+            TextRequest::ClientError => {
+                put_line(buf, &format!("CLIENT_ERROR {}", description(&msg)))
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Write a `VALUE` line and data block for a `get` hit.
+fn put_value(buf: &mut BytesMut, key: &[u8], payload: &Payload, cas: bool) {
+    let data = match payload.as_u64() {
+        Some(n) => n.to_string().into_bytes(),
+        None => payload.data().to_vec(),
+    };
+    let flags = payload.flags();
+
+    buf.extend_from_slice(b"VALUE ");
+    buf.extend_from_slice(key);
+    if cas {
+        put_line(buf, &format!(" {} {} {}", flags, data.len(), payload.version()));
+    } else {
+        put_line(buf, &format!(" {} {}", flags, data.len()));
+    }
+    buf.extend_from_slice(&data);
+    buf.extend_from_slice(b"\r\n");
+}
+
+fn put_server_error(buf: &mut BytesMut, msg: &Message) {
+    match msg.code() {
+        Code::ReadOnly => put_line(buf, "SERVER_ERROR read only replica"),
+        Code::Moved => put_line(buf, &format!("SERVER_ERROR moved to {}", description(msg))),
+        _ => put_line(buf, &format!("SERVER_ERROR {}", description(msg))),
+    }
+}
+
+fn put_line(buf: &mut BytesMut, line: &str) {
+    buf.extend_from_slice(line.as_bytes());
+    buf.extend_from_slice(b"\r\n");
+}
+
+/// The description carried by an error response.
+fn description(msg: &Message) -> String {
+    match msg.payload() {
+        Some(payload) => String::from_utf8_lossy(payload.data()).into_owned(),
+        None => format!("unexpected response {}", msg),
+    }
+}
+
+/// Split a stats string such as `keys: 10, shard_keys: [4, 6], avg_request_time: 3 μs` into
+/// (name, value) pairs. Values are cut at the first space, and commas inside lists are kept.
+pub(crate) fn parse_stats(stats: &str) -> Vec<(String, String)> {
+    let mut pairs: Vec<(String, String)> = Vec::new();
+    for part in stats.split(", ") {
+        match part.find(": ") {
+            Some(i) => {
+                let value = part[i + 2..].split(' ').next().unwrap_or("");
+                pairs.push((part[..i].to_owned(), value.to_owned()));
+            }
+            None => {
+                if let Some(last) = pairs.last_mut() {
+                    last.1.push(',');
+                    last.1.push_str(part);
+                }
+            }
+        }
+    }
+    pairs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::time::Duration;
+    use service::Protocol;
+    use testing::{self, exchange};
+
+    fn encode(req: TextRequest, msg: Message) -> String {
+        let mut buf = BytesMut::new();
+        TextCodec::default().encode((req, msg), &mut buf).unwrap();
+        String::from_utf8(buf.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_decode() {
+        let items = testing::decode_all(
+            &mut TextCodec::default(),
+            b"set foo 5 60 3\r\nbar\r\nget foo baz\r\ndelete foo noreply\r\nincr n 2\r\n\
+              cas foo 0 0 1 7\r\nx\r\n",
+        );
+
+        assert_eq!(items[0].0, TextRequest::Store { noreply: false });
+        assert_eq!(
+            items[0].1,
+            message::request(
+                Op::Set,
+                "foo".into(),
+                Some(message::payload(0, "bar".into()).with_flags(5).with_ttl(60)),
+            )
+        );
+        assert_eq!(
+            items[1].1,
+            message::batch_request(Op::Get, vec![("foo".into(), None), ("baz".into(), None)])
+        );
+        assert_eq!(items[2].0, TextRequest::Delete { noreply: true });
+        assert_eq!(items[2].1, message::request(Op::Del, "foo".into(), None));
+        assert_eq!(
+            items[3].1,
+            message::request(Op::Incr, "n".into(), Some(message::counter_payload(2, None)))
+        );
+        assert_eq!(items[4].1.op(), Op::Cas);
+        assert_eq!(items[4].1.payload().unwrap().version(), 7);
+    }
+
+    #[test]
+    fn test_decode_partial() {
+        let mut buf = BytesMut::from(&b"set foo 0 0 6\r\nbar"[..]);
+        let mut codec = TextCodec::default();
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+
+        buf.extend_from_slice(b"baz\r\n");
+        let (_, msg) = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(msg.payload().unwrap().data(), b"barbaz");
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let input = b"bogus\r\nset foo 0 0 x\r\nset foo 0 0 1\r\nab\r\nget\r\n";
+        let items = testing::decode_all(&mut TextCodec::default(), input);
+        assert_eq!(items[0].0, TextRequest::Error);
+        assert_eq!(items[1].0, TextRequest::ClientError);
+        assert_eq!(items[2].0, TextRequest::ClientError);
+        // Like memcached, whatever follows a bad data chunk is read as the next command.
+        assert_eq!(items[3].0, TextRequest::Error);
+        assert_eq!(items[4].0, TextRequest::ClientError);
+        assert!(items.iter().all(|item| item.1.code() == Code::Error));
+        let ops: Vec<Op> = items.iter().map(|item| item.1.op()).collect();
+        assert_eq!(ops, vec![Op::Get, Op::Set, Op::Set, Op::Get, Op::Get]);
+
+        let mut buf = BytesMut::from(vec![b'a'; MAX_LINE_LEN + Limits::default().max_key_len + 1]);
+        assert!(TextCodec::default().decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut codec = TextCodec::with_limits(Limits {
+            max_key_len: 4,
+            max_value_len: 8,
+            ..Limits::default()
+        });
+        let items = testing::decode_all(&mut codec, b"get fooba\r\nset foob 0 0 8\r\n12345678\r\n");
+        assert_eq!(items[0].0, TextRequest::ClientError);
+        assert_eq!(items[1].1.payload().unwrap().data(), b"12345678");
+
+        let mut buf = BytesMut::from(&b"set foo 0 0 9\r\n"[..]);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_ttl() {
+        assert_eq!(ttl(0, 1_500_000_000), Some(0));
+        assert_eq!(ttl(60, 1_500_000_000), Some(60));
+        assert_eq!(ttl(-1, 1_500_000_000), None);
+        assert_eq!(ttl(1_500_000_100, 1_500_000_000), Some(100));
+        assert_eq!(ttl(1_400_000_000, 1_500_000_000), None);
+    }
+
+    #[test]
+    fn test_encode() {
+        let get = TextRequest::Get {
+            keys: vec!["foo".into(), "baz".into(), "n".into()],
+            cas: true,
+        };
+        let resp = message::batch_response(
+            Op::Get,
+            vec![
+                (Code::Hit, Some(message::payload(0, "bar".into()).with_flags(5).with_version(3))),
+                (Code::Miss, None),
+                (Code::Hit, Some(message::u64_payload(42).with_version(4))),
+            ],
+        );
+        assert_eq!(
+            encode(get, resp),
+            "VALUE foo 5 3 3\r\nbar\r\nVALUE n 0 2 4\r\n42\r\nEND\r\n"
+        );
+
+        let store = TextRequest::Store { noreply: false };
+        assert_eq!(encode(store.clone(), message::response(Op::Add, Code::NotStored, None)), "NOT_STORED\r\n");
+        assert_eq!(encode(store, message::response(Op::Cas, Code::Exists, None)), "EXISTS\r\n");
+        assert_eq!(
+            encode(TextRequest::Store { noreply: true }, message::response(Op::Set, Code::Ok, None)),
+            ""
+        );
+
+        let stats = message::response(
+            Op::Stats,
+            Code::Ok,
+            Some(message::payload(1, "keys: 2, shard_keys: [1, 1], avg_request_time: 3 μs".into())),
+        );
+        assert_eq!(
+            encode(TextRequest::Stats, stats),
+            "STAT keys 2\r\nSTAT shard_keys [1,1]\r\nSTAT avg_request_time 3\r\nEND\r\n"
+        );
+    }
+
+    #[test]
+    fn test_transcript() {
+        let addr = testing::spawn_cache(Protocol::MemcachedText);
+
+        let mut stream = TcpStream::connect(&addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        exchange(&mut stream, "get foo\r\n", "END\r\n");
+        exchange(&mut stream, "set foo 3 0 3\r\nbar\r\n", "STORED\r\n");
+        exchange(&mut stream, "add foo 0 0 1\r\nx\r\n", "NOT_STORED\r\n");
+        exchange(&mut stream, "append foo 0 0 3\r\nbaz\r\n", "STORED\r\n");
+        exchange(&mut stream, "get foo missing\r\n", "VALUE foo 3 6\r\nbarbaz\r\nEND\r\n");
+
+        exchange(&mut stream, "set n 0 0 2 noreply\r\n10\r\n", "");
+        exchange(&mut stream, "incr n 5\r\n", "15\r\n");
+        exchange(&mut stream, "decr n 20\r\n", "0\r\n");
+        exchange(&mut stream, "incr foo 1\r\n", "CLIENT_ERROR value is not a 64-bit integer\r\n");
+        exchange(&mut stream, "incr missing 1\r\n", "NOT_FOUND\r\n");
+
+        // Flags that happen to be the type id of counters don't make a value one.
+        exchange(&mut stream, "set raw 8 0 8\r\n12345678\r\n", "STORED\r\n");
+        exchange(&mut stream, "get raw\r\n", "VALUE raw 8 8\r\n12345678\r\nEND\r\n");
+        exchange(&mut stream, "incr raw 1\r\n", "12345679\r\n");
+        exchange(&mut stream, "get raw\r\n", "VALUE raw 8 8\r\n12345679\r\nEND\r\n");
+
+        exchange(&mut stream, "cas foo 0 0 1 999\r\nx\r\n", "EXISTS\r\n");
+        exchange(&mut stream, "delete foo\r\n", "DELETED\r\n");
+        exchange(&mut stream, "delete foo\r\n", "NOT_FOUND\r\n");
+        exchange(&mut stream, "cas foo 0 0 1 1\r\nx\r\n", "NOT_FOUND\r\n");
+
+        exchange(&mut stream, "flush_all\r\n", "ERROR\r\n");
+        exchange(&mut stream, "set foo 0 0 1\r\nxy\r\n", "CLIENT_ERROR bad data chunk\r\nERROR\r\n");
+        exchange(&mut stream, "version\r\n", &format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION")));
+
+        stream.write_all(b"stats\r\n").unwrap();
+        let mut buf = [0; 10];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"STAT keys ");
+    }
+}
@@ -0,0 +1,174 @@
+use futures::{Async, Poll, Stream};
+use mio_uds;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net;
+use std::path::{Path, PathBuf};
+use std::process;
+use tokio_core::reactor::{Handle, PollEvented};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+/// A Unix domain socket listener on the tokio-core reactor, built on `mio_uds`.
+pub struct UnixListener {
+    io: PollEvented<mio_uds::UnixListener>,
+    handle: Handle,
+}
+
+impl UnixListener {
+    /// Bind to `path` on the reactor, see `bind`.
+    pub fn bind(path: &Path, mode: Option<u32>, handle: &Handle) -> io::Result<UnixListener> {
+        UnixListener::from_listener(bind(path, mode)?, handle)
+    }
+
+    /// Serve a listener that is already bound on the reactor.
+    pub fn from_listener(
+        listener: mio_uds::UnixListener,
+        handle: &Handle,
+    ) -> io::Result<UnixListener> {
+        Ok(UnixListener {
+            io: PollEvented::new(listener, handle)?,
+            handle: handle.clone(),
+        })
+    }
+
+    /// The stream of accepted connections.
+    pub fn incoming(self) -> Incoming {
+        Incoming { listener: self }
+    }
+}
+
+/// Bind to `path`, replacing a socket left behind by an earlier server, but not one a server is
+/// still listening on. If `mode` is given, the socket file gets those permissions before it
+/// appears at `path`, so that access can be limited to a user or group from the start.
+pub fn bind(path: &Path, mode: Option<u32>) -> io::Result<mio_uds::UnixListener> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            match net::UnixStream::connect(path) {
+                Ok(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AddrInUse,
+                        format!("a server is already listening on {}", path.display()),
+                    ))
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                    fs::remove_file(path)?
+                }
+                Err(_) => {}
+            }
+        }
+    }
+
+    let mode = match mode {
+        Some(mode) => mode,
+        None => return mio_uds::UnixListener::bind(path),
+    };
+
+    // Bind in a directory only we can enter, which sits beside `path` so that the socket can be
+    // renamed into place once nobody else could have connected to it under the wrong permissions.
+    let private = private_dir(path);
+    fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let bound = private.join("sock");
+    let listener = mio_uds::UnixListener::bind(&bound).and_then(|listener| {
+        fs::set_permissions(&bound, fs::Permissions::from_mode(mode))?;
+        fs::rename(&bound, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_file(&bound);
+    fs::remove_dir(&private)?;
+    listener
+}
+
+/// A directory name beside `path` for `bind` to use while setting the socket's permissions.
+fn private_dir(path: &Path) -> PathBuf {
+    let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    path.with_file_name(format!(".{}.{}.tmp", name, process::id()))
+}
+
+/// A `Stream` of the connections accepted by a `UnixListener`.
+pub struct Incoming {
+    listener: UnixListener,
+}
+
+impl Stream for Incoming {
+    type Item = UnixStream;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<UnixStream>, io::Error> {
+        if let Async::NotReady = self.listener.io.poll_read() {
+            return Ok(Async::NotReady);
+        }
+
+        match self.listener.io.get_ref().accept() {
+            Ok(Some((stream, _))) => {
+                let stream = UnixStream { io: PollEvented::new(stream, &self.listener.handle)? };
+                Ok(Async::Ready(Some(stream)))
+            }
+            Ok(None) => {
+                self.listener.io.need_read();
+                Ok(Async::NotReady)
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                self.listener.io.need_read();
+                Ok(Async::NotReady)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// A Unix domain socket connection on the tokio-core reactor.
+pub struct UnixStream {
+    io: PollEvented<mio_uds::UnixStream>,
+}
+
+impl UnixStream {
+    pub fn connect(path: &Path, handle: &Handle) -> io::Result<UnixStream> {
+        let stream = mio_uds::UnixStream::connect(path)?;
+        Ok(UnixStream { io: PollEvented::new(stream, handle)? })
+    }
+}
+
+impl Read for UnixStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.io.read(buf)
+    }
+}
+
+impl Write for UnixStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.io.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.flush()
+    }
+}
+
+impl AsyncRead for UnixStream {}
+
+impl AsyncWrite for UnixStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.io.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing;
+
+    #[test]
+    fn test_bind_replaces_stale_socket() {
+        let dir = testing::TempDir::new();
+        let path = dir.path().join("rcache.sock");
+
+        let listener = bind(&path, None).unwrap();
+        assert_eq!(bind(&path, None).unwrap_err().kind(), io::ErrorKind::AddrInUse);
+
+        drop(listener);
+        bind(&path, Some(0o600)).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}